@@ -0,0 +1,6 @@
+-- Leases for fleet wide maintenance tasks (e.g. purge_ttl)
+CREATE TABLE `maintenance_locks` (
+    `name` VARCHAR(64) PRIMARY KEY NOT NULL,
+    -- lease expiration in milliseconds since epoch
+    `expiry` BIGINT                NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
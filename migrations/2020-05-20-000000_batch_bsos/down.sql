@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS `batch_bsos`;
+ALTER TABLE `batches` ADD COLUMN `bsos` LONGTEXT NOT NULL;
@@ -0,0 +1,26 @@
+-- Appended batch BSOs, replacing the serialized batches.bsos column. Its bso
+-- fields are nullable as the batch upload may or may not set each individual
+-- field of each item. There's no modified column as the modification
+-- timestamp gets set on batch commit.
+CREATE TABLE `batch_bsos` (
+    `userid` BIGINT(20)  NOT NULL,
+    `collection` INT(11) NOT NULL,
+    `batch_id` BIGINT    NOT NULL,
+    `id` VARCHAR(64)     NOT NULL,
+
+    `sortindex` INT,
+    `payload` MEDIUMTEXT,
+    -- in seconds
+    `ttl` BIGINT,
+
+    PRIMARY KEY (`userid`, `collection`, `batch_id`, `id`),
+    FOREIGN KEY (`userid`, `collection`, `batch_id`)
+        REFERENCES `batches` (`userid`, `collection`, `id`)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
+
+-- NOTE: discards any batches pending during the migration (their serialized
+-- BSOs aren't converted, run_migrations logs how many): their clients' commits
+-- fail as with expired batches, restarting their uploads
+DELETE FROM `batches`;
+ALTER TABLE `batches` DROP COLUMN `bsos`;
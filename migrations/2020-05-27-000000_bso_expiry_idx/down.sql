@@ -0,0 +1 @@
+DROP INDEX `bso_usr_col_exp_idx` ON `bso`;
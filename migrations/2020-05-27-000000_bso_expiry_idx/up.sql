@@ -0,0 +1,3 @@
+-- Covers every collection read's unexpired row filtering (userid,
+-- collection, ttl > ?)
+CREATE INDEX `bso_usr_col_exp_idx` ON `bso` (`userid`, `collection`, `ttl`);
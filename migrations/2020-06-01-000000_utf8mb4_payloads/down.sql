@@ -0,0 +1,7 @@
+-- NOTE: characters unrepresentable in latin1 are lost
+ALTER TABLE `batch_bsos`
+    DEFAULT CHARACTER SET latin1,
+    MODIFY `payload` MEDIUMTEXT CHARACTER SET latin1;
+ALTER TABLE `bso`
+    DEFAULT CHARACTER SET latin1,
+    MODIFY `payload` MEDIUMTEXT CHARACTER SET latin1 NOT NULL;
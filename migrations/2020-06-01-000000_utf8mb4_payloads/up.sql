@@ -0,0 +1,10 @@
+-- Store payloads as full (4-byte) UTF-8: latin1/utf8 (3-byte) columns reject
+-- or mangle astral plane characters (e.g. emoji). batch_bsos replaced the
+-- serialized batches.bsos column. Ids remain latin1 (their case sensitivity
+-- and key lengths are unchanged).
+ALTER TABLE `bso`
+    DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_bin,
+    MODIFY `payload` MEDIUMTEXT CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL;
+ALTER TABLE `batch_bsos`
+    DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_bin,
+    MODIFY `payload` MEDIUMTEXT CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
@@ -0,0 +1,3 @@
+-- Covers sort=index keyset pagination, ordered by (sortindex, id): InnoDB
+-- secondary indexes implicitly end with the primary key's id
+CREATE INDEX `bso_usr_col_sortindex_idx` ON `bso` (`userid`, `collection`, `sortindex`);
@@ -0,0 +1,70 @@
+//! Export all of a user's data from a Sync Storage database.
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{export::export_user, pool_from_settings},
+    logging::{init_logging, reset_logging},
+    server::metrics::Metrics,
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Export all of a user's collections and BSOs from the configured database
+(MySQL or Spanner) to a file of newline delimited JSON: one BSO per line.
+
+Usage: export_user [options] <legacy_id> <fxa_uid> <fxa_kid> <output>
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    arg_legacy_id: u64,
+    arg_fxa_uid: String,
+    arg_fxa_kid: String,
+    arg_output: String,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(&settings).expect("Logging failed to initialize");
+
+    let pool = pool_from_settings(&settings, &Metrics::noop()).map_err(|e| e.to_string())?;
+    let user_id = HawkIdentifier {
+        legacy_id: args.arg_legacy_id,
+        fxa_uid: args.arg_fxa_uid,
+        fxa_kid: args.arg_fxa_kid,
+    };
+    let mut out = BufWriter::new(File::create(&args.arg_output)?);
+
+    let db = pool.get().await.map_err(|e| e.to_string())?;
+    db.begin(false).await.map_err(|e| e.to_string())?;
+    let export = export_user(db.as_ref(), &user_id, &mut out)
+        .await
+        .map_err(|e| e.to_string())?;
+    db.commit().await.map_err(|e| e.to_string())?;
+    info!(
+        "Exported user";
+        "collections" => export.collections,
+        "bsos" => export.bsos,
+        "output" => &args.arg_output
+    );
+    reset_logging();
+    Ok(())
+}
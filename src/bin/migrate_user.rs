@@ -0,0 +1,79 @@
+//! Migrate a user's data between Sync Storage database backends.
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{migrate::migrate_user, pool_from_settings},
+    logging::{init_logging, reset_logging},
+    server::metrics::Metrics,
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Migrate all of a user's collections and BSOs from one database to another.
+
+Safe to rerun: already migrated collections are skipped. Users with open
+batches are refused: rerun once they've been committed or expired.
+
+Usage: migrate_user [options] <source_url> <dest_url> <legacy_id> <fxa_uid> <fxa_kid>
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    arg_source_url: String,
+    arg_dest_url: String,
+    arg_legacy_id: u64,
+    arg_fxa_uid: String,
+    arg_fxa_kid: String,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(&settings).expect("Logging failed to initialize");
+
+    let metrics = Metrics::noop();
+    let pool = |database_url: String| {
+        pool_from_settings(
+            &Settings {
+                database_url,
+                ..settings.clone()
+            },
+            &metrics,
+        )
+        .map_err(|e| e.to_string())
+    };
+    let source = pool(args.arg_source_url)?;
+    let dest = pool(args.arg_dest_url)?;
+    let user_id = HawkIdentifier {
+        legacy_id: args.arg_legacy_id,
+        fxa_uid: args.arg_fxa_uid,
+        fxa_kid: args.arg_fxa_kid,
+    };
+
+    let migration = migrate_user(source.as_ref(), dest.as_ref(), &user_id, &user_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!(
+        "Migrated user";
+        "collections" => migration.migrated.len(),
+        "skipped" => migration.skipped.len(),
+        "bsos" => migration.bsos
+    );
+    reset_logging();
+    Ok(())
+}
@@ -1,399 +1,229 @@
+//! Purge expired BSOs and batches (and unreferenced payload blobs) from a
+//! Sync Storage database.
+#[macro_use]
+extern crate slog_scope;
+
 use std::env;
 use std::error::Error;
-use std::net::UdpSocket;
-use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, Instant};
-
-use cadence::{
-    BufferedUdpMetricSink, Metric, QueuingMetricSink, StatsdClient, Timed, DEFAULT_PORT,
-};
-use googleapis_raw::spanner::v1::{
-    spanner::{
-        BeginTransactionRequest, CommitRequest, CreateSessionRequest, ExecuteSqlRequest, Session,
-    },
-    spanner_grpc::SpannerClient,
-    transaction::{
-        TransactionOptions, TransactionOptions_PartitionedDml, TransactionOptions_ReadOnly,
-        TransactionOptions_ReadWrite, TransactionSelector,
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{
+        blob, params, pool_from_settings,
+        purge::{purge_blobs, purge_expired, PurgeOptions, PURGE_LOCK_NAME},
+        DbPool,
     },
+    error::ApiError,
+    logging::{init_logging, reset_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    web::extractors::HawkIdentifier,
 };
-use grpcio::{CallOption, ChannelBuilder, ChannelCredentials, EnvBuilder, MetadataBuilder};
-use log::{info, trace, warn};
-use url::{Host, Url};
-
-const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
-const RETRY_ENV_VAR: &str = "PURGE_TTL_RETRY_COUNT"; // Default value = 10
-const SLEEP_ENV_VAR: &str = "PURGE_TTL_RETRY_SLEEP_MILLIS"; // Default value = 0
 
-use protobuf::well_known_types::Value;
-
-pub struct MetricTimer {
-    pub client: StatsdClient,
-    pub label: String,
-    pub start: Instant,
+const USAGE: &str = "
+Purge expired BSOs and batches from the configured database (MySQL or Spanner).
+Then, when a payload_blob_store is configured, delete its blobs no longer
+referenced by any BSO or batch.
+
+Only one instance purges at a time: exits without purging when another holds
+the purge lock.
+
+Usage: purge_ttl [options]
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --batch-size=ROWS        Rows deleted per transaction (default: 1000).
+    --max-runtime=SECS       Stop after this many seconds (the remainder is
+                             purged by the next run).
+    --max-rows=ROWS          Move on from a table after purging this many of
+                             its rows (the remainder is purged by the next run).
+    --dry-run                Only count the expired rows (of the first chunk of
+                             each table) without deleting them.
+    --retries=COUNT          Retries of a chunk aborted by a conflict
+                             (default: 10).
+    --retry-sleep=MS         Delay before each retry (default: 0).
+    --lock-ttl=SECS          Lease of the purge lock, when no --max-runtime is
+                             given (default: 3600).
+    --legacy-id=ID           Only purge this user's rows (MySQL).
+    --fxa-uid=UID            Only purge this user's rows (Spanner, along with
+                             --fxa-kid).
+    --fxa-kid=KID            See --fxa-uid.
+    --collection=NAME        Only purge this collection's rows.
+    --blob-grace=SECS        Keep unreferenced blobs put within this many seconds
+                             (their writes may be in flight) [default: 86400].
+
+Environment:
+    The former (Spanner only) purge_ttl's variables remain the defaults of
+    their options: PURGE_TTL_CHUNK_SIZE (--batch-size), PURGE_TTL_MAX_TO_DELETE
+    (--max-rows), PURGE_TTL_RETRY_COUNT (--retries),
+    PURGE_TTL_RETRY_SLEEP_MILLIS (--retry-sleep) and PURGE_TTL_LOCK_TTL_SECS
+    (--lock-ttl).
+
+    Its partitioned DML mode (the default, unless PURGE_TTL_INCREMENTAL was
+    set) was removed: rows are always deleted incrementally, in transactions
+    of --batch-size rows. PURGE_TTL_INCREMENTAL is ignored.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    flag_batch_size: Option<u32>,
+    flag_max_runtime: Option<u32>,
+    flag_max_rows: Option<u64>,
+    flag_dry_run: bool,
+    flag_retries: Option<u32>,
+    flag_retry_sleep: Option<u64>,
+    flag_lock_ttl: Option<u32>,
+    flag_legacy_id: Option<u64>,
+    flag_fxa_uid: Option<String>,
+    flag_fxa_kid: Option<String>,
+    flag_collection: Option<String>,
+    flag_blob_grace: u64,
 }
 
-impl Drop for MetricTimer {
-    fn drop(&mut self) {
-        let lapse = (Instant::now() - self.start).as_millis() as u64;
-        match self.client.time(&self.label, lapse) {
-            Err(e) => {
-                warn!("⚠️ Metric {} error: {:?}", self.label, e);
-            }
-            Ok(v) => {
-                info!("⌚ {:?}", v.as_metric_str());
-            }
-        }
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(&settings).expect("Logging failed to initialize");
+
+    let result = purge(&settings, args).await;
+    if let Err(ref e) = result {
+        error!("Purge failed: {}", e);
     }
+    reset_logging();
+    result
 }
 
-pub fn start_timer(client: &StatsdClient, label: &str) -> MetricTimer {
-    trace!("⌚ Starting timer... {:?}", label);
-    MetricTimer {
-        start: Instant::now(),
-        label: label.to_owned(),
-        client: client.clone(),
-    }
-}
+async fn purge(settings: &Settings, args: Args) -> Result<(), Box<dyn Error>> {
+    let metrics = Metrics::from(&metrics_from_opts(settings).map_err(|e| e.to_string())?);
+    let pool = pool_from_settings(settings, &metrics).map_err(|e| e.to_string())?;
 
-pub fn statsd_from_env() -> Result<StatsdClient, Box<dyn Error>> {
-    let statsd_host = env::var("STATSD_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let statsd_port = match env::var("STATSD_PORT") {
-        Ok(port) => port.parse::<u16>()?,
-        Err(_) => DEFAULT_PORT,
-    };
-
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_nonblocking(true)?;
-    let host = (statsd_host.as_str(), statsd_port);
-    let udp_sink = BufferedUdpMetricSink::from(host, socket)?;
-    let sink = QueuingMetricSink::from(udp_sink);
-    let builder = StatsdClient::builder("syncstorage", sink);
-
-    Ok(builder
-        .with_error_handler(|err| {
-            warn!("Metric send error: {:?}", err);
+    let user_id = if args.flag_legacy_id.is_some()
+        || args.flag_fxa_uid.is_some()
+        || args.flag_fxa_kid.is_some()
+    {
+        Some(HawkIdentifier {
+            legacy_id: args.flag_legacy_id.unwrap_or_default(),
+            fxa_uid: args.flag_fxa_uid.unwrap_or_default(),
+            fxa_kid: args.flag_fxa_kid.unwrap_or_default(),
         })
-        .build())
-}
-
-pub enum RequestType {
-    ReadOnly,
-    ReadWrite,
-    PartitionedDml,
-}
-
-fn begin_transaction(
-    client: &SpannerClient,
-    session: &Session,
-    request_type: RequestType,
-) -> Result<(ExecuteSqlRequest, Vec<u8>), Box<grpcio::Error>> {
-    // Create a transaction
-    let mut opt = TransactionOptions::new();
-    match request_type {
-        RequestType::ReadWrite => {
-            opt.set_read_write(TransactionOptions_ReadWrite::new());
-        }
-        RequestType::ReadOnly => {
-            opt.set_read_only(TransactionOptions_ReadOnly::new());
-        }
-        RequestType::PartitionedDml => {
-            opt.set_partitioned_dml(TransactionOptions_PartitionedDml::new());
-        }
-    }
-
-    let mut req = BeginTransactionRequest::new();
-    req.set_session(session.get_name().to_owned());
-    req.set_options(opt);
-    let mut txn = client.begin_transaction(&req)?;
-
-    let id = txn.take_id();
-    let mut ts = TransactionSelector::new();
-    ts.set_id(id.clone());
-
-    let mut req = ExecuteSqlRequest::new();
-    req.set_session(session.get_name().to_string());
-    req.set_transaction(ts);
-
-    Ok((req, id))
-}
-
-fn continue_transaction(
-    session: &Session,
-    transaction_id: Vec<u8>,
-) -> Result<ExecuteSqlRequest, Box<grpcio::Error>> {
-    let mut ts = TransactionSelector::new();
-    ts.set_id(transaction_id);
-    let mut req = ExecuteSqlRequest::new();
-    req.set_session(session.get_name().to_string());
-    req.set_transaction(ts);
-    Ok(req)
-}
-
-fn commit_transaction(
-    client: &SpannerClient,
-    session: &Session,
-    txn: Vec<u8>,
-) -> Result<(), Box<grpcio::Error>> {
-    let mut req = CommitRequest::new();
-    req.set_session(session.get_name().to_owned());
-    req.set_transaction_id(txn);
-    client.commit(&req)?;
-    Ok(())
-}
-
-pub struct SyncResultSet {
-    result: googleapis_raw::spanner::v1::result_set::ResultSet,
-}
-
-impl Iterator for SyncResultSet {
-    type Item = Vec<Value>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let rows = &mut self.result.rows;
-        if rows.is_empty() {
-            None
-        } else {
-            let row = rows.remove(0);
-            Some(row.get_values().to_vec())
-        }
+    } else {
+        None
+    };
+    if env::var("PURGE_TTL_INCREMENTAL").is_ok() {
+        warn!("PURGE_TTL_INCREMENTAL is ignored: rows are always deleted incrementally");
     }
-}
+    let blob_grace = Duration::from_secs(args.flag_blob_grace);
+    let opts = PurgeOptions {
+        batch_size: flag_or_env(args.flag_batch_size, "PURGE_TTL_CHUNK_SIZE", 1000)?,
+        max_runtime: args
+            .flag_max_runtime
+            .map(|secs| Duration::from_secs(u64::from(secs))),
+        max_rows: match args.flag_max_rows {
+            Some(rows) => Some(rows),
+            None => env_var("PURGE_TTL_MAX_TO_DELETE")?,
+        },
+        dry_run: args.flag_dry_run,
+        retries: flag_or_env(args.flag_retries, "PURGE_TTL_RETRY_COUNT", 10)?,
+        retry_sleep: Duration::from_millis(flag_or_env(
+            args.flag_retry_sleep,
+            "PURGE_TTL_RETRY_SLEEP_MILLIS",
+            0,
+        )?),
+        user_id,
+        collection: args.flag_collection,
+    };
 
-fn delete_incremental(
-    client: &SpannerClient,
-    session: &Session,
-    table: String,
-    column: String,
-    chunk_size: u64,
-    max_to_delete: u64,
-) -> Result<(), Box<grpcio::Error>> {
-    let mut total: u64 = 0;
-    let (mut req, mut txn) = begin_transaction(&client, &session, RequestType::ReadWrite)?;
-    loop {
-        let select_sql = format!("SELECT fxa_uid, fxa_kid, collection_id, {} FROM {} WHERE expiry < CURRENT_TIMESTAMP() LIMIT {}", column, table, chunk_size);
-        trace!("Selecting rows to delete: {}", select_sql);
-        req.set_sql(select_sql.clone());
-        let mut result = SyncResultSet {
-            result: client.execute_sql(&req)?,
+    if !opts.dry_run {
+        let ttl = match args.flag_max_runtime {
+            Some(secs) => secs,
+            None => flag_or_env(args.flag_lock_ttl, "PURGE_TTL_LOCK_TTL_SECS", 60 * 60)?,
         };
-
-        if result.result.rows.is_empty() || total >= max_to_delete {
-            info!("{}: done", table);
-            break;
-        }
-        let mut delete_sql = format!(
-            "DELETE FROM {} WHERE (fxa_uid, fxa_kid, collection_id, {}) IN (",
-            table, column,
-        );
-        for row in &mut result {
-            // Count starting at 1 so that i % chunk_size is false when on the first row
-            let fxa_uid = row[0].get_string_value().to_owned();
-            let fxa_kid = row[1].get_string_value().to_owned();
-            let collection_id = row[2].get_string_value().parse::<i32>().unwrap();
-            let id = row[3].get_string_value().to_owned();
-            trace!(
-                "Selected row for delete: i={} fxa_uid={} fxa_kid={} collection_id={} {}={}",
-                total,
-                fxa_uid,
-                fxa_kid,
-                collection_id,
-                column,
-                id
-            );
-            delete_sql = format!(
-                "{}('{}', '{}', {}, '{}'), ",
-                delete_sql, fxa_uid, fxa_kid, collection_id, id
+        let acquired = match try_acquire_lock(pool.as_ref(), ttl).await {
+            // A competing instance won the race for the lock
+            Err(e) if e.is_conflict() => false,
+            result => result.map_err(|e| e.to_string())?,
+        };
+        if !acquired {
+            info!(
+                "Another instance holds the {} lock, exiting",
+                PURGE_LOCK_NAME
             );
-
-            total += 1;
+            return Ok(());
         }
-        delete_sql = format!(
-            "{})",
-            delete_sql.trim_end_matches(&", ".to_string()).to_string()
-        );
-        trace!("Deleting chunk with: {}", delete_sql);
-        let mut delete_req = continue_transaction(&session, txn.clone())?;
-        delete_req.set_sql(delete_sql);
-        client.execute_sql(&delete_req)?;
-        info!("{}: removed {} rows", table, total);
-        commit_transaction(&client, &session, txn.clone())?;
-        let (newreq, newtxn) = begin_transaction(&client, &session, RequestType::ReadWrite)?;
-        req = newreq;
-        txn = newtxn;
     }
 
-    Ok(())
-}
-
-fn delete_all(
-    client: &SpannerClient,
-    session: &Session,
-    table: String,
-) -> Result<(), Box<grpcio::Error>> {
-    let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
-    req.set_sql(format!(
-        "DELETE FROM {} WHERE expiry < CURRENT_TIMESTAMP()",
-        table
-    ));
-    let result = client.execute_sql(&req)?;
+    let purge = purge_expired(pool.as_ref(), &opts, &metrics)
+        .await
+        .map_err(|e| e.to_string())?;
     info!(
-        "{}: removed {} rows",
-        table,
-        result.get_stats().get_row_count_lower_bound()
+        "Completed purge_ttl";
+        "dry_run" => opts.dry_run,
+        "batches" => purge.batches,
+        "bsos" => purge.bsos,
+        "timed_out" => purge.timed_out
     );
-    Ok(())
-}
 
-fn retryable(err: &grpcio::Error) -> bool {
-    // if it is NOT an ABORT, we should not retry this function.
-    match err {
-        grpcio::Error::RpcFailure(ref status)
-            if status.status == grpcio::RpcStatusCode::ABORTED =>
-        {
-            true
+    // Purges scoped to a user or collection leave the (shared) blobs alone
+    let full = opts.user_id.is_none() && opts.collection.is_none();
+    if let Some(store) = blob::from_settings(settings).map_err(|e| e.to_string())? {
+        if !opts.dry_run && !purge.timed_out && full {
+            let blobs = purge_blobs(pool.as_ref(), store, blob_grace, &metrics)
+                .await
+                .map_err(|e| e.to_string())?;
+            info!("Completed blob purge"; "blobs" => blobs);
         }
-        grpcio::Error::RpcFinished(Some(ref status))
-            if status.status == grpcio::RpcStatusCode::ABORTED =>
-        {
-            true
-        }
-        _ => false,
     }
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::try_init()?;
-
-    let chunk_size: u64 = env::var("PURGE_TTL_CHUNK_SIZE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-    let max_to_delete: u64 = env::var("PURGE_TTL_MAX_TO_DELETE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-
-    const INCREMENTAL_ENV: &str = "PURGE_TTL_INCREMENTAL";
-    let incremental = env::var(INCREMENTAL_ENV)
-        .map(|x| x == "1" || x.to_lowercase() == "true")
-        .unwrap_or(false);
-    info!("INCREMENTAL: {:?}", incremental);
-
-    const DB_ENV: &str = "SYNC_DATABASE_URL";
-    let db_url = env::var(DB_ENV).map_err(|_| format!("Invalid or undefined {}", DB_ENV))?;
-    let url = Url::parse(&db_url).map_err(|e| format!("Invalid {}: {}", DB_ENV, e))?;
-    if url.scheme() != "spanner" || url.host() != Some(Host::Domain("projects")) {
-        return Err(format!("Invalid {}", DB_ENV).into());
+/// An option's value: its flag's, else its environment variable's, else
+/// `default`
+fn flag_or_env<T>(flag: Option<T>, var: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match flag {
+        Some(value) => Ok(value),
+        None => Ok(env_var(var)?.unwrap_or(default)),
     }
-    let retries: u64 =
-        str::parse::<u64>(&env::var(RETRY_ENV_VAR).unwrap_or_else(|_| "10".to_owned()))
-            .unwrap_or(10);
-    let nap_time: Duration = Duration::from_millis(
-        str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
-    );
-
-    let database = db_url["spanner://".len()..].to_owned();
-    info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
-    info!("For {}", database);
-
-    // Set up the gRPC environment.
-    let env = Arc::new(EnvBuilder::new().build());
-    let creds = ChannelCredentials::google_default_credentials()?;
-
-    // Create a Spanner client.
-    let chan = ChannelBuilder::new(env)
-        .max_send_message_len(100 << 20)
-        .max_receive_message_len(100 << 20)
-        .secure_connect(SPANNER_ADDRESS, creds);
-    let client = SpannerClient::new(chan);
-
-    // Create a session
-    let mut req = CreateSessionRequest::new();
-    req.set_database(database.to_string());
-    let mut meta = MetadataBuilder::new();
-    meta.add_str("google-cloud-resource-prefix", &database)?;
-    meta.add_str("x-goog-api-client", "googleapis-rs")?;
-    let opt = CallOption::default().headers(meta.build());
-    let session = client.create_session_opt(&req, opt)?;
-
-    let statsd = statsd_from_env()?;
+}
 
-    {
-        let _timer_total = start_timer(&statsd, "purge_ttl.total_duration");
-        {
-            let _timer_batches = start_timer(&statsd, "purge_ttl.batches_duration");
-            let mut success = false;
-            for i in 0..retries {
-                match if incremental {
-                    delete_incremental(
-                        &client,
-                        &session,
-                        "batches".to_owned(),
-                        "batch_id".to_owned(),
-                        chunk_size,
-                        max_to_delete,
-                    )
-                } else {
-                    delete_all(&client, &session, "batches".to_owned())
-                } {
-                    Ok(_) => {
-                        success = true;
-                        break;
-                    }
-                    Err(e) => {
-                        warn!("Batch transaction error: {}: {:?}", i, e);
-                        if !retryable(&e) {
-                            return Err(e);
-                        }
-                        if nap_time.as_millis() > 0 {
-                            thread::sleep(nap_time);
-                        }
-                    }
-                }
-            }
-            if !success {
-                panic!(
-                    "Could not delete expired batches after {} attempts",
-                    retries
-                );
-            }
-        }
-        {
-            let _timer_bso = start_timer(&statsd, "purge_ttl.bso_duration");
-            let mut success = false;
-            for i in 0..retries {
-                match if incremental {
-                    delete_incremental(
-                        &client,
-                        &session,
-                        "bsos".to_owned(),
-                        "bso_id".to_owned(),
-                        chunk_size,
-                        max_to_delete,
-                    )
-                } else {
-                    delete_all(&client, &session, "bsos".to_owned())
-                } {
-                    Ok(_) => {
-                        success = true;
-                        break;
-                    }
-                    Err(e) => {
-                        warn!("BSO transaction error {}: {:?}", i, e);
-                        if nap_time.as_millis() > 0 {
-                            thread::sleep(nap_time);
-                        }
-                    }
-                }
-            }
-            if !success {
-                panic!("Could not delete expired bsos after {} attempts", retries);
-            }
-        }
-        info!("Completed purge_ttl");
+/// The value of an environment variable, when set
+fn env_var<T>(var: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid {}: {}", var, e).into()),
+        Err(_) => Ok(None),
     }
+}
 
-    Ok(())
+/// Take the fleet wide purge lock for `ttl` seconds
+async fn try_acquire_lock(pool: &dyn DbPool, ttl: u32) -> Result<bool, ApiError> {
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let acquired = db
+        .try_acquire_maintenance_lock(params::TryAcquireMaintenanceLock {
+            name: PURGE_LOCK_NAME.to_owned(),
+            ttl,
+        })
+        .await?;
+    db.commit().await?;
+    Ok(acquired)
 }
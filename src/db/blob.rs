@@ -0,0 +1,213 @@
+//! External stores of large BSO payloads.
+//!
+//! When a `payload_blob_store` is configured, payloads exceeding
+//! `payload_blob_threshold` bytes are offloaded to it (by the `Offloading`
+//! codec), leaving only a reference to their blob in the `payload` column.
+//!
+//! Blobs aren't deleted along with their records (they're content
+//! addressed, so possibly shared by several): the unreferenced ones are
+//! instead swept by `collect_garbage` (run by purge_ttl).
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use url::Url;
+
+use super::error::{DbError, DbErrorKind};
+use crate::settings::Settings;
+
+type Result<T> = std::result::Result<T, DbError>;
+
+pub trait BlobStore: fmt::Debug + Send + Sync {
+    /// Store a blob under `key` (replacing any existing one)
+    fn put(&self, key: &str, blob: &[u8]) -> Result<()>;
+
+    /// Read back the blob stored under `key`
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// The keys of every stored blob, along with when each was last put
+    fn list(&self) -> Result<Vec<(String, SystemTime)>>;
+
+    /// Delete the blob stored under `key` (if any)
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Stores blobs as files beneath a directory (which may be e.g. a mounted
+/// S3 or GCS bucket)
+#[derive(Debug)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root).map_err(|e| blob_error(root, e))?;
+        Ok(Self {
+            root: root.to_owned(),
+        })
+    }
+
+    /// Blobs are spread across subdirectories named by their keys' first
+    /// two characters
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.len() < 3 || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            Err(DbErrorKind::Integrity(format!("Invalid blob key: {}", key)))?
+        }
+        Ok(self.root.join(&key[..2]).join(key))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, key: &str, blob: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| blob_error(dir, e))?;
+        }
+        // Written aside then renamed into place, so a blob's never read
+        // partially written
+        let partial = path.with_extension(uuid::Uuid::new_v4().to_simple().to_string());
+        fs::write(&partial, blob).map_err(|e| blob_error(&partial, e))?;
+        fs::rename(&partial, &path).map_err(|e| {
+            let _ = fs::remove_file(&partial);
+            blob_error(&path, e)
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.path(key)?;
+        fs::read(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                DbErrorKind::Integrity(format!("Missing payload blob: {}", key)).into()
+            }
+            _ => blob_error(&path, e),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<(String, SystemTime)>> {
+        let mut blobs = vec![];
+        for dir in fs::read_dir(&self.root).map_err(|e| blob_error(&self.root, e))? {
+            let dir = dir.map_err(|e| blob_error(&self.root, e))?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir).map_err(|e| blob_error(&dir, e))? {
+                let path = entry.map_err(|e| blob_error(&dir, e))?.path();
+                // Skips partially written blobs (named with an extension)
+                let key = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(key) if self.path(key).ok().as_ref() == Some(&path) => key.to_owned(),
+                    _ => continue,
+                };
+                let modified = fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|e| blob_error(&path, e))?;
+                blobs.push((key, modified));
+            }
+        }
+        Ok(blobs)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(blob_error(&path, e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Delete the blobs not among the `referenced` keys, returning how many were
+/// deleted.
+///
+/// Blobs put within the last `grace` are kept regardless: their referencing
+/// writes may not have committed yet (identical payloads re-put their blob,
+/// renewing it).
+pub fn collect_garbage(
+    store: &dyn BlobStore,
+    referenced: &HashSet<String>,
+    grace: Duration,
+) -> Result<u64> {
+    let cutoff = SystemTime::now() - grace;
+    let mut deleted = 0;
+    for (key, modified) in store.list()? {
+        if modified < cutoff && !referenced.contains(&key) {
+            store.delete(&key)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+fn blob_error(path: &Path, e: io::Error) -> DbError {
+    DbError::internal(&format!("Blob store error ({}): {}", path.display(), e))
+}
+
+/// The blob store at the `payload_blob_store` url (if any): currently only
+/// `file://` urls are supported
+pub fn from_settings(settings: &Settings) -> Result<Option<Arc<dyn BlobStore>>> {
+    let url = match &settings.payload_blob_store {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let invalid = || DbError::internal(&format!("Invalid payload_blob_store: {}", url));
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    match parsed.scheme() {
+        "file" => {
+            let root = parsed.to_file_path().map_err(|_| invalid())?;
+            Ok(Some(Arc::new(FsBlobStore::new(&root)?)))
+        }
+        scheme => Err(DbError::internal(&format!(
+            "Unsupported payload_blob_store scheme: {}",
+            scheme
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, env, time::Duration};
+
+    use super::{collect_garbage, BlobStore, FsBlobStore};
+
+    #[test]
+    fn fs_blobs_roundtrip() {
+        let root = env::temp_dir().join(format!("blobs-{}", uuid::Uuid::new_v4()));
+        let store = FsBlobStore::new(&root).unwrap();
+        store.put("abc123", b"blob").unwrap();
+        assert_eq!(store.get("abc123").unwrap(), b"blob");
+        assert!(root.join("ab").join("abc123").is_file());
+        // Replaced in place
+        store.put("abc123", b"blob2").unwrap();
+        assert_eq!(store.get("abc123").unwrap(), b"blob2");
+
+        assert!(store.get("def456").is_err());
+        // Keys never escape the store's directory
+        assert!(store.put("../x", b"blob").is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unreferenced_blobs_collected() {
+        let root = env::temp_dir().join(format!("blobs-{}", uuid::Uuid::new_v4()));
+        let store = FsBlobStore::new(&root).unwrap();
+        store.put("abc123", b"blob").unwrap();
+        store.put("def456", b"blob").unwrap();
+        let referenced: HashSet<_> = vec!["abc123".to_owned()].into_iter().collect();
+
+        // Recently put blobs are kept
+        let grace = Duration::from_secs(60);
+        assert_eq!(collect_garbage(&store, &referenced, grace).unwrap(), 0);
+
+        assert_eq!(
+            collect_garbage(&store, &referenced, Duration::from_secs(0)).unwrap(),
+            1
+        );
+        assert!(store.get("abc123").is_ok());
+        assert!(store.get("def456").is_err());
+        assert_eq!(store.list().unwrap().len(), 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
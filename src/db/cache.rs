@@ -0,0 +1,222 @@
+//! Pool level cache of collection ids and names, shared by every backend.
+//!
+//! Caching is safe because a collection's name is immutable and ids are never
+//! reused (collections rows are never deleted, only users' data within them).
+//! An id/name pair therefore never goes stale once its row is committed.
+//!
+//! The one hazard is caching a row that's never committed: a collection
+//! created (or read back) within a write transaction that's later rolled back.
+//! `Db`s only populate the cache from reads outside of write transactions, so
+//! newly created collections are cached by the first read after they commit.
+//!
+//! Nor can a panic while the cache is locked (poisoning its lock) leave it
+//! inconsistent: at worst a pair's cached in only one direction. The cache
+//! therefore recovers its poisoned locks rather than failing every lookup
+//! thereafter.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use super::{error::DbError, params, Db, STD_COLLS};
+use crate::error::ApiError;
+
+type Result<T> = std::result::Result<T, DbError>;
+
+/// Number of collections read per query when warming the cache
+pub const WARM_BATCH_SIZE: u32 = 1000;
+
+#[derive(Debug)]
+pub struct CollectionCache {
+    pub by_name: RwLock<HashMap<String, i32>>,
+    pub by_id: RwLock<HashMap<i32, String>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The cache's size and lookups, reported by the pool's periodic gauges
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectionCacheStats {
+    pub collections: usize,
+    /// Lookups (by id or name) answered since the cache's creation
+    pub hits: u64,
+    /// Lookups falling through to the db since the cache's creation
+    pub misses: u64,
+}
+
+impl CollectionCache {
+    /// Create a cache prepopulated with the standard collections
+    pub fn new(standard_collections: &[(i32, String)]) -> Self {
+        Self {
+            by_name: RwLock::new(
+                standard_collections
+                    .iter()
+                    .map(|(id, name)| (name.to_owned(), *id))
+                    .collect(),
+            ),
+            by_id: RwLock::new(
+                standard_collections
+                    .iter()
+                    .map(|(id, name)| (*id, name.to_owned()))
+                    .collect(),
+            ),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cache a committed collection row
+    pub fn put(&self, id: i32, name: String) -> Result<()> {
+        // Not locking both maps together: a lookup racing this may only find
+        // the pair in one direction, missing to the db (which agrees, pairs
+        // being immutable). The cache's size is already gauged (see `stats`)
+        write(&self.by_name).insert(name.clone(), id);
+        write(&self.by_id).insert(id, name);
+        Ok(())
+    }
+
+    pub fn get_id(&self, name: &str) -> Result<Option<i32>> {
+        Ok(self.count(read(&self.by_name).get(name).cloned()))
+    }
+
+    pub fn get_name(&self, id: i32) -> Result<Option<String>> {
+        Ok(self.count(read(&self.by_id).get(&id).cloned()))
+    }
+
+    pub fn stats(&self) -> CollectionCacheStats {
+        CollectionCacheStats {
+            collections: read(&self.by_id).len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a lookup as a hit or miss
+    fn count<T>(&self, cached: Option<T>) -> Option<T> {
+        let counter = if cached.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    /// Preload every collection, reading `batch_size` rows at a time, and
+    /// return how many were read.
+    ///
+    /// Reads via `db`'s current transaction (if any), which shouldn't be a
+    /// write transaction (see above).
+    pub async fn warm(&self, db: &dyn Db, batch_size: u32) -> std::result::Result<usize, ApiError> {
+        let mut after_id = 0;
+        let mut count = 0;
+        loop {
+            let collections = db
+                .get_collections(params::GetCollections {
+                    after_id,
+                    limit: batch_size,
+                })
+                .await?;
+            let len = collections.len();
+            for (id, name) in collections {
+                after_id = id;
+                self.put(id, name)?;
+            }
+            count += len;
+            if len < batch_size as usize {
+                return Ok(count);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn clear(&self) {
+        write(&self.by_name).clear();
+        write(&self.by_id).clear();
+    }
+}
+
+/// Lock for reading, recovering the lock when poisoned (the panic that
+/// poisoned it was already reported)
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock for writing, recovering the lock when poisoned
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Default for CollectionCache {
+    fn default() -> Self {
+        Self::new(
+            &STD_COLLS
+                .iter()
+                .map(|(id, name)| (*id, (*name).to_owned()))
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::CollectionCache;
+
+    #[test]
+    fn prepopulated_with_standard_collections() {
+        let cache = CollectionCache::default();
+        assert_eq!(cache.get_id("clients").unwrap(), Some(1));
+        assert_eq!(cache.get_name(13).unwrap(), Some("creditcards".to_owned()));
+        assert_eq!(cache.get_id("custom").unwrap(), None);
+
+        let cache = CollectionCache::new(&[(14, "containers".to_owned())]);
+        assert_eq!(cache.get_id("containers").unwrap(), Some(14));
+        assert_eq!(cache.get_id("clients").unwrap(), None);
+    }
+
+    #[test]
+    fn put_caches_both_directions() {
+        let cache = CollectionCache::default();
+        cache.put(101, "custom".to_owned()).unwrap();
+        assert_eq!(cache.get_id("custom").unwrap(), Some(101));
+        assert_eq!(cache.get_name(101).unwrap(), Some("custom".to_owned()));
+
+        cache.clear();
+        assert_eq!(cache.get_id("custom").unwrap(), None);
+        assert_eq!(cache.get_name(1).unwrap(), None);
+    }
+
+    #[test]
+    fn stats() {
+        let cache = CollectionCache::new(&[(14, "containers".to_owned())]);
+        cache.get_id("containers").unwrap();
+        cache.get_name(14).unwrap();
+        cache.get_id("custom").unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn recovers_from_poisoning() {
+        let cache = Arc::new(CollectionCache::default());
+        let poisoner = Arc::clone(&cache);
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.by_id.write().unwrap();
+            panic!("poisoning by_id");
+        })
+        .join();
+        assert!(cache.by_id.is_poisoned());
+
+        assert_eq!(cache.get_name(1).unwrap(), Some("clients".to_owned()));
+        cache.put(101, "custom".to_owned()).unwrap();
+        assert_eq!(cache.get_name(101).unwrap(), Some("custom".to_owned()));
+        assert_eq!(cache.get_id("custom").unwrap(), Some(101));
+    }
+}
@@ -0,0 +1,335 @@
+//! Codecs applied to BSO payloads at rest.
+//!
+//! Payloads are encoded before they're written to the `payload` column (of
+//! both the bso and batch tables) and decoded after they're read back, so the
+//! stored representation (e.g. encrypted or compressed) never reaches the
+//! wire. Storage usage reflects the stored (encoded) sizes, including those
+//! of offloaded payloads.
+//!
+//! Changing a deployment's codec doesn't re-encode existing payloads: they'd
+//! fail to decode.
+use std::{fmt, sync::Arc};
+
+use actix_web::{error::BlockingError, web::block};
+use ring::digest;
+
+use super::{
+    blob::{self, BlobStore},
+    error::{DbError, DbErrorKind},
+    params, results,
+};
+use crate::settings::Settings;
+
+type Result<T> = std::result::Result<T, DbError>;
+
+pub trait PayloadCodec: fmt::Debug + Send + Sync {
+    /// Encode a payload for storage
+    fn encode(&self, payload: String) -> Result<String>;
+
+    /// Decode a stored payload
+    fn decode(&self, stored: String) -> Result<String>;
+
+    /// Whether encoding or decoding may block on I/O (so should be run off
+    /// the async executor, see `run`)
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    /// Whether stored payloads may be references to offloaded blobs (only
+    /// then are `blob:` prefixed payloads interpreted as such, e.g. by the
+    /// backends' storage usage queries)
+    fn offloads(&self) -> bool {
+        false
+    }
+
+    fn encode_bso(&self, bso: params::PostCollectionBso) -> Result<params::PostCollectionBso> {
+        Ok(params::PostCollectionBso {
+            payload: bso.payload.map(|p| self.encode(p)).transpose()?,
+            ..bso
+        })
+    }
+
+    fn decode_bso(&self, bso: results::GetBso) -> Result<results::GetBso> {
+        Ok(results::GetBso {
+            payload: self.decode(bso.payload)?,
+            ..bso
+        })
+    }
+
+    fn decode_batch_bso(
+        &self,
+        bso: params::PostCollectionBso,
+    ) -> Result<params::PostCollectionBso> {
+        Ok(params::PostCollectionBso {
+            payload: bso.payload.map(|p| self.decode(p)).transpose()?,
+            ..bso
+        })
+    }
+}
+
+/// Stores payloads as is (the default)
+#[derive(Debug, Default)]
+pub struct Identity;
+
+impl PayloadCodec for Identity {
+    fn encode(&self, payload: String) -> Result<String> {
+        Ok(payload)
+    }
+
+    fn decode(&self, stored: String) -> Result<String> {
+        Ok(stored)
+    }
+}
+
+/// Stores payloads base64 encoded. Of little use beyond verifying a
+/// deployment's codec plumbing (it inflates payloads by a third).
+#[derive(Debug, Default)]
+pub struct Base64;
+
+impl PayloadCodec for Base64 {
+    fn encode(&self, payload: String) -> Result<String> {
+        Ok(base64::encode(&payload))
+    }
+
+    fn decode(&self, stored: String) -> Result<String> {
+        let bytes = base64::decode(&stored)
+            .map_err(|e| DbErrorKind::Integrity(format!("Undecodable payload: {}", e)))?;
+        Ok(String::from_utf8(bytes)
+            .map_err(|e| DbErrorKind::Integrity(format!("Undecodable payload: {}", e)))?)
+    }
+}
+
+/// Run a codec operation, on the blocking thread pool when the codec
+/// `blocks`
+pub async fn run<T, F>(codec: &Arc<dyn PayloadCodec>, op: F) -> Result<T>
+where
+    F: FnOnce(&dyn PayloadCodec) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    if !codec.blocks() {
+        return op(codec.as_ref());
+    }
+    let codec = Arc::clone(codec);
+    block(move || op(codec.as_ref()))
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => DbError::internal("Payload codec canceled"),
+        })
+}
+
+/// The prefix of a stored reference to an offloaded payload's blob
+const BLOB_REFERENCE: &str = "blob:";
+
+/// The blob key of a stored reference to an offloaded payload (`None` for
+/// payloads stored inline)
+pub fn blob_key(stored: &str) -> Option<&str> {
+    if !stored.starts_with(BLOB_REFERENCE) {
+        return None;
+    }
+    stored[BLOB_REFERENCE.len()..].split(':').next()
+}
+
+/// Offloads the payloads (as encoded by `inner`) exceeding `threshold` bytes
+/// to a blob store, storing a reference to their blob in their place. Blobs
+/// are keyed by their SHA-256 digest.
+///
+/// References take the form `blob:<key>:<size>`, recording the offloaded
+/// payload's size so storage usage (summed by the backends' queries) still
+/// reflects it.
+///
+/// Payloads that happen to begin like a reference are offloaded regardless
+/// of their size, so a stored reference is never ambiguous. Without
+/// offloading such payloads are stored (and sized) as is.
+#[derive(Debug)]
+pub struct Offloading {
+    inner: Arc<dyn PayloadCodec>,
+    store: Arc<dyn BlobStore>,
+    threshold: usize,
+}
+
+impl Offloading {
+    pub fn new(inner: Arc<dyn PayloadCodec>, store: Arc<dyn BlobStore>, threshold: usize) -> Self {
+        Self {
+            inner,
+            store,
+            threshold,
+        }
+    }
+}
+
+impl PayloadCodec for Offloading {
+    fn encode(&self, payload: String) -> Result<String> {
+        let stored = self.inner.encode(payload)?;
+        if stored.len() <= self.threshold && !stored.starts_with(BLOB_REFERENCE) {
+            return Ok(stored);
+        }
+        let key = digest::digest(&digest::SHA256, stored.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        self.store.put(&key, stored.as_bytes())?;
+        Ok(format!("{}{}:{}", BLOB_REFERENCE, key, stored.len()))
+    }
+
+    fn decode(&self, stored: String) -> Result<String> {
+        let key = match blob_key(&stored) {
+            Some(key) => key,
+            None => return self.inner.decode(stored),
+        };
+        let blob = self.store.get(key)?;
+        let stored = String::from_utf8(blob)
+            .map_err(|e| DbErrorKind::Integrity(format!("Undecodable payload blob: {}", e)))?;
+        self.inner.decode(stored)
+    }
+
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    fn offloads(&self) -> bool {
+        true
+    }
+}
+
+/// The codec named by the `payload_codec` setting, offloading large payloads
+/// to the `payload_blob_store` (when one's configured)
+pub fn from_settings(settings: &Settings) -> Result<Arc<dyn PayloadCodec>> {
+    let codec: Arc<dyn PayloadCodec> = match settings.payload_codec.as_str() {
+        "identity" => Arc::new(Identity),
+        "base64" => Arc::new(Base64),
+        name => Err(DbError::internal(&format!(
+            "Unknown payload_codec: {}",
+            name
+        )))?,
+    };
+    Ok(match blob::from_settings(settings)? {
+        Some(store) => Arc::new(Offloading::new(
+            codec,
+            store,
+            settings.payload_blob_threshold,
+        )),
+        None => codec,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    use super::{blob_key, Base64, Identity, Offloading, PayloadCodec};
+    use crate::db::{
+        blob::BlobStore,
+        error::{DbError, DbErrorKind},
+        params::PostCollectionBso,
+    };
+
+    #[derive(Debug, Default)]
+    struct MemoryBlobStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl BlobStore for MemoryBlobStore {
+        fn put(&self, key: &str, blob: &[u8]) -> Result<(), DbError> {
+            self.0.lock().unwrap().insert(key.to_owned(), blob.to_vec());
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>, DbError> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| DbErrorKind::Integrity(key.to_owned()))?)
+        }
+
+        fn list(&self) -> Result<Vec<(String, SystemTime)>, DbError> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .keys()
+                .map(|key| (key.clone(), SystemTime::UNIX_EPOCH))
+                .collect())
+        }
+
+        fn delete(&self, key: &str) -> Result<(), DbError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn base64_roundtrips() {
+        let codec = Base64;
+        let payload = r#"{"ciphertext": "☃"}"#.to_owned();
+        let stored = codec.encode(payload.clone()).unwrap();
+        assert_ne!(stored, payload);
+        assert_eq!(codec.decode(stored).unwrap(), payload);
+        assert!(codec.decode("not base64!".to_owned()).is_err());
+    }
+
+    #[test]
+    fn absent_payloads_untouched() {
+        let bso = PostCollectionBso {
+            id: "b0".to_owned(),
+            sortindex: Some(1),
+            payload: None,
+            ttl: None,
+        };
+        let bso = Base64.encode_bso(bso).unwrap();
+        assert_eq!(bso.payload, None);
+        assert_eq!(bso.sortindex, Some(1));
+    }
+
+    #[test]
+    fn large_payloads_offloaded() {
+        let store = Arc::new(MemoryBlobStore::default());
+        let codec = Offloading::new(Arc::new(Identity), store.clone(), 8);
+
+        let small = "{}".to_owned();
+        assert_eq!(codec.encode(small.clone()).unwrap(), small);
+
+        let large = r#"{"ciphertext": "large"}"#.to_owned();
+        let stored = codec.encode(large.clone()).unwrap();
+        assert!(stored.starts_with("blob:"));
+        // The reference records the payload's size
+        assert!(stored.ends_with(&format!(":{}", large.len())));
+        assert_eq!(blob_key(&stored).map(str::len), Some(64));
+        assert_eq!(store.0.lock().unwrap().len(), 1);
+        assert_eq!(codec.decode(stored.clone()).unwrap(), large);
+        // Identical payloads share a blob
+        assert_eq!(codec.encode(large).unwrap(), stored);
+        assert_eq!(store.0.lock().unwrap().len(), 1);
+
+        // Never mistaken for a reference
+        let lookalike = "blob:x".to_owned();
+        let stored = codec.encode(lookalike.clone()).unwrap();
+        assert_ne!(stored, lookalike);
+        assert_eq!(codec.decode(stored).unwrap(), lookalike);
+
+        assert!(codec.decode("blob:missing".to_owned()).is_err());
+    }
+
+    #[test]
+    fn lookalikes_inline_without_offloading() {
+        let lookalike = "blob:x:0".to_owned();
+        assert!(!Identity.offloads());
+        assert_eq!(Identity.encode(lookalike.clone()).unwrap(), lookalike);
+
+        let codec = Offloading::new(
+            Arc::new(Identity),
+            Arc::new(MemoryBlobStore::default()),
+            1024,
+        );
+        assert!(codec.offloads());
+        let stored = codec.encode(lookalike.clone()).unwrap();
+        // Its reference records its true size
+        assert!(stored.ends_with(&format!(":{}", lookalike.len())));
+    }
+}
@@ -2,11 +2,26 @@ use std::fmt;
 
 use actix_web::http::StatusCode;
 use failure::{Backtrace, Context, Fail};
+use grpcio::RpcStatusCode;
+
+/// Messages of the MySQL errors (ER_LOCK_DEADLOCK, ER_LOCK_WAIT_TIMEOUT)
+/// resulting from contention with a concurrent transaction
+const MYSQL_LOCK_CONFLICTS: [&str; 2] = [
+    "Deadlock found when trying to get lock",
+    "Lock wait timeout exceeded",
+];
 
 #[derive(Debug)]
 pub struct DbError {
     inner: Context<DbErrorKind>,
     pub status: StatusCode,
+    /// The backend ("mysql" or "spanner") the error originated from
+    pub backend: Option<&'static str>,
+    /// The `Db` operation the error originated from
+    pub operation: Option<&'static str>,
+    /// Whether Spanner aborted the transaction (a `Conflict` the transaction
+    /// may simply be retried after)
+    aborted: bool,
 }
 
 #[derive(Debug, Fail)]
@@ -32,24 +47,48 @@ pub enum DbErrorKind {
     #[fail(display = "Error migrating the database: {}", _0)]
     Migration(diesel_migrations::RunMigrationsError),
 
+    #[fail(display = "Error migrating the database: {}", _0)]
+    SpannerMigration(String),
+
+    #[fail(
+        display = "Unusable Spanner credentials from the {}. Set database_spanner_credentials (or GOOGLE_APPLICATION_CREDENTIALS) to a service account key file, or run on GCE/GKE under a service account",
+        _0
+    )]
+    SpannerCredentials(String),
+
+    #[fail(
+        display = "Database is missing migration {} (with run_migrations disabled)",
+        _0
+    )]
+    MissingMigration(String),
+
     #[fail(display = "Specified collection does not exist")]
     CollectionNotFound,
 
     #[fail(display = "Specified bso does not exist")]
     BsoNotFound,
 
+    #[fail(display = "Specified bso already exists")]
+    BsoExists,
+
     #[fail(display = "Specified batch does not exist")]
     BatchNotFound,
 
     #[fail(display = "An attempt at a conflicting write")]
     Conflict,
 
+    #[fail(display = "User has too many collections")]
+    TooManyCollections,
+
     #[fail(display = "Database integrity error: {}", _0)]
     Integrity(String),
 
     #[fail(display = "Invalid SYNC_DATABASE_URL: {}", _0)]
     InvalidUrl(String),
 
+    #[fail(display = "Invalid standard_collections: {}", _0)]
+    InvalidStandardCollections(String),
+
     #[fail(display = "Unexpected error: {}", _0)]
     Internal(String),
 }
@@ -62,6 +101,70 @@ impl DbError {
     pub fn internal(msg: &str) -> Self {
         DbErrorKind::Internal(msg.to_owned()).into()
     }
+
+    /// Whether Spanner aborted the transaction
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Whether Spanner's temporarily unable to serve the call (UNAVAILABLE
+    /// or RESOURCE_EXHAUSTED): it may simply be retried (after backing off)
+    pub fn is_retryable(&self) -> bool {
+        self.grpc_status().map_or(false, |code| {
+            code == RpcStatusCode::UNAVAILABLE || code == RpcStatusCode::RESOURCE_EXHAUSTED
+        })
+    }
+
+    /// Whether a Spanner write failed on an existing row (or unique index
+    /// entry)
+    pub fn is_already_exists(&self) -> bool {
+        self.grpc_status() == Some(RpcStatusCode::ALREADY_EXISTS)
+    }
+
+    /// The name of the transient Spanner status the call failed with (if
+    /// any): a capacity signal rather than a bug
+    pub fn transient_status(&self) -> Option<&'static str> {
+        let code = self.grpc_status()?;
+        if code == RpcStatusCode::UNAVAILABLE {
+            Some("unavailable")
+        } else if code == RpcStatusCode::RESOURCE_EXHAUSTED {
+            Some("resource_exhausted")
+        } else if code == RpcStatusCode::DEADLINE_EXCEEDED {
+            Some("deadline_exceeded")
+        } else {
+            None
+        }
+    }
+
+    fn grpc_status(&self) -> Option<RpcStatusCode> {
+        match self.kind() {
+            DbErrorKind::SpannerGrpc(grpcio::Error::RpcFailure(status))
+            | DbErrorKind::SpannerGrpc(grpcio::Error::RpcFinished(Some(status))) => {
+                Some(status.status)
+            }
+            _ => None,
+        }
+    }
+
+    /// Label the error with the backend and `Db` operation it originated
+    /// from. These are static names only: they're used as Sentry tags and
+    /// fingerprints so they must never include user data.
+    pub fn with_operation(mut self, backend: &'static str, operation: &'static str) -> Self {
+        self.backend = Some(backend);
+        self.operation = Some(operation);
+        self
+    }
+}
+
+/// A `map_err` adapter labeling a `DbError` with its backend and `Db`
+/// operation (see `DbError::with_operation`) as it's converted to an
+/// `ApiError`
+macro_rules! db_op_error {
+    ($backend:expr, $operation:ident) => {
+        |e: $crate::db::error::DbError| -> $crate::error::ApiError {
+            e.with_operation($backend, stringify!($operation)).into()
+        }
+    };
 }
 
 impl From<Context<DbErrorKind>> for DbError {
@@ -76,35 +179,145 @@ impl From<Context<DbErrorKind>> for DbError {
             //  * desktop bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959034
             //  * android bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959032
             DbErrorKind::Conflict => StatusCode::SERVICE_UNAVAILABLE,
+            DbErrorKind::TooManyCollections => StatusCode::FORBIDDEN,
+            DbErrorKind::BsoExists => StatusCode::PRECONDITION_FAILED,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        Self { inner, status }
+        Self {
+            inner,
+            status,
+            backend: None,
+            operation: None,
+            aborted: false,
+        }
     }
 }
 
 failure_boilerplate!(DbError, DbErrorKind);
 
-from_error!(diesel::result::Error, DbError, DbErrorKind::DieselQuery);
+from_error!(
+    diesel::result::Error,
+    DbError,
+    |inner: diesel::result::Error| {
+        // Convert MySQL deadlocks (1213) and lock wait timeouts (1205) into 503s:
+        // their transactions may simply be retried. diesel doesn't expose MySQL
+        // error codes, so match on their messages
+        match inner {
+            diesel::result::Error::DatabaseError(_, ref info)
+                if MYSQL_LOCK_CONFLICTS
+                    .iter()
+                    .any(|msg| info.message().starts_with(msg)) =>
+            {
+                DbErrorKind::Conflict
+            }
+            _ => DbErrorKind::DieselQuery(inner),
+        }
+    }
+);
 from_error!(
     diesel::result::ConnectionError,
     DbError,
     DbErrorKind::DieselConnection
 );
-from_error!(grpcio::Error, DbError, |inner: grpcio::Error| {
-    // Convert ABORTED (typically due to a transaction abort) into 503s
-    match inner {
-        grpcio::Error::RpcFailure(ref status) | grpcio::Error::RpcFinished(Some(ref status))
-            if status.status == grpcio::RpcStatusCode::ABORTED =>
-        {
-            DbErrorKind::Conflict
+impl From<grpcio::Error> for DbError {
+    fn from(inner: grpcio::Error) -> Self {
+        // Convert ABORTED (typically due to a transaction abort) into 503s
+        match inner {
+            grpcio::Error::RpcFailure(ref status)
+            | grpcio::Error::RpcFinished(Some(ref status))
+                if status.status == RpcStatusCode::ABORTED =>
+            {
+                let mut error: Self = DbErrorKind::Conflict.into();
+                error.aborted = true;
+                error
+            }
+            _ => {
+                let mut error: Self = DbErrorKind::SpannerGrpc(inner).into();
+                // As are the transient statuses (once any retries are
+                // exhausted)
+                if error.transient_status().is_some() {
+                    error.status = StatusCode::SERVICE_UNAVAILABLE;
+                }
+                error
+            }
         }
-        _ => DbErrorKind::SpannerGrpc(inner),
     }
-});
+}
 from_error!(diesel::r2d2::PoolError, DbError, DbErrorKind::Pool);
 from_error!(
     diesel_migrations::RunMigrationsError,
     DbError,
     DbErrorKind::Migration
 );
+
+#[cfg(test)]
+mod tests {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    use super::*;
+
+    fn database_error(msg: &str) -> DbError {
+        DieselError::DatabaseError(DatabaseErrorKind::__Unknown, Box::new(msg.to_owned())).into()
+    }
+
+    #[test]
+    fn mysql_lock_conflicts() {
+        for msg in &[
+            "Deadlock found when trying to get lock; try restarting transaction",
+            "Lock wait timeout exceeded; try restarting transaction",
+        ] {
+            let err = database_error(msg);
+            assert!(matches!(err.kind(), DbErrorKind::Conflict));
+            assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let err = database_error("Table 'syncstorage.bso' doesn't exist");
+        assert!(matches!(err.kind(), DbErrorKind::DieselQuery(_)));
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn spanner_aborted() {
+        let status = |code| {
+            grpcio::Error::RpcFailure(grpcio::RpcStatus::new(code, Some("details".to_owned())))
+        };
+        let err: DbError = status(grpcio::RpcStatusCode::ABORTED).into();
+        assert!(matches!(err.kind(), DbErrorKind::Conflict));
+        assert!(err.is_aborted());
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let err: DbError = status(grpcio::RpcStatusCode::UNAVAILABLE).into();
+        assert!(matches!(err.kind(), DbErrorKind::SpannerGrpc(_)));
+        assert!(!err.is_aborted());
+        assert!(!DbError::from(DbErrorKind::Conflict).is_aborted());
+    }
+
+    #[test]
+    fn spanner_transient() {
+        let status = |code| {
+            let error =
+                grpcio::Error::RpcFailure(grpcio::RpcStatus::new(code, Some("details".to_owned())));
+            DbError::from(error)
+        };
+        for &(code, name, retryable) in &[
+            (RpcStatusCode::UNAVAILABLE, "unavailable", true),
+            (
+                RpcStatusCode::RESOURCE_EXHAUSTED,
+                "resource_exhausted",
+                true,
+            ),
+            (RpcStatusCode::DEADLINE_EXCEEDED, "deadline_exceeded", false),
+        ] {
+            let err = status(code);
+            assert_eq!(err.transient_status(), Some(name));
+            assert_eq!(err.is_retryable(), retryable);
+            assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let err = status(RpcStatusCode::INTERNAL);
+        assert_eq!(err.transient_status(), None);
+        assert!(!err.is_retryable());
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!status(RpcStatusCode::ABORTED).is_retryable());
+    }
+}
@@ -0,0 +1,86 @@
+//! Export of all of a user's data (e.g. for data portability requests).
+use std::io::Write;
+
+use serde_derive::Serialize;
+
+use super::{params, results::GetBso, Db, DbError, Sorting};
+use crate::error::ApiError;
+use crate::web::extractors::HawkIdentifier;
+
+/// The number of BSOs read per page
+const EXPORT_PAGE_SIZE: u32 = 1000;
+
+/// Summary of an `export_user` run
+#[derive(Debug, Default)]
+pub struct Export {
+    /// The number of collections exported
+    pub collections: usize,
+    /// The number of BSOs exported
+    pub bsos: usize,
+}
+
+/// A line of the export: a BSO along with its collection and expiry
+#[derive(Serialize)]
+struct ExportedBso<'a> {
+    collection: &'a str,
+    #[serde(flatten)]
+    bso: &'a GetBso,
+    expiry: i64,
+}
+
+/// Write all of a user's (unexpired) BSOs to `out` as newline delimited JSON,
+/// one BSO per line, ordered by collection name then oldest first.
+///
+/// BSOs are read and written a page at a time, so large accounts aren't
+/// buffered in memory. Begin a read transaction on `db` beforehand for a
+/// consistent snapshot of the user's data.
+pub async fn export_user(
+    db: &dyn Db,
+    user_id: &HawkIdentifier,
+    out: &mut dyn Write,
+) -> Result<Export, ApiError> {
+    let mut collections: Vec<_> = db
+        .get_collection_timestamps(user_id.clone())
+        .await?
+        .into_iter()
+        .map(|(collection, _)| collection)
+        .collect();
+    collections.sort();
+
+    let mut export = Export::default();
+    for collection in collections {
+        let mut after = None;
+        loop {
+            let page = db
+                .get_bsos_after(params::GetBsosAfter {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                    after,
+                    sort: Sorting::Oldest,
+                    limit: EXPORT_PAGE_SIZE,
+                })
+                .await?;
+            for bso in &page.items {
+                let line = ExportedBso {
+                    collection: &collection,
+                    bso,
+                    expiry: bso.expiry,
+                };
+                serde_json::to_writer(&mut *out, &line).map_err(|e| export_error(&e))?;
+                out.write_all(b"\n").map_err(|e| export_error(&e))?;
+            }
+            export.bsos += page.items.len();
+            after = match page.items.last() {
+                Some(last) if page.more => Some((last.modified, last.id.clone())),
+                _ => break,
+            };
+        }
+        export.collections += 1;
+    }
+    out.flush().map_err(|e| export_error(&e))?;
+    Ok(export)
+}
+
+fn export_error(e: &dyn std::error::Error) -> DbError {
+    DbError::internal(&format!("Export write error: {}", e))
+}
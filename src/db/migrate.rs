@@ -0,0 +1,352 @@
+//! Migration of a user's data between `Db` backends.
+use std::collections::HashSet;
+use std::future::Future;
+
+use super::{params, results::GetBso, util::SyncTimestamp, Db, DbError, DbPool, Sorting};
+use crate::error::ApiError;
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
+
+/// Maximum number of BSOs written per destination transaction
+const MIGRATE_CHUNK_SIZE: usize = 100;
+
+/// The number of BSOs (or ids) read per page
+const MIGRATE_PAGE_SIZE: u32 = 1000;
+
+/// Summary of a `migrate_user` run
+#[derive(Debug, Default)]
+pub struct Migration {
+    /// Collections copied to the destination
+    pub migrated: Vec<String>,
+    /// Collections skipped as they were already migrated
+    pub skipped: Vec<String>,
+    /// The number of BSOs copied
+    pub bsos: usize,
+}
+
+/// Copy all of a user's collections and BSOs from `source` to `dest` (where
+/// they're identified as `dest_user_id`, usually the same user), preserving
+/// their ids, modified timestamps and expiries.
+///
+/// Resumable and idempotent: collections whose destination timestamp and
+/// record count already match the source are skipped, any others are
+/// rewritten in full (removing destination BSOs absent from the source). Each
+/// migrated collection's record count and timestamp are verified afterwards.
+///
+/// Pending batches are short lived and aren't migrated: users with any
+/// (unexpired) open batches are refused, to be retried once they've been
+/// committed or expired.
+pub async fn migrate_user(
+    source: &dyn DbPool,
+    dest: &dyn DbPool,
+    user_id: &HawkIdentifier,
+    dest_user_id: &HawkIdentifier,
+) -> Result<Migration, ApiError> {
+    let db = source.get().await?;
+    db.begin(false).await?;
+    let batches = db
+        .count_batches(params::CountBatches {
+            user_id: Some(user_id.clone()),
+        })
+        .await?;
+    let mut collections: Vec<_> = db
+        .get_collection_timestamps(user_id.clone())
+        .await?
+        .into_iter()
+        .collect();
+    let counts = db
+        .get_collection_counts(params::GetCollectionCounts {
+            user_id: user_id.clone(),
+            newer: None,
+        })
+        .await?;
+    db.commit().await?;
+    // Released ahead of each collection's copy (reading from its own db)
+    drop(db);
+
+    let pending = batches.open - batches.stale;
+    if pending > 0 {
+        Err(DbError::internal(&format!(
+            "User has {} open batches: retry once they're committed or expired",
+            pending
+        )))?
+    }
+    collections.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut migration = Migration::default();
+    for (collection, modified) in collections {
+        let count = counts.get(&collection).cloned().unwrap_or_default();
+        let dest_db = dest.get().await?;
+        dest_db.begin(false).await?;
+        let dest_modified = dest_db
+            .get_collection_timestamp(params::GetCollectionTimestamp {
+                user_id: dest_user_id.clone(),
+                collection: collection.clone(),
+            })
+            .await;
+        let dest_count = dest_db
+            .get_collection_counts(params::GetCollectionCounts {
+                user_id: dest_user_id.clone(),
+                newer: None,
+            })
+            .await?
+            .get(&collection)
+            .cloned()
+            .unwrap_or_default();
+        dest_db.commit().await?;
+        match dest_modified {
+            Ok(dest_modified) if dest_modified == modified && dest_count == count => {
+                info!("Skipping already migrated collection"; "collection" => &collection);
+                migration.skipped.push(collection);
+                continue;
+            }
+            Err(e) if !e.is_collection_not_found() => return Err(e),
+            _ => (),
+        }
+
+        let copied =
+            migrate_collection(source, dest, user_id, dest_user_id, &collection, modified).await?;
+        verify_collection(dest, dest_user_id, &collection, modified, copied as i64).await?;
+        migration.bsos += copied;
+        info!("Migrated collection"; "collection" => &collection);
+        migration.migrated.push(collection);
+    }
+    Ok(migration)
+}
+
+/// Copy a single collection, returning the number of BSOs copied
+async fn migrate_collection(
+    source: &dyn DbPool,
+    dest: &dyn DbPool,
+    user_id: &HawkIdentifier,
+    dest_user_id: &HawkIdentifier,
+    collection: &str,
+    modified: SyncTimestamp,
+) -> Result<usize, ApiError> {
+    // Copied a page at a time, in order of their modified timestamps
+    let db = source.get().await?;
+    db.lock_for_read(params::LockCollectionForRead {
+        user_id: user_id.clone(),
+        collection: collection.to_owned(),
+        strong: true,
+    })
+    .await?;
+    let mut source_ids = HashSet::new();
+    let mut after = None;
+    loop {
+        let page = db
+            .get_bsos_after(params::GetBsosAfter {
+                user_id: user_id.clone(),
+                collection: collection.to_owned(),
+                after,
+                sort: Sorting::Oldest,
+                limit: MIGRATE_PAGE_SIZE,
+            })
+            .await?;
+        source_ids.extend(page.items.iter().map(|bso| bso.id.clone()));
+        write_bsos(dest, dest_user_id, collection, &page.items).await?;
+        after = match page.items.last() {
+            Some(last) if page.more => Some((last.modified, last.id.clone())),
+            _ => break,
+        };
+    }
+    db.commit().await?;
+
+    let stale: Vec<_> = bso_ids(dest, dest_user_id, collection)
+        .await?
+        .into_iter()
+        .filter(|id| !source_ids.contains(id))
+        .collect();
+
+    // Finally remove any stale BSOs, (re)setting the collection's timestamp
+    let (user_id, collection) = (dest_user_id.clone(), collection.to_owned());
+    write(
+        dest,
+        modified,
+        user_id.clone(),
+        collection.clone(),
+        |db| async move {
+            if stale.is_empty() {
+                db.post_bsos(params::PostBsos {
+                    user_id,
+                    collection,
+                    bsos: vec![],
+                    failed: Default::default(),
+                    breakdown: false,
+                })
+                .await
+                .map(|_| ())
+            } else {
+                db.delete_bsos(params::DeleteBsos {
+                    user_id,
+                    collection,
+                    ids: stale,
+                })
+                .await
+                .map(|_| ())
+            }
+        },
+    )
+    .await?;
+    Ok(source_ids.len())
+}
+
+/// Write each run of the (modified ordered) BSOs sharing a modified
+/// timestamp with that timestamp
+async fn write_bsos(
+    dest: &dyn DbPool,
+    user_id: &HawkIdentifier,
+    collection: &str,
+    mut bsos: &[GetBso],
+) -> Result<(), ApiError> {
+    while !bsos.is_empty() {
+        let bso_modified = bsos[0].modified;
+        let len = bsos
+            .iter()
+            .take(MIGRATE_CHUNK_SIZE)
+            .take_while(|bso| bso.modified == bso_modified)
+            .count();
+        let (chunk, rest) = bsos.split_at(len);
+        bsos = rest;
+        let pbsos = chunk
+            .iter()
+            .map(|bso| params::PostCollectionBso {
+                id: bso.id.clone(),
+                sortindex: bso.sortindex,
+                payload: Some(bso.payload.clone()),
+                ttl: Some(((bso.expiry - bso.modified.as_i64()).max(0) / 1000) as u32),
+            })
+            .collect();
+        let (user_id, collection) = (user_id.clone(), collection.to_owned());
+        write(
+            dest,
+            bso_modified,
+            user_id.clone(),
+            collection.clone(),
+            |db| async move {
+                db.post_bsos(params::PostBsos {
+                    user_id,
+                    collection,
+                    bsos: pbsos,
+                    failed: Default::default(),
+                    breakdown: false,
+                })
+                .await
+                .map(|_| ())
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The ids of all of a collection's BSOs
+async fn bso_ids(
+    pool: &dyn DbPool,
+    user_id: &HawkIdentifier,
+    collection: &str,
+) -> Result<Vec<String>, ApiError> {
+    let db = pool.get().await?;
+    db.begin(false).await?;
+    let mut ids = vec![];
+    let mut offset = None;
+    loop {
+        let page = match db
+            .get_bso_ids(params::GetBsos {
+                user_id: user_id.clone(),
+                collection: collection.to_owned(),
+                params: BsoQueryParams {
+                    offset,
+                    limit: Some(MIGRATE_PAGE_SIZE),
+                    ..Default::default()
+                },
+            })
+            .await
+        {
+            Ok(page) => page,
+            Err(e) if e.is_collection_not_found() => break,
+            Err(e) => return Err(e),
+        };
+        ids.extend(page.items);
+        offset = match page.offset {
+            Some(offset) => Some(parse_offset(&offset)?),
+            None => break,
+        };
+    }
+    db.commit().await?;
+    Ok(ids)
+}
+
+/// Run `f` within a write transaction of a fresh `pool` Db whose writes are
+/// all stamped with `modified`
+async fn write<F, Fut>(
+    pool: &dyn DbPool,
+    modified: SyncTimestamp,
+    user_id: HawkIdentifier,
+    collection: String,
+    f: F,
+) -> Result<(), ApiError>
+where
+    F: FnOnce(Box<dyn Db>) -> Fut,
+    Fut: Future<Output = Result<(), ApiError>>,
+{
+    let db = pool.get().await?;
+    db.lock_for_write(params::LockCollection {
+        user_id,
+        collection,
+    })
+    .await?;
+    db.set_timestamp(modified);
+    match f(db.clone()).await {
+        Ok(()) => db.commit().await,
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Verify the destination collection's record count and timestamp match the
+/// migrated values
+async fn verify_collection(
+    pool: &dyn DbPool,
+    user_id: &HawkIdentifier,
+    collection: &str,
+    modified: SyncTimestamp,
+    count: i64,
+) -> Result<(), ApiError> {
+    let db = pool.get().await?;
+    db.begin(false).await?;
+    let dest_modified = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: user_id.clone(),
+            collection: collection.to_owned(),
+        })
+        .await?;
+    let dest_count = db
+        .get_collection_counts(params::GetCollectionCounts {
+            user_id: user_id.clone(),
+            newer: None,
+        })
+        .await?
+        .get(collection)
+        .cloned()
+        .unwrap_or_default();
+    db.commit().await?;
+    if dest_modified != modified || dest_count != count {
+        Err(DbError::internal(&format!(
+            "Migrated collection {} mismatch: modified {} (expected {}), count {} (expected {})",
+            collection,
+            dest_modified.as_i64(),
+            modified.as_i64(),
+            dest_count,
+            count
+        )))?
+    }
+    Ok(())
+}
+
+fn parse_offset(offset: &str) -> Result<Offset, DbError> {
+    offset
+        .parse()
+        .map_err(|_| DbError::internal("Invalid pagination offset"))
+}
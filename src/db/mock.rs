@@ -25,6 +25,10 @@ impl DbPool for MockDbPool {
     fn box_clone(&self) -> Box<dyn DbPool> {
         Box::new(self.clone())
     }
+
+    fn warm_collection_cache(&self, _batch_size: u32) -> DbFuture<usize> {
+        Box::pin(future::ok(0))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -53,6 +57,10 @@ impl Db for MockDb {
         Box::pin(future::ok(()))
     }
 
+    fn commit_and_get_timestamp(&self) -> DbFuture<SyncTimestamp> {
+        Box::pin(future::ok(Default::default()))
+    }
+
     fn rollback(&self) -> DbFuture<()> {
         Box::pin(future::ok(()))
     }
@@ -69,9 +77,18 @@ impl Db for MockDb {
         Box::pin(future::ok(true))
     }
 
-    mock_db_method!(lock_for_read, LockCollection);
+    fn health_detail(&self) -> DbFuture<results::HealthDetail> {
+        Box::pin(future::ok(Default::default()))
+    }
+
+    fn last_success(&self) -> Option<u64> {
+        None
+    }
+
+    mock_db_method!(lock_for_read, LockCollectionForRead);
     mock_db_method!(lock_for_write, LockCollection);
     mock_db_method!(get_collection_timestamps, GetCollectionTimestamps);
+    mock_db_method!(get_collection_names, GetCollectionNames);
     mock_db_method!(get_collection_timestamp, GetCollectionTimestamp);
     mock_db_method!(get_collection_counts, GetCollectionCounts);
     mock_db_method!(get_collection_usage, GetCollectionUsage);
@@ -80,9 +97,13 @@ impl Db for MockDb {
     mock_db_method!(delete_storage, DeleteStorage);
     mock_db_method!(delete_collection, DeleteCollection);
     mock_db_method!(delete_bsos, DeleteBsos);
+    mock_db_method!(bulk_set_ttl, BulkSetTtl);
     mock_db_method!(get_bsos, GetBsos);
     mock_db_method!(get_bso_ids, GetBsoIds);
+    mock_db_method!(get_bsos_after, GetBsosAfter);
+    mock_db_method!(count_bsos, CountBsos);
     mock_db_method!(post_bsos, PostBsos);
+    mock_db_method!(reset_collection, ResetCollection);
     mock_db_method!(delete_bso, DeleteBso);
     mock_db_method!(get_bso, GetBso, Option<results::GetBso>);
     mock_db_method!(get_bso_timestamp, GetBsoTimestamp);
@@ -91,32 +112,50 @@ impl Db for MockDb {
     mock_db_method!(validate_batch, ValidateBatch);
     mock_db_method!(append_to_batch, AppendToBatch);
     mock_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
+    mock_db_method!(get_batch_bsos, GetBatchBsos);
     mock_db_method!(commit_batch, CommitBatch);
+    mock_db_method!(try_acquire_maintenance_lock, TryAcquireMaintenanceLock);
+    mock_db_method!(purge_expired_bsos, PurgeExpiredBsos);
+    mock_db_method!(purge_expired_batches, PurgeExpiredBatches);
+    mock_db_method!(get_collections, GetCollections);
+    mock_db_method!(get_collection_id, GetCollectionId);
+    mock_db_method!(get_or_create_collection_id, GetOrCreateCollectionId);
+
+    fn count_batches(&self, _: params::CountBatches) -> DbFuture<results::CountBatches> {
+        Box::pin(future::ok(Default::default()))
+    }
+
+    fn get_blob_references(&self) -> DbFuture<results::GetBlobReferences> {
+        Box::pin(future::ok(Default::default()))
+    }
 
     fn validate_batch_id(&self, _: params::ValidateBatchId) -> Result<(), DbError> {
         Ok(())
     }
 
-    #[cfg(test)]
-    mock_db_method!(get_collection_id, GetCollectionId);
     #[cfg(test)]
     mock_db_method!(create_collection, CreateCollection);
     #[cfg(test)]
     mock_db_method!(touch_collection, TouchCollection);
 
-    #[cfg(test)]
     fn timestamp(&self) -> SyncTimestamp {
         Default::default()
     }
 
-    #[cfg(test)]
     fn set_timestamp(&self, _: SyncTimestamp) {}
 
+    fn set_metrics_collection(&mut self, _: &str) {}
+
     #[cfg(test)]
     mock_db_method!(delete_batch, DeleteBatch);
 
     #[cfg(test)]
     fn clear_coll_cache(&self) {}
+
+    #[cfg(test)]
+    fn cached_collection_id(&self, _name: &str) -> Option<i32> {
+        None
+    }
 }
 
 unsafe impl Send for MockDb {}
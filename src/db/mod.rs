@@ -1,9 +1,16 @@
 //! Generic db abstration.
 
+pub mod blob;
+pub mod cache;
+pub mod codec;
+#[macro_use]
 pub mod error;
+pub mod export;
+pub mod migrate;
 pub mod mock;
 pub mod mysql;
 pub mod params;
+pub mod purge;
 pub mod results;
 pub mod spanner;
 #[cfg(test)]
@@ -12,7 +19,7 @@ pub mod util;
 
 use std::{fmt::Debug, time::Duration};
 
-use cadence::{Gauged, StatsdClient};
+use cadence::Gauged;
 use futures::future::{self, LocalBoxFuture, TryFutureExt};
 use lazy_static::lazy_static;
 use mozsvc_common::get_hostname;
@@ -20,9 +27,12 @@ use serde::Deserialize;
 use url::Url;
 
 pub use self::error::{DbError, DbErrorKind};
-use self::util::SyncTimestamp;
+use self::{cache::CollectionCacheStats, util::SyncTimestamp};
 use crate::error::ApiError;
-use crate::server::metrics::Metrics;
+use crate::server::{
+    metrics::{Metrics, MetricsClient},
+    shutdown::Shutdown,
+};
 use crate::settings::Settings;
 use crate::web::extractors::HawkIdentifier;
 
@@ -50,15 +60,21 @@ lazy_static! {
     };
 }
 
+/// Whether `name` is one of the standard collections (those with fixed ids)
+pub fn is_std_collection(name: &str) -> bool {
+    STD_COLLS.iter().any(|(_, std_name)| *std_name == name)
+}
+
 /// Non-standard collections will be allocated IDs beginning with this value
 pub const FIRST_CUSTOM_COLLECTION_ID: i32 = 101;
 
+/// Placeholder row (in MySQL) reserving the ids beneath it for standard
+/// collections
+const RESERVED_COLLECTION_ID: i32 = FIRST_CUSTOM_COLLECTION_ID - 1;
+
 /// Rough guesstimate of the maximum reasonable life span of a batch
 pub const BATCH_LIFETIME: i64 = 2 * 60 * 60 * 1000; // 2 hours, in milliseconds
 
-/// DbPools' worker ThreadPool size
-pub const DB_THREAD_POOL_SIZE: usize = 50;
-
 type DbFuture<T> = LocalBoxFuture<'static, Result<T, ApiError>>;
 
 pub trait DbPool: Sync + Send + Debug {
@@ -67,6 +83,10 @@ pub trait DbPool: Sync + Send + Debug {
     fn state(&self) -> results::PoolState;
 
     fn box_clone(&self) -> Box<dyn DbPool>;
+
+    /// Preload the pool's `CollectionCache` with every collection,
+    /// returning how many were loaded
+    fn warm_collection_cache(&self, batch_size: u32) -> DbFuture<usize>;
 }
 
 impl Clone for Box<dyn DbPool> {
@@ -76,7 +96,10 @@ impl Clone for Box<dyn DbPool> {
 }
 
 pub trait Db: Send + Debug {
-    fn lock_for_read(&self, params: params::LockCollection) -> DbFuture<()>;
+    /// Lock a collection for the remainder of a read-only request. `strong`
+    /// requires reading the latest data (e.g. to evaluate a precondition),
+    /// otherwise backends may read a slightly stale snapshot
+    fn lock_for_read(&self, params: params::LockCollectionForRead) -> DbFuture<()>;
 
     fn lock_for_write(&self, params: params::LockCollection) -> DbFuture<()>;
 
@@ -84,6 +107,12 @@ pub trait Db: Send + Debug {
 
     fn commit(&self) -> DbFuture<()>;
 
+    /// The timestamp of the request's writes, to echo back to clients (e.g.
+    /// as X-Last-Modified). Spanner's writes record their commit's timestamp,
+    /// so it commits them first: other backends' timestamp is already final,
+    /// leaving their commit to the end of the request.
+    fn commit_and_get_timestamp(&self) -> DbFuture<SyncTimestamp>;
+
     fn rollback(&self) -> DbFuture<()>;
 
     fn get_collection_timestamps(
@@ -91,6 +120,12 @@ pub trait Db: Send + Debug {
         params: params::GetCollectionTimestamps,
     ) -> DbFuture<results::GetCollectionTimestamps>;
 
+    /// The names of every collection the user has, in sorted order
+    fn get_collection_names(
+        &self,
+        params: params::GetCollectionNames,
+    ) -> DbFuture<results::GetCollectionNames>;
+
     fn get_collection_timestamp(
         &self,
         params: params::GetCollectionTimestamp,
@@ -101,6 +136,7 @@ pub trait Db: Send + Debug {
         params: params::GetCollectionCounts,
     ) -> DbFuture<results::GetCollectionCounts>;
 
+    /// The size (in bytes) of the user's unexpired payloads per collection
     fn get_collection_usage(
         &self,
         params: params::GetCollectionUsage,
@@ -111,6 +147,7 @@ pub trait Db: Send + Debug {
         params: params::GetStorageTimestamp,
     ) -> DbFuture<results::GetStorageTimestamp>;
 
+    /// The total size (in bytes) of the user's unexpired payloads
     fn get_storage_usage(
         &self,
         params: params::GetStorageUsage,
@@ -125,12 +162,39 @@ pub trait Db: Send + Debug {
 
     fn delete_bsos(&self, params: params::DeleteBsos) -> DbFuture<results::DeleteBsos>;
 
+    /// Extend the lifetime of the given (unexpired) BSOs to `ttl` seconds
+    /// from now, bumping their modified (but leaving their payloads and
+    /// sortindexes untouched)
+    fn bulk_set_ttl(&self, params: params::BulkSetTtl) -> DbFuture<results::BulkSetTtl>;
+
     fn get_bsos(&self, params: params::GetBsos) -> DbFuture<results::GetBsos>;
 
     fn get_bso_ids(&self, params: params::GetBsos) -> DbFuture<results::GetBsoIds>;
 
+    /// Up to `limit` of a collection's BSOs strictly following the `after`
+    /// (modified, id) key in the (id tie broken) sort order: keyset
+    /// pagination, neither rescanning the preceding BSOs nor skipping or
+    /// repeating any when the collection's modified between pages. The last
+    /// item's key continues the iteration.
+    ///
+    /// A `limit` of 0 is treated as 1, so every page makes progress.
+    fn get_bsos_after(&self, params: params::GetBsosAfter) -> DbFuture<results::GetBsosAfter>;
+
+    /// The total number of BSOs matching the query's filters (ignoring its
+    /// sort, limit and offset)
+    fn count_bsos(&self, params: params::CountBsos) -> DbFuture<results::CountBsos>;
+
     fn post_bsos(&self, params: params::PostBsos) -> DbFuture<results::PostBsos>;
 
+    /// Replace all of a collection's BSOs with the given ones
+    ///
+    /// Within the current (write) transaction: readers never observe the
+    /// collection emptied in between.
+    fn reset_collection(
+        &self,
+        params: params::ResetCollection,
+    ) -> DbFuture<results::ResetCollection>;
+
     fn delete_bso(&self, params: params::DeleteBso) -> DbFuture<results::DeleteBso>;
 
     fn get_bso(&self, params: params::GetBso) -> DbFuture<Option<results::GetBso>>;
@@ -150,6 +214,9 @@ pub trait Db: Send + Debug {
 
     fn get_batch(&self, params: params::GetBatch) -> DbFuture<Option<results::GetBatch>>;
 
+    /// The BSOs appended to a batch (so far), ordered by id
+    fn get_batch_bsos(&self, params: params::GetBatchBsos) -> DbFuture<results::GetBatchBsos>;
+
     fn commit_batch(&self, params: params::CommitBatch) -> DbFuture<results::CommitBatch>;
 
     fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), DbError>;
@@ -158,6 +225,125 @@ pub trait Db: Send + Debug {
 
     fn check(&self) -> DbFuture<results::Check>;
 
+    /// Diagnostics for monitoring: the latency of a health check query, when
+    /// a query last succeeded and the pool's saturation
+    fn health_detail(&self) -> DbFuture<results::HealthDetail>;
+
+    /// When a query (by any of the pool's connections) last succeeded, in
+    /// milliseconds since the epoch: reported even when a health check fails
+    fn last_success(&self) -> Option<u64>;
+
+    /// The "current time" of this Db's transaction
+    ///
+    /// Fixed for the lifetime of the transaction: every write within it
+    /// records this value as its modified timestamp, so it's safe to echo
+    /// back to clients (e.g. as X-Last-Modified). Except for Spanner's
+    /// writes (of bsos and user_collections), which record their commit's
+    /// timestamp: committing replaces this value with it, so write results
+    /// are only final once committed (see `commit_and_get_timestamp`). Unless
+    /// the value was overridden via `set_timestamp`, or within the db tests'
+    /// test transactions (which are never committed).
+    fn timestamp(&self) -> SyncTimestamp;
+
+    /// Override the "current time" of this Db's transaction
+    ///
+    /// Only for writing records with a predetermined modified timestamp
+    /// (e.g. when migrating them between backends, or during tests): call
+    /// after locking the collection for write.
+    fn set_timestamp(&self, timestamp: SyncTimestamp);
+
+    /// Tag the metrics of this Db's operations with the request's
+    /// `collection` (see `Tags::insert_collection`)
+    fn set_metrics_collection(&mut self, collection: &str);
+
+    /// Attempt to take the named, fleet wide maintenance lock for `ttl`
+    /// seconds, returning whether it was acquired.
+    ///
+    /// The lock is a lease: it's never explicitly released, instead becoming
+    /// available again once expired. The lease is only held once the current
+    /// transaction commits.
+    fn try_acquire_maintenance_lock(
+        &self,
+        params: params::TryAcquireMaintenanceLock,
+    ) -> DbFuture<results::TryAcquireMaintenanceLock>;
+
+    /// Delete up to `limit` expired BSOs (optionally only those of a single
+    /// user and/or collection), returning the number deleted.
+    ///
+    /// A `dry_run` only counts them. Collection timestamps are left
+    /// untouched: expired BSOs are already invisible to clients.
+    fn purge_expired_bsos(
+        &self,
+        params: params::PurgeExpiredBsos,
+    ) -> DbFuture<results::PurgeExpiredBsos>;
+
+    /// Delete up to `limit` expired batches (and their BSOs) as
+    /// `purge_expired_bsos` does.
+    fn purge_expired_batches(
+        &self,
+        params: params::PurgeExpiredBatches,
+    ) -> DbFuture<results::PurgeExpiredBatches>;
+
+    /// Count the open (uncommitted) batches of every user (or only of
+    /// `user_id`), and how many of them have expired.
+    fn count_batches(&self, params: params::CountBatches) -> DbFuture<results::CountBatches>;
+
+    /// The keys of every blob referenced by an offloaded payload (of every
+    /// user's BSOs, expired or not, and batches).
+    fn get_blob_references(&self) -> DbFuture<results::GetBlobReferences>;
+
+    /// Up to `limit` collections (ids and names) with ids greater than
+    /// `after_id`, ordered by id.
+    fn get_collections(&self, params: params::GetCollections) -> DbFuture<results::GetCollections>;
+
+    /// The id of the named collection: from the pool's `CollectionCache`,
+    /// otherwise read from the db (and cached once committed).
+    ///
+    /// Never creates the collection, failing with `CollectionNotFound`.
+    fn get_collection_id(
+        &self,
+        name: params::GetCollectionId,
+    ) -> DbFuture<results::GetCollectionId>;
+
+    /// The id of the collection the user's writing to (as
+    /// `get_collection_id`), creating the collection when it doesn't exist.
+    ///
+    /// Fails with `TooManyCollections` when writing to it would exceed the
+    /// user's `max_collections_per_user`. Call within a write transaction.
+    fn get_or_create_collection_id(
+        &self,
+        params: params::GetOrCreateCollectionId,
+    ) -> DbFuture<results::GetOrCreateCollectionId>;
+
+    /// Whether the user has the collection: its id's read from the
+    /// `CollectionCache` and its timestamp from `user_collections` (or
+    /// from the timestamps read when it was locked for the request).
+    ///
+    /// Collections merely pretouched by a pending batch don't exist yet.
+    fn collection_exists(
+        &self,
+        params: params::CollectionExists,
+    ) -> DbFuture<results::CollectionExists> {
+        let params::CollectionExists {
+            user_id,
+            collection,
+        } = params;
+        Box::pin(
+            self.get_collection_timestamp(params::GetCollectionTimestamp {
+                user_id,
+                collection,
+            })
+            .map_ok(|_| true)
+            .or_else(|e| {
+                if e.is_collection_not_found() {
+                    future::ok(false)
+                } else {
+                    future::err(e)
+                }
+            }),
+        )
+    }
+
     /// Retrieve the timestamp for an item/collection
     ///
     /// Modeled on the Python `get_resource_timestamp` function.
@@ -207,10 +393,38 @@ pub trait Db: Send + Debug {
         )
     }
 
-    /// Internal methods used by the db tests
+    /// An opaque ETag of the collection, derived from its last modified
+    /// timestamp and record count: it changes whenever any BSO in the
+    /// collection changes.
+    fn get_collection_etag(
+        &self,
+        params: params::GetCollectionEtag,
+    ) -> DbFuture<results::GetCollectionEtag> {
+        let db = self.box_clone();
+        let params::GetCollectionEtag {
+            user_id,
+            collection,
+        } = params;
+        Box::pin(
+            self.extract_resource(user_id.clone(), Some(collection.clone()), None)
+                .and_then(move |modified| {
+                    let params = params::CountBsos {
+                        user_id,
+                        collection,
+                        params: Default::default(),
+                    };
+                    db.count_bsos(params)
+                        .or_else(|e| match e.kind() {
+                            // Never created: as empty as its (0) timestamp
+                            DbErrorKind::CollectionNotFound => future::ok(0),
+                            _ => future::err(e),
+                        })
+                        .map_ok(move |count| format!("\"{}-{}\"", modified.as_i64(), count))
+                }),
+        )
+    }
 
-    #[cfg(test)]
-    fn get_collection_id(&self, name: String) -> DbFuture<i32>;
+    /// Internal methods used by the db tests
 
     #[cfg(test)]
     fn create_collection(&self, name: String) -> DbFuture<i32>;
@@ -218,17 +432,15 @@ pub trait Db: Send + Debug {
     #[cfg(test)]
     fn touch_collection(&self, params: params::TouchCollection) -> DbFuture<SyncTimestamp>;
 
-    #[cfg(test)]
-    fn timestamp(&self) -> SyncTimestamp;
-
-    #[cfg(test)]
-    fn set_timestamp(&self, timestamp: SyncTimestamp);
-
     #[cfg(test)]
     fn delete_batch(&self, params: params::DeleteBatch) -> DbFuture<()>;
 
     #[cfg(test)]
     fn clear_coll_cache(&self);
+
+    /// The collection's id if it's in the pool's `CollectionCache`
+    #[cfg(test)]
+    fn cached_collection_id(&self, name: &str) -> Option<i32>;
 }
 
 impl Clone for Box<dyn Db> {
@@ -267,18 +479,133 @@ pub fn pool_from_settings(
     })
 }
 
-/// Emit DbPool metrics periodically
+/// Apply the database's pending migrations (on a dedicated connection)
+pub fn run_migrations(settings: &Settings) -> Result<(), DbError> {
+    let url =
+        Url::parse(&settings.database_url).map_err(|e| DbErrorKind::InvalidUrl(e.to_string()))?;
+    match url.scheme() {
+        "mysql" => mysql::pool::run_migrations(settings),
+        "spanner" => spanner::migrations::run_migrations(settings),
+        _ => Err(DbErrorKind::InvalidUrl(settings.database_url.to_owned()))?,
+    }
+}
+
+/// Create a pool of read replica connections when `Settings::database_read_url`
+/// is configured.
+///
+/// Migrations are left to the primary's pool.
+pub fn read_pool_from_settings(
+    settings: &Settings,
+    metrics: &Metrics,
+) -> Result<Option<Box<dyn DbPool>>, DbError> {
+    let read_url = match &settings.database_read_url {
+        Some(read_url) => read_url,
+        None => return Ok(None),
+    };
+    let url = Url::parse(read_url).map_err(|e| DbErrorKind::InvalidUrl(e.to_string()))?;
+    if url.scheme() != "mysql" {
+        Err(DbErrorKind::InvalidUrl(read_url.to_owned()))?
+    }
+    let settings = Settings {
+        database_url: read_url.to_owned(),
+        ..settings.clone()
+    };
+    Ok(Some(Box::new(
+        mysql::pool::MysqlDbPool::new_without_migrations(&settings, metrics)?,
+    )))
+}
+
+/// The built-in standard collections along with those configured via
+/// `Settings::standard_collections`.
+///
+/// Configured collections must be allocated an id between the built-in ones
+/// and `RESERVED_COLLECTION_ID`, never reusing a name or id.
+pub fn standard_collections(settings: &Settings) -> Result<Vec<(i32, String)>, DbError> {
+    let max_std_id = STD_COLLS
+        .iter()
+        .map(|(id, _)| *id)
+        .max()
+        .unwrap_or_default();
+    let mut colls: Vec<_> = STD_COLLS
+        .iter()
+        .map(|(id, name)| (*id, (*name).to_owned()))
+        .collect();
+    let mut configured: Vec<_> = settings.standard_collections.iter().collect();
+    configured.sort_by_key(|(_, id)| **id);
+    for (name, &id) in configured {
+        if id <= max_std_id || id >= RESERVED_COLLECTION_ID {
+            Err(DbErrorKind::InvalidStandardCollections(format!(
+                "{} id {} is outside of the reserved range {}..{}",
+                name,
+                id,
+                max_std_id + 1,
+                RESERVED_COLLECTION_ID
+            )))?
+        }
+        if let Some((other_id, other)) = colls
+            .iter()
+            .find(|(other_id, other)| *other_id == id || other == name)
+        {
+            Err(DbErrorKind::InvalidStandardCollections(format!(
+                "{} ({}) collides with {} ({})",
+                name, id, other, other_id
+            )))?
+        }
+        colls.push((id, name.to_owned()));
+    }
+    Ok(colls)
+}
+
+/// Emit gauges of the open (and stale) batches periodically, until shut
+/// down
+pub fn spawn_batch_periodic_reporter(
+    interval: Duration,
+    metrics: MetricsClient,
+    pool: Box<dyn DbPool>,
+    shutdown: Shutdown,
+) {
+    actix_rt::spawn(async move {
+        loop {
+            match count_batches(&*pool).await {
+                Ok(results::CountBatches { open, stale }) => {
+                    metrics.gauge_with_tags("db.batches.open", open).send();
+                    metrics.gauge_with_tags("db.batches.stale", stale).send();
+                }
+                Err(e) => warn!("Counting batches failed: {}", e),
+            }
+            if shutdown.sleep(interval).await {
+                break;
+            }
+        }
+    });
+}
+
+async fn count_batches(pool: &dyn DbPool) -> Result<results::CountBatches, ApiError> {
+    let db = pool.get().await?;
+    db.begin(false).await?;
+    let counts = db
+        .count_batches(params::CountBatches { user_id: None })
+        .await?;
+    db.commit().await?;
+    Ok(counts)
+}
+
+/// Emit DbPool metrics periodically, until shut down: its connections and
+/// the size and hit ratio (over the last interval) of its collection cache
 pub fn spawn_pool_periodic_reporter(
     interval: Duration,
-    metrics: StatsdClient,
+    metrics: MetricsClient,
     pool: Box<dyn DbPool>,
+    shutdown: Shutdown,
 ) -> Result<(), DbError> {
     let hostname = get_hostname().ok_or_else(|| DbError::internal("Couldn't get_hostname"))?;
     actix_rt::spawn(async move {
+        let mut last_cache = CollectionCacheStats::default();
         loop {
             let results::PoolState {
                 connections,
                 idle_connections,
+                collection_cache,
             } = pool.state();
             metrics
                 .gauge_with_tags(
@@ -291,7 +618,25 @@ pub fn spawn_pool_periodic_reporter(
                 .gauge_with_tags("storage.pool.connections.idle", idle_connections as u64)
                 .with_tag("hostname", &hostname)
                 .send();
-            actix_rt::time::delay_for(interval).await;
+            metrics
+                .gauge_with_tags(
+                    "storage.collection_cache.size",
+                    collection_cache.collections as u64,
+                )
+                .with_tag("hostname", &hostname)
+                .send();
+            let hits = collection_cache.hits - last_cache.hits;
+            let lookups = hits + collection_cache.misses - last_cache.misses;
+            if lookups > 0 {
+                metrics
+                    .gauge_with_tags("storage.collection_cache.hit_percent", hits * 100 / lookups)
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            last_cache = collection_cache;
+            if shutdown.sleep(interval).await {
+                break;
+            }
         }
     });
     Ok(())
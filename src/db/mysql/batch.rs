@@ -2,14 +2,18 @@ use diesel::{
     self,
     dsl::sql,
     insert_into,
-    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
-    sql_types::Integer,
-    update, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, TextExpressionMethods,
+    mysql::Mysql,
+    query_builder::{AstPass, QueryFragment},
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError, QueryResult},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
 };
 
 use super::{
-    models::{MysqlDb, Result},
-    schema::batches,
+    diesel_ext::InsertOnDuplicateKeyUpdate,
+    models::{estimated_row_bytes, group_by_collection, MysqlDb, Result, DEFAULT_BSO_TTL},
+    schema::{batch_bsos, batches},
 };
 use crate::db::{params, results, DbError, DbErrorKind, BATCH_LIFETIME};
 
@@ -17,13 +21,11 @@ pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::Crea
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
     let timestamp = db.timestamp().as_i64();
-    let bsos = bsos_to_batch_string(&params.bsos)?;
     insert_into(batches::table)
         .values((
             batches::user_id.eq(&user_id),
             batches::collection_id.eq(&collection_id),
             batches::id.eq(&timestamp),
-            batches::bsos.eq(&bsos),
             batches::expiry.eq(timestamp + BATCH_LIFETIME),
         ))
         .execute(&db.conn)
@@ -34,13 +36,19 @@ pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::Crea
                 _ => e.into(),
             }
         })?;
+    do_append(db, user_id, collection_id, timestamp, params.bsos)?;
     Ok(encode_id(timestamp))
 }
 
 pub fn validate(db: &MysqlDb, params: params::ValidateBatch) -> Result<bool> {
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
-    let collection_id = db.get_collection_id(&params.collection)?;
+    let collection_id = match db.get_collection_id(&params.collection) {
+        Ok(collection_id) => collection_id,
+        // No batch can outlive its collection
+        Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
     let exists = batches::table
         .select(sql::<Integer>("1"))
         .filter(batches::user_id.eq(&user_id))
@@ -53,28 +61,118 @@ pub fn validate(db: &MysqlDb, params: params::ValidateBatch) -> Result<bool> {
 }
 
 pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<()> {
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.id.clone(),
+        },
+    )?;
+    if !exists {
+        Err(DbErrorKind::BatchNotFound)?
+    }
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
-    let bsos = bsos_to_batch_string(&params.bsos)?;
-    let affected_rows = update(batches::table)
-        .filter(batches::user_id.eq(&user_id))
-        .filter(batches::collection_id.eq(&collection_id))
-        .filter(batches::id.eq(&id))
-        .filter(batches::expiry.gt(&db.timestamp().as_i64()))
-        .set(batches::bsos.eq(batches::bsos.concat(&bsos)))
+    do_append(db, user_id, collection_id, id, params.bsos)
+}
+
+/// Insert the BSOs into batch_bsos
+///
+/// A BSO appended more than once has its later supplied fields overwrite
+/// the earlier ones, matching how they'd apply as individual writes.
+fn do_append(
+    db: &MysqlDb,
+    user_id: i64,
+    collection_id: i32,
+    batch_id: i64,
+    bsos: Vec<params::PostCollectionBso>,
+) -> Result<()> {
+    let encoded = bsos
+        .into_iter()
+        .map(|bso| db.codec.encode_bso(bso))
+        .collect::<Result<Vec<_>>>()?;
+    let mut bsos = &encoded[..];
+    while !bsos.is_empty() {
+        // Keep each statement beneath max_statement_bytes
+        let mut len = 0;
+        let mut bytes = 0;
+        for bso in bsos.iter().take(MAX_APPEND_ROWS) {
+            bytes += estimated_row_bytes(bso);
+            if len > 0 && bytes > db.max_statement_bytes {
+                break;
+            }
+            len += 1;
+        }
+        let (chunk, rest) = bsos.split_at(len);
+        bsos = rest;
+        let rows: Vec<_> = chunk
+            .iter()
+            .map(|bso| BatchBsoRow {
+                user_id,
+                collection_id,
+                batch_id,
+                id: &bso.id,
+                sortindex: bso.sortindex,
+                payload: bso.payload.as_deref(),
+                ttl: bso.ttl.map(i64::from),
+            })
+            .collect();
+        InsertOnDuplicateKeyUpdate {
+            insert: "INSERT INTO batch_bsos \
+                     (userid, collection, batch_id, id, sortindex, payload, ttl)",
+            rows: &rows,
+            update: "sortindex = COALESCE(VALUES(sortindex), sortindex), \
+                     payload = COALESCE(VALUES(payload), payload), \
+                     ttl = COALESCE(VALUES(ttl), ttl)",
+        }
         .execute(&db.conn)?;
-    if affected_rows == 1 {
+    }
+    Ok(())
+}
+
+/// Bounds the rows of a single append statement (the handler's limits
+/// typically keep appends far smaller)
+const MAX_APPEND_ROWS: usize = 1000;
+
+/// A row of batch_bsos. Its bso fields are nullable as the batch upload may
+/// or may not set each individual field of each item.
+struct BatchBsoRow<'a> {
+    user_id: i64,
+    collection_id: i32,
+    batch_id: i64,
+    id: &'a str,
+    sortindex: Option<i32>,
+    payload: Option<&'a str>,
+    /// in seconds
+    ttl: Option<i64>,
+}
+
+impl<'a> QueryFragment<Mysql> for BatchBsoRow<'a> {
+    fn walk_ast(&self, mut out: AstPass<'_, Mysql>) -> QueryResult<()> {
+        out.push_sql("(");
+        out.push_bind_param::<BigInt, _>(&self.user_id)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Integer, _>(&self.collection_id)?;
+        out.push_sql(", ");
+        out.push_bind_param::<BigInt, _>(&self.batch_id)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Text, _>(&self.id)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Nullable<Integer>, _>(&self.sortindex)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Nullable<Text>, _>(&self.payload)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Nullable<BigInt>, _>(&self.ttl)?;
+        out.push_sql(")");
         Ok(())
-    } else {
-        Err(DbErrorKind::BatchNotFound.into())
     }
 }
 
 #[derive(Debug, Default, Queryable)]
 pub struct Batch {
     pub id: i64,
-    pub bsos: String,
     pub expiry: i64,
 }
 
@@ -82,25 +180,68 @@ pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::Get
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
-    Ok(batches::table
-        .select((batches::id, batches::bsos, batches::expiry))
+    let batch = batches::table
+        .select((batches::id, batches::expiry))
         .filter(batches::user_id.eq(&user_id))
         .filter(batches::collection_id.eq(&collection_id))
         .filter(batches::id.eq(&id))
         .filter(batches::expiry.gt(&db.timestamp().as_i64()))
         .get_result::<Batch>(&db.conn)
-        .optional()?
-        .map(|batch| results::GetBatch {
+        .optional()?;
+    Ok(match batch {
+        Some(batch) => Some(results::GetBatch {
             id: encode_id(batch.id),
-            bsos: batch.bsos,
+            bsos: batch_bsos(db, user_id, collection_id, batch.id)?,
             expiry: batch.expiry,
-        }))
+        }),
+        None => None,
+    })
+}
+
+pub fn get_bsos(db: &MysqlDb, params: params::GetBatchBsos) -> Result<results::GetBatchBsos> {
+    let id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    batch_bsos(db, user_id, collection_id, id)?
+        .into_iter()
+        .map(|bso| db.codec.decode_batch_bso(bso))
+        .collect()
+}
+
+/// The batch's BSOs as stored (their payloads still encoded)
+fn batch_bsos(
+    db: &MysqlDb,
+    user_id: i64,
+    collection_id: i32,
+    batch_id: i64,
+) -> Result<Vec<params::PostCollectionBso>> {
+    Ok(batch_bsos::table
+        .select((
+            batch_bsos::id,
+            batch_bsos::sortindex,
+            batch_bsos::payload,
+            batch_bsos::ttl,
+        ))
+        .filter(batch_bsos::user_id.eq(&user_id))
+        .filter(batch_bsos::collection_id.eq(&collection_id))
+        .filter(batch_bsos::batch_id.eq(&batch_id))
+        .order(batch_bsos::id)
+        .load::<(String, Option<i32>, Option<String>, Option<i64>)>(&db.conn)?
+        .into_iter()
+        .map(|(id, sortindex, payload, ttl)| params::PostCollectionBso {
+            id,
+            sortindex,
+            payload,
+            ttl: ttl.map(|ttl| ttl as u32),
+        })
+        .collect())
 }
 
 pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    // Also deletes its batch_bsos rows (ON DELETE CASCADE)
     diesel::delete(batches::table)
         .filter(batches::user_id.eq(&user_id))
         .filter(batches::collection_id.eq(&collection_id))
@@ -109,17 +250,88 @@ pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
     Ok(())
 }
 
+/// Deletes up to `params.limit` expired batches (and their batch_bsos rows)
+pub fn purge_expired(
+    db: &MysqlDb,
+    params: params::PurgeExpiredBatches,
+) -> Result<results::PurgeExpiredBatches> {
+    let collection_id = match db.purge_collection_id(&params)? {
+        Some(collection_id) => collection_id,
+        None => return Ok(0),
+    };
+    let now = db.timestamp().as_i64();
+    let mut query = batches::table
+        .select((batches::user_id, batches::collection_id, batches::id))
+        .filter(batches::expiry.lt(now))
+        .limit(i64::from(params.limit))
+        .into_boxed();
+    if let Some(ref user_id) = params.user_id {
+        query = query.filter(batches::user_id.eq(user_id.legacy_id as i64));
+    }
+    if let Some(collection_id) = collection_id {
+        query = query.filter(batches::collection_id.eq(collection_id));
+    }
+    let expired: Vec<(i64, i32, i64)> = query.load(&db.conn)?;
+    if params.dry_run {
+        return Ok(expired.len() as u64);
+    }
+
+    let mut deleted = 0;
+    for ((user_id, collection_id), ids) in group_by_collection(expired) {
+        // Also deletes their batch_bsos rows (ON DELETE CASCADE)
+        deleted += diesel::delete(batches::table)
+            .filter(batches::user_id.eq(user_id))
+            .filter(batches::collection_id.eq(collection_id))
+            .filter(batches::id.eq_any(ids))
+            .filter(batches::expiry.lt(now))
+            .execute(&db.conn)?;
+    }
+    Ok(deleted as u64)
+}
+
+pub fn count(db: &MysqlDb, params: params::CountBatches) -> Result<results::CountBatches> {
+    let now = db.timestamp().as_i64();
+    let query = || {
+        let mut query = batches::table.into_boxed();
+        if let Some(user_id) = &params.user_id {
+            query = query.filter(batches::user_id.eq(user_id.legacy_id as i64));
+        }
+        query
+    };
+    let open: i64 = query().count().get_result(&db.conn)?;
+    let stale: i64 = query()
+        .filter(batches::expiry.lt(now))
+        .count()
+        .get_result(&db.conn)?;
+    Ok(results::CountBatches {
+        open: open as u64,
+        stale: stale as u64,
+    })
+}
+
 /// Commits a batch to the bsos table, deleting the batch when succesful
 pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::CommitBatch> {
-    let bsos = batch_string_to_bsos(&params.batch.bsos)?;
+    let id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let timestamp = db.timestamp();
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.sql.apply_batch", None);
-    let result = db.post_bsos_sync(params::PostBsos {
-        user_id: params.user_id.clone(),
-        collection: params.collection.clone(),
-        bsos,
-        failed: Default::default(),
-    });
+    // Lock user_collections before bso (as lock_for_write does)
+    db.touch_collection(user_id, collection_id)?;
+    // Updates of existing BSOs only overwrite the fields supplied (and only
+    // bump modified when the payload or sortindex were)
+    // (an INSERT ... SELECT: the payloads never cross the wire, so its size
+    // is unaffected by max_allowed_packet)
+    sql_query(include_str!("batch_commit.sql"))
+        .bind::<Nullable<Integer>, _>(db.default_sortindex)
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .bind::<BigInt, _>(i64::from(DEFAULT_BSO_TTL))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(collection_id)
+        .bind::<BigInt, _>(id)
+        .execute(&db.conn)?;
     delete(
         db,
         params::DeleteBatch {
@@ -128,7 +340,13 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::Comm
             id: params.batch.id,
         },
     )?;
-    result
+    // XXX: returning results::PostBsos here isn't needed
+    Ok(results::PostBsos {
+        modified: timestamp,
+        success: Default::default(),
+        failed: Default::default(),
+        created: None,
+    })
 }
 
 pub fn validate_batch_id(id: &str) -> Result<()> {
@@ -147,36 +365,6 @@ fn decode_id(id: &str) -> Result<i64> {
         .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
 }
 
-/// Deserialize a batch string into bsos
-fn batch_string_to_bsos(bsos: &str) -> Result<Vec<params::PostCollectionBso>> {
-    bsos.lines()
-        .map(|line| {
-            serde_json::from_str(line).map_err(|e| {
-                DbError::internal(&format!("Couldn't deserialize batch::load_bsos bso: {}", e))
-            })
-        })
-        .collect()
-}
-
-/// Serialize bsos into strings separated by newlines
-fn bsos_to_batch_string(bsos: &[params::PostCollectionBso]) -> Result<String> {
-    let batch_strings: Result<Vec<String>> = bsos
-        .iter()
-        .map(|bso| {
-            serde_json::to_string(bso).map_err(|e| {
-                DbError::internal(&format!("Couldn't serialize batch::create bso: {}", e))
-            })
-        })
-        .collect();
-    batch_strings.map(|bs| {
-        format!(
-            "{}{}",
-            bs.join("\n"),
-            if bsos.is_empty() { "" } else { "\n" }
-        )
-    })
-}
-
 #[macro_export]
 macro_rules! batch_db_method {
     ($name:ident, $batch_name:ident, $type:ident) => {
@@ -0,0 +1,19 @@
+INSERT INTO bso (userid, collection, id, sortindex, payload, modified, ttl)
+SELECT batch_bsos.userid,
+       batch_bsos.collection,
+       batch_bsos.id,
+       COALESCE(batch_bsos.sortindex, ?),
+       COALESCE(batch_bsos.payload, ''),
+       ?,
+       ? + COALESCE(batch_bsos.ttl, ?) * 1000
+  FROM batch_bsos
+ WHERE batch_bsos.userid = ?
+   AND batch_bsos.collection = ?
+   AND batch_bsos.batch_id = ?
+    ON DUPLICATE KEY UPDATE
+       modified = IF(batch_bsos.sortindex IS NULL AND batch_bsos.payload IS NULL,
+                     bso.modified,
+                     VALUES(modified)),
+       sortindex = COALESCE(batch_bsos.sortindex, bso.sortindex),
+       payload = COALESCE(batch_bsos.payload, bso.payload),
+       ttl = IF(batch_bsos.ttl IS NULL, bso.ttl, VALUES(ttl))
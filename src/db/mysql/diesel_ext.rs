@@ -1,36 +1,72 @@
 use diesel::{
     mysql::Mysql,
-    query_builder::{AstPass, QueryFragment},
-    query_dsl::methods::LockingDsl,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
     result::QueryResult,
+    sql_types::Text,
+    RunQueryDsl,
 };
 
-/// Emit MySQL <= 5.7's `LOCK IN SHARE MODE`
-///
-/// MySQL 8 supports `FOR SHARE` as an alias (which diesel natively supports)
-pub trait LockInShareModeDsl {
-    type Output;
+/// Wrap a query in `EXPLAIN FORMAT=JSON`, yielding its plan as a JSON string
+#[derive(Debug, Clone, Copy)]
+pub struct Explain<T>(pub T);
+
+impl<T> QueryId for Explain<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
 
-    fn lock_in_share_mode(self) -> Self::Output;
+impl<T> Query for Explain<T> {
+    type SqlType = Text;
 }
 
-impl<T> LockInShareModeDsl for T
+impl<T, Conn> RunQueryDsl<Conn> for Explain<T> {}
+
+impl<T> QueryFragment<Mysql> for Explain<T>
 where
-    T: LockingDsl<LockInShareMode>,
+    T: QueryFragment<Mysql>,
 {
-    type Output = <T as LockingDsl<LockInShareMode>>::Output;
-
-    fn lock_in_share_mode(self) -> Self::Output {
-        self.with_lock(LockInShareMode)
+    fn walk_ast(&self, mut out: AstPass<'_, Mysql>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN FORMAT=JSON ");
+        self.0.walk_ast(out.reborrow())
     }
 }
 
-#[derive(Debug, Clone, Copy, QueryId)]
-pub struct LockInShareMode;
+/// A multi-row `INSERT ... VALUES (..), (..) ON DUPLICATE KEY UPDATE ..`
+///
+/// Each row emits its own parenthesized (bound) values.
+#[derive(Debug)]
+pub struct InsertOnDuplicateKeyUpdate<'a, R> {
+    /// The `INSERT INTO table (columns)` prefix
+    pub insert: &'a str,
+    pub rows: &'a [R],
+    /// The assignments following `ON DUPLICATE KEY UPDATE`
+    pub update: &'a str,
+}
+
+impl<'a, R> QueryId for InsertOnDuplicateKeyUpdate<'a, R> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
 
-impl QueryFragment<Mysql> for LockInShareMode {
+impl<'a, R, Conn> RunQueryDsl<Conn> for InsertOnDuplicateKeyUpdate<'a, R> {}
+
+impl<'a, R> QueryFragment<Mysql> for InsertOnDuplicateKeyUpdate<'a, R>
+where
+    R: QueryFragment<Mysql>,
+{
     fn walk_ast(&self, mut out: AstPass<'_, Mysql>) -> QueryResult<()> {
-        out.push_sql(" LOCK IN SHARE MODE");
+        out.push_sql(self.insert);
+        out.push_sql(" VALUES ");
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            row.walk_ast(out.reborrow())?;
+        }
+        out.push_sql(" ON DUPLICATE KEY UPDATE ");
+        out.push_sql(self.update);
         Ok(())
     }
 }
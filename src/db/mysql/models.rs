@@ -1,37 +1,40 @@
 use actix_web::web::block;
 
-use futures::future::TryFutureExt;
+use futures::future::{self, TryFutureExt};
 
-use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc, time::Instant};
 
 use diesel::{
     connection::TransactionManager,
-    delete,
+    debug_query, delete,
     dsl::max,
     expression::sql_literal::sql,
-    mysql::MysqlConnection,
+    mysql::{Mysql, MysqlConnection},
+    query_builder::{AstPass, QueryFragment, QueryId},
     r2d2::{ConnectionManager, PooledConnection},
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError, QueryResult},
     sql_query,
     sql_types::{BigInt, Integer, Nullable, Text},
-    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    update, Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, RunQueryDsl,
 };
 #[cfg(test)]
 use diesel_logger::LoggingConnection;
 
 use super::{
     batch,
-    diesel_ext::LockInShareModeDsl,
-    pool::CollectionCache,
-    schema::{bso, collections, user_collections},
+    diesel_ext::Explain,
+    schema::{batch_bsos, batches, bso, collections, user_collections},
 };
 use crate::db::{
+    cache::CollectionCache,
+    codec::{self, PayloadCodec},
     error::{DbError, DbErrorKind},
     params, results,
-    util::SyncTimestamp,
-    Db, DbFuture, Sorting,
+    util::{PoolHealth, QueryPlanSampler, ResponseCeiling, SyncTimestamp},
+    Db, DbFuture, Sorting, FIRST_CUSTOM_COLLECTION_ID,
 };
 use crate::server::metrics::Metrics;
-use crate::web::extractors::{BsoQueryParams, HawkIdentifier};
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Keyset, Offset};
 
 no_arg_sql_function!(last_insert_id, Integer);
 
@@ -42,6 +45,22 @@ type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
 pub const DEFAULT_BSO_TTL: u32 = 2_100_000_000;
 
 pub const TOMBSTONE: i32 = 0;
+/// The modified timestamp of user_collections rows only inserted for
+/// lock_for_write to lock: they don't represent an existing collection until
+/// touched
+const PRETOUCH_TS: i64 = 0;
+/// Limits the rows of a single multi-row BSO upsert (its size is limited by
+/// the pool's `max_statement_bytes`)
+const MAX_UPSERT_ROWS: usize = 1000;
+/// Sums the stored payloads' sizes, of offloaded payloads those recorded in
+/// their references (`blob:<key>:<size>`). Only for codecs that offload:
+/// otherwise client payloads resembling a reference are sized as is (see
+/// `STORED_SIZE_SUM_INLINE`)
+const STORED_SIZE_SUM: &str = "SUM(IF(payload REGEXP '^blob:[0-9a-f]{64}:[0-9]+$', \
+                               CAST(SUBSTRING_INDEX(payload, ':', -1) AS SIGNED), \
+                               LENGTH(payload)))";
+/// Sums the stored payloads' sizes, all stored inline
+const STORED_SIZE_SUM_INLINE: &str = "SUM(LENGTH(payload))";
 /// SQL Variable remapping
 /// These names are the legacy values mapped to the new names.
 pub const COLLECTION_ID: &str = "collection";
@@ -62,9 +81,9 @@ struct MysqlDbSession {
     /// The "current time" on the server used for this session's operations
     timestamp: SyncTimestamp,
     /// Cache of collection modified timestamps per (user_id, collection_id)
-    coll_modified_cache: HashMap<(u32, i32), SyncTimestamp>,
+    coll_modified_cache: HashMap<(i64, i32), SyncTimestamp>,
     /// Currently locked collections
-    coll_locks: HashMap<(u32, i32), CollectionLock>,
+    coll_locks: HashMap<(i64, i32), CollectionLock>,
     /// Whether a transaction was started (begin() called)
     in_transaction: bool,
     in_write_transaction: bool,
@@ -85,6 +104,25 @@ pub struct MysqlDb {
     coll_cache: Arc<CollectionCache>,
 
     pub metrics: Metrics,
+
+    /// Sortindex applied to newly created BSOs lacking one
+    pub(super) default_sortindex: Option<i32>,
+
+    /// Limit of custom collections per user
+    max_collections_per_user: Option<u32>,
+
+    /// Pool level rate limiter for logging query plans
+    query_plans: Arc<QueryPlanSampler>,
+
+    /// Pool level health statistics
+    health: Arc<PoolHealth<ConnectionManager<MysqlConnection>>>,
+
+    /// Limit of a single multi-row write statement: a fraction of the
+    /// server's max_allowed_packet
+    pub(super) max_statement_bytes: usize,
+
+    /// Codec applied to payloads at rest
+    pub(super) codec: Arc<dyn PayloadCodec>,
 }
 
 /// Despite the db conn structs being !Sync (see Arc<MysqlDbInner> above) we
@@ -101,6 +139,23 @@ pub struct MysqlDbInner {
     session: RefCell<MysqlDbSession>,
 }
 
+impl Drop for MysqlDbInner {
+    /// Roll back a transaction left open (e.g. by a request aborted at the
+    /// end of a shutdown's drain) before the connection's returned to the
+    /// pool
+    fn drop(&mut self) {
+        if self.session.borrow().in_transaction {
+            if let Err(e) = self
+                .conn
+                .transaction_manager()
+                .rollback_transaction(&self.conn)
+            {
+                warn!("Rolling back an abandoned transaction failed: {}", e);
+            }
+        }
+    }
+}
+
 impl fmt::Debug for MysqlDbInner {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "MysqlDbInner {{ session: {:?} }}", self.session)
@@ -116,7 +171,18 @@ impl Deref for MysqlDb {
 }
 
 impl MysqlDb {
-    pub fn new(conn: Conn, coll_cache: Arc<CollectionCache>, metrics: &Metrics) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        default_sortindex: Option<i32>,
+        max_collections_per_user: Option<u32>,
+        query_plans: Arc<QueryPlanSampler>,
+        health: Arc<PoolHealth<ConnectionManager<MysqlConnection>>>,
+        max_statement_bytes: usize,
+        codec: Arc<dyn PayloadCodec>,
+    ) -> Self {
         let inner = MysqlDbInner {
             #[cfg(not(test))]
             conn,
@@ -128,19 +194,29 @@ impl MysqlDb {
             inner: Arc::new(inner),
             coll_cache,
             metrics: metrics.clone(),
+            default_sortindex,
+            max_collections_per_user,
+            query_plans,
+            health,
+            max_statement_bytes,
+            codec,
         }
     }
 
     /// APIs for collection-level locking
     ///
-    /// Explicitly lock the matching row in the user_collections table. Read
-    /// locks do SELECT ... LOCK IN SHARE MODE and write locks do SELECT
-    /// ... FOR UPDATE.
+    /// Write locks explicitly lock the matching row in the user_collections
+    /// table (SELECT ... FOR UPDATE), serializing concurrent writers to a
+    /// collection. Read locks don't lock: they read from the transaction's
+    /// consistent snapshot.
     ///
     /// In theory it would be possible to use serializable transactions rather
     /// than explicit locking, but our ops team have expressed concerns about
     /// the efficiency of that approach at scale.
-    pub fn lock_for_read_sync(&self, params: params::LockCollection) -> Result<()> {
+    ///
+    /// MySQL always reads the latest data (from the primary, or the replica
+    /// chosen for the request): `strong` is ignored.
+    pub fn lock_for_read_sync(&self, params: params::LockCollectionForRead) -> Result<()> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id =
             self.get_collection_id(&params.collection)
@@ -156,19 +232,19 @@ impl MysqlDb {
             .session
             .borrow()
             .coll_locks
-            .get(&(user_id as u32, collection_id))
+            .get(&(user_id, collection_id))
             .is_some()
         {
             return Ok(());
         }
 
-        // Lock the db
+        // Begin the snapshot
         self.begin(false)?;
         let modified = user_collections::table
             .select(user_collections::modified)
             .filter(user_collections::user_id.eq(user_id))
             .filter(user_collections::collection_id.eq(collection_id))
-            .lock_in_share_mode()
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
             .first(&self.conn)
             .optional()?;
         if let Some(modified) = modified {
@@ -176,13 +252,13 @@ impl MysqlDb {
             self.session
                 .borrow_mut()
                 .coll_modified_cache
-                .insert((user_id as u32, collection_id), modified); // why does it still expect a u32 int?
+                .insert((user_id, collection_id), modified);
         }
         // XXX: who's responsible for unlocking (removing the entry)
         self.session
             .borrow_mut()
             .coll_locks
-            .insert((user_id as u32, collection_id), CollectionLock::Read);
+            .insert((user_id, collection_id), CollectionLock::Read);
         Ok(())
     }
 
@@ -193,21 +269,40 @@ impl MysqlDb {
             .session
             .borrow()
             .coll_locks
-            .get(&(user_id as u32, collection_id))
+            .get(&(user_id, collection_id))
         {
             Err(DbError::internal("Can't escalate read-lock to write-lock"))?
         }
 
-        // Lock the db
-        self.begin(true)?;
+        // Lock the db (unless another collection's lock already began the
+        // transaction)
+        if !self.session.borrow().in_write_transaction {
+            self.begin(true)?;
+        }
+        // Ensure there's a row to lock: locking reads of a missing row only
+        // take gap locks, which don't exclude one another. This takes an
+        // exclusive lock on an existing row (unlike INSERT IGNORE's shared
+        // one, which would deadlock concurrent writers upgrading it)
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON DUPLICATE KEY UPDATE
+                      {modified} = {modified}"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(PRETOUCH_TS)
+        .execute(&self.conn)?;
         let modified = user_collections::table
             .select(user_collections::modified)
             .filter(user_collections::user_id.eq(user_id))
             .filter(user_collections::collection_id.eq(collection_id))
             .for_update()
-            .first(&self.conn)
-            .optional()?;
-        if let Some(modified) = modified {
+            .first::<i64>(&self.conn)?;
+        if modified > PRETOUCH_TS {
             let modified = SyncTimestamp::from_i64(modified)?;
             // Forbid the write if it would not properly incr the timestamp
             if modified >= self.timestamp() {
@@ -217,12 +312,12 @@ impl MysqlDb {
             self.session
                 .borrow_mut()
                 .coll_modified_cache
-                .insert((user_id as u32, collection_id), modified);
+                .insert((user_id, collection_id), modified);
         }
         self.session
             .borrow_mut()
             .coll_locks
-            .insert((user_id as u32, collection_id), CollectionLock::Write);
+            .insert((user_id, collection_id), CollectionLock::Write);
         Ok(())
     }
 
@@ -246,6 +341,8 @@ impl MysqlDb {
             self.conn
                 .transaction_manager()
                 .commit_transaction(&self.conn)?;
+            self.end_transaction();
+            self.health.record_success();
         }
         Ok(())
     }
@@ -255,11 +352,20 @@ impl MysqlDb {
             self.conn
                 .transaction_manager()
                 .rollback_transaction(&self.conn)?;
+            self.end_transaction();
         }
         Ok(())
     }
 
-    fn erect_tombstone(&self, user_id: i32) -> Result<()> {
+    /// Note the transaction's finished (so a repeated commit/rollback, or the
+    /// drop, is a no-op)
+    fn end_transaction(&self) {
+        let mut session = self.session.borrow_mut();
+        session.in_transaction = false;
+        session.in_write_transaction = false;
+    }
+
+    fn erect_tombstone(&self, user_id: i64) -> Result<()> {
         sql_query(format!(
             r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
                VALUES (?, ?, ?)
@@ -269,7 +375,7 @@ impl MysqlDb {
             collection_id = COLLECTION_ID,
             modified = LAST_MODIFIED
         ))
-        .bind::<BigInt, _>(user_id as i64)
+        .bind::<BigInt, _>(user_id)
         .bind::<Integer, _>(TOMBSTONE)
         .bind::<BigInt, _>(self.timestamp().as_i64())
         .execute(&self.conn)?;
@@ -286,6 +392,11 @@ impl MysqlDb {
         delete(user_collections::table)
             .filter(user_collections::user_id.eq(user_id))
             .execute(&self.conn)?;
+        // Delete pending batches (and their batch_bsos rows via ON DELETE
+        // CASCADE).
+        delete(batches::table)
+            .filter(batches::user_id.eq(user_id))
+            .execute(&self.conn)?;
         Ok(())
     }
 
@@ -305,29 +416,74 @@ impl MysqlDb {
         count += delete(user_collections::table)
             .filter(user_collections::user_id.eq(user_id))
             .filter(user_collections::collection_id.eq(&collection_id))
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
+            .execute(&self.conn)?;
+        // Pending batches would otherwise survive to be committed into the
+        // emptied collection
+        count += delete(batches::table)
+            .filter(batches::user_id.eq(user_id))
+            .filter(batches::collection_id.eq(&collection_id))
             .execute(&self.conn)?;
         if count == 0 {
             Err(DbErrorKind::CollectionNotFound)?
         } else {
-            self.erect_tombstone(user_id as i32)?;
+            self.erect_tombstone(user_id)?;
         }
         self.get_storage_timestamp_sync(params.user_id)
     }
 
     pub(super) fn create_collection(&self, name: &str) -> Result<i32> {
         // XXX: handle concurrent attempts at inserts
-        let id = self.conn.transaction(|| {
+        let id = self.conn.transaction::<_, DbError, _>(|| {
             sql_query(
                 "INSERT INTO collections (name)
                  VALUES (?)",
             )
             .bind::<Text, _>(name)
             .execute(&self.conn)?;
-            collections::table.select(last_insert_id).first(&self.conn)
+            let id = collections::table
+                .select(last_insert_id)
+                .first(&self.conn)?;
+            // Ids beneath this are reserved for standard collections
+            if id < FIRST_CUSTOM_COLLECTION_ID {
+                Err(DbErrorKind::Integrity(format!(
+                    "Collection {} allocated reserved id {}",
+                    name, id
+                )))?
+            }
+            Ok(id)
         })?;
         Ok(id)
     }
 
+    /// Create any missing rows for the standard collections, at their fixed
+    /// ids
+    pub(super) fn create_standard_collections(&self, colls: &[(i32, String)]) -> Result<()> {
+        self.conn.transaction(|| {
+            for (id, name) in colls {
+                sql_query(
+                    "INSERT IGNORE INTO collections (id, name)
+                     VALUES (?, ?)",
+                )
+                .bind::<Integer, _>(id)
+                .bind::<Text, _>(name)
+                .execute(&self.conn)?;
+                let stored = collections::table
+                    .select(collections::name)
+                    .filter(collections::id.eq(id))
+                    .first::<String>(&self.conn)
+                    .optional()?;
+                if stored.as_ref() != Some(name) {
+                    Err(DbErrorKind::Integrity(format!(
+                        "Standard collection {} ({}) conflicts with an existing collection",
+                        name, id
+                    )))?
+                }
+            }
+            Ok(())
+        })
+    }
+
     fn get_or_create_collection_id(&self, name: &str) -> Result<i32> {
         self.get_collection_id(name).or_else(|e| match e.kind() {
             DbErrorKind::CollectionNotFound => self.create_collection(name),
@@ -350,12 +506,28 @@ impl MysqlDb {
         .optional()?
         .ok_or(DbErrorKind::CollectionNotFound)?
         .id;
-        if !self.session.borrow().in_write_transaction {
-            self.coll_cache.put(id, name.to_owned())?;
-        }
+        self.cache_collection_id(id, name)?;
+        Ok(id)
+    }
+
+    pub fn get_or_create_collection_id_sync(
+        &self,
+        params: params::GetOrCreateCollectionId,
+    ) -> Result<i32> {
+        let id = self.get_or_create_collection_id(&params.collection)?;
+        self.check_collection_limit(params.user_id.legacy_id as i64, id)?;
         Ok(id)
     }
 
+    /// Cache the collection's id, unless within a write transaction (whose
+    /// writes, including the collection, may yet be rolled back)
+    fn cache_collection_id(&self, id: i32, name: &str) -> Result<()> {
+        if self.session.borrow().in_write_transaction {
+            return Ok(());
+        }
+        self.coll_cache.put(id, name.to_owned())
+    }
+
     fn _get_collection_name(&self, id: i32) -> Result<String> {
         let name = if let Some(name) = self.coll_cache.get_name(id)? {
             name
@@ -371,6 +543,7 @@ impl MysqlDb {
             .ok_or(DbErrorKind::CollectionNotFound)?
             .name
         };
+        self.cache_collection_id(id, &name)?;
         Ok(name)
     }
 
@@ -384,146 +557,232 @@ impl MysqlDb {
         */
 
         let collection_id = self.get_or_create_collection_id(&bso.collection)?;
-        let user_id: u64 = bso.user_id.legacy_id;
-        let timestamp = self.timestamp().as_i64();
-
+        let user_id = bso.user_id.legacy_id as i64;
+        let create_only = bso.create_only;
+        let bso = self.codec.encode_bso(params::PostCollectionBso {
+            id: bso.id,
+            sortindex: bso.sortindex,
+            payload: bso.payload,
+            ttl: bso.ttl,
+        })?;
+        // Lock user_collections before bso (as lock_for_write does) so
+        // concurrent writers can't deadlock
         self.conn.transaction(|| {
-            let payload = bso.payload.as_deref().unwrap_or_default();
-            let sortindex = bso.sortindex;
-            let ttl = bso.ttl.map_or(DEFAULT_BSO_TTL, |ttl| ttl);
-            let q = format!(r#"
-            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
-            VALUES (?, ?, ?, ?, ?, ?, ?)
-                ON DUPLICATE KEY UPDATE
-                   {user_id} = VALUES({user_id}),
-                   {collection_id} = VALUES({collection_id}),
-                   id = VALUES(id)
-            "#, user_id=USER_ID, modified=MODIFIED, collection_id=COLLECTION_ID, expiry=EXPIRY);
-            let q = format!(
-                "{}{}",
-                q,
-                if bso.sortindex.is_some() {
-                    ", sortindex = VALUES(sortindex)"
-                } else {
-                    ""
-                },
-            );
-            let q = format!(
-                "{}{}",
-                q,
-                if bso.payload.is_some() {
-                    ", payload = VALUES(payload)"
-                } else {
-                    ""
-                },
-            );
-            let q = format!(
-                "{}{}",
-                q,
-                if bso.ttl.is_some() {
-                    format!(", {expiry} = VALUES({expiry})", expiry=EXPIRY)
-                } else {
-                    "".to_owned()
-                },
-            );
-            let q = format!(
-                "{}{}",
-                q,
-                if bso.payload.is_some() || bso.sortindex.is_some() {
-                    format!(", {modified} = VALUES({modified})", modified=MODIFIED)
-                } else {
-                    "".to_owned()
-                },
-            );
-
-            sql_query(q)
-                .bind::<BigInt, _>(user_id as i64) // XXX:
-                .bind::<Integer, _>(&collection_id)
-                .bind::<Text, _>(&bso.id)
-                .bind::<Nullable<Integer>, _>(sortindex)
-                .bind::<Text, _>(payload)
-                .bind::<BigInt, _>(timestamp)
-                .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000))
-                .execute(&self.conn)?;
-
-            self.touch_collection(user_id as u32, collection_id)
+            let timestamp = self.touch_collection(user_id, collection_id)?;
+            if create_only {
+                // An expired BSO's replaced as though it were absent
+                delete(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(collection_id))
+                    .filter(bso::id.eq(&bso.id))
+                    .filter(bso::expiry.le(self.timestamp().as_i64()))
+                    .execute(&self.conn)?;
+            }
+            self.upsert_bsos(user_id, collection_id, &[bso], create_only)?;
+            Ok(timestamp)
         })
     }
 
-    pub fn get_bsos_sync(&self, params: params::GetBsos) -> Result<results::GetBsos> {
-        let user_id = params.user_id.legacy_id as i64;
-        let collection_id = self.get_collection_id(&params.collection)?;
-        let BsoQueryParams {
-            newer,
-            older,
-            sort,
-            limit,
-            offset,
-            ids,
-            ..
-        } = params.params;
+    /// Insert or update the BSOs in a single statement (see `UpsertBsos`)
+    ///
+    /// A `create_only` upsert only inserts, failing with `BsoExists` when
+    /// any of the BSOs exist.
+    fn upsert_bsos(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        bsos: &[params::PostCollectionBso],
+        create_only: bool,
+    ) -> Result<()> {
+        if bsos.is_empty() {
+            return Ok(());
+        }
+        UpsertBsos {
+            user_id,
+            collection_id,
+            bsos,
+            default_sortindex: self.default_sortindex,
+            timestamp: self.timestamp().as_i64(),
+            create_only,
+        }
+        .execute(&self.conn)
+        .map_err(|e| -> DbError {
+            match e {
+                DieselError::DatabaseError(UniqueViolation, _) if create_only => {
+                    DbErrorKind::BsoExists.into()
+                }
+                _ => e.into(),
+            }
+        })?;
+        Ok(())
+    }
 
-        let mut query = bso::table
-            .select((
-                bso::id,
-                bso::modified,
-                bso::payload,
-                bso::sortindex,
-                bso::expiry,
-            ))
+    /// Log the query's plan (and bound parameters) when sampled
+    ///
+    /// Diagnostic only: failures are logged, never returned.
+    fn log_query_plan<Q>(&self, query: &Q)
+    where
+        Q: QueryFragment<Mysql> + QueryId,
+    {
+        if !self.query_plans.sample() {
+            return;
+        }
+        let sql = debug_query::<Mysql, _>(query).to_string();
+        match Explain(query).get_result::<String>(&self.conn) {
+            Ok(plan) => info!("Query plan"; "query" => sql, "plan" => plan),
+            Err(e) => warn!("Query plan failed"; "query" => sql, "error" => e.to_string()),
+        }
+    }
+
+    /// A collection's unexpired BSOs
+    ///
+    /// Filters in the order of (and with bare column comparisons usable by)
+    /// the `bso_usr_col_exp_idx` index.
+    pub(super) fn unexpired_bsos(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+    ) -> bso::BoxedQuery<'static, Mysql> {
+        bso::table
             .filter(bso::user_id.eq(user_id))
-            .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
+            .filter(bso::collection_id.eq(collection_id))
             .filter(bso::expiry.gt(self.timestamp().as_i64()))
-            .into_boxed();
+            .into_boxed()
+    }
 
-        if let Some(older) = older {
+    /// A collection's unexpired BSOs matching the query's filters
+    fn filtered_bsos(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        params: &BsoQueryParams,
+    ) -> bso::BoxedQuery<'static, Mysql> {
+        let mut query = self.unexpired_bsos(user_id, collection_id);
+        // Compare against the bounds of each timestamp's stored values (see
+        // SyncTimestamp::storage_next)
+        if let Some(older) = params.older {
             query = query.filter(bso::modified.lt(older.as_i64()));
         }
-        if let Some(newer) = newer {
-            query = query.filter(bso::modified.gt(newer.as_i64()));
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.ge(newer.storage_next()));
         }
+        if let Some(older_eq) = params.older_eq {
+            query = query.filter(bso::modified.lt(older_eq.storage_next()));
+        }
+        if let Some(newer_eq) = params.newer_eq {
+            query = query.filter(bso::modified.ge(newer_eq.as_i64()));
+        }
+        if let Some(expiring_before) = params.expiring_before {
+            query = query.filter(bso::expiry.lt(expiring_before.as_i64()));
+        }
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids.clone()));
+        }
+        query
+    }
 
-        if !ids.is_empty() {
-            query = query.filter(bso::id.eq_any(ids));
+    /// A collection's BSOs matching the query in a stable (id tie broken)
+    /// sort order, beginning after the query's keyset offset (if any).
+    ///
+    /// Keyset pagination neither rescans the preceding rows nor skips or
+    /// repeats records when the collection's modified between pages (unlike
+    /// LIMIT/OFFSET, still used for plain numeric offsets)
+    fn sorted_bsos(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        params: &BsoQueryParams,
+    ) -> bso::BoxedQuery<'static, Mysql> {
+        let mut query = self.filtered_bsos(user_id, collection_id, params);
+        if let Some(Keyset { sort_key, id }) = params
+            .offset
+            .as_ref()
+            .and_then(|offset| offset.keyset.clone())
+        {
+            query = match (params.sort, sort_key) {
+                (Sorting::Newest, Some(modified)) => query.filter(
+                    bso::modified
+                        .lt(modified)
+                        .or(bso::modified.eq(modified).and(bso::id.lt(id))),
+                ),
+                (Sorting::Oldest, Some(modified)) => query.filter(
+                    bso::modified
+                        .gt(modified)
+                        .or(bso::modified.eq(modified).and(bso::id.gt(id))),
+                ),
+                // NULL sortindexes sort last
+                (Sorting::Index, Some(sortindex)) => {
+                    let sortindex = sortindex as i32;
+                    query.filter(
+                        bso::sortindex
+                            .lt(sortindex)
+                            .or(bso::sortindex.eq(sortindex).and(bso::id.lt(id)))
+                            .or(bso::sortindex.is_null()),
+                    )
+                }
+                (Sorting::Index, None) => {
+                    query.filter(bso::sortindex.is_null().and(bso::id.lt(id)))
+                }
+                _ => query.filter(bso::id.gt(id)),
+            };
+        }
+        match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+            Sorting::None => query.order(bso::id.asc()),
         }
+    }
 
-        query = match sort {
-            // issue559: Revert to previous sorting
-            /*
-            Sorting::Index => query.order(bso::id.desc()).order(bso::sortindex.desc()),
-            Sorting::Newest | Sorting::None => {
-                query.order(bso::id.desc()).order(bso::modified.desc())
-            }
-            Sorting::Oldest => query.order(bso::id.asc()).order(bso::modified.asc()),
-            */
-            Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
-            _ => query,
-        };
+    pub fn get_bsos_sync(&self, params: params::GetBsos) -> Result<results::GetBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut query = self
+            .sorted_bsos(user_id, collection_id, &params.params)
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ));
 
-        let limit = limit.map(i64::from).unwrap_or(-1);
+        let limit = params.params.limit.map(i64::from).unwrap_or(-1);
         // fetch an extra row to detect if there are more rows that
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
-
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
-
-        if numeric_offset != 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
+        if let Some(numeric_offset) = numeric_offset(&params.params) {
             query = query.offset(numeric_offset);
         }
-        let mut bsos = query.load::<results::GetBso>(&self.conn)?;
+        self.log_query_plan(&query);
+        let mut rows = query.load::<results::GetBso>(&self.conn)?;
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
         //if bsos.len() == 0 {
         //}
 
-        let next_offset = if limit >= 0 && bsos.len() > limit as usize {
-            bsos.pop();
-            Some((limit + numeric_offset).to_string())
+        let more = limit >= 0 && rows.len() > limit as usize;
+        if more {
+            rows.pop();
+        }
+        // Records beyond the response's ceiling are never decoded
+        let mut ceiling = ResponseCeiling::new(params.params.max_bytes);
+        let mut bsos = Vec::with_capacity(rows.len());
+        for row in rows {
+            let bso = self.codec.decode_bso(row)?;
+            if !ceiling.fits(&bso) {
+                break;
+            }
+            bsos.push(bso);
+        }
+
+        let next_offset = if more || ceiling.reached() {
+            let last = bsos.last().map(|bso| Keyset {
+                sort_key: sort_key(params.params.sort, bso.modified.as_i64(), bso.sortindex),
+                id: bso.id.clone(),
+            });
+            Some(next_offset(&params.params, bsos.len(), last))
         } else {
             None
         };
@@ -531,82 +790,118 @@ impl MysqlDb {
         Ok(results::GetBsos {
             items: bsos,
             offset: next_offset,
+            truncated: ceiling.reached(),
         })
     }
 
-    pub fn get_bso_ids_sync(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
+    pub fn get_bsos_after_sync(
+        &self,
+        params: params::GetBsosAfter,
+    ) -> Result<results::GetBsosAfter> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        let BsoQueryParams {
-            newer,
-            older,
+        let limit = params.limit.max(1);
+        let sort = match params.sort {
+            Sorting::Newest => Sorting::Newest,
+            _ => Sorting::Oldest,
+        };
+        // Resumed from the key as from a keyset offset
+        let query_params = BsoQueryParams {
             sort,
-            limit,
-            offset,
-            ids,
-            ..
-        } = params.params;
-
-        let mut query = bso::table
-            .select(bso::id)
-            .filter(bso::user_id.eq(user_id))
-            .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
-            .filter(bso::expiry.gt(self.timestamp().as_i64()))
-            .into_boxed();
-
-        if let Some(older) = older {
-            query = query.filter(bso::modified.lt(older.as_i64()));
-        }
-        if let Some(newer) = newer {
-            query = query.filter(bso::modified.gt(newer.as_i64()));
-        }
+            offset: params.after.map(|(modified, id)| Offset {
+                keyset: Some(Keyset {
+                    sort_key: Some(modified.as_i64()),
+                    id,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let query = self
+            .sorted_bsos(user_id, collection_id, &query_params)
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ))
+            // fetch an extra row to detect if there are more rows
+            .limit(i64::from(limit) + 1);
+        self.log_query_plan(&query);
+        let mut bsos = query
+            .load::<results::GetBso>(&self.conn)?
+            .into_iter()
+            .map(|bso| self.codec.decode_bso(bso))
+            .collect::<Result<Vec<_>>>()?;
 
-        if !ids.is_empty() {
-            query = query.filter(bso::id.eq_any(ids));
-        }
+        let more = bsos.len() > limit as usize;
+        bsos.truncate(limit as usize);
+        Ok(results::GetBsosAfter { items: bsos, more })
+    }
 
-        query = match sort {
-            Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
-            _ => query,
-        };
+    pub fn get_bso_ids_sync(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut query = self
+            .sorted_bsos(user_id, collection_id, &params.params)
+            .select((bso::id, bso::modified, bso::sortindex));
 
-        let limit = limit.map(i64::from).unwrap_or(-1);
+        let limit = params.params.limit.map(i64::from).unwrap_or(-1);
         // fetch an extra row to detect if there are more rows that
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
-
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
-        if numeric_offset != 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
+        if let Some(numeric_offset) = numeric_offset(&params.params) {
             query = query.offset(numeric_offset);
         }
-        let mut ids = query.load::<String>(&self.conn)?;
+        self.log_query_plan(&query);
+        let mut rows = query.load::<(String, i64, Option<i32>)>(&self.conn)?;
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
         //if bsos.len() == 0 {
         //}
 
-        let next_offset = if limit >= 0 && ids.len() > limit as usize {
-            ids.pop();
-            Some((limit + numeric_offset).to_string())
+        let more = limit >= 0 && rows.len() > limit as usize;
+        if more {
+            rows.pop();
+        }
+        let mut ceiling = ResponseCeiling::new(params.params.max_bytes);
+        if let Some(kept) = rows.iter().position(|(id, _, _)| !ceiling.fits(id)) {
+            rows.truncate(kept);
+        }
+
+        let next_offset = if more || ceiling.reached() {
+            let last = rows.last().map(|(id, modified, sortindex)| Keyset {
+                sort_key: sort_key(params.params.sort, *modified, *sortindex),
+                id: id.clone(),
+            });
+            Some(next_offset(&params.params, rows.len(), last))
         } else {
             None
         };
 
         Ok(results::GetBsoIds {
-            items: ids,
+            items: rows.into_iter().map(|(id, _, _)| id).collect(),
             offset: next_offset,
+            truncated: ceiling.reached(),
         })
     }
 
+    pub fn count_bsos_sync(&self, params: params::CountBsos) -> Result<results::CountBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let count = self
+            .filtered_bsos(user_id, collection_id, &params.params)
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        Ok(count as u64)
+    }
+
     pub fn get_bso_sync(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        Ok(bso::table
+        bso::table
             .select((
                 bso::id,
                 bso::modified,
@@ -617,16 +912,18 @@ impl MysqlDb {
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq(&params.id))
-            .filter(bso::expiry.ge(self.timestamp().as_i64()))
+            .filter(bso::expiry.gt(self.timestamp().as_i64()))
             .get_result::<results::GetBso>(&self.conn)
-            .optional()?)
+            .optional()?
+            .map(|bso| self.codec.decode_bso(bso))
+            .transpose()
     }
 
     pub fn delete_bso_sync(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
-        let user_id = params.user_id.legacy_id;
+        let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
         let affected_rows = delete(bso::table)
-            .filter(bso::user_id.eq(user_id as i64))
+            .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq(params.id))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
@@ -634,18 +931,40 @@ impl MysqlDb {
         if affected_rows == 0 {
             Err(DbErrorKind::BsoNotFound)?
         }
-        self.touch_collection(user_id as u32, collection_id)
+        self.touch_collection(user_id, collection_id)
     }
 
     pub fn delete_bsos_sync(&self, params: params::DeleteBsos) -> Result<results::DeleteBsos> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        delete(bso::table)
+        let deleted = delete(bso::table)
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq_any(params.ids))
             .execute(&self.conn)?;
-        self.touch_collection(user_id as u32, collection_id)
+        Ok(results::DeleteBsos {
+            modified: self.touch_collection(user_id, collection_id)?,
+            deleted: deleted as u64,
+        })
+    }
+
+    pub fn bulk_set_ttl_sync(&self, params: params::BulkSetTtl) -> Result<results::BulkSetTtl> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let timestamp = self.timestamp().as_i64();
+        // Lock user_collections before bso (as lock_for_write does)
+        let modified = self.touch_collection(user_id, collection_id)?;
+        update(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq_any(params.ids))
+            .filter(bso::expiry.gt(timestamp))
+            .set((
+                bso::modified.eq(timestamp),
+                bso::expiry.eq(timestamp + i64::from(params.ttl) * 1000),
+            ))
+            .execute(&self.conn)?;
+        Ok(modified)
     }
 
     pub fn post_bsos_sync(&self, input: params::PostBsos) -> Result<results::PostBsos> {
@@ -654,33 +973,88 @@ impl MysqlDb {
             modified: self.timestamp(),
             success: Default::default(),
             failed: input.failed,
+            created: None,
         };
 
-        for pbso in input.bsos {
-            let id = pbso.id;
-            let put_result = self.put_bso_sync(params::PutBso {
-                user_id: input.user_id.clone(),
-                collection: input.collection.clone(),
-                id: id.clone(),
-                payload: pbso.payload,
-                sortindex: pbso.sortindex,
-                ttl: pbso.ttl,
-            });
+        // Lock user_collections before bso (as lock_for_write does) so
+        // concurrent writers can't deadlock
+        let user_id = input.user_id.legacy_id as i64;
+        self.touch_collection(user_id, collection_id)?;
+        // Whether each upsert inserts or updates is determined upfront (the
+        // statements' affected row counts are only of the entire run)
+        let existing = if input.breakdown {
+            let ids: Vec<_> = input.bsos.iter().map(|bso| bso.id.as_str()).collect();
+            Some(
+                bso::table
+                    .select(bso::id)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .filter(bso::id.eq_any(ids))
+                    .load::<String>(&self.conn)?,
+            )
+        } else {
+            None
+        };
+
+        // Upsert the BSOs in order, keeping each statement beneath
+        // max_statement_bytes (they all share the transaction's timestamp)
+        let encoded = input
+            .bsos
+            .into_iter()
+            .map(|bso| self.codec.encode_bso(bso))
+            .collect::<Result<Vec<_>>>()?;
+        let mut bsos = &encoded[..];
+        while !bsos.is_empty() {
+            let mut len = 0;
+            let mut bytes = 0;
+            for bso in bsos.iter().take(MAX_UPSERT_ROWS) {
+                bytes += estimated_row_bytes(bso);
+                if len > 0 && bytes > self.max_statement_bytes {
+                    break;
+                }
+                len += 1;
+            }
+            let (chunk, rest) = bsos.split_at(len);
+            bsos = rest;
             // XXX: python version doesn't report failures from db
             // layer.. (wouldn't db failures abort the entire transaction
             // anyway?)
             // XXX: sanitize to.to_string()?
-            match put_result {
-                Ok(_) => result.success.push(id),
+            match self.upsert_bsos(user_id, collection_id, chunk, false) {
+                Ok(_) => result
+                    .success
+                    .extend(chunk.iter().map(|bso| bso.id.clone())),
+                // The entire transaction was rolled back
+                Err(e) if matches!(e.kind(), DbErrorKind::Conflict) => Err(e)?,
                 Err(e) => {
-                    result.failed.insert(id, e.to_string());
+                    let e = e.to_string();
+                    for bso in chunk {
+                        result.failed.insert(bso.id.clone(), e.clone());
+                    }
                 }
             }
         }
-        self.touch_collection(input.user_id.legacy_id as u32, collection_id)?;
+        if let Some(existing) = existing {
+            result.set_created(existing.into_iter().collect());
+        }
         Ok(result)
     }
 
+    pub fn reset_collection_sync(
+        &self,
+        input: params::ResetCollection,
+    ) -> Result<results::ResetCollection> {
+        let collection_id = self.get_or_create_collection_id(&input.collection)?;
+        let user_id = input.user_id.legacy_id as i64;
+        // Lock user_collections before bso (as lock_for_write does)
+        self.touch_collection(user_id, collection_id)?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .execute(&self.conn)?;
+        self.post_bsos_sync(input)
+    }
+
     pub fn get_storage_timestamp_sync(&self, user_id: HawkIdentifier) -> Result<SyncTimestamp> {
         let user_id = user_id.legacy_id as i64;
         let modified = user_collections::table
@@ -695,7 +1069,7 @@ impl MysqlDb {
         &self,
         params: params::GetCollectionTimestamp,
     ) -> Result<SyncTimestamp> {
-        let user_id = params.user_id.legacy_id as u32;
+        let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
         if let Some(modified) = self
             .session
@@ -707,8 +1081,9 @@ impl MysqlDb {
         }
         user_collections::table
             .select(user_collections::modified)
-            .filter(user_collections::user_id.eq(user_id as i64))
+            .filter(user_collections::user_id.eq(user_id))
             .filter(user_collections::collection_id.eq(collection_id))
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
             .first(&self.conn)
             .optional()?
             .ok_or_else(|| DbErrorKind::CollectionNotFound.into())
@@ -736,13 +1111,15 @@ impl MysqlDb {
             "SELECT {collection_id}, {modified}
                FROM user_collections
               WHERE {user_id} = ?
-               AND {collection_id} != ?",
+               AND {collection_id} != ?
+               AND {modified} > ?",
             collection_id = COLLECTION_ID,
             user_id = USER_ID,
             modified = LAST_MODIFIED
         ))
         .bind::<BigInt, _>(user_id.legacy_id as i64)
         .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(PRETOUCH_TS)
         .load::<UserCollectionsResult>(&self.conn)?
         .into_iter()
         .map(|cr| SyncTimestamp::from_i64(cr.last_modified).and_then(|ts| Ok((cr.collection, ts))))
@@ -750,12 +1127,130 @@ impl MysqlDb {
         self.map_collection_names(modifieds)
     }
 
+    pub fn get_collection_names_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetCollectionNames> {
+        let collection_ids = user_collections::table
+            .select(user_collections::collection_id)
+            .filter(user_collections::user_id.eq(user_id.legacy_id as i64))
+            .filter(user_collections::collection_id.ne(TOMBSTONE))
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
+            .load::<i32>(&self.conn)?
+            .into_iter()
+            .map(|id| (id, ()))
+            .collect();
+        let mut names: Vec<_> = self
+            .map_collection_names(collection_ids)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
     fn check_sync(&self) -> Result<results::Check> {
         // has the database been up for more than 0 seconds?
         let result = sql_query("SHOW STATUS LIKE \"Uptime\"").execute(&self.conn)?;
+        self.health.record_success();
         Ok(result as u64 > 0)
     }
 
+    fn health_detail_sync(&self) -> Result<results::HealthDetail> {
+        let start = Instant::now();
+        if !self.check_sync()? {
+            Err(DbError::internal("check failed without error"))?
+        }
+        Ok(self.health.detail(start.elapsed()))
+    }
+
+    pub fn try_acquire_maintenance_lock_sync(
+        &self,
+        params: params::TryAcquireMaintenanceLock,
+    ) -> Result<results::TryAcquireMaintenanceLock> {
+        let now = self.timestamp().as_i64();
+        // Only take over the row when its lease has expired. MySQL reports 1
+        // affected row for an insert, 2 for a changed row and 0 when the
+        // existing (live) lease was left untouched
+        let affected = sql_query(
+            "INSERT INTO maintenance_locks (name, expiry)
+             VALUES (?, ?)
+                 ON DUPLICATE KEY UPDATE
+                    expiry = IF(expiry <= ?, VALUES(expiry), expiry)",
+        )
+        .bind::<Text, _>(&params.name)
+        .bind::<BigInt, _>(now + i64::from(params.ttl) * 1000)
+        .bind::<BigInt, _>(now)
+        .execute(&self.conn)?;
+        Ok(affected > 0)
+    }
+
+    pub fn get_collections_sync(
+        &self,
+        params: params::GetCollections,
+    ) -> Result<results::GetCollections> {
+        Ok(collections::table
+            .select((collections::id, collections::name))
+            .filter(collections::id.gt(params.after_id))
+            .order(collections::id)
+            .limit(i64::from(params.limit))
+            .load(&self.conn)?)
+    }
+
+    pub fn purge_expired_bsos_sync(
+        &self,
+        params: params::PurgeExpiredBsos,
+    ) -> Result<results::PurgeExpiredBsos> {
+        let collection_id = match self.purge_collection_id(&params)? {
+            Some(collection_id) => collection_id,
+            None => return Ok(0),
+        };
+        let now = self.timestamp().as_i64();
+        let mut query = bso::table
+            .select((bso::user_id, bso::collection_id, bso::id))
+            .filter(bso::expiry.lt(now))
+            .limit(i64::from(params.limit))
+            .into_boxed();
+        if let Some(ref user_id) = params.user_id {
+            query = query.filter(bso::user_id.eq(user_id.legacy_id as i64));
+        }
+        if let Some(collection_id) = collection_id {
+            query = query.filter(bso::collection_id.eq(collection_id));
+        }
+        let expired: Vec<(i64, i32, String)> = query.load(&self.conn)?;
+        if params.dry_run {
+            return Ok(expired.len() as u64);
+        }
+
+        let mut deleted = 0;
+        for ((user_id, collection_id), ids) in group_by_collection(expired) {
+            deleted += delete(bso::table)
+                .filter(bso::user_id.eq(user_id))
+                .filter(bso::collection_id.eq(collection_id))
+                .filter(bso::id.eq_any(ids))
+                .filter(bso::expiry.lt(now))
+                .execute(&self.conn)?;
+        }
+        Ok(deleted as u64)
+    }
+
+    /// The id of the collection a purge is restricted to (if any), or `None`
+    /// when it doesn't exist (leaving nothing to purge)
+    pub(super) fn purge_collection_id(
+        &self,
+        params: &params::PurgeExpired,
+    ) -> Result<Option<Option<i32>>> {
+        let collection = match params.collection {
+            Some(ref collection) => collection,
+            None => return Ok(Some(None)),
+        };
+        match self.get_collection_id(collection) {
+            Ok(id) => Ok(Some(Some(id))),
+            Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> Result<HashMap<String, T>> {
         let mut names = self.load_collection_names(by_id.keys())?;
         by_id
@@ -790,10 +1285,8 @@ impl MysqlDb {
                 .load::<(i32, String)>(&self.conn)?;
 
             for (id, name) in result {
-                names.insert(id, name.clone());
-                if !self.session.borrow().in_write_transaction {
-                    self.coll_cache.put(id, name)?;
-                }
+                self.cache_collection_id(id, &name)?;
+                names.insert(id, name);
             }
         }
 
@@ -802,9 +1295,10 @@ impl MysqlDb {
 
     pub(super) fn touch_collection(
         &self,
-        user_id: u32,
+        user_id: i64,
         collection_id: i32,
     ) -> Result<SyncTimestamp> {
+        self.check_collection_limit(user_id, collection_id)?;
         let upsert = format!(
             r#"
                 INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
@@ -817,7 +1311,7 @@ impl MysqlDb {
             modified = LAST_MODIFIED
         );
         sql_query(upsert)
-            .bind::<BigInt, _>(user_id as i64)
+            .bind::<BigInt, _>(user_id)
             .bind::<Integer, _>(&collection_id)
             .bind::<BigInt, _>(&self.timestamp().as_i64())
             .bind::<BigInt, _>(&self.timestamp().as_i64())
@@ -825,12 +1319,54 @@ impl MysqlDb {
         Ok(self.timestamp())
     }
 
+    /// Ensure writing to the collection won't create a custom collection
+    /// beyond the user's `max_collections_per_user`
+    ///
+    /// Concurrent requests creating different collections may slightly
+    /// exceed the limit.
+    fn check_collection_limit(&self, user_id: i64, collection_id: i32) -> Result<()> {
+        let max = match self.max_collections_per_user {
+            Some(max) if collection_id >= FIRST_CUSTOM_COLLECTION_ID => max,
+            _ => return Ok(()),
+        };
+        let exists = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
+            .first::<i64>(&self.conn)
+            .optional()?
+            .is_some();
+        if exists {
+            return Ok(());
+        }
+        let count: i64 = user_collections::table
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ge(FIRST_CUSTOM_COLLECTION_ID))
+            .filter(user_collections::modified.gt(PRETOUCH_TS))
+            .count()
+            .get_result(&self.conn)?;
+        if count >= i64::from(max) {
+            Err(DbErrorKind::TooManyCollections)?
+        }
+        Ok(())
+    }
+
+    /// The storage usage sum for the codec's stored payloads
+    fn stored_size_sum(&self) -> &'static str {
+        if self.codec.offloads() {
+            STORED_SIZE_SUM
+        } else {
+            STORED_SIZE_SUM_INLINE
+        }
+    }
+
     pub fn get_storage_usage_sync(
         &self,
         user_id: HawkIdentifier,
     ) -> Result<results::GetStorageUsage> {
         let total_size = bso::table
-            .select(sql::<Nullable<BigInt>>("SUM(LENGTH(payload))"))
+            .select(sql::<Nullable<BigInt>>(self.stored_size_sum()))
             .filter(bso::user_id.eq(user_id.legacy_id as i64))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
             .get_result::<Option<i64>>(&self.conn)?;
@@ -842,7 +1378,7 @@ impl MysqlDb {
         user_id: HawkIdentifier,
     ) -> Result<results::GetCollectionUsage> {
         let counts = bso::table
-            .select((bso::collection_id, sql::<BigInt>("SUM(LENGTH(payload))")))
+            .select((bso::collection_id, sql::<BigInt>(self.stored_size_sum())))
             .filter(bso::user_id.eq(user_id.legacy_id as i64))
             .filter(bso::expiry.gt(&self.timestamp().as_i64()))
             .group_by(bso::collection_id)
@@ -854,9 +1390,10 @@ impl MysqlDb {
 
     pub fn get_collection_counts_sync(
         &self,
-        user_id: HawkIdentifier,
+        params: params::GetCollectionCounts,
     ) -> Result<results::GetCollectionCounts> {
-        let counts = bso::table
+        let user_id = params.user_id.legacy_id as i64;
+        let query = bso::table
             .select((
                 bso::collection_id,
                 sql::<BigInt>(&format!(
@@ -864,22 +1401,63 @@ impl MysqlDb {
                     collection_id = COLLECTION_ID
                 )),
             ))
-            .filter(bso::user_id.eq(user_id.legacy_id as i64))
-            .filter(bso::expiry.gt(&self.timestamp().as_i64()))
-            .group_by(bso::collection_id)
-            .load(&self.conn)?
-            .into_iter()
-            .collect();
-        self.map_collection_names(counts)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::expiry.gt(&self.timestamp().as_i64()));
+        let counts: Vec<(i32, i64)> = match params.newer {
+            Some(newer) => query
+                .filter(
+                    bso::collection_id.eq_any(
+                        user_collections::table
+                            .select(user_collections::collection_id)
+                            .filter(user_collections::user_id.eq(user_id))
+                            .filter(user_collections::modified.gt(newer.as_i64())),
+                    ),
+                )
+                .group_by(bso::collection_id)
+                .load(&self.conn)?,
+            None => query.group_by(bso::collection_id).load(&self.conn)?,
+        };
+        self.map_collection_names(counts.into_iter().collect())
     }
 
     batch_db_method!(create_batch_sync, create, CreateBatch);
     batch_db_method!(validate_batch_sync, validate, ValidateBatch);
     batch_db_method!(append_to_batch_sync, append, AppendToBatch);
     batch_db_method!(commit_batch_sync, commit, CommitBatch);
+    batch_db_method!(get_batch_bsos_sync, get_bsos, GetBatchBsos);
+    batch_db_method!(
+        purge_expired_batches_sync,
+        purge_expired,
+        PurgeExpiredBatches
+    );
     pub fn validate_batch_id(&self, id: String) -> Result<()> {
         batch::validate_batch_id(&id)
     }
+    pub fn count_batches_sync(
+        &self,
+        params: params::CountBatches,
+    ) -> Result<results::CountBatches> {
+        batch::count(&self, params)
+    }
+
+    pub fn get_blob_references_sync(&self) -> Result<results::GetBlobReferences> {
+        let bsos = bso::table
+            .select(bso::payload)
+            .filter(bso::payload.like("blob:%"))
+            .distinct()
+            .load::<String>(&self.conn)?;
+        let batch_bsos = batch_bsos::table
+            .select(batch_bsos::payload)
+            .filter(batch_bsos::payload.like("blob:%"))
+            .distinct()
+            .load::<Option<String>>(&self.conn)?;
+        Ok(bsos
+            .iter()
+            .chain(batch_bsos.iter().flatten())
+            .filter_map(|stored| codec::blob_key(stored))
+            .map(ToOwned::to_owned)
+            .collect())
+    }
     #[cfg(test)]
     batch_db_method!(delete_batch_sync, delete, DeleteBatch);
 
@@ -899,7 +1477,10 @@ macro_rules! sync_db_method {
     ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
         fn $name(&self, params: params::$type) -> DbFuture<$result> {
             let db = self.clone();
-            Box::pin(block(move || db.$sync_name(params).map_err(Into::into)).map_err(Into::into))
+            Box::pin(
+                block(move || db.$sync_name(params).map_err(db_op_error!("mysql", $name)))
+                    .map_err(Into::into),
+            )
         }
     };
 }
@@ -907,17 +1488,31 @@ macro_rules! sync_db_method {
 impl Db for MysqlDb {
     fn commit(&self) -> DbFuture<()> {
         let db = self.clone();
-        Box::pin(block(move || db.commit_sync().map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || db.commit_sync().map_err(db_op_error!("mysql", commit)))
+                .map_err(Into::into),
+        )
+    }
+
+    fn commit_and_get_timestamp(&self) -> DbFuture<SyncTimestamp> {
+        Box::pin(future::ok(self.timestamp()))
     }
 
     fn rollback(&self) -> DbFuture<()> {
         let db = self.clone();
-        Box::pin(block(move || db.rollback_sync().map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || db.rollback_sync().map_err(db_op_error!("mysql", rollback)))
+                .map_err(Into::into),
+        )
     }
 
     fn begin(&self, for_write: bool) -> DbFuture<()> {
         let db = self.clone();
-        Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
+        Box::pin(async move {
+            db.begin_async(for_write)
+                .map_err(db_op_error!("mysql", begin))
+                .await
+        })
     }
 
     fn box_clone(&self) -> Box<dyn Db> {
@@ -926,16 +1521,39 @@ impl Db for MysqlDb {
 
     fn check(&self) -> DbFuture<results::Check> {
         let db = self.clone();
-        Box::pin(block(move || db.check_sync().map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || db.check_sync().map_err(db_op_error!("mysql", check)))
+                .map_err(Into::into),
+        )
+    }
+
+    fn health_detail(&self) -> DbFuture<results::HealthDetail> {
+        let db = self.clone();
+        Box::pin(
+            block(move || {
+                db.health_detail_sync()
+                    .map_err(db_op_error!("mysql", health_detail))
+            })
+            .map_err(Into::into),
+        )
+    }
+
+    fn last_success(&self) -> Option<u64> {
+        self.health.last_success()
     }
 
-    sync_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
+    sync_db_method!(lock_for_read, lock_for_read_sync, LockCollectionForRead);
     sync_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
     sync_db_method!(
         get_collection_timestamps,
         get_collection_timestamps_sync,
         GetCollectionTimestamps
     );
+    sync_db_method!(
+        get_collection_names,
+        get_collection_names_sync,
+        GetCollectionNames
+    );
     sync_db_method!(
         get_collection_timestamp,
         get_collection_timestamp_sync,
@@ -960,9 +1578,13 @@ impl Db for MysqlDb {
     sync_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
     sync_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
     sync_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
+    sync_db_method!(bulk_set_ttl, bulk_set_ttl_sync, BulkSetTtl);
     sync_db_method!(get_bsos, get_bsos_sync, GetBsos);
     sync_db_method!(get_bso_ids, get_bso_ids_sync, GetBsoIds);
+    sync_db_method!(get_bsos_after, get_bsos_after_sync, GetBsosAfter);
+    sync_db_method!(count_bsos, count_bsos_sync, CountBsos);
     sync_db_method!(post_bsos, post_bsos_sync, PostBsos);
+    sync_db_method!(reset_collection, reset_collection_sync, ResetCollection);
     sync_db_method!(delete_bso, delete_bso_sync, DeleteBso);
     sync_db_method!(get_bso, get_bso_sync, GetBso, Option<results::GetBso>);
     sync_db_method!(
@@ -981,22 +1603,78 @@ impl Db for MysqlDb {
         GetBatch,
         Option<results::GetBatch>
     );
+    sync_db_method!(get_batch_bsos, get_batch_bsos_sync, GetBatchBsos);
     sync_db_method!(commit_batch, commit_batch_sync, CommitBatch);
+    sync_db_method!(
+        try_acquire_maintenance_lock,
+        try_acquire_maintenance_lock_sync,
+        TryAcquireMaintenanceLock
+    );
+    sync_db_method!(
+        purge_expired_bsos,
+        purge_expired_bsos_sync,
+        PurgeExpiredBsos
+    );
+    sync_db_method!(
+        purge_expired_batches,
+        purge_expired_batches_sync,
+        PurgeExpiredBatches
+    );
+    sync_db_method!(get_collections, get_collections_sync, GetCollections);
 
-    fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<()> {
-        self.validate_batch_id(params)
+    fn get_collection_id(&self, name: params::GetCollectionId) -> DbFuture<i32> {
+        let db = self.clone();
+        Box::pin(
+            block(move || {
+                db.get_collection_id(&name)
+                    .map_err(db_op_error!("mysql", get_collection_id))
+            })
+            .map_err(Into::into),
+        )
     }
 
-    #[cfg(test)]
-    fn get_collection_id(&self, name: String) -> DbFuture<i32> {
+    sync_db_method!(
+        get_or_create_collection_id,
+        get_or_create_collection_id_sync,
+        GetOrCreateCollectionId
+    );
+
+    fn count_batches(&self, params: params::CountBatches) -> DbFuture<results::CountBatches> {
         let db = self.clone();
-        Box::pin(block(move || db.get_collection_id(&name).map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || {
+                db.count_batches_sync(params)
+                    .map_err(db_op_error!("mysql", count_batches))
+            })
+            .map_err(Into::into),
+        )
+    }
+
+    fn get_blob_references(&self) -> DbFuture<results::GetBlobReferences> {
+        let db = self.clone();
+        Box::pin(
+            block(move || {
+                db.get_blob_references_sync()
+                    .map_err(db_op_error!("mysql", get_blob_references))
+            })
+            .map_err(Into::into),
+        )
+    }
+
+    fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<()> {
+        self.validate_batch_id(params)
     }
 
     #[cfg(test)]
     fn create_collection(&self, name: String) -> DbFuture<i32> {
         let db = self.clone();
-        Box::pin(block(move || db.create_collection(&name).map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || {
+                db.create_collection(&name)
+                    .map_err(db_op_error!("mysql", create_collection))
+            })
+            .map_err(Into::into),
+        )
     }
 
     #[cfg(test)]
@@ -1004,23 +1682,25 @@ impl Db for MysqlDb {
         let db = self.clone();
         Box::pin(
             block(move || {
-                db.touch_collection(param.user_id.legacy_id as u32, param.collection_id)
-                    .map_err(Into::into)
+                db.touch_collection(param.user_id.legacy_id as i64, param.collection_id)
+                    .map_err(db_op_error!("mysql", touch_collection))
             })
             .map_err(Into::into),
         )
     }
 
-    #[cfg(test)]
     fn timestamp(&self) -> SyncTimestamp {
         self.timestamp()
     }
 
-    #[cfg(test)]
     fn set_timestamp(&self, timestamp: SyncTimestamp) {
         self.session.borrow_mut().timestamp = timestamp;
     }
 
+    fn set_metrics_collection(&mut self, collection: &str) {
+        self.metrics = self.metrics.clone().with_collection(collection);
+    }
+
     #[cfg(test)]
     sync_db_method!(delete_batch, delete_batch_sync, DeleteBatch);
 
@@ -1028,6 +1708,143 @@ impl Db for MysqlDb {
     fn clear_coll_cache(&self) {
         self.coll_cache.clear();
     }
+
+    #[cfg(test)]
+    fn cached_collection_id(&self, name: &str) -> Option<i32> {
+        self.coll_cache.get_id(name).unwrap_or_default()
+    }
+}
+
+/// The SQL OFFSET of a query resuming from a plain numeric (not keyset)
+/// offset
+fn numeric_offset(params: &BsoQueryParams) -> Option<i64> {
+    match params.offset {
+        Some(Offset {
+            offset,
+            keyset: None,
+            ..
+        }) if offset > 0 => Some(offset as i64),
+        _ => None,
+    }
+}
+
+/// A BSO's key in the given sort order
+fn sort_key(sort: Sorting, modified: i64, sortindex: Option<i32>) -> Option<i64> {
+    match sort {
+        Sorting::Newest | Sorting::Oldest => Some(modified),
+        Sorting::Index => sortindex.map(i64::from),
+        Sorting::None => None,
+    }
+}
+
+/// Encode the offset of the page following one of `len` records ending with
+/// `last`
+fn next_offset(params: &BsoQueryParams, len: usize, last: Option<Keyset>) -> String {
+    let offset = params.offset.clone().unwrap_or_default();
+    match last {
+        Some(keyset) => Offset {
+            timestamp: None,
+            offset: offset.offset + len as u64,
+            keyset: Some(keyset),
+        }
+        .to_string(),
+        // An empty page: resume from the same position
+        None => offset.to_string(),
+    }
+}
+
+/// Group expired rows' ids by their (user_id, collection_id)
+pub(super) fn group_by_collection<T>(rows: Vec<(i64, i32, T)>) -> HashMap<(i64, i32), Vec<T>> {
+    let mut groups: HashMap<_, Vec<T>> = HashMap::new();
+    for (user_id, collection_id, id) in rows {
+        groups.entry((user_id, collection_id)).or_default().push(id);
+    }
+    groups
+}
+
+/// Rough size of a BSO's row within a multi-row insert on the wire
+pub(super) fn estimated_row_bytes(bso: &params::PostCollectionBso) -> usize {
+    bso.id.len() + bso.payload.as_ref().map_or(0, String::len) + 64
+}
+
+/// A single `INSERT ... SELECT ... ON DUPLICATE KEY UPDATE` of a user's
+/// BSOs (within a collection), selected from a derived table of their
+/// (nullable) fields
+///
+/// Updates of existing BSOs only overwrite the fields supplied (and only
+/// bump modified when the payload or sortindex were), as batch_commit.sql
+/// does: BSOs supplying differing fields share the statement. A
+/// `create_only` upsert omits the `ON DUPLICATE KEY UPDATE`, so fails with a
+/// `UniqueViolation` when a BSO exists.
+struct UpsertBsos<'a> {
+    user_id: i64,
+    collection_id: i32,
+    bsos: &'a [params::PostCollectionBso],
+    /// Only applies to the INSERT: updates of existing BSOs leave their
+    /// sortindex untouched when omitted
+    default_sortindex: Option<i32>,
+    timestamp: i64,
+    create_only: bool,
+}
+
+impl<'a> QueryId for UpsertBsos<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for UpsertBsos<'a> {}
+
+impl<'a> QueryFragment<Mysql> for UpsertBsos<'a> {
+    fn walk_ast(&self, mut out: AstPass<'_, Mysql>) -> QueryResult<()> {
+        out.push_sql(&format!(
+            "INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry}) SELECT ",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = MODIFIED,
+            expiry = EXPIRY
+        ));
+        out.push_bind_param::<BigInt, _>(&self.user_id)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Integer, _>(&self.collection_id)?;
+        out.push_sql(", new_bsos.id, COALESCE(new_bsos.sortindex, ");
+        out.push_bind_param::<Nullable<Integer>, _>(&self.default_sortindex)?;
+        out.push_sql("), COALESCE(new_bsos.payload, ''), ");
+        out.push_bind_param::<BigInt, _>(&self.timestamp)?;
+        out.push_sql(", ");
+        out.push_bind_param::<BigInt, _>(&self.timestamp)?;
+        out.push_sql(" + COALESCE(new_bsos.ttl, ");
+        out.push_bind_param::<BigInt, _>(&i64::from(DEFAULT_BSO_TTL))?;
+        out.push_sql(") * 1000 FROM (");
+        for (i, bso) in self.bsos.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(" UNION ALL ");
+            }
+            out.push_sql("SELECT ");
+            out.push_bind_param::<Text, _>(&bso.id)?;
+            out.push_sql(" AS id, ");
+            out.push_bind_param::<Nullable<Integer>, _>(&bso.sortindex)?;
+            out.push_sql(" AS sortindex, ");
+            out.push_bind_param::<Nullable<Text>, _>(&bso.payload)?;
+            out.push_sql(" AS payload, ");
+            out.push_bind_param::<Nullable<BigInt>, _>(&bso.ttl.map(i64::from))?;
+            out.push_sql(" AS ttl");
+        }
+        out.push_sql(") AS new_bsos");
+        if !self.create_only {
+            out.push_sql(&format!(
+                " ON DUPLICATE KEY UPDATE \
+                 {modified} = IF(new_bsos.sortindex IS NULL AND new_bsos.payload IS NULL, \
+                                 bso.{modified}, VALUES({modified})), \
+                 sortindex = COALESCE(new_bsos.sortindex, bso.sortindex), \
+                 payload = COALESCE(new_bsos.payload, bso.payload), \
+                 {expiry} = IF(new_bsos.ttl IS NULL, bso.{expiry}, VALUES({expiry}))",
+                modified = MODIFIED,
+                expiry = EXPIRY
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, QueryableByName)]
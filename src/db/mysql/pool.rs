@@ -2,36 +2,112 @@ use actix_web::web::block;
 
 use futures::future::TryFutureExt;
 
-use std::{
-    collections::HashMap,
-    fmt,
-    sync::{Arc, RwLock},
-};
+use std::{fmt, result::Result as StdResult, sync::Arc, time::Duration};
 
 use diesel::{
+    connection::SimpleConnection,
+    dsl::sql,
     mysql::MysqlConnection,
-    r2d2::{ConnectionManager, Pool},
-    Connection,
+    r2d2::{ConnectionManager, CustomizeConnection, Error as PoolError, Pool},
+    sql_query,
+    sql_types::{BigInt, Text},
+    Connection, ExpressionMethods, QueryDsl, RunQueryDsl,
 };
+use diesel_migrations::MigrationConnection;
 
-use super::models::{MysqlDb, Result};
-#[cfg(test)]
-use super::test::TestTransactionCustomizer;
-use crate::db::{error::DbError, results, Db, DbFuture, DbPool, STD_COLLS};
+use super::{
+    models::{MysqlDb, Result},
+    schema::batches,
+};
+use crate::db::{
+    cache::CollectionCache,
+    codec::{self, PayloadCodec},
+    error::DbErrorKind,
+    results, standard_collections,
+    util::{acquire_conn, build_pool, ms_since_epoch, PoolHealth, QueryPlanSampler},
+    Db, DbFuture, DbPool,
+};
 use crate::server::metrics::Metrics;
 use crate::settings::Settings;
 
 embed_migrations!();
 
+/// Statements initializing every new connection's session, so behavior
+/// doesn't vary with the server's defaults:
+///
+/// - Strict mode: over-length or out of range values error rather than being
+///   silently truncated (diesel requires PIPES_AS_CONCAT)
+/// - UTC: timestamps are computed as milliseconds since the (UTC) epoch,
+///   any SQL date/time functions must agree with them
+/// - utf8mb4: payloads may contain 4-byte UTF-8
+pub const DEFAULT_SESSION_INIT: &str = "\
+    SET SESSION sql_mode = 'STRICT_TRANS_TABLES,ERROR_FOR_DIVISION_BY_ZERO,NO_ENGINE_SUBSTITUTION,PIPES_AS_CONCAT'; \
+    SET SESSION time_zone = '+00:00'; \
+    SET NAMES utf8mb4 COLLATE utf8mb4_bin";
+
+/// Versions of the embedded migrations (the `migrations` directory), oldest
+/// first
+pub(super) const MIGRATION_VERSIONS: &[&str] = &[
+    "20180828010336",
+    "20190911164500",
+    "20190925174347",
+    "20200403102015",
+    "20200513000000",
+    "20200520000000",
+    "20200527000000",
+    "20200601000000",
+    "20200608000000",
+];
+
+/// The migration moving batches' BSOs into batch_bsos, discarding the
+/// pending batches (their serialized BSOs aren't converted)
+const BATCH_BSOS_MIGRATION: &str = "20200520000000";
+
 /// Run the diesel embedded migrations
 ///
 /// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
-/// begin_test_transaction during tests. So this runs on its own separate conn.
-pub fn run_embedded_migrations(settings: &Settings) -> Result<()> {
+/// begin_test_transaction during tests. So this runs on its own separate
+/// (non pooled) conn.
+pub fn run_migrations(settings: &Settings) -> Result<()> {
     let conn = MysqlConnection::establish(&settings.database_url)?;
+    warn_of_discarded_batches(&conn)?;
     Ok(embedded_migrations::run(&conn)?)
 }
 
+/// Log the pending batches about to be discarded by the batch_bsos
+/// migration: their clients' commits then fail (as with expired batches),
+/// restarting their uploads
+fn warn_of_discarded_batches(conn: &MysqlConnection) -> Result<()> {
+    // No migrations table: none have ran (so there's no batches table)
+    let applied = conn.previously_run_migration_versions().unwrap_or_default();
+    if applied.is_empty() || applied.contains(BATCH_BSOS_MIGRATION) {
+        return Ok(());
+    }
+    let pending = batches::table
+        .filter(batches::expiry.gt(ms_since_epoch()))
+        .count()
+        .get_result::<i64>(conn)?;
+    if pending > 0 {
+        warn!("Migrating to batch_bsos discards the pending batches"; "batches" => pending);
+    }
+    Ok(())
+}
+
+/// Ensure the database schema isn't behind the code when migrations are left
+/// to a separate deploy step
+pub fn check_migrations(settings: &Settings) -> Result<()> {
+    let conn = MysqlConnection::establish(&settings.database_url)?;
+    // No migrations table: none have ran
+    let applied = conn.previously_run_migration_versions().unwrap_or_default();
+    if let Some(missing) = MIGRATION_VERSIONS
+        .iter()
+        .find(|version| !applied.contains(**version))
+    {
+        Err(DbErrorKind::MissingMigration((*missing).to_owned()))?
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MysqlDbPool {
     /// Pool of db connections
@@ -41,40 +117,86 @@ pub struct MysqlDbPool {
     coll_cache: Arc<CollectionCache>,
 
     metrics: Metrics,
+    /// Sortindex applied to newly created BSOs lacking one
+    default_sortindex: Option<i32>,
+    /// Limit of custom collections per user
+    max_collections_per_user: Option<u32>,
+    /// Rate limiter for logging query plans
+    query_plans: Arc<QueryPlanSampler>,
+    /// Health statistics of the pool
+    health: Arc<PoolHealth<ConnectionManager<MysqlConnection>>>,
+    /// Log waits for a connection longer than this
+    acquire_warn: Duration,
+    /// Limit of a single multi-row write statement
+    max_statement_bytes: usize,
+    /// Codec applied to payloads at rest
+    codec: Arc<dyn PayloadCodec>,
 }
 
 impl MysqlDbPool {
     /// Creates a new pool of Mysql db connections.
     ///
-    /// Also initializes the Mysql db, ensuring all migrations are ran.
+    /// Also initializes the Mysql db, ensuring all migrations are ran (or
+    /// were, when `run_migrations` is disabled) and the standard collections
+    /// exist.
     pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
-        run_embedded_migrations(settings)?;
-        Self::new_without_migrations(settings, metrics)
+        if settings.run_migrations() {
+            run_migrations(settings)?;
+        } else {
+            check_migrations(settings)?;
+        }
+        let pool = Self::new_without_migrations(settings, metrics)?;
+        pool.get_sync()?
+            .create_standard_collections(&standard_collections(settings)?)?;
+        Ok(pool)
     }
 
     pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
         let manager = ConnectionManager::<MysqlConnection>::new(settings.database_url.clone());
-        let builder = Pool::builder().max_size(settings.database_pool_max_size.unwrap_or(10));
+        let max_size = settings.database_pool_max_size.unwrap_or(10);
+        let builder = Pool::builder()
+            .max_size(max_size)
+            .min_idle(settings.database_pool_min_idle.map(|n| n.min(max_size)))
+            .connection_customizer(Box::new(MysqlConnectionCustomizer {
+                session_init: settings.database_session_init.clone(),
+                label: settings.database_connection_label(),
+                #[cfg(test)]
+                use_test_transactions: settings.database_use_test_transactions,
+            }));
 
-        #[cfg(test)]
-        let builder = if settings.database_use_test_transactions {
-            builder.connection_customizer(Box::new(TestTransactionCustomizer))
-        } else {
-            builder
+        let pool = build_pool(builder, manager, settings.database_pool_warmup)?;
+        let max_allowed_packet = match settings.database_max_allowed_packet {
+            Some(max_allowed_packet) => u64::from(max_allowed_packet),
+            None => diesel::select(sql::<BigInt>("@@max_allowed_packet"))
+                .get_result::<i64>(&pool.get()?)? as u64,
         };
-
+        let max_statement_bytes =
+            (max_allowed_packet as f64 * settings.database_max_packet_fraction) as usize;
         Ok(Self {
-            pool: builder.build(manager)?,
-            coll_cache: Default::default(),
+            health: Arc::new(PoolHealth::new(pool.clone())),
+            pool,
+            coll_cache: Arc::new(CollectionCache::new(&standard_collections(settings)?)),
             metrics: metrics.clone(),
+            default_sortindex: settings.default_sortindex,
+            max_collections_per_user: settings.max_collections_per_user,
+            query_plans: Arc::new(QueryPlanSampler::new(settings.database_query_plan_interval)),
+            acquire_warn: Duration::from_millis(settings.database_pool_acquire_warn_ms),
+            max_statement_bytes,
+            codec: codec::from_settings(settings)?,
         })
     }
 
     pub fn get_sync(&self) -> Result<MysqlDb> {
         Ok(MysqlDb::new(
-            self.pool.get()?,
+            acquire_conn(&self.pool, &self.metrics, self.acquire_warn)?,
             Arc::clone(&self.coll_cache),
             &self.metrics,
+            self.default_sortindex,
+            self.max_collections_per_user,
+            Arc::clone(&self.query_plans),
+            Arc::clone(&self.health),
+            self.max_statement_bytes,
+            Arc::clone(&self.codec),
         ))
     }
 }
@@ -93,12 +215,26 @@ impl DbPool for MysqlDbPool {
     }
 
     fn state(&self) -> results::PoolState {
-        self.pool.state().into()
+        results::PoolState {
+            collection_cache: self.coll_cache.stats(),
+            ..self.pool.state().into()
+        }
     }
 
     fn box_clone(&self) -> Box<dyn DbPool> {
         Box::new(self.clone())
     }
+
+    fn warm_collection_cache(&self, batch_size: u32) -> DbFuture<usize> {
+        let pool = self.clone();
+        Box::pin(async move {
+            let db = pool.get().await?;
+            db.begin(false).await?;
+            let count = pool.coll_cache.warm(&*db, batch_size).await?;
+            db.commit().await?;
+            Ok(count)
+        })
+    }
 }
 
 impl fmt::Debug for MysqlDbPool {
@@ -107,68 +243,37 @@ impl fmt::Debug for MysqlDbPool {
     }
 }
 
+/// Initializes the session of each newly established connection
 #[derive(Debug)]
-pub struct CollectionCache {
-    pub by_name: RwLock<HashMap<String, i32>>,
-    pub by_id: RwLock<HashMap<i32, String>>,
-}
-
-impl CollectionCache {
-    pub fn put(&self, id: i32, name: String) -> Result<()> {
-        // XXX: should this emit a metric?
-        // XXX: should probably either lock both simultaneously during
-        // writes or use an RwLock alternative
-        self.by_name
-            .write()
-            .map_err(|_| DbError::internal("by_name write"))?
-            .insert(name.clone(), id);
-        self.by_id
-            .write()
-            .map_err(|_| DbError::internal("by_id write"))?
-            .insert(id, name);
-        Ok(())
-    }
-
-    pub fn get_id(&self, name: &str) -> Result<Option<i32>> {
-        Ok(self
-            .by_name
-            .read()
-            .map_err(|_| DbError::internal("by_name read"))?
-            .get(name)
-            .cloned())
-    }
-
-    pub fn get_name(&self, id: i32) -> Result<Option<String>> {
-        Ok(self
-            .by_id
-            .read()
-            .map_err(|_| DbError::internal("by_id read"))?
-            .get(&id)
-            .cloned())
-    }
-
+struct MysqlConnectionCustomizer {
+    /// Semicolon separated statements run on each new connection
+    session_init: String,
+    /// Set as the `@application_name` session variable (listed by
+    /// performance_schema.user_variables_by_thread)
+    label: Option<String>,
     #[cfg(test)]
-    pub fn clear(&self) {
-        self.by_name.write().expect("by_name write").clear();
-        self.by_id.write().expect("by_id write").clear();
-    }
+    use_test_transactions: bool,
 }
 
-impl Default for CollectionCache {
-    fn default() -> Self {
-        Self {
-            by_name: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| ((*v).to_owned(), *k))
-                    .collect(),
-            ),
-            by_id: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| (*k, (*v).to_owned()))
-                    .collect(),
-            ),
+impl CustomizeConnection<MysqlConnection, PoolError> for MysqlConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut MysqlConnection) -> StdResult<(), PoolError> {
+        if !self.session_init.trim().is_empty() {
+            conn.batch_execute(&self.session_init)
+                .map_err(PoolError::QueryError)?;
         }
+        if let Some(label) = &self.label {
+            sql_query("SET @application_name = ?")
+                .bind::<Text, _>(label)
+                .execute(conn)
+                .map_err(PoolError::QueryError)?;
+        }
+        #[cfg(test)]
+        {
+            if self.use_test_transactions {
+                conn.begin_test_transaction()
+                    .map_err(PoolError::QueryError)?;
+            }
+        }
+        Ok(())
     }
 }
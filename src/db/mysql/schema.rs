@@ -5,11 +5,24 @@ table! {
         #[sql_name="collection"]
         collection_id -> Integer,
         id -> Bigint,
-        bsos -> Longtext,
         expiry -> Bigint,
     }
 }
 
+table! {
+    batch_bsos (user_id, collection_id, batch_id, id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        #[sql_name="collection"]
+        collection_id -> Integer,
+        batch_id -> Bigint,
+        id -> Varchar,
+        sortindex -> Nullable<Integer>,
+        payload -> Nullable<Mediumtext>,
+        ttl -> Nullable<Bigint>,
+    }
+}
+
 table! {
     bso (user_id, collection_id, id) {
         #[sql_name="userid"]
@@ -34,6 +47,13 @@ table! {
     }
 }
 
+table! {
+    maintenance_locks (name) {
+        name -> Varchar,
+        expiry -> Bigint,
+    }
+}
+
 table! {
     user_collections (user_id, collection_id) {
         #[sql_name="userid"]
@@ -45,4 +65,11 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(batches, bso, collections, user_collections);
+allow_tables_to_appear_in_same_query!(
+    batches,
+    batch_bsos,
+    bso,
+    collections,
+    maintenance_locks,
+    user_collections,
+);
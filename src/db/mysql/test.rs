@@ -1,29 +1,30 @@
-use std::{collections::HashMap, result::Result as StdResult};
-
-use diesel::{
-    expression_methods::TextExpressionMethods,
-    mysql::MysqlConnection,
-    r2d2::{CustomizeConnection, Error as PoolError},
-    Connection, ExpressionMethods, QueryDsl, RunQueryDsl,
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
+
+use diesel::{expression_methods::TextExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel_migrations::version_from_path;
 use url::Url;
 
 use crate::db::mysql::{
+    diesel_ext::Explain,
     models::{MysqlDb, Result},
-    pool::MysqlDbPool,
-    schema::collections,
+    pool::{check_migrations, MysqlDbPool, MIGRATION_VERSIONS},
+    schema::{bso, collections},
 };
+use crate::db::{error::DbErrorKind, params, util::SyncTimestamp, Sorting};
 use crate::server::metrics;
 use crate::settings::{Secrets, ServerLimits, Settings};
-
-#[derive(Debug)]
-pub struct TestTransactionCustomizer;
-
-impl CustomizeConnection<MysqlConnection, PoolError> for TestTransactionCustomizer {
-    fn on_acquire(&self, conn: &mut MysqlConnection) -> StdResult<(), PoolError> {
-        conn.begin_test_transaction().map_err(PoolError::QueryError)
-    }
-}
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
 
 pub fn settings() -> Result<Settings> {
     let settings = Settings::with_env_and_config_file(&None).unwrap();
@@ -97,3 +98,442 @@ fn static_collection_id() -> Result<()> {
     assert!(cid >= 100);
     Ok(())
 }
+
+#[test]
+fn expiry_index_used() -> Result<()> {
+    // Skip this test unless a mysql test db is configured
+    let database_url = match env::var("TEST_MYSQL_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => return Ok(()),
+    };
+    let db = db(&Settings {
+        database_url,
+        ..settings()?
+    })?;
+
+    let uid = 4_000_000_000u32;
+    for id in &["b0", "b1", "b2"] {
+        db.put_bso_sync(params::PutBso {
+            user_id: HawkIdentifier::new_legacy(u64::from(uid)),
+            collection: "clients".to_owned(),
+            id: (*id).to_owned(),
+            sortindex: None,
+            payload: Some("payload".to_owned()),
+            ttl: None,
+            create_only: false,
+        })?;
+    }
+    let cid = db.get_collection_id("clients")?;
+    let plan = Explain(db.unexpired_bsos(i64::from(uid), cid).select(bso::id))
+        .get_result::<String>(&db.inner.conn)?;
+    assert!(
+        plan.contains(r#""key": "bso_usr_col_exp_idx""#),
+        "bso_usr_col_exp_idx unused: {}",
+        plan
+    );
+    Ok(())
+}
+
+#[test]
+fn strict_session_sql_mode() -> Result<()> {
+    // Skip this test unless a mysql test db is configured
+    let database_url = match env::var("TEST_MYSQL_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => return Ok(()),
+    };
+    let db = db(&Settings {
+        database_url,
+        ..settings()?
+    })?;
+
+    // bso ids are a VARCHAR(64): an over-length value must error rather than
+    // be silently truncated
+    let result = db.put_bso_sync(params::PutBso {
+        user_id: HawkIdentifier::new_legacy(4_000_000_002),
+        collection: "clients".to_owned(),
+        id: "x".repeat(65),
+        sortindex: None,
+        payload: Some("payload".to_owned()),
+        ttl: None,
+        create_only: false,
+    });
+    match result.map(|_| ()).map_err(|e| e.kind().to_string()) {
+        Err(e) => assert!(e.contains("Data too long for column 'id'"), "{}", e),
+        Ok(()) => panic!("Over-length id was accepted"),
+    }
+    Ok(())
+}
+
+#[test]
+fn lock_for_write_serializes_writers() -> Result<()> {
+    // Skip this test unless a mysql test db is configured
+    let database_url = match env::var("TEST_MYSQL_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => return Ok(()),
+    };
+    // Writers must commit to observe one another
+    let settings = Settings {
+        database_url,
+        database_pool_max_size: Some(4),
+        database_use_test_transactions: false,
+        ..settings()?
+    };
+    let pool = MysqlDbPool::new(&settings, &metrics::Metrics::noop())?;
+
+    let user_id = HawkIdentifier::new_legacy(4_000_000_001);
+    let coll = "xxx_lock_for_write";
+    let writes = 10;
+    // Timestamps of each write, in the order their locks were held
+    let timestamps = Arc::new(Mutex::new(vec![]));
+    let writers: Vec<_> = (0..2)
+        .map(|writer| {
+            let (pool, user_id, timestamps) =
+                (pool.clone(), user_id.clone(), Arc::clone(&timestamps));
+            thread::spawn(move || -> Result<()> {
+                for i in 0..writes {
+                    // Retry writes conflicting with the other writer's
+                    loop {
+                        let db = pool.get_sync()?;
+                        let lock = db.lock_for_write_sync(params::LockCollection {
+                            user_id: user_id.clone(),
+                            collection: coll.to_owned(),
+                        });
+                        match lock {
+                            Ok(()) => (),
+                            Err(e) if matches!(e.kind(), DbErrorKind::Conflict) => {
+                                db.rollback_sync()?;
+                                thread::sleep(Duration::from_millis(10));
+                                continue;
+                            }
+                            Err(e) => Err(e)?,
+                        }
+                        db.post_bsos_sync(params::PostBsos {
+                            user_id: user_id.clone(),
+                            collection: coll.to_owned(),
+                            bsos: vec![params::PostCollectionBso {
+                                id: format!("{}-{}", writer, i),
+                                sortindex: None,
+                                payload: Some("payload".to_owned()),
+                                ttl: None,
+                            }],
+                            failed: Default::default(),
+                            breakdown: false,
+                        })?;
+                        timestamps.lock().unwrap().push(db.timestamp());
+                        db.commit_sync()?;
+                        break;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let db = pool.get_sync()?;
+    let counts = db.get_collection_counts_sync(params::GetCollectionCounts {
+        user_id: user_id.clone(),
+        newer: None,
+    })?;
+    db.delete_storage_sync(user_id)?;
+    db.commit_sync()?;
+
+    let timestamps = timestamps.lock().unwrap();
+    assert!(
+        timestamps.windows(2).all(|pair| pair[0] < pair[1]),
+        "timestamps not strictly increasing: {:?}",
+        timestamps
+    );
+    // No lost updates
+    assert_eq!(counts.get(coll), Some(&(2 * writes)));
+    Ok(())
+}
+
+/// Write `bsos` to the collection (in a committed transaction), retrying
+/// conflicts with concurrent writers
+fn write_bsos(
+    pool: &MysqlDbPool,
+    user_id: &HawkIdentifier,
+    coll: &str,
+    bsos: Vec<params::PostCollectionBso>,
+) -> Result<()> {
+    loop {
+        let db = pool.get_sync()?;
+        let lock = db.lock_for_write_sync(params::LockCollection {
+            user_id: user_id.clone(),
+            collection: coll.to_owned(),
+        });
+        match lock {
+            Ok(()) => (),
+            Err(e) if matches!(e.kind(), DbErrorKind::Conflict) => {
+                db.rollback_sync()?;
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => Err(e)?,
+        }
+        db.post_bsos_sync(params::PostBsos {
+            user_id: user_id.clone(),
+            collection: coll.to_owned(),
+            bsos,
+            failed: Default::default(),
+            breakdown: false,
+        })?;
+        return db.commit_sync();
+    }
+}
+
+#[test]
+fn keyset_pagination_with_concurrent_writes() -> Result<()> {
+    // Skip this test unless a mysql test db is configured
+    let database_url = match env::var("TEST_MYSQL_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => return Ok(()),
+    };
+    // The pages and the concurrent writer must commit to observe one another
+    let settings = Settings {
+        database_url,
+        database_pool_max_size: Some(2),
+        database_use_test_transactions: false,
+        ..settings()?
+    };
+    let pool = MysqlDbPool::new(&settings, &metrics::Metrics::noop())?;
+
+    let user_id = HawkIdentifier::new_legacy(4_000_000_003);
+    let coll = "xxx_keyset_pagination";
+    // Few distinct modified timestamps and sortindexes (including NULLs):
+    // most pages end amid a run of equal sort keys
+    let mut snapshot = HashSet::new();
+    for chunk in 0..50 {
+        let bsos = (0..100)
+            .map(|i| {
+                let id = format!("b{}-{}", chunk, i);
+                snapshot.insert(id.clone());
+                params::PostCollectionBso {
+                    id,
+                    sortindex: if i % 7 == 0 { None } else { Some(i % 10) },
+                    payload: Some("payload".to_owned()),
+                    ttl: None,
+                }
+            })
+            .collect();
+        write_bsos(&pool, &user_id, coll, bsos)?;
+    }
+
+    // Insert new BSOs (sorting before and after the readers' positions)
+    // until the readers are done
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (pool, user_id, done) = (pool.clone(), user_id.clone(), Arc::clone(&done));
+        thread::spawn(move || -> Result<()> {
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                let bso = params::PostCollectionBso {
+                    id: format!("new-{}", i),
+                    sortindex: Some(i % 10),
+                    payload: Some("payload".to_owned()),
+                    ttl: None,
+                };
+                write_bsos(&pool, &user_id, coll, vec![bso])?;
+                i += 1;
+            }
+            Ok(())
+        })
+    };
+
+    let read = || -> Result<()> {
+        for &sort in &[
+            Sorting::Newest,
+            Sorting::Oldest,
+            Sorting::Index,
+            Sorting::None,
+        ] {
+            let mut ids = vec![];
+            let mut offset = None;
+            loop {
+                let page = pool.get_sync()?.get_bso_ids_sync(params::GetBsos {
+                    user_id: user_id.clone(),
+                    collection: coll.to_owned(),
+                    params: BsoQueryParams {
+                        sort,
+                        limit: Some(100),
+                        offset,
+                        ..Default::default()
+                    },
+                })?;
+                ids.extend(page.items);
+                offset = match page.offset {
+                    Some(offset) => Some(Offset::from_str(&offset).unwrap()),
+                    None => break,
+                };
+            }
+            let unique: HashSet<_> = ids.iter().cloned().collect();
+            assert_eq!(unique.len(), ids.len(), "{:?}: duplicate ids", sort);
+            assert!(unique.is_superset(&snapshot), "{:?}: skipped ids", sort);
+        }
+        Ok(())
+    };
+    let result = read();
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap()?;
+
+    let db = pool.get_sync()?;
+    db.delete_storage_sync(user_id)?;
+    db.commit_sync()?;
+    result
+}
+
+#[test]
+fn migration_versions_match_directory() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut versions: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| version_from_path(&entry.unwrap().path()).unwrap())
+        .collect();
+    versions.sort();
+    assert_eq!(versions, MIGRATION_VERSIONS);
+}
+
+#[test]
+fn migrations_checked_when_disabled() -> Result<()> {
+    let settings = settings()?;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    // db() ran any pending migrations
+    db(&settings)?;
+    let settings = Settings {
+        run_migrations: Some(false),
+        ..settings
+    };
+    check_migrations(&settings)?;
+    MysqlDbPool::new(&settings, &metrics::Metrics::noop())?;
+    Ok(())
+}
+
+#[test]
+fn packet_limited_writes_chunked() -> Result<()> {
+    let settings = settings()?;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&Settings {
+        database_max_allowed_packet: Some(64 * 1024),
+        ..settings
+    })?;
+    assert_eq!(db.max_statement_bytes, 32 * 1024);
+
+    let user_id = HawkIdentifier::new_legacy(4_000_000_004);
+    // ~160KB of payloads: several statements each
+    let bsos = |prefix: &str| -> Vec<params::PostCollectionBso> {
+        (0..40)
+            .map(|i| params::PostCollectionBso {
+                id: format!("{}{}", prefix, i),
+                sortindex: None,
+                payload: Some("x".repeat(4096)),
+                ttl: None,
+            })
+            .collect()
+    };
+    let uid = user_id.legacy_id as i64;
+    let modified_of = |db: &MysqlDb, prefix: &str| -> Result<HashSet<i64>> {
+        Ok(bso::table
+            .filter(bso::user_id.eq(uid))
+            .filter(bso::id.like(format!("{}%", prefix)))
+            .select(bso::modified)
+            .load::<i64>(&db.inner.conn)?
+            .into_iter()
+            .collect())
+    };
+
+    let result = db.post_bsos_sync(params::PostBsos {
+        user_id: user_id.clone(),
+        collection: "clients".to_owned(),
+        bsos: bsos("p"),
+        failed: Default::default(),
+        breakdown: false,
+    })?;
+    assert_eq!(result.success.len(), 40);
+    assert!(result.failed.is_empty());
+    let modified = modified_of(&db, "p")?;
+    assert_eq!(modified.len(), 1);
+    assert!(modified.contains(&result.modified.as_i64()));
+
+    let id = db.create_batch_sync(params::CreateBatch {
+        user_id: user_id.clone(),
+        collection: "clients".to_owned(),
+        bsos: bsos("b"),
+    })?;
+    db.append_to_batch_sync(params::AppendToBatch {
+        user_id: user_id.clone(),
+        collection: "clients".to_owned(),
+        id: id.clone(),
+        bsos: bsos("c"),
+    })?;
+    let batch = db
+        .get_batch_sync(params::GetBatch {
+            user_id: user_id.clone(),
+            collection: "clients".to_owned(),
+            id,
+        })?
+        .unwrap();
+    let result = db.commit_batch_sync(params::CommitBatch {
+        user_id,
+        collection: "clients".to_owned(),
+        batch,
+    })?;
+    let mut modified = modified_of(&db, "b")?;
+    modified.extend(modified_of(&db, "c")?);
+    assert_eq!(modified.len(), 1);
+    assert!(modified.contains(&result.modified.as_i64()));
+    Ok(())
+}
+
+#[test]
+fn stray_milliseconds_truncated() -> Result<()> {
+    let settings = settings()?;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let uid = 4_000_000_005u64;
+    let user_id = HawkIdentifier::new_legacy(uid);
+    let modified = db.put_bso_sync(params::PutBso {
+        user_id: user_id.clone(),
+        collection: "clients".to_owned(),
+        id: "b0".to_owned(),
+        sortindex: None,
+        payload: Some("payload".to_owned()),
+        ttl: None,
+        create_only: false,
+    })?;
+    assert_eq!(modified.as_i64() % 10, 0);
+    // As stored by an implementation that didn't truncate
+    diesel::update(bso::table.filter(bso::user_id.eq(uid as i64)))
+        .set(bso::modified.eq(modified.as_i64() + 7))
+        .execute(&db.inner.conn)?;
+
+    let newer = |newer: SyncTimestamp| {
+        db.get_bsos_sync(params::GetBsos {
+            user_id: user_id.clone(),
+            collection: "clients".to_owned(),
+            params: BsoQueryParams {
+                newer: Some(newer),
+                ..Default::default()
+            },
+        })
+    };
+    assert!(newer(modified)?.items.is_empty());
+    let bsos = newer(SyncTimestamp::from_milliseconds(
+        modified.as_i64() as u64 - 10,
+    ))?;
+    assert_eq!(bsos.items.len(), 1);
+    assert_eq!(bsos.items[0].modified, modified);
+    Ok(())
+}
@@ -3,11 +3,12 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::db::{util::SyncTimestamp, Sorting};
 use crate::web::extractors::{BatchBsoBody, BsoQueryParams, HawkIdentifier};
 
 macro_rules! data {
     ($name:ident {$($property:ident: $type:ty,)*}) => {
-        #[derive(Debug)]
+        #[derive(Clone, Debug)]
         pub struct $name {
             $(pub $property: $type,)*
         }
@@ -47,7 +48,7 @@ macro_rules! bso_data {
 
 uid_data! {
     GetCollectionTimestamps,
-    GetCollectionCounts,
+    GetCollectionNames,
     GetCollectionUsage,
     GetStorageTimestamp,
     GetStorageUsage,
@@ -55,18 +56,39 @@ uid_data! {
 }
 
 collection_data! {
+    GetOrCreateCollectionId {},
     LockCollection {},
+    LockCollectionForRead {
+        strong: bool,
+    },
     DeleteCollection {},
     GetCollectionTimestamp {},
+    CollectionExists {},
+    GetCollectionEtag {},
     DeleteBsos {
         ids: Vec<String>,
     },
+    BulkSetTtl {
+        ids: Vec<String>,
+        // new ttl in seconds, from now
+        ttl: u32,
+    },
     GetBsos {
         params: BsoQueryParams,
     },
+    GetBsosAfter {
+        // the (modified, id) key of the previous page's last BSO, None for
+        // the first page
+        after: Option<(SyncTimestamp, String)>,
+        // Sorting::Newest pages newest first, any other sort oldest first
+        sort: Sorting,
+        limit: u32,
+    },
     PostBsos {
         bsos: Vec<PostCollectionBso>,
         failed: HashMap<String, String>,
+        // report which BSOs were created (vs updated) in the result
+        breakdown: bool,
     },
 
     CreateBatch {
@@ -91,7 +113,59 @@ collection_data! {
 }
 
 pub type ValidateBatchId = String;
+pub type GetBatchBsos = GetBatch;
+pub type ResetCollection = PostBsos;
 pub type GetBsoIds = GetBsos;
+pub type CountBsos = GetBsos;
+
+data! {
+    TryAcquireMaintenanceLock {
+        name: String,
+        // lease duration in seconds
+        ttl: u32,
+    }
+}
+
+data! {
+    PurgeExpired {
+        // only of this user (by legacy_id on MySQL, fxa_uid/fxa_kid on Spanner)
+        user_id: Option<HawkIdentifier>,
+        // only of this collection
+        collection: Option<String>,
+        // maximum number of rows purged per call
+        limit: u32,
+        // only count the rows that would be purged
+        dry_run: bool,
+    }
+}
+
+pub type PurgeExpiredBsos = PurgeExpired;
+pub type PurgeExpiredBatches = PurgeExpired;
+
+data! {
+    CountBatches {
+        // only of this user
+        user_id: Option<HawkIdentifier>,
+    }
+}
+
+data! {
+    GetCollectionCounts {
+        user_id: HawkIdentifier,
+        // only of the collections modified since
+        newer: Option<SyncTimestamp>,
+    }
+}
+
+data! {
+    GetCollections {
+        // only collections with a greater id
+        after_id: i32,
+        limit: u32,
+    }
+}
+
+pub type GetCollectionId = String;
 
 bso_data! {
     DeleteBso {},
@@ -99,13 +173,17 @@ bso_data! {
     GetBsoTimestamp {},
 }
 
-#[derive(Debug, Default, Queryable)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Batch {
     pub id: String,
-    pub bsos: String,
+    /// The BSOs appended (so far), ordered by id, as stored: their payloads
+    /// still encoded by the pool's codec (see `Db::get_batch_bsos` for the
+    /// decoded BSOs)
+    pub bsos: Vec<PostCollectionBso>,
     pub expiry: i64,
 }
 
+#[derive(Clone)]
 pub struct PutBso {
     pub user_id: HawkIdentifier,
     pub collection: String,
@@ -114,9 +192,12 @@ pub struct PutBso {
     pub payload: Option<String>,
     // ttl in seconds
     pub ttl: Option<u32>,
+    /// Fail with `BsoExists` rather than overwrite an existing BSO
+    /// (`If-None-Match: *`)
+    pub create_only: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct PostCollectionBso {
     pub id: String,
     pub sortindex: Option<i32>,
@@ -136,9 +217,6 @@ impl From<BatchBsoBody> for PostCollectionBso {
     }
 }
 
-#[cfg(test)]
-pub type GetCollectionId = String;
-
 #[cfg(test)]
 pub type CreateCollection = String;
 
@@ -0,0 +1,173 @@
+//! Purging of expired BSOs and batches, and of unreferenced payload blobs.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_rt::time::delay_for;
+use actix_web::web::block;
+
+use super::{blob, blob::BlobStore, params, DbPool};
+use crate::error::ApiError;
+use crate::server::metrics::Metrics;
+use crate::web::extractors::HawkIdentifier;
+
+/// Name of the maintenance lock held while purging
+pub const PURGE_LOCK_NAME: &str = "purge_ttl";
+
+#[derive(Clone, Debug)]
+pub struct PurgeOptions {
+    /// Maximum number of rows deleted per transaction
+    pub batch_size: u32,
+    /// Stop (leaving the remainder for the next run) after this long
+    pub max_runtime: Option<Duration>,
+    /// Move on from a table (leaving its remainder for the next run) after
+    /// purging this many of its rows
+    pub max_rows: Option<u64>,
+    /// Only count the rows of the first chunk of each table
+    pub dry_run: bool,
+    /// How many times a chunk aborted by a conflict is retried
+    pub retries: u32,
+    /// Delay before retrying a chunk
+    pub retry_sleep: Duration,
+    /// Only purge this user's rows
+    pub user_id: Option<HawkIdentifier>,
+    /// Only purge this collection's rows
+    pub collection: Option<String>,
+}
+
+/// Summary of a `purge_expired` run
+#[derive(Debug, Default)]
+pub struct Purge {
+    /// The number of batches purged (or counted)
+    pub batches: u64,
+    /// The number of BSOs purged (or counted)
+    pub bsos: u64,
+    /// Whether `max_runtime` elapsed before everything was purged
+    pub timed_out: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Table {
+    Batches,
+    Bsos,
+}
+
+/// Delete the expired batches and then the expired BSOs, each in
+/// transactions of up to `batch_size` rows
+pub async fn purge_expired(
+    pool: &dyn DbPool,
+    opts: &PurgeOptions,
+    metrics: &Metrics,
+) -> Result<Purge, ApiError> {
+    let mut total_timer = metrics.clone();
+    total_timer.start_timer("purge_ttl.total_duration", None);
+    let deadline = opts
+        .max_runtime
+        .map(|max_runtime| Instant::now() + max_runtime);
+
+    let mut purge = Purge::default();
+    {
+        let mut timer = metrics.clone();
+        timer.start_timer("purge_ttl.batches_duration", None);
+        let (batches, timed_out) = purge_table(pool, opts, Table::Batches, deadline).await?;
+        purge.batches = batches;
+        purge.timed_out = timed_out;
+    }
+    if !purge.timed_out {
+        let mut timer = metrics.clone();
+        timer.start_timer("purge_ttl.bso_duration", None);
+        let (bsos, timed_out) = purge_table(pool, opts, Table::Bsos, deadline).await?;
+        purge.bsos = bsos;
+        purge.timed_out = timed_out;
+    }
+    Ok(purge)
+}
+
+/// Purge a table chunk by chunk, returning the number of rows purged and
+/// whether the deadline was hit
+async fn purge_table(
+    pool: &dyn DbPool,
+    opts: &PurgeOptions,
+    table: Table,
+    deadline: Option<Instant>,
+) -> Result<(u64, bool), ApiError> {
+    let mut total = 0;
+    loop {
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Ok((total, true));
+        }
+        let purged = purge_chunk(pool, opts, table).await?;
+        total += purged;
+        debug!("Purged chunk"; "table" => format!("{:?}", table), "rows" => purged);
+        if opts.dry_run || purged < u64::from(opts.batch_size) {
+            return Ok((total, false));
+        }
+        if opts.max_rows.map_or(false, |max_rows| total >= max_rows) {
+            info!("Purged the maximum rows"; "table" => format!("{:?}", table), "rows" => total);
+            return Ok((total, false));
+        }
+    }
+}
+
+/// Purge a single chunk in its own transaction, retrying conflicts
+async fn purge_chunk(
+    pool: &dyn DbPool,
+    opts: &PurgeOptions,
+    table: Table,
+) -> Result<u64, ApiError> {
+    let mut attempt = 0;
+    loop {
+        let db = pool.get().await?;
+        db.begin(!opts.dry_run).await?;
+        let params = params::PurgeExpired {
+            user_id: opts.user_id.clone(),
+            collection: opts.collection.clone(),
+            limit: opts.batch_size,
+            dry_run: opts.dry_run,
+        };
+        let result = match table {
+            Table::Batches => db.purge_expired_batches(params).await,
+            Table::Bsos => db.purge_expired_bsos(params).await,
+        };
+        let result = match result {
+            Ok(purged) => db.commit().await.map(|_| purged),
+            Err(e) => {
+                db.rollback().await?;
+                Err(e)
+            }
+        };
+        match result {
+            Err(e) if e.is_conflict() && attempt < opts.retries => {
+                attempt += 1;
+                warn!(
+                    "Purge chunk conflict, retrying";
+                    "attempt" => attempt,
+                    "error" => e.to_string()
+                );
+                if opts.retry_sleep > Duration::from_secs(0) {
+                    delay_for(opts.retry_sleep).await;
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Delete the payload blobs no longer referenced by any BSO or batch (see
+/// `blob::collect_garbage`), returning how many were deleted
+pub async fn purge_blobs(
+    pool: &dyn DbPool,
+    store: Arc<dyn BlobStore>,
+    grace: Duration,
+    metrics: &Metrics,
+) -> Result<u64, ApiError> {
+    let mut timer = metrics.clone();
+    timer.start_timer("purge_ttl.blobs_duration", None);
+    let db = pool.get().await?;
+    let referenced = db.get_blob_references().await?;
+    Ok(block(move || {
+        blob::collect_garbage(store.as_ref(), &referenced, grace).map_err(ApiError::from)
+    })
+    .await?)
+}
@@ -1,36 +1,57 @@
 //! Result types for database methods.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use diesel::sql_types::{BigInt, Integer, Nullable, Text};
 use serde::{Deserialize, Serialize};
 
 use super::params;
-use crate::db::util::SyncTimestamp;
+use crate::db::{cache::CollectionCacheStats, util::SyncTimestamp};
 
 pub type LockCollection = ();
 pub type GetBsoTimestamp = SyncTimestamp;
 pub type GetCollectionTimestamps = HashMap<String, SyncTimestamp>;
+pub type GetCollectionNames = Vec<String>;
 pub type GetCollectionTimestamp = SyncTimestamp;
+pub type CollectionExists = bool;
+pub type GetCollectionEtag = String;
 pub type GetCollectionCounts = HashMap<String, i64>;
 pub type GetCollectionUsage = HashMap<String, i64>;
 pub type GetStorageTimestamp = SyncTimestamp;
 pub type GetStorageUsage = u64;
 pub type DeleteStorage = ();
 pub type DeleteCollection = SyncTimestamp;
-pub type DeleteBsos = SyncTimestamp;
 pub type DeleteBso = SyncTimestamp;
+pub type BulkSetTtl = SyncTimestamp;
 pub type PutBso = SyncTimestamp;
+pub type CountBsos = u64;
 
 pub type CreateBatch = String;
 pub type ValidateBatch = bool;
 pub type AppendToBatch = ();
 pub type GetBatch = params::Batch;
+pub type GetBatchBsos = Vec<params::PostCollectionBso>;
 pub type DeleteBatch = ();
 pub type CommitBatch = PostBsos;
+pub type ResetCollection = PostBsos;
 pub type ValidateBatchId = ();
 pub type Check = bool;
+pub type TryAcquireMaintenanceLock = bool;
+pub type PurgeExpiredBsos = u64;
+pub type PurgeExpiredBatches = u64;
 
-#[derive(Debug, Default, Deserialize, Queryable, QueryableByName, Serialize)]
+/// Counts of the batches (of every user) not yet committed
+#[derive(Debug, Default, PartialEq)]
+pub struct CountBatches {
+    pub open: u64,
+    /// Expired batches, awaiting their purge
+    pub stale: u64,
+}
+pub type GetBlobReferences = HashSet<String>;
+pub type GetCollections = Vec<(i32, String)>;
+pub type GetCollectionId = i32;
+pub type GetOrCreateCollectionId = i32;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Queryable, QueryableByName, Serialize)]
 pub struct GetBso {
     #[sql_type = "Text"]
     pub id: String,
@@ -50,23 +71,77 @@ pub struct GetBso {
     pub expiry: i64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Paginated<T>
 where
     T: Serialize,
 {
     pub items: Vec<T>,
     pub offset: Option<String>,
+    /// Whether the page was cut short by the query's `max_bytes` ceiling
+    /// (its `offset` resuming after the last of its items)
+    pub truncated: bool,
 }
 
 pub type GetBsos = Paginated<GetBso>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetBsosAfter {
+    pub items: Vec<GetBso>,
+    /// Whether more BSOs follow the last of the items
+    pub more: bool,
+}
 pub type GetBsoIds = Paginated<String>;
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DeleteBsos {
+    pub modified: SyncTimestamp,
+    /// How many of the ids were deleted (nonexistent ones aren't counted)
+    pub deleted: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct PostBsos {
     pub modified: SyncTimestamp,
     pub success: Vec<String>,
     pub failed: HashMap<String, String>,
+    /// The ids of `success` that were newly created (the rest updated
+    /// existing BSOs), when a breakdown was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<Vec<String>>,
+}
+
+impl PostBsos {
+    /// Break down `success` into created vs updated, given the ids of the
+    /// BSOs that existed beforehand
+    pub fn set_created(&mut self, mut existing: HashSet<String>) {
+        // An id posted twice is created (at most) once
+        let created = self
+            .success
+            .iter()
+            .filter(|id| existing.insert((*id).clone()))
+            .cloned()
+            .collect();
+        self.created = Some(created);
+    }
+}
+
+/// Structured diagnostics of a `Db`'s health
+#[derive(Debug, Default, Serialize)]
+pub struct HealthDetail {
+    /// Round trip time of a trivial query, in milliseconds
+    pub latency_ms: u64,
+    /// When a query (by any of the pool's connections) last succeeded, in
+    /// milliseconds since the epoch
+    pub last_success_ms: Option<u64>,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_connections: u32,
+    /// The fraction of the pool's maximum connections in use
+    pub saturation: f64,
+    /// Whether the pool's still establishing its initial (min_idle)
+    /// connections, or initializing the db
+    pub warming_up: bool,
 }
 
 #[derive(Debug, Default)]
@@ -74,6 +149,7 @@ pub struct PostBsos {
 pub struct PoolState {
     pub connections: u32,
     pub idle_connections: u32,
+    pub collection_cache: CollectionCacheStats,
 }
 
 impl From<diesel::r2d2::State> for PoolState {
@@ -81,13 +157,11 @@ impl From<diesel::r2d2::State> for PoolState {
         PoolState {
             connections: state.connections,
             idle_connections: state.idle_connections,
+            collection_cache: CollectionCacheStats::default(),
         }
     }
 }
 
-#[cfg(test)]
-pub type GetCollectionId = i32;
-
 #[cfg(test)]
 pub type CreateCollection = i32;
 
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
 use googleapis_raw::spanner::v1::type_pb::{StructType, Type, TypeCode};
 use protobuf::{
@@ -7,21 +10,29 @@ use protobuf::{
 };
 use uuid::Uuid;
 
-use super::support::{null_value, struct_type_field};
+use super::support::{
+    as_list_value, bso_to_insert_row, bso_to_update_row, null_value, struct_type_field, BsoWrite,
+};
 use super::{
     models::{Result, SpannerDb, DEFAULT_BSO_TTL, PRETOUCH_TS},
     support::as_value,
 };
 use crate::{
-    db::{params, results, util::to_rfc3339, DbError, DbErrorKind, BATCH_LIFETIME},
+    db::{codec, params, results, util::to_rfc3339, DbError, DbErrorKind, BATCH_LIFETIME},
     web::extractors::HawkIdentifier,
 };
 
+/// A new batch's id (chosen ahead of `create_async`, so a replay of its
+/// transaction recreates the same batch)
+pub fn new_batch_id() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
 pub async fn create_async(
     db: &SpannerDb,
-    params: params::CreateBatch,
+    params: &params::CreateBatch,
+    batch_id: String,
 ) -> Result<results::CreateBatch> {
-    let batch_id = Uuid::new_v4().to_simple().to_string();
     let collection_id = db.get_collection_id_async(&params.collection).await?;
     let timestamp = db.timestamp()?.as_i64();
 
@@ -32,7 +43,8 @@ pub async fn create_async(
     db.sql(
         "INSERT INTO batches (fxa_uid, fxa_kid, collection_id, batch_id, expiry)
          VALUES (@fxa_uid, @fxa_kid, @collection_id, @batch_id, @expiry)",
-    )?
+    )
+    .await?
     .params(params! {
         "fxa_uid" => params.user_id.fxa_uid.clone(),
         "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -48,17 +60,22 @@ pub async fn create_async(
 
     do_append_async(
         db,
-        params.user_id,
+        params.user_id.clone(),
         collection_id,
         batch_id.clone(),
-        params.bsos,
+        params.bsos.clone(),
     )
     .await?;
     Ok(batch_id)
 }
 
 pub async fn validate_async(db: &SpannerDb, params: params::ValidateBatch) -> Result<bool> {
-    let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let collection_id = match db.get_collection_id_async(&params.collection).await {
+        Ok(collection_id) => collection_id,
+        // No batch can outlive its collection
+        Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
     let exists = db
         .sql(
             "SELECT 1
@@ -68,7 +85,8 @@ pub async fn validate_async(db: &SpannerDb, params: params::ValidateBatch) -> Re
                 AND collection_id = @collection_id
                 AND batch_id = @batch_id
                 AND expiry > CURRENT_TIMESTAMP()",
-        )?
+        )
+        .await?
         .params(params! {
             "fxa_uid" => params.user_id.fxa_uid,
             "fxa_kid" => params.user_id.fxa_kid,
@@ -81,7 +99,7 @@ pub async fn validate_async(db: &SpannerDb, params: params::ValidateBatch) -> Re
     Ok(exists.is_some())
 }
 
-pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> Result<()> {
+pub async fn append_async(db: &SpannerDb, params: &params::AppendToBatch) -> Result<()> {
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.append_items_to_batch", None);
 
@@ -101,7 +119,14 @@ pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> Resu
     }
 
     let collection_id = db.get_collection_id_async(&params.collection).await?;
-    do_append_async(db, params.user_id, collection_id, params.id, params.bsos).await?;
+    do_append_async(
+        db,
+        params.user_id.clone(),
+        collection_id,
+        params.id.clone(),
+        params.bsos.clone(),
+    )
+    .await?;
     Ok(())
 }
 
@@ -119,7 +144,8 @@ pub async fn get_async(
                 AND collection_id = @collection_id
                 AND batch_id = @batch_id
                 AND expiry > CURRENT_TIMESTAMP()",
-        )?
+        )
+        .await?
         .params(params! {
             "fxa_uid" => params.user_id.fxa_uid.clone(),
             "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -128,18 +154,84 @@ pub async fn get_async(
         })
         .execute_async(&db.conn)?
         .one_or_none()
+        .await?;
+    if batch.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(params::Batch {
+        bsos: batch_bsos_async(db, &params.user_id, collection_id, params.id.clone()).await?,
+        id: params.id,
+        // Not read back (the batches' expiry is a TIMESTAMP), as no caller
+        // needs it
+        expiry: 0,
+    }))
+}
+
+pub async fn get_bsos_async(
+    db: &SpannerDb,
+    params: params::GetBatchBsos,
+) -> Result<results::GetBatchBsos> {
+    let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let bsos = batch_bsos_async(db, &params.user_id, collection_id, params.id).await?;
+    codec::run(&db.codec, move |codec| {
+        bsos.into_iter()
+            .map(|bso| codec.decode_batch_bso(bso))
+            .collect()
+    })
+    .await
+}
+
+/// The batch's BSOs as stored (their payloads still encoded)
+async fn batch_bsos_async(
+    db: &SpannerDb,
+    user_id: &HawkIdentifier,
+    collection_id: i32,
+    batch_id: String,
+) -> Result<Vec<params::PostCollectionBso>> {
+    let mut streaming = db
+        .sql(
+            "SELECT batch_bso_id, sortindex, payload, ttl
+               FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id
+              ORDER BY batch_bso_id",
+        )
         .await?
-        .map(move |_| {
-            params::Batch {
-                id: params.id,
-                // XXX: we don't use bsos/expiry (but they're currently needed
-                // for mysql/diesel compat). converting expiry back to i64 is
-                // maybe suspicious
-                bsos: "".to_owned(),
-                expiry: 0,
-            }
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "batch_id" => batch_id,
+        })
+        .execute_async(&db.conn)?;
+    let int = |value: &Value| -> Result<Option<i64>> {
+        if value.has_null_value() {
+            return Ok(None);
+        }
+        Ok(Some(
+            value
+                .get_string_value()
+                .parse::<i64>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+        ))
+    };
+    let mut bsos = vec![];
+    while let Some(row) = streaming.next_async().await {
+        let mut row = row?;
+        bsos.push(params::PostCollectionBso {
+            id: row[0].take_string_value(),
+            sortindex: int(&row[1])?.map(|sortindex| sortindex as i32),
+            payload: if row[2].has_null_value() {
+                None
+            } else {
+                Some(row[2].take_string_value())
+            },
+            ttl: int(&row[3])?.map(|ttl| ttl as u32),
         });
-    Ok(batch)
+    }
+    Ok(bsos)
 }
 
 pub async fn delete_async(db: &SpannerDb, params: params::DeleteBatch) -> Result<()> {
@@ -152,7 +244,8 @@ pub async fn delete_async(db: &SpannerDb, params: params::DeleteBatch) -> Result
             AND fxa_kid = @fxa_kid
             AND collection_id = @collection_id
             AND batch_id = @batch_id",
-    )?
+    )
+    .await?
     .params(params! {
         "fxa_uid" => params.user_id.fxa_uid.clone(),
         "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -171,6 +264,98 @@ pub async fn commit_async(
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.apply_batch", None);
     let collection_id = db.get_collection_id_async(&params.collection).await?;
+    // The batch's parent user_collections row exists (see
+    // pretouch_collection_async), though the mutation would create it either
+    // way
+    let timestamp = db
+        .touch_collection_mutation_async(&params.user_id, collection_id)
+        .await?;
+
+    let user_id = &params.user_id;
+    let bsos = params.batch.bsos;
+    let mut existing = HashSet::new();
+    if !bsos.is_empty() {
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert(
+            "ids".to_owned(),
+            as_list_value(bsos.iter().map(|bso| bso.id.clone())),
+        );
+        let mut streaming = db
+            .sql(
+                "SELECT bso_id
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)",
+            )
+            .await?
+            .params(sqlparams)
+            .execute_async(&db.conn)?;
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            existing.insert(row[0].take_string_value());
+        }
+    }
+
+    let writes = bsos
+        .into_iter()
+        .map(|mut bso| {
+            if existing.contains(&bso.id) {
+                let (mut columns, mut row) =
+                    bso_to_update_row(user_id, collection_id, bso, timestamp)?;
+                if !columns.contains(&"modified") {
+                    // Committing always modifies a BSO (even when only its
+                    // ttl changes)
+                    columns.push("modified");
+                    row.mut_values().push(as_value(timestamp.as_rfc3339()?));
+                }
+                Ok(BsoWrite::Update(columns, row))
+            } else {
+                bso.sortindex = bso.sortindex.or(db.default_sortindex);
+                let row = bso_to_insert_row(user_id, collection_id, bso, timestamp)?;
+                Ok(BsoWrite::Insert(row))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Batches too large for one commit are committed in several, each with
+    // the same timestamp: all but the final commit's writes survive a
+    // failure, but the batch (deleted by the final commit) may be committed
+    // again
+    db.write_bsos_async(writes).await?;
+
+    delete_async(
+        db,
+        params::DeleteBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.batch.id,
+        },
+    )
+    .await?;
+    Ok(results::PostBsos {
+        modified: timestamp,
+        success: Default::default(),
+        failed: Default::default(),
+        created: None,
+    })
+}
+
+// NOTE: the DML version, used within the db tests' test transactions (never
+// committed, so never applying mutations)
+#[cfg(test)]
+pub async fn commit_async_test(
+    db: &SpannerDb,
+    params: params::CommitBatch,
+) -> Result<results::CommitBatch> {
+    let mut metrics = db.metrics.clone();
+    metrics.start_timer("storage.spanner.apply_batch", None);
+    let collection_id = db.get_collection_id_async(&params.collection).await?;
 
     // Ensure a parent record exists in user_collections before writing to bsos
     // (INTERLEAVE IN PARENT user_collections)
@@ -184,7 +369,8 @@ pub async fn commit_async(
         // supplied in this batch
         let mut timer2 = db.metrics.clone();
         timer2.start_timer("storage.spanner.apply_batch_update", None);
-        db.sql(include_str!("batch_commit_update.sql"))?
+        db.sql(include_str!("batch_commit_update.sql"))
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid.clone(),
                 "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -204,18 +390,27 @@ pub async fn commit_async(
         // table (that didn't already exist there)
         let mut timer3 = db.metrics.clone();
         timer3.start_timer("storage.spanner.apply_batch_insert", None);
-        db.sql(include_str!("batch_commit_insert.sql"))?
-            .params(params! {
-                "fxa_uid" => params.user_id.fxa_uid.clone(),
-                "fxa_kid" => params.user_id.fxa_kid.clone(),
-                "collection_id" => collection_id.to_string(),
-                "batch_id" => params.batch.id.clone(),
-                "timestamp" => as_rfc3339,
-                "default_bso_ttl" => DEFAULT_BSO_TTL.to_string(),
-            })
+        let mut sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid.clone(),
+            "fxa_kid" => params.user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "batch_id" => params.batch.id.clone(),
+            "timestamp" => as_rfc3339,
+            "default_bso_ttl" => DEFAULT_BSO_TTL.to_string(),
+        };
+        sqlparams.insert(
+            "default_sortindex".to_owned(),
+            db.default_sortindex
+                .map(|sortindex| as_value(sortindex.to_string()))
+                .unwrap_or_else(null_value),
+        );
+        db.sql(include_str!("batch_commit_insert.sql"))
+            .await?
+            .params(sqlparams)
             .param_types(param_types! {
                 "timestamp" => TypeCode::TIMESTAMP,
                 "default_bso_ttl" => TypeCode::INT64,
+                "default_sortindex" => TypeCode::INT64,
             })
             .execute_dml_async(&db.conn)
             .await?;
@@ -235,6 +430,7 @@ pub async fn commit_async(
         modified: timestamp,
         success: Default::default(),
         failed: Default::default(),
+        created: None,
     })
 }
 
@@ -249,7 +445,9 @@ pub async fn do_append_async(
     // [("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso1", NULL, "payload1", NULL),
     //  ("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso2", NULL, "payload2", NULL)]
     // https://cloud.google.com/spanner/docs/structs#creating_struct_objects
-    let rows: Vec<_> = bsos
+    let rows = db
+        .encode_bsos(bsos)
+        .await?
         .into_iter()
         .map(|bso| {
             let sortindex = bso
@@ -317,7 +515,8 @@ pub async fn do_append_async(
         "INSERT INTO batch_bsos (fxa_uid, fxa_kid, collection_id, batch_id, batch_bso_id,
                                  sortindex, payload, ttl)
          SELECT * FROM UNNEST(@values)",
-    )?
+    )
+    .await?
     .params(sqlparams)
     .param_types(sqlparam_types)
     .execute_dml_async(&db.conn)
@@ -351,7 +550,8 @@ async fn pretouch_collection_async(
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
                 AND collection_id = @collection_id",
-        )?
+        )
+        .await?
         .params(sqlparams.clone())
         .execute_async(&db.conn)?
         .one_or_none()
@@ -361,7 +561,8 @@ async fn pretouch_collection_async(
         db.sql(
             "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
              VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)",
-        )?
+        )
+        .await?
         .params(sqlparams)
         .param_types(param_types! {
             "modified" => TypeCode::TIMESTAMP,
@@ -5,7 +5,7 @@ SELECT
        batch_bsos.collection_id,
        batch_bsos.batch_bso_id,
 
-       batch_bsos.sortindex,
+       COALESCE(batch_bsos.sortindex, @default_sortindex),
        COALESCE(batch_bsos.payload, ''),
        @timestamp,
        COALESCE(
@@ -0,0 +1,504 @@
+//! Credentials authorizing calls to Spanner.
+//!
+//! Each call carries an OAuth2 access token from the first of these sources
+//! that's configured:
+//!
+//! - the service account key file of the `database_spanner_credentials`
+//!   setting
+//! - the service account key file named by `GOOGLE_APPLICATION_CREDENTIALS`
+//! - the GCE/GKE metadata server (the instance's or workload's service
+//!   account)
+//!
+//! Tokens are cached, and refreshed ahead of their expiry by a background
+//! thread: calls only ever read the cache. A failed refresh is retried
+//! shortly, the cached token remaining in use while it's valid.
+use std::{
+    env, fmt, fs,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use grpcio::{CallOption, MetadataBuilder, RpcStatus, RpcStatusCode};
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::manager::emulator_host;
+use crate::db::error::{DbError, DbErrorKind};
+use crate::settings::Settings;
+
+const GOOGLE_APPLICATION_CREDENTIALS: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+
+/// Audience of self-signed service account tokens
+const SPANNER_AUDIENCE: &str = "https://spanner.googleapis.com/";
+/// Lifetime of self-signed service account tokens (the maximum allowed)
+const SELF_SIGNED_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+const METADATA_HOST: &str = "metadata.google.internal";
+const METADATA_TOKEN_PATH: &str = "/computeMetadata/v1/instance/service-accounts/default/token";
+const METADATA_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Tokens are refreshed once within this long of their expiry
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Delay before retrying a failed refresh
+const REFRESH_RETRY: Duration = Duration::from_secs(10);
+
+/// Authorization of calls to Spanner (none for its emulator)
+#[derive(Clone)]
+pub struct SpannerCredentials {
+    tokens: Option<Arc<TokenCache>>,
+}
+
+impl SpannerCredentials {
+    /// Select the source of the credentials, failing fast when it can't
+    /// provide a token
+    pub fn from_settings(settings: &Settings) -> Result<Self, DbError> {
+        if emulator_host().is_some() {
+            return Ok(Self { tokens: None });
+        }
+        let source = CredentialSource::select(
+            settings.database_spanner_credentials.as_deref(),
+            env::var(GOOGLE_APPLICATION_CREDENTIALS).ok(),
+        );
+        info!("Using Spanner credentials"; "source" => source.to_string());
+        let unusable = |e: DbError| DbErrorKind::SpannerCredentials(format!("{}: {}", source, e));
+        let tokens = Arc::new(TokenCache::new(source.provider().map_err(unusable)?));
+        tokens.refresh_at(Instant::now()).map_err(unusable)?;
+        TokenCache::spawn_refresher(&tokens)?;
+        Ok(Self {
+            tokens: Some(tokens),
+        })
+    }
+
+    /// Add the authorization header (when any) to a call's metadata
+    pub fn authorize(&self, meta: &mut MetadataBuilder) -> Result<(), grpcio::Error> {
+        if let Some(tokens) = &self.tokens {
+            let token = tokens.token().map_err(|e| {
+                grpcio::Error::RpcFailure(RpcStatus::new(
+                    RpcStatusCode::UNAUTHENTICATED,
+                    Some(e.to_string()),
+                ))
+            })?;
+            meta.add_str("authorization", &format!("Bearer {}", token))?;
+        }
+        Ok(())
+    }
+
+    /// The options of an authorized call
+    pub fn call_option(&self) -> Result<CallOption, grpcio::Error> {
+        let mut meta = MetadataBuilder::new();
+        self.authorize(&mut meta)?;
+        Ok(CallOption::default().headers(meta.build()))
+    }
+}
+
+/// Where access tokens come from
+#[derive(Debug, PartialEq)]
+enum CredentialSource {
+    /// A service account key file, and the setting/env var naming it
+    KeyFile {
+        path: String,
+        origin: &'static str,
+    },
+    MetadataServer,
+}
+
+impl CredentialSource {
+    fn select(configured: Option<&str>, env_var: Option<String>) -> Self {
+        if let Some(path) = configured.filter(|path| !path.is_empty()) {
+            return CredentialSource::KeyFile {
+                path: path.to_owned(),
+                origin: "database_spanner_credentials",
+            };
+        }
+        match env_var.filter(|path| !path.is_empty()) {
+            Some(path) => CredentialSource::KeyFile {
+                path,
+                origin: GOOGLE_APPLICATION_CREDENTIALS,
+            },
+            None => CredentialSource::MetadataServer,
+        }
+    }
+
+    fn provider(&self) -> Result<Box<dyn TokenProvider>, DbError> {
+        Ok(match self {
+            CredentialSource::KeyFile { path, .. } => Box::new(ServiceAccountKey::from_file(path)?),
+            CredentialSource::MetadataServer => Box::new(MetadataServer),
+        })
+    }
+}
+
+impl fmt::Display for CredentialSource {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialSource::KeyFile { path, origin } => {
+                write!(fmt, "service account key file {} (via {})", path, origin)
+            }
+            CredentialSource::MetadataServer => write!(fmt, "GCE/GKE metadata server"),
+        }
+    }
+}
+
+/// An OAuth2 access token
+#[derive(Debug)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_in: Duration,
+}
+
+/// A source of access tokens
+pub trait TokenProvider: Send + Sync {
+    fn fetch(&self) -> Result<AccessToken, DbError>;
+}
+
+/// The current access token of a provider
+pub struct TokenCache {
+    provider: Box<dyn TokenProvider>,
+    /// The token and when it expires
+    cached: RwLock<Option<(String, Instant)>>,
+}
+
+impl TokenCache {
+    pub fn new(provider: Box<dyn TokenProvider>) -> Self {
+        Self {
+            provider,
+            cached: RwLock::new(None),
+        }
+    }
+
+    /// The cached token (never fetching one)
+    pub fn token(&self) -> Result<String, DbError> {
+        self.token_at(Instant::now())
+    }
+
+    fn token_at(&self, now: Instant) -> Result<String, DbError> {
+        match &*self.cached.read().unwrap() {
+            Some((token, expires_at)) if now < *expires_at => Ok(token.clone()),
+            _ => Err(DbErrorKind::SpannerCredentials(
+                "No unexpired access token (its refreshes are failing)".to_owned(),
+            )
+            .into()),
+        }
+    }
+
+    /// Fetch a new token when the cached one's due for a refresh
+    fn refresh_at(&self, now: Instant) -> Result<(), DbError> {
+        if self.until_refresh(now) > Duration::from_secs(0) {
+            return Ok(());
+        }
+        let AccessToken { token, expires_in } = self.provider.fetch()?;
+        *self.cached.write().unwrap() = Some((token, now + expires_in));
+        Ok(())
+    }
+
+    /// How long until the cached token's due for a refresh (within
+    /// `REFRESH_MARGIN` of its expiry)
+    fn until_refresh(&self, now: Instant) -> Duration {
+        match &*self.cached.read().unwrap() {
+            Some((_, expires_at)) if now + REFRESH_MARGIN < *expires_at => {
+                *expires_at - REFRESH_MARGIN - now
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Refresh the cache's token in the background for as long as the cache
+    /// lives
+    fn spawn_refresher(cache: &Arc<Self>) -> Result<(), DbError> {
+        let cache = Arc::downgrade(cache);
+        thread::Builder::new()
+            .name("spanner-token-refresh".to_owned())
+            .spawn(move || {
+                while let Some(cache) = cache.upgrade() {
+                    let wait = match cache.refresh_at(Instant::now()) {
+                        Ok(()) => cache.until_refresh(Instant::now()),
+                        Err(e) => {
+                            warn!("Failed to refresh the Spanner access token"; "error" => e.to_string());
+                            REFRESH_RETRY
+                        }
+                    };
+                    drop(cache);
+                    thread::sleep(wait.max(Duration::from_secs(1)));
+                }
+            })
+            .map_err(|e| {
+                DbError::internal(&format!("Couldn't spawn the token refresher: {}", e))
+            })?;
+        Ok(())
+    }
+}
+
+/// The fields of a credentials file used by a service account key
+#[derive(Deserialize)]
+struct KeyFile {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    client_email: String,
+    #[serde(default)]
+    private_key_id: String,
+    #[serde(default)]
+    private_key: String,
+}
+
+/// Self-signed JWTs of a service account key (no token exchange required)
+struct ServiceAccountKey {
+    client_email: String,
+    private_key_id: String,
+    key_pair: RsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl ServiceAccountKey {
+    fn from_file(path: &str) -> Result<Self, DbError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DbError::internal(&format!("Couldn't read the key file: {}", e)))?;
+        let key: KeyFile = serde_json::from_str(&contents)
+            .map_err(|e| DbError::internal(&format!("Invalid key file: {}", e)))?;
+        if key.kind != "service_account" {
+            Err(DbError::internal(&format!(
+                "Unsupported \"{}\" credentials (only service account keys are)",
+                key.kind
+            )))?
+        }
+        let der: String = key
+            .private_key
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let der = base64::decode(&der)
+            .map_err(|e| DbError::internal(&format!("Invalid private_key: {}", e)))?;
+        let key_pair = RsaKeyPair::from_pkcs8(&der)
+            .map_err(|e| DbError::internal(&format!("Invalid private_key: {}", e)))?;
+        Ok(Self {
+            client_email: key.client_email,
+            private_key_id: key.private_key_id,
+            key_pair,
+            rng: SystemRandom::new(),
+        })
+    }
+}
+
+impl TokenProvider for ServiceAccountKey {
+    fn fetch(&self) -> Result<AccessToken, DbError> {
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let header = json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "kid": self.private_key_id,
+        });
+        let claims = json!({
+            "iss": self.client_email,
+            "sub": self.client_email,
+            "aud": SPANNER_AUDIENCE,
+            "iat": iat,
+            "exp": iat + SELF_SIGNED_LIFETIME.as_secs(),
+        });
+        let encode = |value: &serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        let message = format!("{}.{}", encode(&header), encode(&claims));
+        let mut signature = vec![0; self.key_pair.public_modulus_len()];
+        self.key_pair
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &self.rng,
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| DbError::internal("Failed to sign the access token"))?;
+        Ok(AccessToken {
+            token: format!(
+                "{}.{}",
+                message,
+                base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+            ),
+            expires_in: SELF_SIGNED_LIFETIME,
+        })
+    }
+}
+
+/// The metadata server's token response
+#[derive(Deserialize)]
+struct MetadataToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Tokens of the default service account of the GCE instance (or GKE
+/// workload)
+struct MetadataServer;
+
+impl TokenProvider for MetadataServer {
+    fn fetch(&self) -> Result<AccessToken, DbError> {
+        let unreachable =
+            |e: std::io::Error| DbError::internal(&format!("Metadata server unreachable: {}", e));
+        let addr = (METADATA_HOST, 80)
+            .to_socket_addrs()
+            .map_err(unreachable)?
+            .next()
+            .ok_or_else(|| DbError::internal("Metadata server unresolvable"))?;
+        let mut stream =
+            TcpStream::connect_timeout(&addr, METADATA_TIMEOUT).map_err(unreachable)?;
+        stream
+            .set_read_timeout(Some(METADATA_TIMEOUT))
+            .map_err(unreachable)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nMetadata-Flavor: Google\r\n\r\n",
+            METADATA_TOKEN_PATH, METADATA_HOST
+        )
+        .map_err(unreachable)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).map_err(unreachable)?;
+
+        let mut parts = response.splitn(2, "\r\n\r\n");
+        let status = parts
+            .next()
+            .unwrap_or_default()
+            .lines()
+            .next()
+            .unwrap_or_default();
+        if !status.contains(" 200 ") {
+            Err(DbError::internal(&format!(
+                "Metadata server responded: {}",
+                status
+            )))?
+        }
+        let token: MetadataToken = serde_json::from_str(parts.next().unwrap_or_default())
+            .map_err(|e| DbError::internal(&format!("Invalid metadata server token: {}", e)))?;
+        Ok(AccessToken {
+            token: token.access_token,
+            expires_in: Duration::from_secs(token.expires_in),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, Instant};
+
+    use super::{AccessToken, CredentialSource, TokenCache, TokenProvider};
+    use crate::db::error::DbError;
+
+    /// Hands out numbered tokens expiring in an hour
+    #[derive(Default)]
+    struct FakeProvider {
+        fetches: AtomicUsize,
+        fail: AtomicBool,
+    }
+
+    impl TokenProvider for Arc<FakeProvider> {
+        fn fetch(&self) -> Result<AccessToken, DbError> {
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(DbError::internal("unavailable"));
+            }
+            Ok(AccessToken {
+                token: format!("token{}", n),
+                expires_in: Duration::from_secs(60 * 60),
+            })
+        }
+    }
+
+    fn minutes(n: u64) -> Duration {
+        Duration::from_secs(n * 60)
+    }
+
+    #[test]
+    fn refreshed_before_expiry() {
+        let provider = Arc::new(FakeProvider::default());
+        let cache = TokenCache::new(Box::new(provider.clone()));
+        let start = Instant::now();
+        cache.refresh_at(start).unwrap();
+        assert_eq!(cache.token_at(start).unwrap(), "token1");
+        assert_eq!(cache.until_refresh(start), minutes(55));
+
+        cache.refresh_at(start + minutes(50)).unwrap();
+        assert_eq!(cache.token_at(start + minutes(50)).unwrap(), "token1");
+        assert_eq!(provider.fetches.load(Ordering::SeqCst), 1);
+        // Within the refresh margin of the expiry
+        cache.refresh_at(start + minutes(56)).unwrap();
+        assert_eq!(cache.token_at(start + minutes(57)).unwrap(), "token2");
+        assert_eq!(provider.fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reads_never_fetch() {
+        let provider = Arc::new(FakeProvider::default());
+        let cache = TokenCache::new(Box::new(provider.clone()));
+        let start = Instant::now();
+        assert!(cache.token_at(start).is_err());
+        cache.refresh_at(start).unwrap();
+        // Due for a refresh, but only the refresher fetches
+        assert_eq!(cache.token_at(start + minutes(58)).unwrap(), "token1");
+        assert_eq!(provider.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_refresh_falls_back() {
+        let provider = Arc::new(FakeProvider::default());
+        let cache = TokenCache::new(Box::new(provider.clone()));
+        let start = Instant::now();
+        cache.refresh_at(start).unwrap();
+
+        provider.fail.store(true, Ordering::SeqCst);
+        // Still valid until it expires
+        assert!(cache.refresh_at(start + minutes(56)).is_err());
+        assert!(cache.refresh_at(start + minutes(58)).is_err());
+        assert_eq!(cache.token_at(start + minutes(58)).unwrap(), "token1");
+        assert!(cache.token_at(start + minutes(61)).is_err());
+
+        provider.fail.store(false, Ordering::SeqCst);
+        cache.refresh_at(start + minutes(62)).unwrap();
+        assert_eq!(cache.token_at(start + minutes(62)).unwrap(), "token4");
+    }
+
+    #[test]
+    fn failed_fetch() {
+        let provider = Arc::new(FakeProvider::default());
+        provider.fail.store(true, Ordering::SeqCst);
+        let cache = TokenCache::new(Box::new(provider));
+        assert!(cache.refresh_at(Instant::now()).is_err());
+        assert!(cache.token().is_err());
+    }
+
+    #[test]
+    fn source_selection() {
+        assert_eq!(
+            CredentialSource::select(Some("settings.json"), Some("env.json".to_owned())),
+            CredentialSource::KeyFile {
+                path: "settings.json".to_owned(),
+                origin: "database_spanner_credentials",
+            }
+        );
+        assert_eq!(
+            CredentialSource::select(None, Some("env.json".to_owned())),
+            CredentialSource::KeyFile {
+                path: "env.json".to_owned(),
+                origin: "GOOGLE_APPLICATION_CREDENTIALS",
+            }
+        );
+        assert_eq!(
+            CredentialSource::select(Some(""), Some("".to_owned())),
+            CredentialSource::MetadataServer
+        );
+        assert_eq!(
+            CredentialSource::select(None, None),
+            CredentialSource::MetadataServer
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Bootstrapping of a Cloud Spanner emulator for local development and tests.
+//!
+//! The emulator starts out empty: the instance and database named by the
+//! `spanner://` url are created when missing (the schema's then applied by
+//! the migrations).
+use std::sync::Arc;
+
+use googleapis_raw::longrunning::{operations::Operation, operations_grpc::OperationsClient};
+use googleapis_raw::spanner::admin::database::v1::{
+    spanner_database_admin::CreateDatabaseRequest, spanner_database_admin_grpc::DatabaseAdminClient,
+};
+use googleapis_raw::spanner::admin::instance::v1::{
+    spanner_instance_admin::{CreateInstanceRequest, Instance},
+    spanner_instance_admin_grpc::InstanceAdminClient,
+};
+use grpcio::{EnvBuilder, RpcStatusCode};
+
+use super::credentials::SpannerCredentials;
+use super::manager::{connect_channel, database_name, emulator_host};
+use super::migrations::wait_for_operation;
+use super::models::Result;
+use crate::db::error::{DbError, DbErrorKind};
+use crate::settings::Settings;
+
+/// The emulator's only instance configuration
+const EMULATOR_CONFIG: &str = "emulator-config";
+
+/// Create the instance and database of `settings.database_url` on the
+/// emulator (when they don't already exist)
+pub fn create_database(settings: &Settings) -> Result<()> {
+    if emulator_host().is_none() {
+        Err(DbError::internal(
+            "Creating a Spanner database requires SPANNER_EMULATOR_HOST",
+        ))?
+    }
+    let database_name = database_name(&settings.database_url)?;
+    let (project, instance, database) = parse_database_name(&database_name)
+        .ok_or_else(|| DbErrorKind::InvalidUrl(settings.database_url.to_owned()))?;
+
+    let channel = connect_channel(Arc::new(EnvBuilder::new().build()))?;
+    let operations = OperationsClient::new(channel.clone());
+    // (none: calls to the emulator are unauthenticated)
+    let credentials = SpannerCredentials::from_settings(settings)?;
+
+    let instance_name = format!("projects/{}/instances/{}", project, instance);
+    let mut req = CreateInstanceRequest::new();
+    req.set_parent(format!("projects/{}", project));
+    req.set_instance_id(instance.to_owned());
+    let mut instance = Instance::new();
+    instance.set_name(instance_name.clone());
+    instance.set_config(format!(
+        "projects/{}/instanceConfigs/{}",
+        project, EMULATOR_CONFIG
+    ));
+    instance.set_display_name(instance_name.clone());
+    instance.set_node_count(1);
+    req.set_instance(instance);
+    let result = InstanceAdminClient::new(channel.clone()).create_instance(&req);
+    created(&operations, &credentials, result)?;
+
+    let mut req = CreateDatabaseRequest::new();
+    req.set_parent(instance_name);
+    req.set_create_statement(format!("CREATE DATABASE `{}`", database));
+    let result = DatabaseAdminClient::new(channel).create_database(&req);
+    if created(&operations, &credentials, result)? {
+        info!("Created Spanner emulator database"; "database" => &database_name);
+    }
+    Ok(())
+}
+
+/// Await the creation of a resource, returning whether it was created (false
+/// when it already existed)
+fn created(
+    operations: &OperationsClient,
+    credentials: &SpannerCredentials,
+    result: grpcio::Result<Operation>,
+) -> Result<bool> {
+    let operation = match result {
+        Ok(operation) => wait_for_operation(operations, credentials, operation)?,
+        Err(grpcio::Error::RpcFailure(ref status))
+            if status.status == RpcStatusCode::ALREADY_EXISTS =>
+        {
+            return Ok(false)
+        }
+        Err(e) => Err(e)?,
+    };
+    if operation.has_error() {
+        let error = operation.get_error();
+        let already_exists: i32 = RpcStatusCode::ALREADY_EXISTS.into();
+        if error.get_code() == already_exists {
+            return Ok(false);
+        }
+        Err(DbError::internal(error.get_message()))?
+    }
+    Ok(true)
+}
+
+/// Split `projects/<project>/instances/<instance>/databases/<database>`
+fn parse_database_name(name: &str) -> Option<(&str, &str, &str)> {
+    match name.split('/').collect::<Vec<_>>().as_slice() {
+        ["projects", project, "instances", instance, "databases", database] => {
+            Some((*project, *instance, *database))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_database_name;
+
+    #[test]
+    fn database_names() {
+        assert_eq!(
+            parse_database_name("projects/test-project/instances/test-instance/databases/sync"),
+            Some(("test-project", "test-instance", "sync"))
+        );
+        assert_eq!(
+            parse_database_name("projects/test-project/instances/i"),
+            None
+        );
+        assert_eq!(parse_database_name("sync"), None);
+    }
+}
@@ -1,4 +1,12 @@
-use std::{fmt, sync::Arc};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use diesel::r2d2::ManageConnection;
 use googleapis_raw::spanner::v1::{
@@ -6,20 +14,29 @@ use googleapis_raw::spanner::v1::{
     spanner_grpc::SpannerClient,
 };
 use grpcio::{
-    CallOption, ChannelBuilder, ChannelCredentials, EnvBuilder, Environment, MetadataBuilder,
+    CallOption, Channel, ChannelBuilder, ChannelCredentialsBuilder, ClientUnaryReceiver,
+    ConnectivityState, EnvBuilder, Environment, MetadataBuilder,
 };
 
+use super::credentials::SpannerCredentials;
 use crate::{
     db::error::{DbError, DbErrorKind},
+    server::metrics::Metrics,
     settings::Settings,
+    web::tags::Tags,
 };
 
 const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
+const SPANNER_EMULATOR_HOST: &str = "SPANNER_EMULATOR_HOST";
 
 pub struct SpannerConnectionManager {
     database_name: String,
     /// The gRPC environment
     env: Arc<Environment>,
+    credentials: SpannerCredentials,
+    /// The `application` label of the sessions
+    label: Option<String>,
+    metrics: Metrics,
 }
 
 impl fmt::Debug for SpannerConnectionManager {
@@ -31,22 +48,180 @@ impl fmt::Debug for SpannerConnectionManager {
 }
 
 impl SpannerConnectionManager {
-    pub fn new(settings: &Settings) -> Result<Self, DbError> {
-        let url = &settings.database_url;
-        if !url.starts_with("spanner://") {
-            Err(DbErrorKind::InvalidUrl(url.to_owned()))?;
-        }
-        let database_name = url["spanner://".len()..].to_owned();
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self, DbError> {
+        let database_name = database_name(&settings.database_url)?;
         let env = Arc::new(EnvBuilder::new().build());
-        Ok(SpannerConnectionManager { database_name, env })
+        Ok(SpannerConnectionManager {
+            database_name,
+            env,
+            credentials: SpannerCredentials::from_settings(settings)?,
+            label: settings.database_connection_label(),
+            metrics: metrics.clone(),
+        })
+    }
+
+    fn create_session(&self, client: &SpannerClient) -> Result<Session, grpcio::Error> {
+        let start = Instant::now();
+        let result = create_session(
+            client,
+            &self.database_name,
+            &self.credentials,
+            self.label.as_deref(),
+        );
+        record_rpc(
+            &self.metrics,
+            "CreateSession",
+            start.elapsed(),
+            result.as_ref().err(),
+        );
+        result
+    }
+}
+
+/// The Spanner database name (`projects/.../databases/...`) of a
+/// `spanner://` url
+pub(super) fn database_name(url: &str) -> Result<String, DbError> {
+    if !url.starts_with("spanner://") {
+        Err(DbErrorKind::InvalidUrl(url.to_owned()))?;
+    }
+    Ok(url["spanner://".len()..].to_owned())
+}
+
+/// The address of the Cloud Spanner emulator to connect to instead of
+/// Spanner, when `SPANNER_EMULATOR_HOST` is set (e.g. `localhost:9010`)
+pub fn emulator_host() -> Option<String> {
+    env::var(SPANNER_EMULATOR_HOST)
+        .ok()
+        .filter(|host| !host.is_empty())
+}
+
+/// Open a channel to Spanner (or its emulator: over plaintext, without
+/// credentials)
+pub(super) fn connect_channel(env: Arc<Environment>) -> Result<Channel, grpcio::Error> {
+    let builder = ChannelBuilder::new(env)
+        .max_send_message_len(100 << 20)
+        .max_receive_message_len(100 << 20);
+    if let Some(host) = emulator_host() {
+        return Ok(builder.connect(&host));
     }
+    // Calls are authorized individually (see `SpannerCredentials`)
+    let creds = ChannelCredentialsBuilder::new().build();
+    Ok(builder.secure_connect(SPANNER_ADDRESS, creds))
 }
 
 pub struct SpannerSession {
     pub client: SpannerClient,
+    /// The client's channel (checked for shutdown by `has_broken`)
+    pub(super) channel: Channel,
     pub session: Session,
+    pub(super) credentials: SpannerCredentials,
 
     pub(super) use_test_transactions: bool,
+    /// Set when an RPC found the session deleted server side (shared with
+    /// the session's in flight result streams)
+    pub(super) lost: Arc<AtomicBool>,
+    /// Records the latency and status of each RPC (see `call`)
+    pub(super) metrics: Metrics,
+}
+
+impl SpannerSession {
+    /// Note whether `result` failed because the session no longer exists, so
+    /// the pool evicts it (see `has_broken`) rather than handing it out again
+    pub(super) fn check<T>(&self, result: Result<T, grpcio::Error>) -> Result<T, grpcio::Error> {
+        check_session(&self.lost, result)
+    }
+
+    /// The options of an authorized call
+    pub(super) fn call_option(&self) -> Result<CallOption, grpcio::Error> {
+        self.credentials.call_option()
+    }
+
+    /// Make the unary `rpc` call issued by `f` (given the client and the
+    /// call's options), recording its latency and status
+    pub(super) async fn call<T, F>(&self, rpc: &'static str, f: F) -> Result<T, grpcio::Error>
+    where
+        F: FnOnce(&SpannerClient, CallOption) -> Result<ClientUnaryReceiver<T>, grpcio::Error>,
+    {
+        let opt = self.call_option()?;
+        let start = Instant::now();
+        let result = match f(&self.client, opt) {
+            Ok(receiver) => receiver.await,
+            Err(e) => Err(e),
+        };
+        record_rpc(&self.metrics, rpc, start.elapsed(), result.as_ref().err());
+        self.check(result)
+    }
+}
+
+/// Record an RPC's latency as the `storage.spanner.rpc` timing, tagged by
+/// the RPC's name and its status (`ok` when it succeeded). Failures are also
+/// counted by `storage.spanner.rpc.error`
+pub(super) fn record_rpc(
+    metrics: &Metrics,
+    rpc: &'static str,
+    elapsed: Duration,
+    error: Option<&grpcio::Error>,
+) {
+    if !metrics.is_enabled() {
+        return;
+    }
+    let mut tags = HashMap::new();
+    tags.insert("rpc".to_owned(), rpc.to_owned());
+    tags.insert(
+        "status".to_owned(),
+        error.map_or_else(|| "ok".to_owned(), rpc_status),
+    );
+    let tags = Tags::with_tags(tags);
+    metrics.timing_with_tags(
+        "storage.spanner.rpc",
+        elapsed.as_millis() as u64,
+        Some(tags.clone()),
+    );
+    if error.is_some() {
+        metrics
+            .clone()
+            .incr_with_tags("storage.spanner.rpc.error", Some(tags));
+    }
+}
+
+/// The (lower case) name of a failed RPC's status, e.g. `unavailable`
+fn rpc_status(e: &grpcio::Error) -> String {
+    match e {
+        grpcio::Error::RpcFailure(status) | grpcio::Error::RpcFinished(Some(status)) => {
+            // Displayed as e.g. "14-UNAVAILABLE"
+            let status = status.status.to_string();
+            status.rsplit('-').next().unwrap_or_default().to_lowercase()
+        }
+        _ => "error".to_owned(),
+    }
+}
+
+/// Note whether `result` failed because its session no longer exists
+pub(super) fn check_session<T>(
+    lost: &AtomicBool,
+    result: Result<T, grpcio::Error>,
+) -> Result<T, grpcio::Error> {
+    if let Err(ref e) = result {
+        if is_session_not_found(e) {
+            lost.store(true, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+/// Whether an RPC failed because its session no longer exists: Spanner
+/// deletes sessions idle for over an hour (among other reasons)
+fn is_session_not_found(e: &grpcio::Error) -> bool {
+    match e {
+        grpcio::Error::RpcFailure(status) | grpcio::Error::RpcFinished(Some(status)) => {
+            status.status == grpcio::RpcStatusCode::NOT_FOUND
+                && status
+                    .details
+                    .as_ref()
+                    .map_or(false, |details| details.contains("Session not found"))
+        }
+        _ => false,
+    }
 }
 
 impl ManageConnection for SpannerConnectionManager {
@@ -54,53 +229,147 @@ impl ManageConnection for SpannerConnectionManager {
     type Error = grpcio::Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        // Requires GOOGLE_APPLICATION_CREDENTIALS=/path/to/service-account.json
-        let creds = ChannelCredentials::google_default_credentials()?;
-
         // Create a Spanner client.
-        let chan = ChannelBuilder::new(self.env.clone())
-            .max_send_message_len(100 << 20)
-            .max_receive_message_len(100 << 20)
-            .secure_connect(SPANNER_ADDRESS, creds);
-        let client = SpannerClient::new(chan);
+        let channel = connect_channel(self.env.clone())?;
+        let client = SpannerClient::new(channel.clone());
 
         // Connect to the instance and create a Spanner session.
-        let session = create_session(&client, &self.database_name)?;
+        let session = self.create_session(&client)?;
 
         Ok(SpannerSession {
             client,
+            channel,
             session,
+            credentials: self.credentials.clone(),
             use_test_transactions: false,
+            lost: Arc::new(AtomicBool::new(false)),
+            metrics: self.metrics.clone(),
         })
     }
 
+    /// Checked on checkout: a session deleted while idle in the pool is
+    /// transparently replaced
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
         let mut req = GetSessionRequest::new();
         req.set_name(conn.session.get_name().to_owned());
-        if let Err(e) = conn.client.get_session(&req) {
+        let start = Instant::now();
+        let result = conn.client.get_session_opt(&req, conn.call_option()?);
+        record_rpc(
+            &self.metrics,
+            "GetSession",
+            start.elapsed(),
+            result.as_ref().err(),
+        );
+        if let Err(e) = result {
             match e {
                 grpcio::Error::RpcFailure(ref status)
                     if status.status == grpcio::RpcStatusCode::NOT_FOUND =>
                 {
-                    conn.session = create_session(&conn.client, &self.database_name)?;
+                    conn.session = self.create_session(&conn.client)?;
+                    self.metrics
+                        .clone()
+                        .incr("storage.spanner.session.recreated");
+                }
+                _ => {
+                    // Dropped by the pool, which tries another connection
+                    self.metrics
+                        .clone()
+                        .incr("storage.spanner.connection.invalid");
+                    return Err(e);
                 }
-                _ => return Err(e),
             }
         }
         Ok(())
     }
 
-    fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
+    /// Checked on return to the pool: a session found deleted while in use,
+    /// or whose channel was shut down, is dropped (and replaced by a new
+    /// connection when next needed)
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        if conn.lost.load(Ordering::Relaxed) {
+            self.metrics.clone().incr("storage.spanner.session.evicted");
+            return true;
+        }
+        if conn.channel.check_connectivity_state(false) == ConnectivityState::GRPC_CHANNEL_SHUTDOWN
+        {
+            self.metrics.clone().incr("storage.spanner.channel.evicted");
+            return true;
+        }
         false
     }
 }
 
-fn create_session(client: &SpannerClient, database_name: &str) -> Result<Session, grpcio::Error> {
+/// Spanner label values are restricted to (at most 63) lower case letters,
+/// digits and dashes, beginning with a letter: e.g.
+/// `syncstorage-rs@0.5.0@web-1` is labeled `syncstorage-rs-0-5-0-web-1`
+fn session_label(label: &str) -> String {
+    let label: String = label
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' => c,
+            _ => '-',
+        })
+        .skip_while(|c| !c.is_ascii_lowercase())
+        .take(63)
+        .collect();
+    label.trim_end_matches('-').to_owned()
+}
+
+/// Create a session, labeling it with the `application` (`label`, see
+/// `session_label`) when given
+pub(super) fn create_session(
+    client: &SpannerClient,
+    database_name: &str,
+    credentials: &SpannerCredentials,
+    label: Option<&str>,
+) -> Result<Session, grpcio::Error> {
     let mut req = CreateSessionRequest::new();
     req.database = database_name.to_owned();
+    if let Some(label) = label.map(session_label).filter(|label| !label.is_empty()) {
+        req.mut_session()
+            .mut_labels()
+            .insert("application".to_owned(), label);
+    }
     let mut meta = MetadataBuilder::new();
+    credentials.authorize(&mut meta)?;
     meta.add_str("google-cloud-resource-prefix", database_name)?;
     meta.add_str("x-goog-api-client", "gcp-grpc-rs")?;
     let opt = CallOption::default().headers(meta.build());
     client.create_session_opt(&req, opt)
 }
+
+#[cfg(test)]
+mod tests {
+    use grpcio::{RpcStatus, RpcStatusCode};
+
+    use super::{rpc_status, session_label};
+
+    #[test]
+    fn rpc_statuses() {
+        let failure = |code| grpcio::Error::RpcFailure(RpcStatus::new(code, None));
+        assert_eq!(
+            rpc_status(&failure(RpcStatusCode::UNAVAILABLE)),
+            "unavailable"
+        );
+        assert_eq!(rpc_status(&failure(RpcStatusCode::NOT_FOUND)), "not_found");
+        assert_eq!(
+            rpc_status(&grpcio::Error::RpcFinished(Some(RpcStatus::new(
+                RpcStatusCode::ABORTED,
+                None
+            )))),
+            "aborted"
+        );
+        assert_eq!(rpc_status(&grpcio::Error::RemoteStopped), "error");
+    }
+
+    #[test]
+    fn session_labels() {
+        assert_eq!(
+            session_label("syncstorage-rs@0.5.0@Web-1.example.com"),
+            "syncstorage-rs-0-5-0-web-1-example-com"
+        );
+        assert_eq!(session_label("@1.0@"), "");
+        assert_eq!(session_label(&"x".repeat(100)).len(), 63);
+    }
+}
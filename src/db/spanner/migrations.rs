@@ -0,0 +1,394 @@
+//! Embedded migrations of the Spanner schema.
+//!
+//! Each migration (a DDL file of the `migrations` directory) is applied via
+//! the database admin API's UpdateDatabaseDdl, then recorded in the
+//! `schema_migrations` table.
+//!
+//! Nodes starting up concurrently may race to apply the same migration: its
+//! schema change is submitted under an operation id derived from its name, so
+//! the losers await the winner's operation instead of applying it again. A
+//! migration whose objects partly exist (e.g. the schema was applied by hand
+//! before migrations were tracked) has its remaining statements applied
+//! individually.
+use std::{sync::Arc, thread, time::Duration};
+
+use googleapis_raw::longrunning::{
+    operations::{GetOperationRequest, Operation},
+    operations_grpc::OperationsClient,
+};
+use googleapis_raw::spanner::admin::database::v1::{
+    spanner_database_admin::{GetDatabaseDdlRequest, UpdateDatabaseDdlRequest},
+    spanner_database_admin_grpc::DatabaseAdminClient,
+};
+use googleapis_raw::spanner::v1::{
+    mutation::{Mutation, Mutation_Write},
+    spanner::{CommitRequest, DeleteSessionRequest, ExecuteSqlRequest, Session},
+    spanner_grpc::SpannerClient,
+    transaction::{TransactionOptions, TransactionOptions_ReadWrite},
+};
+use grpcio::{EnvBuilder, RpcStatusCode};
+use protobuf::{well_known_types::ListValue, RepeatedField};
+
+use super::credentials::SpannerCredentials;
+use super::manager::{connect_channel, create_session, database_name};
+use super::models::Result;
+use super::support::as_value;
+use crate::db::error::DbErrorKind;
+use crate::settings::Settings;
+
+/// The embedded migrations (name, DDL), oldest first
+pub(super) const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "2019_10_01_initial",
+        include_str!("migrations/2019_10_01_initial.ddl"),
+    ),
+    (
+        "2020_06_15_maintenance_locks",
+        include_str!("migrations/2020_06_15_maintenance_locks.ddl"),
+    ),
+    (
+        "2020_07_14_commit_timestamps",
+        include_str!("migrations/2020_07_14_commit_timestamps.ddl"),
+    ),
+];
+
+const SCHEMA_MIGRATIONS_DDL: &str = "CREATE TABLE schema_migrations (
+  name STRING(MAX)  NOT NULL,
+  applied TIMESTAMP NOT NULL OPTIONS (allow_commit_timestamp = true),
+) PRIMARY KEY(name)";
+
+/// How often an in progress schema change is polled for completion
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Apply the pending embedded migrations (on a dedicated session)
+pub fn run_migrations(settings: &Settings) -> Result<()> {
+    let migrator = Migrator::new(settings)?;
+    if !migrator.has_migrations_table()? {
+        migrator.apply("schema_migrations", &[SCHEMA_MIGRATIONS_DDL.to_owned()])?;
+    }
+    let applied = migrator.applied()?;
+    for (name, ddl) in MIGRATIONS {
+        if applied.iter().any(|applied| applied == name) {
+            continue;
+        }
+        info!("Applying Spanner migration"; "name" => name);
+        migrator.apply(name, &statements(ddl))?;
+        migrator.record(name)?;
+    }
+    Ok(())
+}
+
+/// Ensure the database schema isn't behind the code when migrations are left
+/// to a separate deploy step
+pub fn check_migrations(settings: &Settings) -> Result<()> {
+    let migrator = Migrator::new(settings)?;
+    // No migrations table: none have ran
+    let applied = if migrator.has_migrations_table()? {
+        migrator.applied()?
+    } else {
+        vec![]
+    };
+    if let Some((missing, _)) = MIGRATIONS
+        .iter()
+        .find(|(name, _)| !applied.iter().any(|applied| applied == name))
+    {
+        Err(DbErrorKind::MissingMigration((*missing).to_owned()))?
+    }
+    Ok(())
+}
+
+/// Split a DDL file into its statements (sans comments)
+fn statements(ddl: &str) -> Vec<String> {
+    let ddl = ddl
+        .lines()
+        .map(|line| match line.find("--") {
+            Some(i) => &line[..i],
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    ddl.split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A failed schema change
+#[derive(Debug)]
+struct DdlFailure {
+    code: i32,
+    message: String,
+}
+
+impl DdlFailure {
+    /// Whether the change failed due to objects it creates already existing
+    fn is_duplicate(&self) -> bool {
+        let already_exists: i32 = RpcStatusCode::ALREADY_EXISTS.into();
+        self.code == already_exists || self.message.contains("Duplicate name in schema")
+    }
+}
+
+struct Migrator {
+    database_name: String,
+    admin: DatabaseAdminClient,
+    operations: OperationsClient,
+    client: SpannerClient,
+    session: Session,
+    credentials: SpannerCredentials,
+}
+
+impl Migrator {
+    fn new(settings: &Settings) -> Result<Self> {
+        let database_name = database_name(&settings.database_url)?;
+        let channel = connect_channel(Arc::new(EnvBuilder::new().build()))?;
+        let client = SpannerClient::new(channel.clone());
+        let credentials = SpannerCredentials::from_settings(settings)?;
+        let label = settings.database_connection_label();
+        let session = create_session(&client, &database_name, &credentials, label.as_deref())?;
+        Ok(Self {
+            database_name,
+            admin: DatabaseAdminClient::new(channel.clone()),
+            operations: OperationsClient::new(channel),
+            client,
+            session,
+            credentials,
+        })
+    }
+
+    fn has_migrations_table(&self) -> Result<bool> {
+        let mut req = GetDatabaseDdlRequest::new();
+        req.set_database(self.database_name.clone());
+        let ddl = self
+            .admin
+            .get_database_ddl_opt(&req, self.credentials.call_option()?)?;
+        Ok(ddl
+            .get_statements()
+            .iter()
+            .any(|statement| statement.starts_with("CREATE TABLE schema_migrations ")))
+    }
+
+    /// The names of the applied migrations
+    fn applied(&self) -> Result<Vec<String>> {
+        let mut req = ExecuteSqlRequest::new();
+        req.set_session(self.session.get_name().to_owned());
+        req.set_sql("SELECT name FROM schema_migrations".to_owned());
+        let result_set = self
+            .client
+            .execute_sql_opt(&req, self.credentials.call_option()?)?;
+        Ok(result_set
+            .get_rows()
+            .iter()
+            .map(|row| row.get_values()[0].get_string_value().to_owned())
+            .collect())
+    }
+
+    /// Record a migration as applied (a no-op when a concurrent node already
+    /// has)
+    fn record(&self, name: &str) -> Result<()> {
+        let mut row = ListValue::new();
+        row.set_values(RepeatedField::from_vec(vec![
+            as_value(name.to_owned()),
+            as_value("spanner.commit_timestamp()".to_owned()),
+        ]));
+        let mut write = Mutation_Write::new();
+        write.set_table("schema_migrations".to_owned());
+        write.set_columns(RepeatedField::from_vec(vec![
+            "name".to_owned(),
+            "applied".to_owned(),
+        ]));
+        write.set_values(RepeatedField::from_vec(vec![row]));
+        let mut mutation = Mutation::new();
+        mutation.set_insert(write);
+
+        let mut options = TransactionOptions::new();
+        options.set_read_write(TransactionOptions_ReadWrite::new());
+        let mut req = CommitRequest::new();
+        req.set_session(self.session.get_name().to_owned());
+        req.set_single_use_transaction(options);
+        req.set_mutations(RepeatedField::from_vec(vec![mutation]));
+        match self
+            .client
+            .commit_opt(&req, self.credentials.call_option()?)
+        {
+            Err(grpcio::Error::RpcFailure(ref status))
+                if status.status == RpcStatusCode::ALREADY_EXISTS =>
+            {
+                Ok(())
+            }
+            result => result.map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    /// Apply a migration's statements
+    fn apply(&self, name: &str, statements: &[String]) -> Result<()> {
+        let operation_id = format!("migration_{}", name);
+        let failure = match self.update_ddl(statements, Some(&operation_id))? {
+            Some(failure) => failure,
+            None => return Ok(()),
+        };
+        // Some of its objects may already exist (or a previous attempt
+        // failed partway): apply whichever remain
+        warn!(
+            "Spanner migration failed, applying its statements individually";
+            "name" => name,
+            "error" => &failure.message
+        );
+        for statement in statements {
+            match self.update_ddl(&[statement.clone()], None)? {
+                Some(failure) if !failure.is_duplicate() => Err(DbErrorKind::SpannerMigration(
+                    format!("{}: {}", name, failure.message),
+                ))?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Submit a schema change and await its completion, returning its
+    /// failure (if any).
+    ///
+    /// Submitting a change under an `operation_id` already taken awaits the
+    /// existing operation instead.
+    fn update_ddl(
+        &self,
+        statements: &[String],
+        operation_id: Option<&str>,
+    ) -> Result<Option<DdlFailure>> {
+        let mut req = UpdateDatabaseDdlRequest::new();
+        req.set_database(self.database_name.clone());
+        req.set_statements(RepeatedField::from_slice(statements));
+        if let Some(operation_id) = operation_id {
+            req.set_operation_id(operation_id.to_owned());
+        }
+        let operation = match self
+            .admin
+            .update_database_ddl_opt(&req, self.credentials.call_option()?)
+        {
+            Ok(operation) => operation,
+            Err(grpcio::Error::RpcFailure(status)) => match operation_id {
+                Some(operation_id) if status.status == RpcStatusCode::ALREADY_EXISTS => {
+                    get_operation(
+                        &self.operations,
+                        &self.credentials,
+                        format!("{}/operations/{}", self.database_name, operation_id),
+                    )?
+                }
+                // Statements are validated upfront
+                _ => {
+                    return Ok(Some(DdlFailure {
+                        code: status.status.into(),
+                        message: status.details.unwrap_or_default(),
+                    }))
+                }
+            },
+            Err(e) => Err(e)?,
+        };
+        let operation = wait_for_operation(&self.operations, &self.credentials, operation)?;
+        Ok(if operation.has_error() {
+            let error = operation.get_error();
+            Some(DdlFailure {
+                code: error.get_code(),
+                message: error.get_message().to_owned(),
+            })
+        } else {
+            None
+        })
+    }
+}
+
+/// Poll a long-running operation until it's done
+pub(super) fn wait_for_operation(
+    operations: &OperationsClient,
+    credentials: &SpannerCredentials,
+    mut operation: Operation,
+) -> Result<Operation> {
+    while !operation.get_done() {
+        thread::sleep(POLL_INTERVAL);
+        operation = get_operation(operations, credentials, operation.get_name().to_owned())?;
+    }
+    Ok(operation)
+}
+
+fn get_operation(
+    operations: &OperationsClient,
+    credentials: &SpannerCredentials,
+    name: String,
+) -> Result<Operation> {
+    let mut req = GetOperationRequest::new();
+    req.set_name(name);
+    Ok(operations.get_operation_opt(&req, credentials.call_option()?)?)
+}
+
+impl Drop for Migrator {
+    fn drop(&mut self) {
+        let mut req = DeleteSessionRequest::new();
+        req.set_name(self.session.get_name().to_owned());
+        let result = self
+            .credentials
+            .call_option()
+            .and_then(|opt| self.client.delete_session_opt(&req, opt));
+        if let Err(e) = result {
+            debug!("Failed to delete the migrations session: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use regex::Regex;
+
+    use super::{check_migrations, run_migrations, statements, MIGRATIONS, SCHEMA_MIGRATIONS_DDL};
+    use crate::db::tests::support::Backend;
+
+    #[test]
+    fn migration_statements() {
+        // UpdateDatabaseDdl's operation ids: [a-z][a-z0-9_]*
+        let operation_id = Regex::new("^[a-z][a-z0-9_]*$").unwrap();
+        for (name, ddl) in MIGRATIONS {
+            assert!(operation_id.is_match(&format!("migration_{}", name)));
+            let statements = statements(ddl);
+            assert!(!statements.is_empty());
+            for statement in statements {
+                assert!(statement.starts_with("CREATE "), "{}", statement);
+                assert!(!statement.contains("--"));
+            }
+        }
+        assert_eq!(statements(SCHEMA_MIGRATIONS_DDL).len(), 1);
+    }
+
+    #[test]
+    fn split_statements() {
+        let ddl = "-- a comment; with a semicolon\n\
+                   CREATE TABLE foo (\n  id INT64, -- trailing\n) PRIMARY KEY(id);\n\n\
+                   CREATE INDEX FooId ON foo(id);\n";
+        assert_eq!(
+            statements(ddl),
+            vec![
+                "CREATE TABLE foo (\n  id INT64, \n) PRIMARY KEY(id)",
+                "CREATE INDEX FooId ON foo(id)",
+            ]
+        );
+    }
+
+    /// Nodes starting up at once both succeed, applying each migration once
+    #[test]
+    fn concurrent_migrations() {
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let nodes: Vec<_> = (0..2)
+            .map(|_| {
+                let settings = settings.clone();
+                thread::spawn(move || run_migrations(&settings))
+            })
+            .collect();
+        for node in nodes {
+            node.join().unwrap().unwrap();
+        }
+        check_migrations(&settings).unwrap();
+    }
+}
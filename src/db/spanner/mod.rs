@@ -2,11 +2,15 @@
 mod macros;
 
 mod batch;
+pub mod credentials;
+pub mod emulator;
 pub mod manager;
+pub mod migrations;
 pub mod models;
 pub mod pool;
 mod support;
 #[cfg(test)]
 mod test_util;
+pub mod write_queue;
 
 pub use self::pool::SpannerDbPool;
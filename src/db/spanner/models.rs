@@ -1,29 +1,39 @@
-use futures::future::TryFutureExt;
+use actix_rt::time::delay_for;
+use futures::future::{Future, FutureExt, LocalBoxFuture, TryFutureExt};
 
 use diesel::r2d2::PooledConnection;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fmt;
+use std::mem;
 use std::ops::Deref;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::manager::SpannerConnectionManager;
-use super::pool::CollectionCache;
+use super::write_queue::{WriteQueue, WriteQueueGuard};
 
 use crate::db::{
+    cache::CollectionCache,
+    codec::{self, PayloadCodec},
     error::{DbError, DbErrorKind},
     params, results,
     spanner::support::{as_type, StreamedResultSetAsync},
-    util::SyncTimestamp,
+    util::{to_rfc3339, PoolHealth, QueryPlanSampler, ResponseCeiling, SyncTimestamp},
     Db, DbFuture, Sorting, FIRST_CUSTOM_COLLECTION_ID,
 };
 use crate::server::metrics::Metrics;
 
 use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
 
-use super::support::{bso_to_insert_row, bso_to_update_row};
+use super::support::{
+    bso_to_insert_row, bso_to_update_row, chunk_by_mutations, stamp_commit_timestamp,
+    sync_timestamp, BsoWrite, BSO_COLUMNS, BSO_DELETE_MUTATIONS, MAX_MUTATIONS_PER_COMMIT,
+    RESERVED_MUTATIONS,
+};
 use super::{
     batch,
     support::{as_list_value, as_value, bso_from_row, ExecuteSqlRequestBuilder},
@@ -31,11 +41,15 @@ use super::{
 
 use googleapis_raw::spanner::v1::transaction;
 use googleapis_raw::spanner::v1::transaction::{
-    TransactionOptions, TransactionOptions_ReadOnly, TransactionOptions_ReadWrite,
+    Transaction, TransactionOptions, TransactionOptions_PartitionedDml,
+    TransactionOptions_ReadOnly, TransactionOptions_ReadWrite,
 };
 use googleapis_raw::spanner::v1::{
     mutation::{Mutation, Mutation_Write},
-    spanner::{BeginTransactionRequest, CommitRequest, ExecuteSqlRequest, RollbackRequest},
+    spanner::{
+        BeginTransactionRequest, CommitRequest, ExecuteSqlRequest, ExecuteSqlRequest_QueryMode,
+        RollbackRequest,
+    },
     type_pb::TypeCode,
 };
 
@@ -63,12 +77,55 @@ pub const PRETOUCH_TS: &str = "0001-01-01T00:00:00.00Z";
 // max load size in bytes
 pub const MAX_SPANNER_LOAD_SIZE: usize = 100_000_000;
 
+/// Maximum number of times a transaction aborted by Spanner is retried (per
+/// operation)
+const MAX_TRANSACTION_RETRIES: u32 = 3;
+
+/// Delay before the first retry of an aborted transaction, doubling with each
+/// subsequent one
+const TRANSACTION_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// An operation performed within the current read-write transaction
+type ReplayOp = Rc<dyn Fn(SpannerDb) -> LocalBoxFuture<'static, Result<()>>>;
+
+/// The operations performed within the current read-write transaction, in
+/// order, to replay when Spanner aborts it
+#[derive(Default)]
+struct Replay(Vec<ReplayOp>);
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Replay({} operations)", self.0.len())
+    }
+}
+
+/// Whether a page of at most `limit` (negative: unlimited) rows has been read,
+/// along with the extra row signalling there's another page
+fn page_read(limit: i64, read: usize) -> bool {
+    limit >= 0 && read > limit as usize
+}
+
+/// The offset of the record following the `read` ones of a page truncated
+/// at its response ceiling (from the request's `offset` and `timestamp`)
+fn truncated_offset(offset: u64, timestamp: Option<SyncTimestamp>, read: usize) -> String {
+    Offset {
+        offset: offset + read as u64,
+        timestamp,
+        ..Default::default()
+    }
+    .to_string()
+}
+
 /// Per session Db metadata
 #[derive(Debug, Default)]
 struct SpannerDbSession {
     /// CURRENT_TIMESTAMP() from Spanner, used for timestamping this session's
-    /// operations
+    /// operations (replaced by the commit timestamp its mutations recorded,
+    /// once committed)
     timestamp: Option<SyncTimestamp>,
+    /// Whether the timestamp was predetermined (see `Db::set_timestamp`):
+    /// writes then record it rather than the commit timestamp
+    fixed_timestamp: bool,
     /// Cache of collection modified timestamps per (HawkIdentifier, collection_id)
     coll_modified_cache: HashMap<(HawkIdentifier, i32), SyncTimestamp>,
     /// Currently locked collections
@@ -81,6 +138,17 @@ struct SpannerDbSession {
     execute_sql_count: u64,
     /// Whether touch_collection has already been called
     touched_collection: bool,
+    /// This session's turn to write to its write-locked collection, held
+    /// until commit/rollback
+    write_turn: Option<WriteQueueGuard>,
+    /// Operations to replay when the read-write transaction's aborted
+    replay: Replay,
+    /// The timestamp of an aborted transaction's first attempt, which its
+    /// retries keep
+    pinned_timestamp: Option<SyncTimestamp>,
+    /// Collection ids read or created by the read-write transaction, cached
+    /// once it's committed
+    pending_collections: Vec<(i32, String)>,
 }
 
 #[derive(Clone, Debug)]
@@ -91,6 +159,27 @@ pub struct SpannerDb {
     coll_cache: Arc<CollectionCache>,
 
     pub metrics: Metrics,
+
+    /// Sortindex applied to newly created BSOs lacking one
+    pub(super) default_sortindex: Option<i32>,
+
+    /// Limit of custom collections per user
+    max_collections_per_user: Option<u32>,
+
+    /// Pool level rate limiter for logging query plans
+    query_plans: Arc<QueryPlanSampler>,
+
+    /// Pool level health statistics
+    health: Arc<PoolHealth<SpannerConnectionManager>>,
+
+    /// Codec applied to payloads at rest
+    pub(super) codec: Arc<dyn PayloadCodec>,
+
+    /// Pool level queue serializing writes to the same collection
+    write_queue: Arc<WriteQueue>,
+
+    /// Staleness of non-strong read-only transactions (strong when `None`)
+    read_staleness: Option<Duration>,
 }
 
 pub struct SpannerDbInner {
@@ -99,6 +188,31 @@ pub struct SpannerDbInner {
     session: RefCell<SpannerDbSession>,
 }
 
+impl Drop for SpannerDbInner {
+    /// Roll back a read-write transaction left open (e.g. by a request
+    /// aborted at the end of a shutdown's drain), releasing its locks rather
+    /// than leaving them to Spanner's idle transaction timeout. The rollback
+    /// isn't awaited.
+    fn drop(&mut self) {
+        let session = self.session.borrow();
+        if !session.in_write_transaction {
+            return;
+        }
+        if let Some(transaction) = session.transaction.as_ref().filter(|t| t.has_id()) {
+            let mut req = RollbackRequest::new();
+            req.set_session(self.conn.session.get_name().to_owned());
+            req.set_transaction_id(transaction.get_id().to_vec());
+            let result = self
+                .conn
+                .call_option()
+                .and_then(|opt| self.conn.client.rollback_async_opt(&req, opt));
+            if let Err(e) = result {
+                warn!("Rolling back an abandoned transaction failed: {}", e);
+            }
+        }
+    }
+}
+
 impl fmt::Debug for SpannerDbInner {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "SpannerDbInner")
@@ -114,7 +228,19 @@ impl Deref for SpannerDb {
 }
 
 impl SpannerDb {
-    pub fn new(conn: Conn, coll_cache: Arc<CollectionCache>, metrics: &Metrics) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        default_sortindex: Option<i32>,
+        max_collections_per_user: Option<u32>,
+        query_plans: Arc<QueryPlanSampler>,
+        health: Arc<PoolHealth<SpannerConnectionManager>>,
+        codec: Arc<dyn PayloadCodec>,
+        write_queue: Arc<WriteQueue>,
+        read_staleness: Option<Duration>,
+    ) -> Self {
         let inner = SpannerDbInner {
             conn,
             session: RefCell::new(Default::default()),
@@ -123,6 +249,13 @@ impl SpannerDb {
             inner: Arc::new(inner),
             coll_cache,
             metrics: metrics.clone(),
+            default_sortindex,
+            max_collections_per_user,
+            query_plans,
+            health,
+            codec,
+            write_queue,
+            read_staleness,
         }
     }
 
@@ -135,7 +268,8 @@ impl SpannerDb {
                 "SELECT collection_id
                    FROM collections
                   WHERE name = @name",
-            )?
+            )
+            .await?
             .params(params! {"name" => name.to_string()})
             .execute_async(&self.conn)?
             .one_or_none()
@@ -145,9 +279,7 @@ impl SpannerDb {
             .get_string_value()
             .parse::<i32>()
             .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
-        if !self.in_write_transaction() {
-            self.coll_cache.put(id, name.to_owned())?;
-        }
+        self.cache_collection_id(id, name)?;
         Ok(id)
     }
 
@@ -155,34 +287,130 @@ impl SpannerDb {
         // This should always run within a r/w transaction, so that: "If a
         // transaction successfully commits, then no other writer modified the
         // data that was read in the transaction after it was read."
+        // (Concurrent creations are then aborted and retried, see `retrying`)
         if !cfg!(test) && !self.in_write_transaction() {
             Err(DbError::internal("Can't escalate read-lock to write-lock"))?
         }
-        let result = self
-            .sql(
-                "SELECT COALESCE(MAX(collection_id), 1)
-                   FROM collections",
-            )?
-            .execute_async(&self.conn)?
-            .one()
-            .await?;
-        let max = result[0]
-            .get_string_value()
-            .parse::<i32>()
-            .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
-        let id = FIRST_CUSTOM_COLLECTION_ID.max(max + 1);
+        // Standard collections (prepopulating the cache) are only created at
+        // their fixed ids (see `create_standard_collections_async`): never
+        // allocate them a custom one, even before they're created
+        if let Some(id) = self.coll_cache.get_id(name)? {
+            if id < FIRST_CUSTOM_COLLECTION_ID {
+                Err(DbErrorKind::Integrity(format!(
+                    "Standard collection {} ({}) can't be created",
+                    name, id
+                )))?
+            }
+        }
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .sql(
+                    "SELECT COALESCE(MAX(collection_id), 1)
+                       FROM collections",
+                )
+                .await?
+                .execute_async(&self.conn)?
+                .one()
+                .await?;
+            let max = result[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+            let id = FIRST_CUSTOM_COLLECTION_ID.max(max + 1);
 
-        self.sql(
-            "INSERT INTO collections (collection_id, name)
-             VALUES (@collection_id, @name)",
-        )?
-        .params(params! {
-            "name" => name.to_string(),
-            "collection_id" => id.to_string(),
-        })
-        .execute_dml_async(&self.conn)
-        .await?;
-        Ok(id)
+            let result = self
+                .sql(
+                    "INSERT INTO collections (collection_id, name)
+                     VALUES (@collection_id, @name)",
+                )
+                .await?
+                .params(params! {
+                    "name" => name.to_string(),
+                    "collection_id" => id.to_string(),
+                })
+                .execute_dml_async(&self.conn)
+                .await;
+            match result {
+                Ok(_) => {
+                    self.cache_collection_id(id, name)?;
+                    return Ok(id);
+                }
+                // Created by a concurrent writer (the name), or its id taken
+                // by another collection
+                Err(e) if e.is_already_exists() && attempt < MAX_TRANSACTION_RETRIES => {
+                    self.metrics
+                        .clone()
+                        .incr("storage.spanner.collection.create.conflict");
+                    attempt += 1;
+                    match self.get_collection_id_async(name).await {
+                        Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => (),
+                        result => return result,
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Cache the collection's id: once the read-write transaction's committed
+    /// when within one (whose writes, including the collection, may yet be
+    /// rolled back)
+    fn cache_collection_id(&self, id: i32, name: &str) -> Result<()> {
+        if self.in_write_transaction() {
+            self.session
+                .borrow_mut()
+                .pending_collections
+                .push((id, name.to_owned()));
+            Ok(())
+        } else {
+            self.coll_cache.put(id, name.to_owned())
+        }
+    }
+
+    /// Create any missing rows for the standard collections, at their fixed
+    /// ids
+    pub(super) async fn create_standard_collections_async(
+        &self,
+        colls: &[(i32, String)],
+    ) -> Result<()> {
+        self.begin_async(true).await?;
+        for (id, name) in colls {
+            let stored = self
+                .sql(
+                    "SELECT name
+                       FROM collections
+                      WHERE collection_id = @collection_id",
+                )
+                .await?
+                .params(params! {
+                    "collection_id" => id.to_string(),
+                })
+                .execute_async(&self.conn)?
+                .one_or_none()
+                .await?;
+            match stored {
+                Some(row) if row[0].get_string_value() == name.as_str() => continue,
+                Some(_) => Err(DbErrorKind::Integrity(format!(
+                    "Standard collection {} ({}) conflicts with an existing collection",
+                    name, id
+                )))?,
+                None => {
+                    self.sql(
+                        "INSERT INTO collections (collection_id, name)
+                         VALUES (@collection_id, @name)",
+                    )
+                    .await?
+                    .params(params! {
+                        "name" => name.to_owned(),
+                        "collection_id" => id.to_string(),
+                    })
+                    .execute_dml_async(&self.conn)
+                    .await?;
+                }
+            }
+        }
+        self.commit_async().await
     }
 
     async fn get_or_create_collection_id_async(&self, name: &str) -> Result<i32> {
@@ -197,9 +425,15 @@ impl SpannerDb {
         }
     }
 
-    pub async fn lock_for_read_async(&self, params: params::LockCollection) -> Result<()> {
+    pub async fn lock_for_read_async(&self, params: params::LockCollectionForRead) -> Result<()> {
         // Begin a transaction
-        self.begin_async(false).await?;
+        let staleness = self.read_staleness.filter(|_| !params.strong);
+        self.metrics.clone().incr(if staleness.is_some() {
+            "storage.spanner.read.stale"
+        } else {
+            "storage.spanner.read.strong"
+        });
+        self.begin_with_staleness_async(false, staleness).await?;
 
         let collection_id = self
             .get_collection_id_async(&params.collection)
@@ -232,8 +466,10 @@ impl SpannerDb {
     }
 
     pub async fn lock_for_write_async(&self, params: params::LockCollection) -> Result<()> {
-        // Begin a transaction
-        self.begin_async(true).await?;
+        // Begin a transaction (unless another collection's lock already has)
+        if !self.in_write_transaction() || self.session.borrow().transaction.is_none() {
+            self.begin_async(true).await?;
+        }
         let collection_id = self
             .get_or_create_collection_id_async(&params.collection)
             .await?;
@@ -247,6 +483,22 @@ impl SpannerDb {
             Err(DbError::internal("Can't escalate read-lock to write-lock"))?
         }
 
+        // Queue behind this instance's other writers to the collection
+        // rather than contending with their transactions
+        if self.session.borrow().write_turn.is_none() {
+            let mut metrics = self.metrics.clone();
+            metrics.start_timer("storage.spanner.write_queue.wait", None);
+            let turn = Arc::clone(&self.write_queue)
+                .enqueue((params.user_id.clone(), collection_id))
+                .await?;
+            if turn.is_none() {
+                self.metrics
+                    .clone()
+                    .incr("storage.spanner.write_queue.full");
+            }
+            self.session.borrow_mut().write_turn = turn;
+        }
+
         let result = self
             .sql(
                 "SELECT CURRENT_TIMESTAMP(), modified
@@ -255,7 +507,8 @@ impl SpannerDb {
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
                     AND modified > @pretouch_ts",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid.clone(),
                 "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -269,11 +522,16 @@ impl SpannerDb {
             .one_or_none()
             .await?;
 
+        let pinned = self.session.borrow().pinned_timestamp;
         let timestamp = if let Some(result) = result {
             let modified = SyncTimestamp::from_rfc3339(result[1].get_string_value())?;
-            let now = SyncTimestamp::from_rfc3339(result[0].get_string_value())?;
+            let now = match pinned {
+                Some(pinned) => pinned,
+                None => SyncTimestamp::from_rfc3339(result[0].get_string_value())?,
+            };
             // Forbid the write if it would not properly incr the modified
-            // timestamp
+            // timestamp (e.g. a retried transaction's collection was written
+            // since its first attempt)
             if modified >= now {
                 self.metrics.clone().incr("db.conflict");
                 Err(DbErrorKind::Conflict)?
@@ -283,9 +541,12 @@ impl SpannerDb {
                 .coll_modified_cache
                 .insert((params.user_id.clone(), collection_id), modified);
             now
+        } else if let Some(pinned) = pinned {
+            pinned
         } else {
             let result = self
-                .sql("SELECT CURRENT_TIMESTAMP()")?
+                .sql("SELECT CURRENT_TIMESTAMP()")
+                .await?
                 .execute_async(&self.conn)?
                 .one()
                 .await?;
@@ -305,39 +566,49 @@ impl SpannerDb {
         self.session.borrow_mut().timestamp = Some(timestamp);
     }
 
-    pub(super) fn begin(&self, for_write: bool) -> Result<()> {
-        let spanner = &self.conn;
-        let mut options = TransactionOptions::new();
-        if for_write {
-            options.set_read_write(TransactionOptions_ReadWrite::new());
-            self.session.borrow_mut().in_write_transaction = true;
-        } else {
-            options.set_read_only(TransactionOptions_ReadOnly::new());
-        }
-        let mut req = BeginTransactionRequest::new();
-        req.set_session(spanner.session.get_name().to_owned());
-        req.set_options(options);
-        let mut transaction = spanner.client.begin_transaction(&req)?;
-
-        let mut ts = TransactionSelector::new();
-        ts.set_id(transaction.take_id());
-        self.session.borrow_mut().transaction = Some(ts);
-        Ok(())
+    pub(super) async fn begin_async(&self, for_write: bool) -> Result<()> {
+        self.begin_with_staleness_async(for_write, None).await
     }
 
-    pub(super) async fn begin_async(&self, for_write: bool) -> Result<()> {
+    /// Begin a transaction: read-only ones read a snapshot `staleness` old
+    /// (otherwise strongly).
+    ///
+    /// The session's timestamp is the snapshot's, so a stale read's
+    /// timestamp is equally stale.
+    async fn begin_with_staleness_async(
+        &self,
+        for_write: bool,
+        staleness: Option<Duration>,
+    ) -> Result<()> {
         let spanner = &self.conn;
         let mut options = TransactionOptions::new();
         if for_write {
             options.set_read_write(TransactionOptions_ReadWrite::new());
             self.session.borrow_mut().in_write_transaction = true;
         } else {
-            options.set_read_only(TransactionOptions_ReadOnly::new());
+            let mut read_only = TransactionOptions_ReadOnly::new();
+            read_only.set_return_read_timestamp(true);
+            match staleness {
+                Some(staleness) => {
+                    let mut exact_staleness = protobuf::well_known_types::Duration::new();
+                    exact_staleness.set_seconds(staleness.as_secs() as i64);
+                    exact_staleness.set_nanos(staleness.subsec_nanos() as i32);
+                    read_only.set_exact_staleness(exact_staleness);
+                }
+                None => read_only.set_strong(true),
+            }
+            options.set_read_only(read_only);
         }
+        self.session.borrow_mut().replay.0.clear();
         let mut req = BeginTransactionRequest::new();
         req.set_session(spanner.session.get_name().to_owned());
         req.set_options(options);
-        let mut transaction = spanner.client.begin_transaction_async(&req)?.await?;
+        let mut transaction = spanner
+            .call("BeginTransaction", |client, opt| {
+                client.begin_transaction_async_opt(&req, opt)
+            })
+            .await?;
+        self.set_read_timestamp(&transaction)?;
 
         let mut ts = TransactionSelector::new();
         ts.set_id(transaction.take_id());
@@ -345,14 +616,14 @@ impl SpannerDb {
         Ok(())
     }
 
-    /// Return the current transaction metadata (TransactionSelector) if one is active.
-    fn get_transaction(&self) -> Result<Option<TransactionSelector>> {
-        Ok(if self.session.borrow().transaction.is_some() {
-            self.session.borrow().transaction.clone()
-        } else {
-            self.begin(true)?;
-            self.session.borrow().transaction.clone()
-        })
+    /// Fix the session's timestamp to a read-only transaction's read
+    /// timestamp (when one wasn't already set)
+    fn set_read_timestamp(&self, transaction: &Transaction) -> Result<()> {
+        if !transaction.has_read_timestamp() || self.session.borrow().timestamp.is_some() {
+            return Ok(());
+        }
+        self.set_timestamp(sync_timestamp(transaction.get_read_timestamp())?);
+        Ok(())
     }
 
     /// Return the current transaction metadata (TransactionSelector) if one is active.
@@ -365,10 +636,14 @@ impl SpannerDb {
         })
     }
 
-    fn sql_request(&self, sql: &str) -> Result<ExecuteSqlRequest> {
+    async fn sql_request(&self, sql: &str) -> Result<ExecuteSqlRequest> {
         let mut sqlr = ExecuteSqlRequest::new();
         sqlr.set_sql(sql.to_owned());
-        if let Some(transaction) = self.get_transaction()? {
+        if self.query_plans.sample() {
+            // Have Spanner return the query plan and execution stats
+            sqlr.set_query_mode(ExecuteSqlRequest_QueryMode::PROFILE);
+        }
+        if let Some(transaction) = self.get_transaction_async().await? {
             sqlr.set_transaction(transaction);
             let mut session = self.session.borrow_mut();
             sqlr.seqno = session
@@ -380,8 +655,8 @@ impl SpannerDb {
         Ok(sqlr)
     }
 
-    pub(super) fn sql(&self, sql: &str) -> Result<ExecuteSqlRequestBuilder> {
-        Ok(ExecuteSqlRequestBuilder::new(self.sql_request(sql)?))
+    pub(super) async fn sql(&self, sql: &str) -> Result<ExecuteSqlRequestBuilder> {
+        Ok(ExecuteSqlRequestBuilder::new(self.sql_request(sql).await?))
     }
 
     pub(super) fn insert(&self, table: &str, columns: &[&str], values: Vec<ListValue>) {
@@ -404,7 +679,6 @@ impl SpannerDb {
             .push(mutation);
     }
 
-    #[allow(unused)]
     pub(super) fn insert_or_update(&self, table: &str, columns: &[&str], values: Vec<ListValue>) {
         let mut mutation = Mutation::new();
         mutation.set_insert_or_update(self.mutation_write(table, columns, values));
@@ -434,76 +708,407 @@ impl SpannerDb {
         self.session.borrow().in_write_transaction
     }
 
-    pub fn commit(&self) -> Result<()> {
+    /// Whether the connection's in the db tests' test transactions (never
+    /// committed): writes then use their DML versions (`*_test`), as
+    /// mutations only apply once committed. Otherwise tests run the
+    /// production (mutation) writes.
+    pub(super) fn uses_test_transactions(&self) -> bool {
+        cfg!(test) && self.conn.use_test_transactions
+    }
+
+    /// Commit the read-write transaction
+    ///
+    /// Its mutations' modified values record the commit's timestamp, which
+    /// then becomes the session's: commit timestamps always follow the order
+    /// of the commits, and exceed the CURRENT_TIMESTAMP() read when the
+    /// collection was locked (so still the collection's prior modified
+    /// value, see `lock_for_write_async`).
+    pub async fn commit_async(&self) -> Result<()> {
         if !self.in_write_transaction() {
             // read-only
             return Ok(());
         }
 
-        let spanner = &self.conn;
-
-        if cfg!(test) && spanner.use_test_transactions {
+        if self.uses_test_transactions() {
             // don't commit test transactions
             return Ok(());
         }
 
-        if let Some(transaction) = self.get_transaction()? {
+        let spanner = &self.conn;
+        let _timer = self.metrics.timer("storage.spanner.commit");
+        let mut attempt = 0;
+        let result = loop {
+            let transaction = match self.get_transaction_async().await? {
+                Some(transaction) => transaction,
+                None => Err(DbError::internal("No transaction to commit"))?,
+            };
             let mut req = CommitRequest::new();
             req.set_session(spanner.session.get_name().to_owned());
             req.set_transaction_id(transaction.get_id().to_vec());
-            if let Some(mutations) = self.session.borrow_mut().mutations.take() {
+            let mut stamped = false;
+            let mut session = self.session.borrow_mut();
+            if let Some(mut mutations) = session.mutations.take() {
+                stamped = !session.fixed_timestamp && stamp_commit_timestamp(&mut mutations);
                 req.set_mutations(RepeatedField::from_vec(mutations));
             }
-            spanner.client.commit(&req)?;
-            Ok(())
-        } else {
-            Err(DbError::internal("No transaction to commit"))?
+            drop(session);
+            let result = spanner
+                .call("Commit", |client, opt| client.commit_async_opt(&req, opt))
+                .await;
+            let e: DbError = match result {
+                Ok(response) => break Ok((stamped, response)),
+                Err(e) => e.into(),
+            };
+            if !e.is_aborted() {
+                // (Not retried: the commit may have been applied)
+                self.record_transient(&e);
+                break Err(e);
+            }
+            self.metrics.clone().incr("storage.spanner.commit.aborted");
+            if let Err(e) = self.retry_transaction(&mut attempt, e).await {
+                break Err(e);
+            }
+        };
+        let pending = mem::take(&mut self.session.borrow_mut().pending_collections);
+        self.end_transaction();
+        let (stamped, response) = result?;
+        if stamped {
+            self.set_timestamp(sync_timestamp(response.get_commit_timestamp())?);
         }
+        for (id, name) in pending {
+            self.coll_cache.put(id, name)?;
+        }
+        self.health.record_success();
+        Ok(())
     }
 
-    pub async fn commit_async(&self) -> Result<()> {
-        if !self.in_write_transaction() {
-            // read-only
+    /// Commit the read-write transaction's pending mutations ahead of its
+    /// final commit, continuing in a new transaction with the same timestamp
+    /// (and write queue turn).
+    ///
+    /// For writes exceeding a single commit's mutation limit: the flushed
+    /// mutations aren't undone by a later rollback. Their user_collections
+    /// writes are repeated by the final commit, leaving the collection's
+    /// modified value its (latest) commit timestamp.
+    pub(super) async fn flush_mutations_async(&self) -> Result<()> {
+        if self.uses_test_transactions() {
+            // Never committed (so never reaching the mutation limit)
             return Ok(());
         }
-
         let spanner = &self.conn;
+        let _timer = self.metrics.timer("storage.spanner.commit");
+        let transaction = match self.get_transaction_async().await? {
+            Some(transaction) => transaction,
+            None => Err(DbError::internal("No transaction to flush"))?,
+        };
+        let mut req = CommitRequest::new();
+        req.set_session(spanner.session.get_name().to_owned());
+        req.set_transaction_id(transaction.get_id().to_vec());
+        let mut touches = vec![];
+        let mut session = self.session.borrow_mut();
+        if let Some(mut mutations) = session.mutations.take() {
+            if !session.fixed_timestamp {
+                stamp_commit_timestamp(&mut mutations);
+            }
+            touches = mutations
+                .iter()
+                .filter(|mutation| {
+                    mutation.get_insert_or_update().get_table() == "user_collections"
+                })
+                .cloned()
+                .collect();
+            req.set_mutations(RepeatedField::from_vec(mutations));
+        }
+        drop(session);
+        spanner
+            .call("Commit", |client, opt| client.commit_async_opt(&req, opt))
+            .await?;
+        self.metrics.clone().incr("storage.spanner.commit.flushed");
 
-        if cfg!(test) && spanner.use_test_transactions {
-            // don't commit test transactions
-            return Ok(());
+        {
+            let mut session = self.session.borrow_mut();
+            session.transaction = None;
+            // Retries of the remainder keep the flushed writes' timestamp
+            session.pinned_timestamp = session.timestamp;
+            if !touches.is_empty() {
+                session.mutations = Some(touches);
+            }
         }
+        // (Also clears the replay: the flushed operations mustn't rerun)
+        self.begin_async(true).await
+    }
+
+    /// Write the BSOs: across several commits when they exceed a single
+    /// commit's mutation limit, leaving the final chunk to the transaction's
+    /// own commit
+    pub(super) async fn write_bsos_async(&self, writes: Vec<BsoWrite>) -> Result<()> {
+        let mut chunks = chunk_by_mutations(
+            writes,
+            BsoWrite::mutations,
+            MAX_MUTATIONS_PER_COMMIT - RESERVED_MUTATIONS,
+        )?;
+        let last = chunks.pop().unwrap_or_default();
+        for chunk in chunks {
+            self.apply_bso_writes(chunk);
+            self.flush_mutations_async().await?;
+        }
+        self.apply_bso_writes(last);
+        Ok(())
+    }
 
-        if let Some(transaction) = self.get_transaction_async().await? {
-            let mut req = CommitRequest::new();
-            req.set_session(spanner.session.get_name().to_owned());
-            req.set_transaction_id(transaction.get_id().to_vec());
-            if let Some(mutations) = self.session.borrow_mut().mutations.take() {
-                req.set_mutations(RepeatedField::from_vec(mutations));
+    /// Buffer the writes' mutations (inserts in one, updates in one per set
+    /// of updated columns)
+    fn apply_bso_writes(&self, writes: Vec<BsoWrite>) {
+        let mut inserts = vec![];
+        let mut updates: HashMap<Vec<&'static str>, Vec<ListValue>> = HashMap::new();
+        for write in writes {
+            match write {
+                BsoWrite::Insert(row) => inserts.push(row),
+                BsoWrite::Update(columns, row) => updates.entry(columns).or_default().push(row),
             }
-            spanner.client.commit_async(&req)?.await?;
-            Ok(())
-        } else {
-            Err(DbError::internal("No transaction to commit"))?
+        }
+        if !inserts.is_empty() {
+            debug!("inserts: {:?}", &inserts);
+            self.insert("bsos", &BSO_COLUMNS, inserts);
+        }
+        for (columns, values) in updates {
+            debug!("columns: {:?}, values:{:?}", &columns, &values);
+            self.update("bsos", &columns, values);
         }
     }
 
-    pub fn rollback(&self) -> Result<()> {
-        if !self.in_write_transaction() {
-            // read-only
+    /// Release the finished transaction's write queue turn (letting the
+    /// collection's next queued writer in) and retry state
+    fn end_transaction(&self) {
+        let mut session = self.session.borrow_mut();
+        // (Later operations begin a new transaction, so a repeated
+        // commit/rollback is a no-op)
+        session.transaction = None;
+        session.in_write_transaction = false;
+        session.fixed_timestamp = false;
+        session.write_turn.take();
+        session.replay.0.clear();
+        session.pinned_timestamp = None;
+        session.pending_collections.clear();
+    }
+
+    /// Run a `Db` operation, recording it for replay when it's performed
+    /// within a read-write transaction.
+    ///
+    /// When Spanner aborts the transaction (as it does to one of two
+    /// contending transactions), the transaction's recorded operations are
+    /// replayed in a new one before the operation's retried (see
+    /// `retry_transaction`). So are they when Spanner's temporarily
+    /// unavailable, while operations outside of a read-write transaction are
+    /// simply retried (see `backoff`).
+    ///
+    /// A replayed operation's result was already returned (and acted upon,
+    /// e.g. sent to the client): a replay producing a different one (as the
+    /// data changed in between) fails with a `Conflict` rather than carrying
+    /// on from the stale result.
+    ///
+    /// The params are held once (behind an `Rc`) for all of the operation's
+    /// attempts and replays.
+    async fn retrying<P, T, F, Fut>(&self, param: P, op: F) -> Result<T>
+    where
+        P: 'static,
+        T: Clone + PartialEq + 'static,
+        F: Fn(SpannerDb, Rc<P>) -> Fut + 'static,
+        Fut: Future<Output = Result<T>> + 'static,
+    {
+        let param = Rc::new(param);
+        let mut attempt = 0;
+        loop {
+            match op(self.clone(), Rc::clone(&param)).await {
+                Err(e) if e.is_aborted() && self.in_write_transaction() => {
+                    self.retry_transaction(&mut attempt, e).await?
+                }
+                Err(e) if e.is_retryable() => {
+                    self.record_transient(&e);
+                    if self.in_write_transaction() {
+                        self.retry_transaction(&mut attempt, e).await?
+                    } else {
+                        self.backoff(&mut attempt, e).await?
+                    }
+                }
+                Err(e) => {
+                    self.record_transient(&e);
+                    return Err(e);
+                }
+                Ok(result) => {
+                    if self.in_write_transaction() {
+                        let expected = Rc::new(result.clone());
+                        self.session.borrow_mut().replay.0.push(Rc::new(move |db| {
+                            let expected = Rc::clone(&expected);
+                            op(db, Rc::clone(&param))
+                                .map(move |replayed| match replayed {
+                                    Ok(replayed) if replayed == *expected => Ok(()),
+                                    Ok(_) => Err(DbErrorKind::Conflict.into()),
+                                    Err(e) => Err(e),
+                                })
+                                .boxed_local()
+                        }));
+                    }
+                    return Ok(result);
+                }
+            }
+        }
+    }
+
+    /// Replay the aborted (or unavailable) read-write transaction's
+    /// operations in a new transaction, failing with the `error` after
+    /// `MAX_TRANSACTION_RETRIES` attempts.
+    ///
+    /// Attempts back off exponentially: grpcio doesn't expose the trailing
+    /// metadata carrying Spanner's suggested retry delay (`RetryInfo`).
+    async fn retry_transaction(&self, attempt: &mut u32, mut error: DbError) -> Result<()> {
+        let ops = mem::take(&mut self.session.borrow_mut().replay.0);
+        loop {
+            if *attempt >= MAX_TRANSACTION_RETRIES {
+                self.metrics
+                    .clone()
+                    .incr("storage.spanner.transaction.retry.exhausted");
+                return Err(error);
+            }
+            *attempt += 1;
+            self.metrics
+                .clone()
+                .incr("storage.spanner.transaction.retry");
+            delay_for(TRANSACTION_RETRY_DELAY * 2u32.pow(*attempt - 1)).await;
+
+            match self.replay_transaction(&ops).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_aborted() || e.is_retryable() => {
+                    self.record_transient(&e);
+                    error = e
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Back off (exponentially) from a retryable `error` ahead of retrying
+    /// its operation, failing with the `error` after
+    /// `MAX_TRANSACTION_RETRIES` attempts
+    async fn backoff(&self, attempt: &mut u32, error: DbError) -> Result<()> {
+        if *attempt >= MAX_TRANSACTION_RETRIES {
+            self.metrics.clone().incr("storage.spanner.retry.exhausted");
+            return Err(error);
+        }
+        *attempt += 1;
+        self.metrics.clone().incr("storage.spanner.retry");
+        delay_for(TRANSACTION_RETRY_DELAY * 2u32.pow(*attempt - 1)).await;
+        Ok(())
+    }
+
+    /// Count a transient Spanner error (UNAVAILABLE, RESOURCE_EXHAUSTED or
+    /// DEADLINE_EXCEEDED) by its status
+    fn record_transient(&self, error: &DbError) {
+        if let Some(status) = error.transient_status() {
+            self.metrics
+                .clone()
+                .incr(&format!("storage.spanner.error.{}", status));
+        }
+    }
+
+    /// Begin a new read-write transaction in place of the session's
+    /// invalidated one (see `delete_bsos_partitioned_async`), replaying its
+    /// operations
+    async fn restart_transaction(&self) -> Result<()> {
+        let ops = mem::take(&mut self.session.borrow_mut().replay.0);
+        self.replay_transaction(&ops).await
+    }
+
+    /// Replay `ops` in a new read-write transaction (with the same
+    /// timestamp)
+    async fn replay_transaction(&self, ops: &[ReplayOp]) -> Result<()> {
+        {
+            let mut session = self.session.borrow_mut();
+            if session.pinned_timestamp.is_none() {
+                session.pinned_timestamp = session.timestamp;
+            }
+            session.transaction = None;
+            session.mutations = None;
+            session.coll_locks.clear();
+            session.coll_modified_cache.clear();
+            session.touched_collection = false;
+            session.replay.0.clear();
+            session.pending_collections.clear();
+        }
+        for op in ops {
+            op(self.clone()).await?;
+            // Recorded as they succeed: an op may itself restart the
+            // transaction
+            self.session.borrow_mut().replay.0.push(Rc::clone(op));
+        }
+        Ok(())
+    }
+
+    /// Delete the user's BSOs (of one collection, or all of them) with
+    /// Partitioned DML, ahead of the transactional delete of their (now
+    /// small) user_collections rows.
+    ///
+    /// Partitioned DML runs outside of the read-write transaction (in as many
+    /// transactions as Spanner sees fit), so heavy users' deletes aren't
+    /// bound by a commit's mutation limit nor lock their data throughout.
+    /// It's idempotent: retrying after a partial delete deletes the
+    /// remainder.
+    ///
+    /// Beginning it invalidates the session's read-write transaction, which
+    /// is restarted afterwards.
+    async fn delete_bsos_partitioned_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: Option<i32>,
+    ) -> Result<()> {
+        if cfg!(test) && self.conn.use_test_transactions {
+            // Nothing's visible outside of (never committed) test
+            // transactions: their cascading deletes suffice
             return Ok(());
         }
+        let mut sql = "DELETE FROM bsos
+                        WHERE fxa_uid = @fxa_uid
+                          AND fxa_kid = @fxa_kid"
+            .to_owned();
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+        };
+        if let Some(collection_id) = collection_id {
+            sql.push_str(" AND collection_id = @collection_id");
+            sqlparams.insert(
+                "collection_id".to_owned(),
+                as_value(collection_id.to_string()),
+            );
+        }
 
-        if let Some(transaction) = self.get_transaction()? {
-            let spanner = &self.conn;
-            let mut req = RollbackRequest::new();
-            req.set_session(spanner.session.get_name().to_owned());
-            req.set_transaction_id(transaction.get_id().to_vec());
-            spanner.client.rollback(&req)?;
-            Ok(())
-        } else {
-            Err(DbError::internal("No transaction to rollback"))?
+        let spanner = &self.conn;
+        let mut options = TransactionOptions::new();
+        options.set_partitioned_dml(TransactionOptions_PartitionedDml::new());
+        let mut req = BeginTransactionRequest::new();
+        req.set_session(spanner.session.get_name().to_owned());
+        req.set_options(options);
+        let mut transaction = spanner
+            .call("BeginTransaction", |client, opt| {
+                client.begin_transaction_async_opt(&req, opt)
+            })
+            .await?;
+        let mut ts = TransactionSelector::new();
+        ts.set_id(transaction.take_id());
+
+        let mut sqlr = ExecuteSqlRequest::new();
+        sqlr.set_sql(sql);
+        sqlr.set_transaction(ts);
+        {
+            let mut timer = self.metrics.clone();
+            timer.start_timer("storage.spanner.delete_bsos_partitioned", None);
+            ExecuteSqlRequestBuilder::new(sqlr)
+                .params(sqlparams)
+                .execute_dml_async(spanner)
+                .await?;
         }
+
+        self.restart_transaction().await
     }
 
     pub async fn rollback_async(&self) -> Result<()> {
@@ -517,7 +1122,13 @@ impl SpannerDb {
             let mut req = RollbackRequest::new();
             req.set_session(spanner.session.get_name().to_owned());
             req.set_transaction_id(transaction.get_id().to_vec());
-            spanner.client.rollback_async(&req)?.await?;
+            let result = spanner
+                .call("Rollback", |client, opt| {
+                    client.rollback_async_opt(&req, opt)
+                })
+                .await;
+            self.end_transaction();
+            result?;
             Ok(())
         } else {
             Err(DbError::internal("No transaction to rollback"))?
@@ -548,7 +1159,8 @@ impl SpannerDb {
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
                     AND modified > @pretouch_ts",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid,
                 "fxa_kid" => params.user_id.fxa_kid,
@@ -578,7 +1190,8 @@ impl SpannerDb {
                     AND fxa_kid = @fxa_kid
                     AND collection_id != @collection_id
                     AND modified > @pretouch_ts",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid,
@@ -602,14 +1215,57 @@ impl SpannerDb {
         self.map_collection_names(results).await
     }
 
-    async fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> Result<HashMap<String, T>> {
-        let mut names = self.load_collection_names(by_id.keys()).await?;
-        by_id
-            .into_iter()
-            .map(|(id, value)| {
-                names
-                    .remove(&id)
-                    .map(|name| (name, value))
+    pub async fn get_collection_names_async(
+        &self,
+        user_id: params::GetCollectionNames,
+    ) -> Result<results::GetCollectionNames> {
+        let mut streaming = self
+            .sql(
+                "SELECT collection_id
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id != @collection_id
+                    AND modified > @pretouch_ts",
+            )
+            .await?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid,
+                "fxa_kid" => user_id.fxa_kid,
+                "collection_id" => TOMBSTONE.to_string(),
+                "pretouch_ts" => PRETOUCH_TS.to_owned(),
+            })
+            .param_types(param_types! {
+                "pretouch_ts" => TypeCode::TIMESTAMP,
+            })
+            .execute_async(&self.conn)?;
+        let mut collection_ids = HashMap::new();
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            let collection_id = row[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+            collection_ids.insert(collection_id, ());
+        }
+        let mut names: Vec<_> = self
+            .map_collection_names(collection_ids)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> Result<HashMap<String, T>> {
+        let mut names = self.load_collection_names(by_id.keys()).await?;
+        by_id
+            .into_iter()
+            .map(|(id, value)| {
+                names
+                    .remove(&id)
+                    .map(|name| (name, value))
                     .ok_or_else(|| DbError::internal("load_collection_names get"))
             })
             .collect()
@@ -640,7 +1296,8 @@ impl SpannerDb {
                     "SELECT collection_id, name
                        FROM collections
                       WHERE collection_id IN UNNEST(@ids)",
-                )?
+                )
+                .await?
                 .params(params)
                 .execute_async(&self.conn)?;
             while let Some(row) = rs.next_async().await {
@@ -662,21 +1319,47 @@ impl SpannerDb {
 
     pub async fn get_collection_counts_async(
         &self,
-        user_id: params::GetCollectionCounts,
+        params: params::GetCollectionCounts,
     ) -> Result<results::GetCollectionCounts> {
-        let mut streaming = self
-            .sql(
+        let mut sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+        };
+        let mut sqltypes = HashMap::new();
+        let query = match params.newer {
+            // Only the collections modified since
+            Some(newer) => {
+                sqlparams.insert(
+                    "newer".to_owned(),
+                    as_value(to_rfc3339(newer.storage_next())?),
+                );
+                sqltypes.insert("newer".to_owned(), as_type(TypeCode::TIMESTAMP));
+                "SELECT bsos.collection_id, COUNT(bsos.collection_id)
+                   FROM bsos
+                   JOIN user_collections
+                     ON user_collections.fxa_uid = bsos.fxa_uid
+                    AND user_collections.fxa_kid = bsos.fxa_kid
+                    AND user_collections.collection_id = bsos.collection_id
+                  WHERE bsos.fxa_uid = @fxa_uid
+                    AND bsos.fxa_kid = @fxa_kid
+                    AND bsos.expiry > CURRENT_TIMESTAMP()
+                    AND user_collections.modified >= @newer
+                  GROUP BY bsos.collection_id"
+            }
+            None => {
                 "SELECT collection_id, COUNT(collection_id)
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND expiry > CURRENT_TIMESTAMP()
-                  GROUP BY collection_id",
-            )?
-            .params(params! {
-                "fxa_uid" => user_id.fxa_uid,
-                "fxa_kid" => user_id.fxa_kid,
-            })
+                  GROUP BY collection_id"
+            }
+        };
+        let mut streaming = self
+            .sql(query)
+            .await?
+            .params(sqlparams)
+            .param_types(sqltypes)
             .execute_async(&self.conn)?;
         let mut counts = HashMap::new();
         while let Some(row) = streaming.next_async().await {
@@ -694,19 +1377,34 @@ impl SpannerDb {
         self.map_collection_names(counts).await
     }
 
+    /// A stored payload's size for the codec: of offloaded payloads that
+    /// recorded in their references (`blob:<key>:<size>`). Without offloading
+    /// client payloads resembling a reference are sized as is
+    fn stored_size(&self) -> &'static str {
+        if self.codec.offloads() {
+            r"IF(REGEXP_CONTAINS(payload, r'^blob:[0-9a-f]{64}:[0-9]+$'),
+                 CAST(SPLIT(payload, ':')[OFFSET(2)] AS INT64),
+                 BYTE_LENGTH(payload))"
+        } else {
+            "BYTE_LENGTH(payload)"
+        }
+    }
+
     pub async fn get_collection_usage_async(
         &self,
         user_id: params::GetCollectionUsage,
     ) -> Result<results::GetCollectionUsage> {
         let mut streaming = self
-            .sql(
-                "SELECT collection_id, SUM(LENGTH(payload))
+            .sql(&format!(
+                "SELECT collection_id, SUM({})
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND expiry > CURRENT_TIMESTAMP()
                   GROUP BY collection_id",
-            )?
+                self.stored_size()
+            ))
+            .await?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid
@@ -739,7 +1437,8 @@ impl SpannerDb {
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND modified > @pretouch_ts",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid,
@@ -763,14 +1462,16 @@ impl SpannerDb {
         user_id: params::GetStorageUsage,
     ) -> Result<results::GetStorageUsage> {
         let result = self
-            .sql(
-                "SELECT SUM(LENGTH(payload))
+            .sql(&format!(
+                "SELECT SUM({})
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND expiry > CURRENT_TIMESTAMP()
                   GROUP BY fxa_uid",
-            )?
+                self.stored_size()
+            ))
+            .await?
             .params(params! {
                 "fxa_uid" => user_id.fxa_uid,
                 "fxa_kid" => user_id.fxa_kid
@@ -790,6 +1491,29 @@ impl SpannerDb {
     }
 
     async fn erect_tombstone(&self, user_id: &HawkIdentifier) -> Result<SyncTimestamp> {
+        if self.uses_test_transactions() {
+            return self.erect_tombstone_test(user_id).await;
+        }
+        // Recording the commit's timestamp, like the other writes
+        let mut row = ListValue::new();
+        row.set_values(RepeatedField::from_vec(vec![
+            as_value(user_id.fxa_uid.clone()),
+            as_value(user_id.fxa_kid.clone()),
+            as_value(TOMBSTONE.to_string()),
+            as_value(self.timestamp()?.as_rfc3339()?),
+        ]));
+        self.insert_or_update(
+            "user_collections",
+            &["fxa_uid", "fxa_kid", "collection_id", "modified"],
+            vec![row],
+        );
+        Ok(self.timestamp()?)
+    }
+
+    // NOTE: the DML version, used within the db tests' test transactions
+    // (never committed, so never applying mutations)
+    #[cfg(test)]
+    async fn erect_tombstone_test(&self, user_id: &HawkIdentifier) -> Result<SyncTimestamp> {
         // Delete the old tombstone (if it exists)
         let params = params! {
             "fxa_uid" => user_id.fxa_uid.clone(),
@@ -806,7 +1530,8 @@ impl SpannerDb {
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
                 AND collection_id = @collection_id",
-        )?
+        )
+        .await?
         .params(params.clone())
         .param_types(types.clone())
         .execute_dml_async(&self.conn)
@@ -815,7 +1540,8 @@ impl SpannerDb {
         self.sql(
             "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
              VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)",
-        )?
+        )
+        .await?
         .params(params)
         .param_types(types)
         .execute_dml_async(&self.conn)
@@ -826,13 +1552,15 @@ impl SpannerDb {
     }
 
     pub async fn delete_storage_async(&self, user_id: params::DeleteStorage) -> Result<()> {
-        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
-        // user_collections ON DELETE CASCADE)
+        self.delete_bsos_partitioned_async(&user_id, None).await?;
+        // Also deletes child batch rows (and any bsos written since)
+        // (INTERLEAVE IN PARENT user_collections ON DELETE CASCADE)
         self.sql(
             "DELETE FROM user_collections
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid",
-        )?
+        )
+        .await?
         .params(params! {
             "fxa_uid" => user_id.fxa_uid,
             "fxa_kid" => user_id.fxa_kid,
@@ -853,20 +1581,24 @@ impl SpannerDb {
         &self,
         params: params::DeleteCollection,
     ) -> Result<results::DeleteCollection> {
-        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
-        // user_collections ON DELETE CASCADE)
-        let affected_rows = self
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        self.delete_bsos_partitioned_async(&params.user_id, Some(collection_id))
+            .await?;
+        // Also deletes child batch rows (and any bsos written since)
+        // (INTERLEAVE IN PARENT user_collections ON DELETE CASCADE)
+        let mut affected_rows = self
             .sql(
                 "DELETE FROM user_collections
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
                     AND modified > @pretouch_ts",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid.clone(),
                 "fxa_kid" => params.user_id.fxa_kid.clone(),
-                "collection_id" => self.get_collection_id_async(&params.collection).await?.to_string(),
+                "collection_id" => collection_id.to_string(),
                 "pretouch_ts" => PRETOUCH_TS.to_owned(),
             })
             .param_types(param_types! {
@@ -874,6 +1606,23 @@ impl SpannerDb {
             })
             .execute_dml_async(&self.conn)
             .await?;
+        // A pretouched parent row (of a collection only written to by
+        // pending batches) isn't deleted above, so nor are its batches
+        affected_rows += self
+            .sql(
+                "DELETE FROM batches
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id",
+            )
+            .await?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid.clone(),
+                "fxa_kid" => params.user_id.fxa_kid.clone(),
+                "collection_id" => collection_id.to_string(),
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
         if affected_rows > 0 {
             self.erect_tombstone(&params.user_id).await
         } else {
@@ -886,21 +1635,21 @@ impl SpannerDb {
         user_id: &HawkIdentifier,
         collection_id: i32,
     ) -> Result<SyncTimestamp> {
-        // NOTE: Spanner supports upserts via its InsertOrUpdate mutation but
-        // lacks a SQL equivalent. This call could be 1 InsertOrUpdate instead
-        // of 2 queries but would require put/post_bsos to also use mutations.
-        // Due to case of when no parent row exists (in user_collections)
-        // before writing to bsos. Spanner requires a parent table row exist
-        // before child table rows are written.
-        // Mutations don't run in the same order as ExecuteSql calls, they are
-        // buffered on the client side and only issued to Spanner in the final
-        // transaction Commit.
-        let timestamp = self.timestamp()?;
-        if !cfg!(test) && self.session.borrow().touched_collection {
-            // No need to touch it again (except during tests where we
-            // currently reuse Dbs for multiple requests)
-            return Ok(timestamp);
+        if !self.uses_test_transactions() {
+            // Recording the commit's timestamp, like the other writes
+            return self
+                .touch_collection_mutation_async(user_id, collection_id)
+                .await;
         }
+        // NOTE: the DML version, used within the db tests' test transactions
+        // (never committed, so never applying mutations). Which reuse Dbs
+        // for multiple requests, so always touch it.
+        // Spanner supports upserts via its InsertOrUpdate mutation but lacks
+        // a SQL equivalent: 2 queries. The DML writes to bsos that follow
+        // require the parent row to exist already.
+        let timestamp = self.timestamp()?;
+        self.check_collection_limit_async(user_id, collection_id)
+            .await?;
 
         let sqlparams = params! {
             "fxa_uid" => user_id.fxa_uid.clone(),
@@ -918,7 +1667,8 @@ impl SpannerDb {
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id",
-            )?
+            )
+            .await?
             .params(sqlparams.clone())
             .execute_async(&self.conn)?
             .one_or_none()
@@ -932,7 +1682,8 @@ impl SpannerDb {
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id",
-            )?
+            )
+            .await?
             .params(sqlparams)
             .param_types(sql_types)
             .execute_dml_async(&self.conn)
@@ -941,7 +1692,8 @@ impl SpannerDb {
             self.sql(
                 "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
                  VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)",
-            )?
+            )
+            .await?
             .params(sqlparams)
             .param_types(sql_types)
             .execute_dml_async(&self.conn)
@@ -951,6 +1703,87 @@ impl SpannerDb {
         Ok(timestamp)
     }
 
+    /// `touch_collection_async` via an InsertOrUpdate mutation, for writes
+    /// made entirely of mutations (which apply in order at commit, parent
+    /// row first), recording the commit's timestamp
+    pub(super) async fn touch_collection_mutation_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+    ) -> Result<SyncTimestamp> {
+        let timestamp = self.timestamp()?;
+        if self.session.borrow().touched_collection {
+            return Ok(timestamp);
+        }
+        self.check_collection_limit_async(user_id, collection_id)
+            .await?;
+
+        let mut row = ListValue::new();
+        row.set_values(RepeatedField::from_vec(vec![
+            as_value(user_id.fxa_uid.clone()),
+            as_value(user_id.fxa_kid.clone()),
+            as_value(collection_id.to_string()),
+            as_value(timestamp.as_rfc3339()?),
+        ]));
+        self.insert_or_update(
+            "user_collections",
+            &["fxa_uid", "fxa_kid", "collection_id", "modified"],
+            vec![row],
+        );
+        self.session.borrow_mut().touched_collection = true;
+        Ok(timestamp)
+    }
+
+    /// Ensure writing to the collection won't create a custom collection
+    /// beyond the user's `max_collections_per_user`
+    ///
+    /// Concurrent requests creating different collections may slightly
+    /// exceed the limit.
+    async fn check_collection_limit_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+    ) -> Result<()> {
+        let max = match self.max_collections_per_user {
+            Some(max) if collection_id >= FIRST_CUSTOM_COLLECTION_ID => max,
+            _ => return Ok(()),
+        };
+        let result = self
+            .sql(
+                "SELECT COUNT(*), COUNTIF(collection_id = @collection_id)
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id >= @first_custom_id
+                    AND modified > @pretouch_ts",
+            )
+            .await?
+            .params(params! {
+                "fxa_uid" => user_id.fxa_uid.clone(),
+                "fxa_kid" => user_id.fxa_kid.clone(),
+                "collection_id" => collection_id.to_string(),
+                "first_custom_id" => FIRST_CUSTOM_COLLECTION_ID.to_string(),
+                "pretouch_ts" => PRETOUCH_TS.to_owned(),
+            })
+            .param_types(param_types! {
+                "collection_id" => TypeCode::INT64,
+                "first_custom_id" => TypeCode::INT64,
+                "pretouch_ts" => TypeCode::TIMESTAMP,
+            })
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        let counts = result
+            .iter()
+            .map(|value| value.get_string_value().parse::<i64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+        if counts[1] == 0 && counts[0] >= i64::from(max) {
+            Err(DbErrorKind::TooManyCollections)?
+        }
+        Ok(())
+    }
+
     pub async fn delete_bso_async(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
         let collection_id = self.get_collection_id_async(&params.collection).await?;
         let touch = self
@@ -963,7 +1796,8 @@ impl SpannerDb {
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
                     AND bso_id = @bso_id",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid,
                 "fxa_kid" => params.user_id.fxa_kid,
@@ -983,27 +1817,153 @@ impl SpannerDb {
         &self,
         params: params::DeleteBsos,
     ) -> Result<results::DeleteBsos> {
-        let user_id = params.user_id.clone();
+        let user_id = params.user_id;
         let collection_id = self.get_collection_id_async(&params.collection).await?;
 
+        // Deleted rows (and their index entries) count toward the commit's
+        // mutation limit too
+        let mut chunks = chunk_by_mutations(
+            params.ids,
+            |_| BSO_DELETE_MUTATIONS,
+            MAX_MUTATIONS_PER_COMMIT - RESERVED_MUTATIONS,
+        )?;
+        let last = chunks.pop().unwrap_or_default();
+        let mut deleted = 0;
+        for chunk in chunks {
+            deleted += self
+                .delete_bso_ids_async(&user_id, collection_id, chunk)
+                .await?;
+            self.flush_mutations_async().await?;
+        }
+        deleted += self
+            .delete_bso_ids_async(&user_id, collection_id, last)
+            .await?;
+        Ok(results::DeleteBsos {
+            modified: self.touch_collection_async(&user_id, collection_id).await?,
+            deleted: deleted as u64,
+        })
+    }
+
+    pub async fn bulk_set_ttl_async(
+        &self,
+        params: params::BulkSetTtl,
+    ) -> Result<results::BulkSetTtl> {
+        let user_id = params.user_id;
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let touch = self.touch_collection_async(&user_id, collection_id).await?;
+        let timestamp = self.timestamp()?;
+        let expiry = to_rfc3339(timestamp.as_i64() + i64::from(params.ttl) * 1000)?;
         let mut sqlparams = params! {
-            "fxa_uid" => user_id.fxa_uid,
-            "fxa_kid" => user_id.fxa_kid,
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert("ids".to_owned(), as_list_value(params.ids.into_iter()));
+        let mut stream = self
+            .sql(
+                "SELECT bso_id
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)
+                    AND expiry > CURRENT_TIMESTAMP()",
+            )
+            .await?
+            .params(sqlparams)
+            .execute_async(&self.conn)?;
+        // Updated by mutations, recording the commit's timestamp
+        let mut writes = vec![];
+        while let Some(row) = stream.next_async().await {
+            let mut row = row?;
+            let mut values = ListValue::new();
+            values.set_values(RepeatedField::from_vec(vec![
+                as_value(user_id.fxa_uid.clone()),
+                as_value(user_id.fxa_kid.clone()),
+                as_value(collection_id.to_string()),
+                as_value(row[0].take_string_value()),
+                as_value(timestamp.as_rfc3339()?),
+                as_value(expiry.clone()),
+            ]));
+            writes.push(BsoWrite::Update(
+                vec![
+                    "fxa_uid",
+                    "fxa_kid",
+                    "collection_id",
+                    "bso_id",
+                    "modified",
+                    "expiry",
+                ],
+                values,
+            ));
+        }
+        self.write_bsos_async(writes).await?;
+        Ok(touch)
+    }
+
+    // NOTE: the DML version, used within the db tests' test transactions
+    // (never committed, so never applying mutations)
+    #[cfg(test)]
+    pub async fn bulk_set_ttl_async_test(
+        &self,
+        params: params::BulkSetTtl,
+    ) -> Result<results::BulkSetTtl> {
+        let user_id = params.user_id;
+        let collection_id = self.get_collection_id_async(&params.collection).await?;
+        let touch = self.touch_collection_async(&user_id, collection_id).await?;
+        let timestamp = self.timestamp()?;
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
             "collection_id" => collection_id.to_string(),
+            "modified" => timestamp.as_rfc3339()?,
+            "expiry" => to_rfc3339(timestamp.as_i64() + i64::from(params.ttl) * 1000)?,
         };
         sqlparams.insert("ids".to_owned(), as_list_value(params.ids.into_iter()));
+        self.sql(
+            "UPDATE bsos
+                SET modified = @modified,
+                    expiry = @expiry
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND bso_id IN UNNEST(@ids)
+                AND expiry > CURRENT_TIMESTAMP()",
+        )
+        .await?
+        .params(sqlparams)
+        .param_types(param_types! {
+            "modified" => TypeCode::TIMESTAMP,
+            "expiry" => TypeCode::TIMESTAMP,
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(touch)
+    }
+
+    async fn delete_bso_ids_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        ids: Vec<String>,
+    ) -> Result<i64> {
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
         self.sql(
             "DELETE FROM bsos
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
                 AND collection_id = @collection_id
                 AND bso_id IN UNNEST(@ids)",
-        )?
+        )
+        .await?
         .params(sqlparams)
         .execute_dml_async(&self.conn)
-        .await?;
-        self.touch_collection_async(&params.user_id, collection_id)
-            .await
+        .await
     }
 
     async fn bsos_query_async(
@@ -1020,6 +1980,9 @@ impl SpannerDb {
         let BsoQueryParams {
             newer,
             older,
+            newer_eq,
+            older_eq,
+            expiring_before,
             sort,
             limit,
             offset,
@@ -1052,16 +2015,43 @@ impl SpannerDb {
             };
         }
         */
+        // Commit timestamps are stored with more precision than they're read
+        // back with: compare against the bounds of each timestamp's stored
+        // values (see SyncTimestamp::storage_next)
         if let Some(older) = older {
             query = format!("{} AND modified < @older", query);
             sqlparams.insert("older".to_string(), as_value(older.as_rfc3339()?));
             sqltypes.insert("older".to_string(), as_type(TypeCode::TIMESTAMP));
         }
         if let Some(newer) = newer {
-            query = format!("{} AND modified > @newer", query);
-            sqlparams.insert("newer".to_string(), as_value(newer.as_rfc3339()?));
+            query = format!("{} AND modified >= @newer", query);
+            sqlparams.insert(
+                "newer".to_string(),
+                as_value(to_rfc3339(newer.storage_next())?),
+            );
             sqltypes.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
         }
+        if let Some(older_eq) = older_eq {
+            query = format!("{} AND modified < @older_eq", query);
+            sqlparams.insert(
+                "older_eq".to_string(),
+                as_value(to_rfc3339(older_eq.storage_next())?),
+            );
+            sqltypes.insert("older_eq".to_string(), as_type(TypeCode::TIMESTAMP));
+        }
+        if let Some(newer_eq) = newer_eq {
+            query = format!("{} AND modified >= @newer_eq", query);
+            sqlparams.insert("newer_eq".to_string(), as_value(newer_eq.as_rfc3339()?));
+            sqltypes.insert("newer_eq".to_string(), as_type(TypeCode::TIMESTAMP));
+        }
+        if let Some(expiring_before) = expiring_before {
+            query = format!("{} AND expiry < @expiring_before", query);
+            sqlparams.insert(
+                "expiring_before".to_string(),
+                as_value(expiring_before.as_rfc3339()?),
+            );
+            sqltypes.insert("expiring_before".to_string(), as_type(TypeCode::TIMESTAMP));
+        }
         query = match sort {
             // issue559: Revert to previous sorting
             /*
@@ -1097,7 +2087,8 @@ impl SpannerDb {
         if let Some(offset) = offset {
             query = format!("{} OFFSET {}", query, offset.offset);
         }
-        self.sql(&query)?
+        self.sql(&query)
+            .await?
             .params(sqlparams)
             .param_types(sqltypes)
             .execute_async(&self.conn)
@@ -1117,6 +2108,7 @@ impl SpannerDb {
             Offset {
                 offset: offset + modifieds.len() as u64,
                 timestamp: None,
+                keyset: None,
             }
             .to_string(),
         )
@@ -1165,14 +2157,29 @@ impl SpannerDb {
                AND collection_id = @collection_id
                AND expiry > CURRENT_TIMESTAMP()";
         let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
+        let Offset {
+            offset, timestamp, ..
+        } = params.params.offset.clone().unwrap_or_default();
         let sort = params.params.sort;
+        let mut ceiling = ResponseCeiling::new(params.params.max_bytes);
 
         let mut streaming = self.bsos_query_async(query, params).await?;
+        // Rows are converted as they arrive, a PartialResultSet at a time
         let mut bsos = vec![];
         while let Some(row) = streaming.next_async().await {
             let row = row?;
-            bsos.push(bso_from_row(row)?);
+            let bso = bso_from_row(row)?;
+            // (The extra row signalling another page isn't part of the
+            // response)
+            if !page_read(limit, bsos.len() + 1) && !ceiling.fits(&bso) {
+                streaming.cancel();
+                break;
+            }
+            bsos.push(bso);
+            if page_read(limit, bsos.len()) {
+                streaming.cancel();
+                break;
+            }
         }
 
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
@@ -1186,23 +2193,108 @@ impl SpannerDb {
             bsos.pop();
             let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
             self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+        } else if ceiling.reached() {
+            Some(truncated_offset(offset, timestamp, bsos.len()))
         } else {
             None
         };
 
         Ok(results::GetBsos {
-            items: bsos,
+            items: self.decode_bsos(bsos).await?,
             offset: next_offset,
+            truncated: ceiling.reached(),
         })
     }
 
-    pub async fn get_bso_ids_async(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
-        let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
-        let sort = params.params.sort;
-
-        let query = "\
-            SELECT bso_id, modified
+    pub async fn get_bsos_after_async(
+        &self,
+        params: params::GetBsosAfter,
+    ) -> Result<results::GetBsosAfter> {
+        let mut query = "\
+            SELECT bso_id, sortindex, payload, modified, expiry
+              FROM bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND expiry > CURRENT_TIMESTAMP()"
+            .to_owned();
+        let mut sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+            "collection_id" => self.get_collection_id_async(&params.collection).await?.to_string(),
+        };
+        let mut sqltypes = HashMap::new();
+        let newest = params.sort == Sorting::Newest;
+        let limit = params.limit.max(1);
+
+        // Commit timestamps are stored with more precision than they're read
+        // back with: BSOs within the bounds of the key's stored values (see
+        // SyncTimestamp::storage_next) share its modified, ordered by id
+        if let Some((modified, id)) = params.after {
+            query = if newest {
+                format!(
+                    "{} AND modified < @modified_next AND (modified < @modified OR bso_id < @bso_id)",
+                    query
+                )
+            } else {
+                format!(
+                    "{} AND modified >= @modified AND (modified >= @modified_next OR bso_id > @bso_id)",
+                    query
+                )
+            };
+            sqlparams.insert("modified".to_owned(), as_value(modified.as_rfc3339()?));
+            sqltypes.insert("modified".to_owned(), as_type(TypeCode::TIMESTAMP));
+            sqlparams.insert(
+                "modified_next".to_owned(),
+                as_value(to_rfc3339(modified.storage_next())?),
+            );
+            sqltypes.insert("modified_next".to_owned(), as_type(TypeCode::TIMESTAMP));
+            sqlparams.insert("bso_id".to_owned(), as_value(id));
+        }
+        let order = if newest { "DESC" } else { "ASC" };
+        // fetch an extra row to detect if there are more rows
+        query = format!(
+            "{} ORDER BY DIV(UNIX_MILLIS(modified), 10) {}, bso_id {} LIMIT {}",
+            query,
+            order,
+            order,
+            i64::from(limit) + 1
+        );
+
+        let mut streaming = self
+            .sql(&query)
+            .await?
+            .params(sqlparams)
+            .param_types(sqltypes)
+            .execute_async(&self.conn)?;
+        let mut bsos = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            bsos.push(bso_from_row(row)?);
+            if page_read(i64::from(limit), bsos.len()) {
+                streaming.cancel();
+                break;
+            }
+        }
+
+        let more = bsos.len() > limit as usize;
+        bsos.truncate(limit as usize);
+        Ok(results::GetBsosAfter {
+            items: self.decode_bsos(bsos).await?,
+            more,
+        })
+    }
+
+    pub async fn get_bso_ids_async(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
+        let limit = params.params.limit.map(i64::from).unwrap_or(-1);
+        let Offset {
+            offset, timestamp, ..
+        } = params.params.offset.clone().unwrap_or_default();
+        let sort = params.params.sort;
+        let mut ceiling = ResponseCeiling::new(params.params.max_bytes);
+
+        let query = "\
+            SELECT bso_id, modified
               FROM bsos
              WHERE fxa_uid = @fxa_uid
                AND fxa_kid = @fxa_kid
@@ -1214,8 +2306,17 @@ impl SpannerDb {
         let mut modifieds = vec![];
         while let Some(row) = stream.next_async().await {
             let mut row = row?;
-            ids.push(row[0].take_string_value());
+            let id = row[0].take_string_value();
+            if !page_read(limit, ids.len() + 1) && !ceiling.fits(&id) {
+                stream.cancel();
+                break;
+            }
+            ids.push(id);
             modifieds.push(SyncTimestamp::from_rfc3339(row[1].get_string_value())?.as_i64());
+            if page_read(limit, ids.len()) {
+                stream.cancel();
+                break;
+            }
         }
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
         // makes an additional call to get_collection_timestamp to potentially
@@ -1228,6 +2329,8 @@ impl SpannerDb {
             ids.pop();
             modifieds.pop();
             self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+        } else if ceiling.reached() {
+            Some(truncated_offset(offset, timestamp, ids.len()))
         } else {
             None
         };
@@ -1235,31 +2338,79 @@ impl SpannerDb {
         Ok(results::GetBsoIds {
             items: ids,
             offset: next_offset,
+            truncated: ceiling.reached(),
         })
     }
 
+    pub async fn count_bsos_async(
+        &self,
+        mut params: params::CountBsos,
+    ) -> Result<results::CountBsos> {
+        let query = "\
+            SELECT COUNT(*)
+              FROM bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND expiry > CURRENT_TIMESTAMP()";
+        // Count every match: not just the requested page
+        params.params.sort = Sorting::None;
+        params.params.limit = None;
+        params.params.offset = None;
+        let result = self.bsos_query_async(query, params).await?.one().await?;
+        Ok(result[0]
+            .get_string_value()
+            .parse::<u64>()
+            .map_err(|e| DbErrorKind::Integrity(e.to_string()))?)
+    }
+
     pub async fn get_bso_async(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
         let collection_id = self.get_collection_id_async(&params.collection).await?;
-        self.sql(
-            "SELECT bso_id, sortindex, payload, modified, expiry
+        let bso = self
+            .sql(
+                "SELECT bso_id, sortindex, payload, modified, expiry
                FROM bsos
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
                 AND collection_id = @collection_id
                 AND bso_id = @bso_id
                 AND expiry > CURRENT_TIMESTAMP()",
-        )?
-        .params(params! {
-            "fxa_uid" => params.user_id.fxa_uid,
-            "fxa_kid" => params.user_id.fxa_kid,
-            "collection_id" => collection_id.to_string(),
-            "bso_id" => params.id,
+            )
+            .await?
+            .params(params! {
+                "fxa_uid" => params.user_id.fxa_uid,
+                "fxa_kid" => params.user_id.fxa_kid,
+                "collection_id" => collection_id.to_string(),
+                "bso_id" => params.id,
+            })
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?
+            .map(bso_from_row)
+            .transpose()?;
+        Ok(match bso {
+            Some(bso) => self.decode_bsos(vec![bso]).await?.pop(),
+            None => None,
         })
-        .execute_async(&self.conn)?
-        .one_or_none()
-        .await?
-        .map(bso_from_row)
-        .transpose()
+    }
+
+    /// Decode the BSOs' stored payloads (see `codec::run`)
+    async fn decode_bsos(&self, bsos: Vec<results::GetBso>) -> Result<Vec<results::GetBso>> {
+        codec::run(&self.codec, move |codec| {
+            bsos.into_iter().map(|bso| codec.decode_bso(bso)).collect()
+        })
+        .await
+    }
+
+    /// Encode the BSOs' payloads for storage (see `codec::run`)
+    pub(super) async fn encode_bsos(
+        &self,
+        bsos: Vec<params::PostCollectionBso>,
+    ) -> Result<Vec<params::PostCollectionBso>> {
+        codec::run(&self.codec, move |codec| {
+            bsos.into_iter().map(|bso| codec.encode_bso(bso)).collect()
+        })
+        .await
     }
 
     pub async fn get_bso_timestamp_async(
@@ -1278,7 +2429,8 @@ impl SpannerDb {
                     AND collection_id = @collection_id
                     AND bso_id = @bso_id
                     AND expiry > CURRENT_TIMESTAMP()",
-            )?
+            )
+            .await?
             .params(params! {
                 "fxa_uid" => params.user_id.fxa_uid,
                 "fxa_kid" => params.user_id.fxa_kid,
@@ -1295,73 +2447,90 @@ impl SpannerDb {
         }
     }
 
-    pub async fn put_bso_async(&self, params: params::PutBso) -> Result<results::PutBso> {
+    /// Fail with `BsoExists` when a create only PUT's BSO already exists
+    async fn check_create_only_async(&self, params: &params::PutBso) -> Result<()> {
+        if !params.create_only {
+            return Ok(());
+        }
+        let modified = self
+            .get_bso_timestamp_async(params::GetBsoTimestamp {
+                user_id: params.user_id.clone(),
+                collection: params.collection.clone(),
+                id: params.id.clone(),
+            })
+            .await;
+        match modified {
+            Ok(modified) if modified.as_i64() > 0 => Err(DbErrorKind::BsoExists.into()),
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn put_bso_async(&self, params: &params::PutBso) -> Result<results::PutBso> {
+        self.check_create_only_async(params).await?;
         let bsos = vec![params::PostCollectionBso {
-            id: params.id,
+            id: params.id.clone(),
             sortindex: params.sortindex,
-            payload: params.payload,
+            payload: params.payload.clone(),
             ttl: params.ttl,
         }];
         let result = self
-            .post_bsos_async(params::PostBsos {
-                user_id: params.user_id,
-                collection: params.collection,
-                bsos,
-                failed: HashMap::new(),
-            })
+            .write_collection_bsos_async(&params.user_id, &params.collection, bsos, false)
             .await?;
         Ok(result.modified)
     }
 
-    pub async fn post_bsos_async(&self, params: params::PostBsos) -> Result<results::PostBsos> {
-        let user_id = params.user_id;
-        let collection_id = self
-            .get_or_create_collection_id_async(&params.collection)
+    pub async fn post_bsos_async(&self, params: &params::PostBsos) -> Result<results::PostBsos> {
+        let mut result = self
+            .write_collection_bsos_async(
+                &params.user_id,
+                &params.collection,
+                params.bsos.clone(),
+                params.breakdown,
+            )
             .await?;
+        result.failed = params.failed.clone();
+        Ok(result)
+    }
+
+    /// Write the BSOs (of a PUT or POST) to the collection
+    async fn write_collection_bsos_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection: &str,
+        bsos: Vec<params::PostCollectionBso>,
+        breakdown: bool,
+    ) -> Result<results::PostBsos> {
+        let collection_id = self.get_or_create_collection_id_async(collection).await?;
         // Ensure a parent record exists in user_collections before writing to
         // bsos (INTERLEAVE IN PARENT user_collections)
-        let timestamp = self.touch_collection_async(&user_id, collection_id).await?;
+        let timestamp = self
+            .touch_collection_mutation_async(user_id, collection_id)
+            .await?;
 
-        let mut sqlparams = params! {
-            "fxa_uid" => user_id.fxa_uid.clone(),
-            "fxa_kid" => user_id.fxa_kid.clone(),
-            "collection_id" => collection_id.to_string(),
-        };
-        sqlparams.insert(
-            "ids".to_owned(),
-            as_list_value(params.bsos.iter().map(|pbso| pbso.id.clone())),
-        );
-        let mut streaming = self
-            .sql(
-                "SELECT bso_id
-                   FROM bsos
-                  WHERE fxa_uid = @fxa_uid
-                    AND fxa_kid = @fxa_kid
-                    AND collection_id = @collection_id
-                    AND bso_id IN UNNEST(@ids)",
-            )?
-            .params(sqlparams)
-            .execute_async(&self.conn)?;
-        let mut existing = vec![];
-        while let Some(row) = streaming.next_async().await {
-            let mut row = row?;
-            existing.push(row[0].take_string_value());
-        }
+        let existing = self
+            .existing_bso_ids_async(
+                user_id,
+                collection_id,
+                bsos.iter().map(|pbso| pbso.id.clone()),
+            )
+            .await?;
 
-        let mut inserts = vec![];
-        let mut updates = HashMap::new();
+        let mut writes = vec![];
         let mut success = vec![];
         let mut load_size: usize = 0;
-        for bso in params.bsos {
+        for mut bso in self.encode_bsos(bsos).await? {
             success.push(bso.id.clone());
             if existing.contains(&bso.id) {
-                let (columns, values) = bso_to_update_row(&user_id, collection_id, bso, timestamp)?;
+                let (columns, values) = bso_to_update_row(user_id, collection_id, bso, timestamp)?;
                 load_size += values.compute_size() as usize;
-                updates.entry(columns).or_insert_with(Vec::new).push(values);
+                writes.push(BsoWrite::Update(columns, values));
             } else {
-                let values = bso_to_insert_row(&user_id, collection_id, bso, timestamp)?;
+                bso.sortindex = bso.sortindex.or(self.default_sortindex);
+                let values = bso_to_insert_row(user_id, collection_id, bso, timestamp)?;
                 load_size += values.compute_size() as usize;
-                inserts.push(values);
+                writes.push(BsoWrite::Insert(values));
             }
         }
         if load_size > MAX_SPANNER_LOAD_SIZE {
@@ -1377,41 +2546,66 @@ impl SpannerDb {
             .into());
         }
 
-        if !inserts.is_empty() {
-            debug!("inserts: {:?}", &inserts);
-            self.insert(
-                "bsos",
-                &[
-                    "fxa_uid",
-                    "fxa_kid",
-                    "collection_id",
-                    "bso_id",
-                    "sortindex",
-                    "payload",
-                    "modified",
-                    "expiry",
-                ],
-                inserts,
-            );
-        }
-        for (columns, values) in updates {
-            debug!("columns: {:?}, values:{:?}", &columns, &values);
-            self.update("bsos", &columns, values);
-        }
+        // Posts too large for one commit are committed in several
+        self.write_bsos_async(writes).await?;
 
-        let result = results::PostBsos {
+        let mut result = results::PostBsos {
             modified: timestamp,
             success,
-            failed: params.failed,
+            failed: HashMap::new(),
+            created: None,
         };
+        if breakdown {
+            result.set_created(existing);
+        }
         Ok(result)
     }
 
+    /// Which of the ids name existing BSOs of the collection
+    async fn existing_bso_ids_async(
+        &self,
+        user_id: &HawkIdentifier,
+        collection_id: i32,
+        ids: impl Iterator<Item = String>,
+    ) -> Result<HashSet<String>> {
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        };
+        sqlparams.insert("ids".to_owned(), as_list_value(ids));
+        let mut streaming = self
+            .sql(
+                "SELECT bso_id
+                   FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id IN UNNEST(@ids)",
+            )
+            .await?
+            .params(sqlparams)
+            .execute_async(&self.conn)?;
+        let mut existing = HashSet::new();
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            existing.insert(row[0].take_string_value());
+        }
+        Ok(existing)
+    }
+
     // NOTE: Currently this put_bso_async_test impl. is only used during db tests,
     // see above for the non-tests version
     #[cfg(test)]
     pub async fn put_bso_async_test(&self, bso: params::PutBso) -> Result<results::PutBso> {
-        use crate::db::util::to_rfc3339;
+        self.check_create_only_async(&bso).await?;
+        let payload = match bso.payload {
+            Some(payload) => codec::run(&self.codec, move |codec| codec.encode(payload))
+                .await
+                .map(Some)?,
+            None => None,
+        };
+        let bso = params::PutBso { payload, ..bso };
         let collection_id = self
             .get_or_create_collection_id_async(&bso.collection)
             .await?;
@@ -1435,7 +2629,8 @@ impl SpannerDb {
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
                     AND bso_id = @bso_id",
-            )?
+            )
+            .await?
             .params(sqlparams.clone())
             .execute_async(&self.conn)?
             .one_or_none()
@@ -1509,8 +2704,8 @@ impl SpannerDb {
                     AND bso_id = @bso_id"
             )
         } else {
-            let use_sortindex = bso
-                .sortindex
+            let sortindex = bso.sortindex.or(self.default_sortindex);
+            let use_sortindex = sortindex
                 .map(|sortindex| sortindex.to_string())
                 .unwrap_or_else(|| "NULL".to_owned())
                 != "NULL";
@@ -1530,8 +2725,7 @@ impl SpannerDb {
 
             if use_sortindex {
                 use super::support::null_value;
-                let sortindex = bso
-                    .sortindex
+                let sortindex = sortindex
                     .map(|sortindex| as_value(sortindex.to_string()))
                     .unwrap_or_else(null_value);
                 sqlparams.insert("sortindex".to_string(), sortindex);
@@ -1559,7 +2753,8 @@ impl SpannerDb {
             sql.to_owned()
         };
 
-        self.sql(&sql)?
+        self.sql(&sql)
+            .await?
             .params(sqlparams)
             .param_types(sqltypes)
             .execute_dml_async(&self.conn)
@@ -1579,6 +2774,19 @@ impl SpannerDb {
             modified: self.timestamp()?,
             success: Default::default(),
             failed: input.failed,
+            created: None,
+        };
+        let existing = if input.breakdown {
+            Some(
+                self.existing_bso_ids_async(
+                    &input.user_id,
+                    collection_id,
+                    input.bsos.iter().map(|pbso| pbso.id.clone()),
+                )
+                .await?,
+            )
+        } else {
+            None
         };
 
         for pbso in input.bsos {
@@ -1590,71 +2798,413 @@ impl SpannerDb {
                 payload: pbso.payload,
                 sortindex: pbso.sortindex,
                 ttl: pbso.ttl,
+                create_only: false,
             })
             .await?;
             result.success.push(id);
         }
         self.touch_collection_async(&input.user_id, collection_id)
             .await?;
+        if let Some(existing) = existing {
+            result.set_created(existing);
+        }
         Ok(result)
     }
 
+    pub async fn reset_collection_async(
+        &self,
+        params: &params::ResetCollection,
+    ) -> Result<results::ResetCollection> {
+        self.clear_collection_async(params).await?;
+        self.post_bsos_async(params).await
+    }
+
+    // see above for the non-tests version
+    #[cfg(test)]
+    pub async fn reset_collection_async_test(
+        &self,
+        params: params::ResetCollection,
+    ) -> Result<results::ResetCollection> {
+        self.clear_collection_async(&params).await?;
+        self.post_bsos_async_test(params).await
+    }
+
+    /// Delete all of a collection's BSOs (ahead of replacing them)
+    async fn clear_collection_async(&self, params: &params::ResetCollection) -> Result<()> {
+        let collection_id = self
+            .get_or_create_collection_id_async(&params.collection)
+            .await?;
+        // The new BSOs' insert mutations are applied at commit, after this
+        self.sql(
+            "DELETE FROM bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id",
+        )
+        .await?
+        .params(params! {
+            "fxa_uid" => params.user_id.fxa_uid.clone(),
+            "fxa_kid" => params.user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+        })
+        .execute_dml_async(&self.conn)
+        .await?;
+        Ok(())
+    }
+
     async fn check_async(&self) -> Result<results::Check> {
         // TODO: is there a better check than just fetching UTC?
-        self.sql("SELECT CURRENT_TIMESTAMP()")?
+        self.sql("SELECT CURRENT_TIMESTAMP()")
+            .await?
             .execute_async(&self.conn)?
             .one()
             .await?;
+        self.health.record_success();
         Ok(true)
     }
-}
-
-unsafe impl Send for SpannerDb {}
-
-impl Db for SpannerDb {
-    fn commit(&self) -> DbFuture<()> {
-        let db = self.clone();
-        Box::pin(async move { db.commit_async().map_err(Into::into).await })
-    }
-
-    fn rollback(&self) -> DbFuture<()> {
-        let db = self.clone();
-        Box::pin(async move { db.rollback_async().map_err(Into::into).await })
-    }
 
-    fn lock_for_read(&self, param: params::LockCollection) -> DbFuture<()> {
-        let db = self.clone();
-        Box::pin(async move { db.lock_for_read_async(param).map_err(Into::into).await })
+    async fn health_detail_async(&self) -> Result<results::HealthDetail> {
+        let start = Instant::now();
+        self.check_async().await?;
+        Ok(self.health.detail(start.elapsed()))
     }
 
-    fn lock_for_write(&self, param: params::LockCollection) -> DbFuture<()> {
-        let db = self.clone();
-        Box::pin(async move { db.lock_for_write_async(param).map_err(Into::into).await })
-    }
+    async fn try_acquire_maintenance_lock_async(
+        &self,
+        params: params::TryAcquireMaintenanceLock,
+    ) -> Result<results::TryAcquireMaintenanceLock> {
+        // The read locks the lease row for the remainder of this r/w
+        // transaction: a competing instance's commit will abort, so the lease
+        // is only held once the caller commits
+        if !self.in_write_transaction() {
+            self.begin_async(true).await?;
+        }
+        let result = self
+            .sql(
+                "SELECT CURRENT_TIMESTAMP(),
+                        (SELECT expiry
+                           FROM maintenance_locks
+                          WHERE name = @name)",
+            )
+            .await?
+            .params(params! {"name" => params.name.clone()})
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        let now = SyncTimestamp::from_rfc3339(result[0].get_string_value())?;
+        let exists = !result[1].has_null_value();
+        if exists {
+            let expiry = SyncTimestamp::from_rfc3339(result[1].get_string_value())?;
+            if expiry > now {
+                return Ok(false);
+            }
+        }
 
-    fn begin(&self, for_write: bool) -> DbFuture<()> {
-        let db = self.clone();
-        Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
+        let sql = if exists {
+            "UPDATE maintenance_locks
+                SET expiry = @expiry
+              WHERE name = @name"
+        } else {
+            "INSERT INTO maintenance_locks (name, expiry)
+             VALUES (@name, @expiry)"
+        };
+        self.sql(sql)
+            .await?
+            .params(params! {
+                "name" => params.name,
+                "expiry" => to_rfc3339(now.as_i64() + i64::from(params.ttl) * 1000)?,
+            })
+            .param_types(param_types! {
+                "expiry" => TypeCode::TIMESTAMP,
+            })
+            .execute_dml_async(&self.conn)
+            .await?;
+        Ok(true)
     }
 
-    fn get_collection_timestamp(
+    pub async fn count_batches_async(
         &self,
-        param: params::GetCollectionTimestamp,
-    ) -> DbFuture<results::GetCollectionTimestamp> {
-        let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_timestamp_async(param)
-                .map_err(Into::into)
-                .await
+        params: params::CountBatches,
+    ) -> Result<results::CountBatches> {
+        let mut query = "SELECT COUNT(*),
+                                COUNTIF(expiry < CURRENT_TIMESTAMP())
+                           FROM batches"
+            .to_owned();
+        let mut sqlparams = HashMap::new();
+        if let Some(user_id) = params.user_id {
+            query = format!("{} WHERE fxa_uid = @fxa_uid AND fxa_kid = @fxa_kid", query);
+            sqlparams.insert("fxa_uid".to_owned(), as_value(user_id.fxa_uid));
+            sqlparams.insert("fxa_kid".to_owned(), as_value(user_id.fxa_kid));
+        }
+        let result = self
+            .sql(&query)
+            .await?
+            .params(sqlparams)
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        let count = |value: &protobuf::well_known_types::Value| {
+            value
+                .get_string_value()
+                .parse::<u64>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))
+        };
+        Ok(results::CountBatches {
+            open: count(&result[0])?,
+            stale: count(&result[1])?,
         })
     }
 
-    fn get_storage_timestamp(
+    pub async fn get_blob_references_async(&self) -> Result<results::GetBlobReferences> {
+        let mut streaming = self
+            .sql(
+                "SELECT DISTINCT payload
+                   FROM bsos
+                  WHERE STARTS_WITH(payload, 'blob:')
+                  UNION DISTINCT
+                 SELECT DISTINCT payload
+                   FROM batch_bsos
+                  WHERE STARTS_WITH(payload, 'blob:')",
+            )
+            .await?
+            .execute_async(&self.conn)?;
+        let mut keys = HashSet::new();
+        while let Some(row) = streaming.next_async().await {
+            let row = row?;
+            if let Some(key) = codec::blob_key(row[0].get_string_value()) {
+                keys.insert(key.to_owned());
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn get_collections_async(
+        &self,
+        params: params::GetCollections,
+    ) -> Result<results::GetCollections> {
+        let mut streaming = self
+            .sql(&format!(
+                "SELECT collection_id, name
+                   FROM collections
+                  WHERE collection_id > @after_id
+                  ORDER BY collection_id
+                  LIMIT {limit}",
+                limit = params.limit
+            ))
+            .await?
+            .params(params! {
+                "after_id" => params.after_id.to_string(),
+            })
+            .param_types(param_types! {
+                "after_id" => TypeCode::INT64,
+            })
+            .execute_async(&self.conn)?;
+        let mut collections = vec![];
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            let id = row[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+            collections.push((id, row[1].take_string_value()));
+        }
+        Ok(collections)
+    }
+
+    pub async fn purge_expired_bsos_async(
+        &self,
+        params: params::PurgeExpiredBsos,
+    ) -> Result<results::PurgeExpiredBsos> {
+        self.purge_expired_async("bsos", "bso_id", params).await
+    }
+
+    pub async fn purge_expired_batches_async(
+        &self,
+        params: params::PurgeExpiredBatches,
+    ) -> Result<results::PurgeExpiredBatches> {
+        // Also deletes child batch_bsos rows (INTERLEAVE IN PARENT batches ON
+        // DELETE CASCADE)
+        self.purge_expired_async("batches", "batch_id", params)
+            .await
+    }
+
+    /// Delete (or count) up to `params.limit` expired rows of a table keyed by
+    /// (fxa_uid, fxa_kid, collection_id, `id_column`)
+    async fn purge_expired_async(
+        &self,
+        table: &str,
+        id_column: &str,
+        params: params::PurgeExpired,
+    ) -> Result<u64> {
+        let mut filter = "expiry < CURRENT_TIMESTAMP()".to_owned();
+        let mut sqlparams = HashMap::new();
+        if let Some(user_id) = params.user_id {
+            filter.push_str(" AND fxa_uid = @fxa_uid AND fxa_kid = @fxa_kid");
+            sqlparams.insert("fxa_uid".to_owned(), as_value(user_id.fxa_uid));
+            sqlparams.insert("fxa_kid".to_owned(), as_value(user_id.fxa_kid));
+        }
+        if let Some(ref collection) = params.collection {
+            let collection_id = match self.get_collection_id_async(collection).await {
+                Ok(collection_id) => collection_id,
+                // Nothing to purge
+                Err(e) if matches!(e.kind(), DbErrorKind::CollectionNotFound) => return Ok(0),
+                Err(e) => return Err(e),
+            };
+            filter.push_str(" AND collection_id = @collection_id");
+            sqlparams.insert(
+                "collection_id".to_owned(),
+                as_value(collection_id.to_string()),
+            );
+        }
+
+        let mut streaming = self
+            .sql(&format!(
+                "SELECT fxa_uid, fxa_kid, collection_id, {id}
+                   FROM {table}
+                  WHERE {filter}
+                  LIMIT {limit}",
+                id = id_column,
+                table = table,
+                filter = filter,
+                limit = params.limit
+            ))
+            .await?
+            .params(sqlparams)
+            .execute_async(&self.conn)?;
+        let mut expired: HashMap<(String, String, String), Vec<String>> = HashMap::new();
+        let mut count = 0;
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            expired
+                .entry((
+                    row[0].take_string_value(),
+                    row[1].take_string_value(),
+                    row[2].take_string_value(),
+                ))
+                .or_default()
+                .push(row[3].take_string_value());
+            count += 1;
+        }
+        if params.dry_run {
+            return Ok(count);
+        }
+
+        let mut deleted = 0;
+        for ((fxa_uid, fxa_kid, collection_id), ids) in expired {
+            let mut sqlparams = params! {
+                "fxa_uid" => fxa_uid,
+                "fxa_kid" => fxa_kid,
+                "collection_id" => collection_id,
+            };
+            sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
+            deleted += self
+                .sql(&format!(
+                    "DELETE FROM {table}
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id
+                        AND {id} IN UNNEST(@ids)
+                        AND expiry < CURRENT_TIMESTAMP()",
+                    id = id_column,
+                    table = table
+                ))
+                .await?
+                .params(sqlparams)
+                .execute_dml_async(&self.conn)
+                .await?;
+        }
+        Ok(deleted as u64)
+    }
+}
+
+unsafe impl Send for SpannerDb {}
+
+impl Db for SpannerDb {
+    fn commit(&self) -> DbFuture<()> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.commit_async()
+                .map_err(db_op_error!("spanner", commit))
+                .await
+        })
+    }
+
+    fn commit_and_get_timestamp(&self) -> DbFuture<SyncTimestamp> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.commit_async()
+                .map_err(db_op_error!("spanner", commit))
+                .await?;
+            Ok(Db::timestamp(&db))
+        })
+    }
+
+    fn rollback(&self) -> DbFuture<()> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.rollback_async()
+                .map_err(db_op_error!("spanner", rollback))
+                .await
+        })
+    }
+
+    fn lock_for_read(&self, param: params::LockCollectionForRead) -> DbFuture<()> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.lock_for_read_async((*param).clone())
+                .map_err(db_op_error!("spanner", lock_for_read))
+                .await
+        })
+    }
+
+    fn lock_for_write(&self, param: params::LockCollection) -> DbFuture<()> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.lock_for_write_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", lock_for_write))
+            .await
+        })
+    }
+
+    fn begin(&self, for_write: bool) -> DbFuture<()> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(for_write, |db, for_write| async move {
+                db.begin_async(*for_write).await
+            })
+            .map_err(db_op_error!("spanner", begin))
+            .await
+        })
+    }
+
+    fn get_collection_timestamp(
+        &self,
+        param: params::GetCollectionTimestamp,
+    ) -> DbFuture<results::GetCollectionTimestamp> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_collection_timestamp_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_collection_timestamp))
+            .await
+        })
+    }
+
+    fn get_storage_timestamp(
         &self,
         param: params::GetStorageTimestamp,
     ) -> DbFuture<results::GetStorageTimestamp> {
         let db = self.clone();
-        Box::pin(async move { db.get_storage_timestamp(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_storage_timestamp((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_storage_timestamp))
+            .await
+        })
     }
 
     fn delete_collection(
@@ -1662,7 +3212,13 @@ impl Db for SpannerDb {
         param: params::DeleteCollection,
     ) -> DbFuture<results::DeleteCollection> {
         let db = self.clone();
-        Box::pin(async move { db.delete_collection_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.delete_collection_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", delete_collection))
+            .await
+        })
     }
 
     fn box_clone(&self) -> Box<dyn Db> {
@@ -1671,7 +3227,24 @@ impl Db for SpannerDb {
 
     fn check(&self) -> DbFuture<results::Check> {
         let db = self.clone();
-        Box::pin(async move { db.check_async().map_err(Into::into).await })
+        Box::pin(async move {
+            db.check_async()
+                .map_err(db_op_error!("spanner", check))
+                .await
+        })
+    }
+
+    fn health_detail(&self) -> DbFuture<results::HealthDetail> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.health_detail_async()
+                .map_err(db_op_error!("spanner", health_detail))
+                .await
+        })
+    }
+
+    fn last_success(&self) -> Option<u64> {
+        self.health.last_success()
     }
 
     fn get_collection_timestamps(
@@ -1680,9 +3253,25 @@ impl Db for SpannerDb {
     ) -> DbFuture<results::GetCollectionTimestamps> {
         let db = self.clone();
         Box::pin(async move {
-            db.get_collection_timestamps_async(user_id)
-                .map_err(Into::into)
-                .await
+            db.retrying(user_id, |db, user_id| async move {
+                db.get_collection_timestamps_async((*user_id).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_collection_timestamps))
+            .await
+        })
+    }
+
+    fn get_collection_names(
+        &self,
+        user_id: params::GetCollectionNames,
+    ) -> DbFuture<results::GetCollectionNames> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(user_id, |db, user_id| async move {
+                db.get_collection_names_async((*user_id).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_collection_names))
+            .await
         })
     }
 
@@ -1692,9 +3281,11 @@ impl Db for SpannerDb {
     ) -> DbFuture<results::GetCollectionCounts> {
         let db = self.clone();
         Box::pin(async move {
-            db.get_collection_counts_async(user_id)
-                .map_err(Into::into)
-                .await
+            db.retrying(user_id, |db, user_id| async move {
+                db.get_collection_counts_async((*user_id).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_collection_counts))
+            .await
         })
     }
 
@@ -1704,9 +3295,11 @@ impl Db for SpannerDb {
     ) -> DbFuture<results::GetCollectionUsage> {
         let db = self.clone();
         Box::pin(async move {
-            db.get_collection_usage_async(user_id)
-                .map_err(Into::into)
-                .await
+            db.retrying(user_id, |db, user_id| async move {
+                db.get_collection_usage_async((*user_id).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_collection_usage))
+            .await
         })
     }
 
@@ -1715,37 +3308,118 @@ impl Db for SpannerDb {
         param: params::GetStorageUsage,
     ) -> DbFuture<results::GetStorageUsage> {
         let db = self.clone();
-        Box::pin(async move { db.get_storage_usage_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_storage_usage_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_storage_usage))
+            .await
+        })
     }
 
     fn delete_storage(&self, param: params::DeleteStorage) -> DbFuture<results::DeleteStorage> {
         let db = self.clone();
-        Box::pin(async move { db.delete_storage_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.delete_storage_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", delete_storage))
+            .await
+        })
     }
 
     fn delete_bso(&self, param: params::DeleteBso) -> DbFuture<results::DeleteBso> {
         let db = self.clone();
-        Box::pin(async move { db.delete_bso_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.delete_bso_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", delete_bso))
+            .await
+        })
     }
 
     fn delete_bsos(&self, param: params::DeleteBsos) -> DbFuture<results::DeleteBsos> {
         let db = self.clone();
-        Box::pin(async move { db.delete_bsos_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.delete_bsos_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", delete_bsos))
+            .await
+        })
+    }
+
+    fn bulk_set_ttl(&self, param: params::BulkSetTtl) -> DbFuture<results::BulkSetTtl> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                #[cfg(test)]
+                {
+                    if db.uses_test_transactions() {
+                        return db.bulk_set_ttl_async_test((*param).clone()).await;
+                    }
+                }
+                db.bulk_set_ttl_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", bulk_set_ttl))
+            .await
+        })
     }
 
     fn get_bsos(&self, param: params::GetBsos) -> DbFuture<results::GetBsos> {
         let db = self.clone();
-        Box::pin(async move { db.get_bsos_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_bsos_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_bsos))
+            .await
+        })
     }
 
     fn get_bso_ids(&self, param: params::GetBsoIds) -> DbFuture<results::GetBsoIds> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_ids_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_bso_ids_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_bso_ids))
+            .await
+        })
+    }
+
+    fn get_bsos_after(&self, param: params::GetBsosAfter) -> DbFuture<results::GetBsosAfter> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_bsos_after_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_bsos_after))
+            .await
+        })
+    }
+
+    fn count_bsos(&self, param: params::CountBsos) -> DbFuture<results::CountBsos> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.count_bsos_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", count_bsos))
+            .await
+        })
     }
 
     fn get_bso(&self, param: params::GetBso) -> DbFuture<Option<results::GetBso>> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_bso_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_bso))
+            .await
+        })
     }
 
     fn get_bso_timestamp(
@@ -1753,31 +3427,67 @@ impl Db for SpannerDb {
         param: params::GetBsoTimestamp,
     ) -> DbFuture<results::GetBsoTimestamp> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_timestamp_async(param).map_err(Into::into).await })
-    }
-
-    #[cfg(not(test))]
-    fn put_bso(&self, param: params::PutBso) -> DbFuture<results::PutBso> {
-        let db = self.clone();
-        Box::pin(async move { db.put_bso_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                db.get_bso_timestamp_async((*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_bso_timestamp))
+            .await
+        })
     }
 
-    #[cfg(test)]
     fn put_bso(&self, param: params::PutBso) -> DbFuture<results::PutBso> {
         let db = self.clone();
-        Box::pin(async move { db.put_bso_async_test(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                #[cfg(test)]
+                {
+                    if db.uses_test_transactions() {
+                        return db.put_bso_async_test((*param).clone()).await;
+                    }
+                }
+                db.put_bso_async(&param).await
+            })
+            .map_err(db_op_error!("spanner", put_bso))
+            .await
+        })
     }
 
-    #[cfg(not(test))]
     fn post_bsos(&self, param: params::PostBsos) -> DbFuture<results::PostBsos> {
         let db = self.clone();
-        Box::pin(async move { db.post_bsos_async(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                #[cfg(test)]
+                {
+                    if db.uses_test_transactions() {
+                        return db.post_bsos_async_test((*param).clone()).await;
+                    }
+                }
+                db.post_bsos_async(&param).await
+            })
+            .map_err(db_op_error!("spanner", post_bsos))
+            .await
+        })
     }
 
-    #[cfg(test)]
-    fn post_bsos(&self, param: params::PostBsos) -> DbFuture<results::PostBsos> {
+    fn reset_collection(
+        &self,
+        param: params::ResetCollection,
+    ) -> DbFuture<results::ResetCollection> {
         let db = self.clone();
-        Box::pin(async move { db.post_bsos_async_test(param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                #[cfg(test)]
+                {
+                    if db.uses_test_transactions() {
+                        return db.reset_collection_async_test((*param).clone()).await;
+                    }
+                }
+                db.reset_collection_async(&param).await
+            })
+            .map_err(db_op_error!("spanner", reset_collection))
+            .await
+        })
     }
 
     fn validate_batch_id(&self, id: String) -> Result<()> {
@@ -1786,39 +3496,177 @@ impl Db for SpannerDb {
 
     fn create_batch(&self, param: params::CreateBatch) -> DbFuture<results::CreateBatch> {
         let db = self.clone();
-        Box::pin(async move { batch::create_async(&db, param).map_err(Into::into).await })
+        // (Also the id of the batch recreated by a replay)
+        let batch_id = batch::new_batch_id();
+        Box::pin(async move {
+            db.retrying(param, move |db, param| {
+                let batch_id = batch_id.clone();
+                async move { batch::create_async(&db, &param, batch_id).await }
+            })
+            .map_err(db_op_error!("spanner", create_batch))
+            .await
+        })
     }
 
     fn validate_batch(&self, param: params::ValidateBatch) -> DbFuture<results::ValidateBatch> {
         let db = self.clone();
-        Box::pin(async move { batch::validate_async(&db, param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                batch::validate_async(&db, (*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", validate_batch))
+            .await
+        })
     }
 
     fn append_to_batch(&self, param: params::AppendToBatch) -> DbFuture<results::AppendToBatch> {
         let db = self.clone();
-        Box::pin(async move { batch::append_async(&db, param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                batch::append_async(&db, &param).await
+            })
+            .map_err(db_op_error!("spanner", append_to_batch))
+            .await
+        })
     }
 
     fn get_batch(&self, param: params::GetBatch) -> DbFuture<Option<results::GetBatch>> {
         let db = self.clone();
-        Box::pin(async move { batch::get_async(&db, param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                batch::get_async(&db, (*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_batch))
+            .await
+        })
+    }
+
+    fn get_batch_bsos(&self, param: params::GetBatchBsos) -> DbFuture<results::GetBatchBsos> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                batch::get_bsos_async(&db, (*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", get_batch_bsos))
+            .await
+        })
     }
 
     fn commit_batch(&self, param: params::CommitBatch) -> DbFuture<results::CommitBatch> {
         let db = self.clone();
-        Box::pin(async move { batch::commit_async(&db, param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                #[cfg(test)]
+                {
+                    if db.uses_test_transactions() {
+                        return batch::commit_async_test(&db, (*param).clone()).await;
+                    }
+                }
+                batch::commit_async(&db, (*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", commit_batch))
+            .await
+        })
     }
 
-    #[cfg(test)]
-    fn get_collection_id(&self, name: String) -> DbFuture<i32> {
+    fn try_acquire_maintenance_lock(
+        &self,
+        param: params::TryAcquireMaintenanceLock,
+    ) -> DbFuture<results::TryAcquireMaintenanceLock> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.try_acquire_maintenance_lock_async((*param).clone())
+                .map_err(db_op_error!("spanner", try_acquire_maintenance_lock))
+                .await
+        })
+    }
+
+    fn purge_expired_bsos(
+        &self,
+        param: params::PurgeExpiredBsos,
+    ) -> DbFuture<results::PurgeExpiredBsos> {
         let db = self.clone();
-        Box::pin(async move { db.get_collection_id_async(&name).map_err(Into::into).await })
+        Box::pin(async move {
+            db.purge_expired_bsos_async((*param).clone())
+                .map_err(db_op_error!("spanner", purge_expired_bsos))
+                .await
+        })
+    }
+
+    fn purge_expired_batches(
+        &self,
+        param: params::PurgeExpiredBatches,
+    ) -> DbFuture<results::PurgeExpiredBatches> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.purge_expired_batches_async((*param).clone())
+                .map_err(db_op_error!("spanner", purge_expired_batches))
+                .await
+        })
+    }
+
+    fn get_collections(&self, param: params::GetCollections) -> DbFuture<results::GetCollections> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.get_collections_async((*param).clone())
+                .map_err(db_op_error!("spanner", get_collections))
+                .await
+        })
+    }
+
+    fn get_collection_id(&self, name: params::GetCollectionId) -> DbFuture<i32> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.get_collection_id_async(&name)
+                .map_err(db_op_error!("spanner", get_collection_id))
+                .await
+        })
+    }
+
+    fn get_or_create_collection_id(
+        &self,
+        param: params::GetOrCreateCollectionId,
+    ) -> DbFuture<results::GetOrCreateCollectionId> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                let id = db
+                    .get_or_create_collection_id_async(&param.collection)
+                    .await?;
+                db.check_collection_limit_async(&param.user_id, id).await?;
+                Ok(id)
+            })
+            .map_err(db_op_error!("spanner", get_or_create_collection_id))
+            .await
+        })
+    }
+
+    fn count_batches(&self, params: params::CountBatches) -> DbFuture<results::CountBatches> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.count_batches_async(params)
+                .map_err(db_op_error!("spanner", count_batches))
+                .await
+        })
+    }
+
+    fn get_blob_references(&self) -> DbFuture<results::GetBlobReferences> {
+        let db = self.clone();
+        Box::pin(async move {
+            db.get_blob_references_async()
+                .map_err(db_op_error!("spanner", get_blob_references))
+                .await
+        })
     }
 
     #[cfg(test)]
     fn create_collection(&self, name: String) -> DbFuture<i32> {
         let db = self.clone();
-        Box::pin(async move { db.create_collection_async(&name).map_err(Into::into).await })
+        Box::pin(async move {
+            db.create_collection_async(&name)
+                .map_err(db_op_error!("spanner", create_collection))
+                .await
+        })
     }
 
     #[cfg(test)]
@@ -1826,30 +3674,49 @@ impl Db for SpannerDb {
         let db = self.clone();
         Box::pin(async move {
             db.touch_collection_async(&param.user_id, param.collection_id)
-                .map_err(Into::into)
+                .map_err(db_op_error!("spanner", touch_collection))
                 .await
         })
     }
 
-    #[cfg(test)]
     fn timestamp(&self) -> SyncTimestamp {
-        self.timestamp()
-            .expect("set_timestamp() not called yet for SpannerDb")
+        // Read-only transactions fix it at their beginning, writes when
+        // locking: otherwise fix it upon first use
+        *self
+            .session
+            .borrow_mut()
+            .timestamp
+            .get_or_insert_with(SyncTimestamp::default)
     }
 
-    #[cfg(test)]
     fn set_timestamp(&self, timestamp: SyncTimestamp) {
-        SpannerDb::set_timestamp(self, timestamp)
+        SpannerDb::set_timestamp(self, timestamp);
+        self.session.borrow_mut().fixed_timestamp = true;
+    }
+
+    fn set_metrics_collection(&mut self, collection: &str) {
+        self.metrics = self.metrics.clone().with_collection(collection);
     }
 
     #[cfg(test)]
     fn delete_batch(&self, param: params::DeleteBatch) -> DbFuture<results::DeleteBatch> {
         let db = self.clone();
-        Box::pin(async move { batch::delete_async(&db, param).map_err(Into::into).await })
+        Box::pin(async move {
+            db.retrying(param, |db, param| async move {
+                batch::delete_async(&db, (*param).clone()).await
+            })
+            .map_err(db_op_error!("spanner", delete_batch))
+            .await
+        })
     }
 
     #[cfg(test)]
     fn clear_coll_cache(&self) {
         self.coll_cache.clear();
     }
+
+    #[cfg(test)]
+    fn cached_collection_id(&self, name: &str) -> Option<i32> {
+        self.coll_cache.get_id(name).unwrap_or_default()
+    }
 }
@@ -1,11 +1,7 @@
 use actix_web::web::block;
-use futures::future::TryFutureExt;
+use futures::{executor::block_on, future::TryFutureExt};
 
-use std::{
-    collections::HashMap,
-    fmt,
-    sync::{Arc, RwLock},
-};
+use std::{fmt, sync::Arc, thread, time::Duration};
 
 use diesel::r2d2;
 use diesel::r2d2::Pool;
@@ -15,23 +11,29 @@ use scheduled_thread_pool::ScheduledThreadPool;
 use super::models::Result;
 #[cfg(test)]
 use super::test_util::SpannerTestTransactionCustomizer;
-use crate::db::{error::DbError, results, Db, DbFuture, DbPool, STD_COLLS};
+use crate::db::{
+    cache::CollectionCache,
+    codec::{self, PayloadCodec},
+    error::DbError,
+    results, standard_collections,
+    util::{acquire_conn, build_pool, PoolHealth, QueryPlanSampler},
+    Db, DbFuture, DbPool,
+};
 use crate::server::metrics::Metrics;
 use crate::settings::Settings;
 
+use super::emulator::create_database;
 use super::manager::SpannerConnectionManager;
+use super::migrations::{check_migrations, run_migrations};
 use super::models::SpannerDb;
+use super::write_queue::WriteQueue;
 
-embed_migrations!();
+/// Spanner deletes sessions idle for over an hour: recycle pooled ones well
+/// before then
+const SESSION_MAX_LIFETIME: Duration = Duration::from_secs(30 * 60);
 
-/// Run the diesel embedded migrations
-///
-/// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
-/// begin_test_transaction during tests. So this runs on its own separate conn.
-//pub fn run_embedded_migrations(settings: &Settings) -> Result<()> {
-//    let conn = MysqlConnection::establish(&settings.database_url)?;
-//    Ok(embedded_migrations::run(&conn)?)
-//}
+/// Delay between background attempts at creating the standard collections
+const STANDARD_COLLECTIONS_RETRY: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct SpannerDbPool {
@@ -41,27 +43,99 @@ pub struct SpannerDbPool {
     coll_cache: Arc<CollectionCache>,
 
     metrics: Metrics,
+    /// Sortindex applied to newly created BSOs lacking one
+    default_sortindex: Option<i32>,
+    /// Limit of custom collections per user
+    max_collections_per_user: Option<u32>,
+    /// Rate limiter for logging query plans
+    query_plans: Arc<QueryPlanSampler>,
+    /// Health statistics of the pool
+    health: Arc<PoolHealth<SpannerConnectionManager>>,
+    /// Log waits for a connection longer than this
+    acquire_warn: Duration,
+    /// Codec applied to payloads at rest
+    codec: Arc<dyn PayloadCodec>,
+    /// Queue serializing writes to the same collection
+    write_queue: Arc<WriteQueue>,
+    /// Staleness of non-strong read-only transactions (strong when `None`)
+    read_staleness: Option<Duration>,
 }
 
 impl SpannerDbPool {
-    /// Creates a new pool of Mysql db connections.
+    /// Creates a new pool of Spanner db connections.
+    ///
+    /// Also initializes the Spanner db, ensuring all migrations are ran (or
+    /// were, when `run_migrations` is disabled) and the standard collections
+    /// exist. A database on the emulator is first created when
+    /// `database_spanner_emulator_create` is enabled (always running its
+    /// migrations).
     ///
-    /// Also initializes the Mysql db, ensuring all migrations are ran.
+    /// Without `database_pool_warmup`, failing to create the standard
+    /// collections doesn't fail startup: it's retried in the background,
+    /// the pool reporting itself as warming up (see `PoolHealth`) until it
+    /// succeeds.
     pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
-        //run_embedded_migrations(settings)?;
-        Self::new_without_migrations(settings, metrics)
+        let create = settings.database_spanner_emulator_create;
+        if create {
+            create_database(settings)?;
+        }
+        if settings.run_migrations() || create {
+            run_migrations(settings)?;
+        } else {
+            check_migrations(settings)?;
+        }
+        let pool = Self::new_without_migrations(settings, metrics)?;
+        let collections = standard_collections(settings)?;
+        if let Err(e) = pool.create_standard_collections(&collections) {
+            if settings.database_pool_warmup {
+                return Err(e);
+            }
+            warn!(
+                "Creating the standard collections failed, retrying in the background: {}",
+                e
+            );
+            pool.health.set_initialized(false);
+            let pool = pool.clone();
+            thread::spawn(move || {
+                while let Err(e) = pool.create_standard_collections(&collections) {
+                    warn!("Creating the standard collections failed: {}", e);
+                    thread::sleep(STANDARD_COLLECTIONS_RETRY);
+                }
+                pool.health.set_initialized(true);
+                info!("Created the standard collections");
+            });
+        }
+        Ok(pool)
+    }
+
+    /// Create the standard collections, on a dedicated thread: `new` may be
+    /// called from within an executor (e.g. the db tests'), where blocking on
+    /// another panics
+    fn create_standard_collections(&self, collections: &[(i32, String)]) -> Result<()> {
+        let pool = self.clone();
+        let collections = collections.to_vec();
+        thread::spawn(move || {
+            block_on(
+                pool.get_sync()?
+                    .create_standard_collections_async(&collections),
+            )
+        })
+        .join()
+        .map_err(|_| DbError::internal("Creating the standard collections panicked"))?
     }
 
     pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
-        let manager = SpannerConnectionManager::new(settings)?;
+        let manager = SpannerConnectionManager::new(settings, metrics)?;
         let max_size = settings.database_pool_max_size.unwrap_or(10);
-        // r2d2 creates max_size count of db connections on creation via its
-        // own thread_pool. increase its default size to quicken their
-        // creation, accommodating large max_size values (otherwise it may
-        // timeout)
+        // r2d2 creates min_idle (by default max_size) count of db
+        // connections on creation via its own thread_pool. increase its
+        // default size to quicken their creation, accommodating large
+        // max_size values (otherwise it may timeout)
         let r2d2_thread_pool_size = ((max_size as f32 * 0.05) as usize).max(3);
         let builder = r2d2::Pool::builder()
             .max_size(max_size)
+            .min_idle(settings.database_pool_min_idle.map(|n| n.min(max_size)))
+            .max_lifetime(Some(SESSION_MAX_LIFETIME))
             .thread_pool(Arc::new(ScheduledThreadPool::new(r2d2_thread_pool_size)));
 
         #[cfg(test)]
@@ -71,24 +145,45 @@ impl SpannerDbPool {
             builder
         };
 
+        let pool = build_pool(builder, manager, settings.database_pool_warmup)?;
         Ok(Self {
-            pool: builder.build(manager)?,
-            coll_cache: Default::default(),
+            health: Arc::new(PoolHealth::new(pool.clone())),
+            pool,
+            coll_cache: Arc::new(CollectionCache::new(&standard_collections(settings)?)),
             metrics: metrics.clone(),
+            default_sortindex: settings.default_sortindex,
+            max_collections_per_user: settings.max_collections_per_user,
+            query_plans: Arc::new(QueryPlanSampler::new(settings.database_query_plan_interval)),
+            acquire_warn: Duration::from_millis(settings.database_pool_acquire_warn_ms),
+            codec: codec::from_settings(settings)?,
+            write_queue: Arc::new(WriteQueue::new(settings.database_write_queue_max_entries)),
+            read_staleness: Some(settings.database_read_staleness_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
         })
     }
 
     pub fn get_sync(&self) -> Result<SpannerDb> {
         Ok(SpannerDb::new(
-            self.pool.get()?,
+            acquire_conn(&self.pool, &self.metrics, self.acquire_warn)?,
             Arc::clone(&self.coll_cache),
             &self.metrics,
+            self.default_sortindex,
+            self.max_collections_per_user,
+            Arc::clone(&self.query_plans),
+            Arc::clone(&self.health),
+            Arc::clone(&self.codec),
+            Arc::clone(&self.write_queue),
+            self.read_staleness,
         ))
     }
 }
 
 impl DbPool for SpannerDbPool {
     fn get(&self) -> DbFuture<Box<dyn Db>> {
+        // SpannerDb's calls are all async, only checking out a connection
+        // (which r2d2 validates via a blocking GetSession call, or creates)
+        // is moved off the event loop
         let pool = self.clone();
         Box::pin(
             block(move || {
@@ -101,12 +196,27 @@ impl DbPool for SpannerDbPool {
     }
 
     fn state(&self) -> results::PoolState {
-        self.pool.state().into()
+        let state = self.pool.state();
+        results::PoolState {
+            collection_cache: self.coll_cache.stats(),
+            ..state.into()
+        }
     }
 
     fn box_clone(&self) -> Box<dyn DbPool> {
         Box::new(self.clone())
     }
+
+    fn warm_collection_cache(&self, batch_size: u32) -> DbFuture<usize> {
+        let pool = self.clone();
+        Box::pin(async move {
+            let db = pool.get().await?;
+            db.begin(false).await?;
+            let count = pool.coll_cache.warm(&*db, batch_size).await?;
+            db.commit().await?;
+            Ok(count)
+        })
+    }
 }
 
 impl fmt::Debug for SpannerDbPool {
@@ -115,68 +225,484 @@ impl fmt::Debug for SpannerDbPool {
     }
 }
 
-#[derive(Debug)]
-pub struct CollectionCache {
-    pub by_name: RwLock<HashMap<String, i32>>,
-    pub by_id: RwLock<HashMap<i32, String>>,
-}
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use actix_rt::time::delay_for;
+    use futures::{executor::block_on, future::join_all, join};
+    use googleapis_raw::spanner::v1::spanner::DeleteSessionRequest;
+
+    use super::SpannerDbPool;
+    use crate::db::{
+        params,
+        spanner::{models::SpannerDb, support::as_value},
+        tests::support::{gbso, gcounts, hid, pbso, uid, Backend},
+        Db,
+    };
+    use crate::server::metrics::Metrics;
+    use crate::settings::Settings;
+    use crate::web::extractors::BsoQueryParams;
 
-impl CollectionCache {
-    pub fn put(&self, id: i32, name: String) -> Result<()> {
-        // XXX: should this emit a metric?
-        // XXX: should probably either lock both simultaneously during
-        // writes or use an RwLock alternative
-        self.by_name
-            .write()
-            .map_err(|_| DbError::internal("by_name write"))?
-            .insert(name.clone(), id);
-        self.by_id
-            .write()
-            .map_err(|_| DbError::internal("by_id write"))?
-            .insert(id, name);
-        Ok(())
-    }
-
-    pub fn get_id(&self, name: &str) -> Result<Option<i32>> {
-        Ok(self
-            .by_name
-            .read()
-            .map_err(|_| DbError::internal("by_name read"))?
-            .get(name)
-            .cloned())
-    }
-
-    pub fn get_name(&self, id: i32) -> Result<Option<String>> {
-        Ok(self
-            .by_id
-            .read()
-            .map_err(|_| DbError::internal("by_id read"))?
-            .get(&id)
-            .cloned())
-    }
-
-    #[cfg(test)]
-    pub fn clear(&self) {
-        self.by_name.write().expect("by_name write").clear();
-        self.by_id.write().expect("by_id write").clear();
+    fn session_name(db: &SpannerDb) -> String {
+        db.conn.session.get_name().to_owned()
     }
-}
 
-impl Default for CollectionCache {
-    fn default() -> Self {
-        Self {
-            by_name: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| ((*v).to_owned(), *k))
-                    .collect(),
-            ),
-            by_id: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| (*k, (*v).to_owned()))
-                    .collect(),
-            ),
+    /// Delete the db's session server side (as Spanner does to idle ones)
+    fn delete_session(db: &SpannerDb) {
+        let mut req = DeleteSessionRequest::new();
+        req.set_name(session_name(db));
+        db.conn
+            .client
+            .delete_session_opt(&req, db.conn.call_option().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn lost_sessions_replaced() {
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+
+        // Lost while idle in the pool: replaced on checkout
+        let db = pool.get_sync().unwrap();
+        let name = session_name(&db);
+        delete_session(&db);
+        drop(db);
+        let db = pool.get_sync().unwrap();
+        assert_ne!(session_name(&db), name);
+        block_on(db.check()).unwrap();
+
+        // Lost while in use: that request fails but the session's evicted
+        delete_session(&db);
+        assert!(block_on(db.check()).is_err());
+        drop(db);
+        let db = pool.get_sync().unwrap();
+        block_on(db.check()).unwrap();
+    }
+
+    /// Two devices writing to the same collection at once both succeed: the
+    /// transaction Spanner aborts is retried
+    #[actix_rt::test]
+    async fn contending_writes_retried() {
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            database_pool_max_size: Some(3),
+            // Spanner only detects the contention upon commit, and the write
+            // queue would otherwise serialize the writes
+            database_use_test_transactions: false,
+            database_write_queue_max_entries: 0,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let uid = uid();
+        let coll = "clients";
+        let lock = || params::LockCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        };
+
+        let db = pool.get_sync().unwrap();
+        db.lock_for_write(lock()).await.unwrap();
+        db.put_bso(pbso(uid, coll, "b0", Some("0"), None, None))
+            .await
+            .unwrap();
+        db.commit().await.unwrap();
+
+        let (db1, db2) = (pool.get_sync().unwrap(), pool.get_sync().unwrap());
+        db1.lock_for_write(lock()).await.unwrap();
+        let first = async {
+            db1.put_bso(pbso(uid, coll, "b1", Some("1"), None, None))
+                .await?;
+            // Give the second writer time to begin contending
+            delay_for(Duration::from_millis(100)).await;
+            db1.commit().await
+        };
+        let second = async {
+            db2.lock_for_write(lock()).await?;
+            db2.put_bso(pbso(uid, coll, "b2", Some("2"), None, None))
+                .await?;
+            db2.commit().await
+        };
+        let (first, second) = join!(first, second);
+        first.unwrap();
+        second.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        for id in &["b0", "b1", "b2"] {
+            assert!(db.get_bso(gbso(uid, coll, id)).await.unwrap().is_some());
         }
+        db.delete_storage(hid(uid)).await.unwrap();
+        db.commit().await.unwrap();
+    }
+
+    /// Deletes via Partitioned DML (outside of the request's transaction)
+    /// still commit along with the rest of the request
+    #[actix_rt::test]
+    async fn partitioned_deletes() {
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            // Partitioned DML only sees committed data
+            database_use_test_transactions: false,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let uid = uid();
+        let colls = ["clients", "bookmarks", "history", "prefs", "tabs"];
+        let lock = |coll: &str| params::LockCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        };
+
+        for &coll in &colls {
+            let db = pool.get_sync().unwrap();
+            db.lock_for_write(lock(coll)).await.unwrap();
+            for i in 0..10 {
+                db.put_bso(pbso(uid, coll, &i.to_string(), Some("x"), None, None))
+                    .await
+                    .unwrap();
+            }
+            db.commit().await.unwrap();
+        }
+
+        // The restarted transaction keeps its lock (and timestamp)
+        let db = pool.get_sync().unwrap();
+        db.lock_for_write(lock("clients")).await.unwrap();
+        let modified = db
+            .delete_collection(params::DeleteCollection {
+                user_id: hid(uid),
+                collection: "clients".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(modified, db.timestamp().unwrap());
+        db.put_bso(pbso(uid, "clients", "new", Some("x"), None, None))
+            .await
+            .unwrap();
+        db.commit().await.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        let counts = db.get_collection_counts(gcounts(uid)).await.unwrap();
+        assert_eq!(counts.get("clients"), Some(&1));
+        assert_eq!(counts.get("tabs"), Some(&10));
+        db.commit().await.unwrap();
+
+        // Idempotent: converges when retried
+        for _ in 0..2 {
+            let db = pool.get_sync().unwrap();
+            db.begin(true).await.unwrap();
+            db.delete_storage(hid(uid)).await.unwrap();
+            db.commit().await.unwrap();
+        }
+        let db = pool.get_sync().unwrap();
+        assert!(db
+            .get_collection_counts(gcounts(uid))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.get_storage_usage(hid(uid)).await.unwrap(), 0);
+    }
+
+    /// Reads are of a stale snapshot, unless strong ones are required
+    #[actix_rt::test]
+    async fn stale_reads() {
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            // Snapshots only include committed data
+            database_use_test_transactions: false,
+            database_read_staleness_ms: 60_000,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let uid = uid();
+        let coll = "clients";
+        let lock = |strong| params::LockCollectionForRead {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            strong,
+        };
+
+        let db = pool.get_sync().unwrap();
+        db.lock_for_write(params::LockCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await
+        .unwrap();
+        let modified = db
+            .put_bso(pbso(uid, coll, "b0", Some("0"), None, None))
+            .await
+            .unwrap();
+        db.commit().await.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        db.lock_for_read(lock(false)).await.unwrap();
+        assert!(db.get_bso(gbso(uid, coll, "b0")).await.unwrap().is_none());
+        assert!(db.timestamp().unwrap() < modified);
+        db.commit().await.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        db.lock_for_read(lock(true)).await.unwrap();
+        assert!(db.get_bso(gbso(uid, coll, "b0")).await.unwrap().is_some());
+        db.commit().await.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        db.delete_storage(hid(uid)).await.unwrap();
+        db.commit().await.unwrap();
+    }
+
+    /// Large collections are read a PartialResultSet at a time, never
+    /// buffering all of their rows at once
+    #[actix_rt::test]
+    async fn streamed_reads() {
+        const ROWS: usize = 50_000;
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            // Written by several transactions (each beneath Spanner's
+            // mutation limit)
+            database_use_test_transactions: false,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let uid = uid();
+        let coll = "clients";
+        let user_id = hid(uid);
+
+        let db = pool.get_sync().unwrap();
+        db.lock_for_write(params::LockCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await
+        .unwrap();
+        db.put_bso(pbso(uid, coll, "b", Some("x"), None, None))
+            .await
+            .unwrap();
+        let collection_id = db.get_collection_id_async(coll).await.unwrap();
+        db.commit().await.unwrap();
+        let filter = format!(
+            "fxa_uid = '{}' AND fxa_kid = '{}' AND collection_id = {}",
+            user_id.fxa_uid, user_id.fxa_kid, collection_id
+        );
+
+        // ~1KB rows: roughly a thousand per PartialResultSet
+        for start in (0..ROWS).step_by(2_000) {
+            let db = pool.get_sync().unwrap();
+            db.begin(true).await.unwrap();
+            db.sql(&format!(
+                "INSERT INTO bsos (fxa_uid, fxa_kid, collection_id, bso_id, payload, modified,
+                                   expiry)
+                 SELECT '{}', '{}', {}, CONCAT('b', CAST(i AS STRING)), REPEAT('x', 1024),
+                        CURRENT_TIMESTAMP(),
+                        TIMESTAMP_ADD(CURRENT_TIMESTAMP(), INTERVAL 1 DAY)
+                   FROM UNNEST(GENERATE_ARRAY({}, {})) AS i",
+                user_id.fxa_uid,
+                user_id.fxa_kid,
+                collection_id,
+                start,
+                start + 1_999
+            ))
+            .await
+            .unwrap()
+            .execute_dml_async(&db.conn)
+            .await
+            .unwrap();
+            db.commit().await.unwrap();
+        }
+
+        let db = pool.get_sync().unwrap();
+        let get_bsos = |limit, max_bytes| params::GetBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            params: BsoQueryParams {
+                limit,
+                full: true,
+                max_bytes,
+                ..Default::default()
+            },
+        };
+        // Read no further than a page's extra row
+        let page = db.get_bsos(get_bsos(Some(1_000), None)).await.unwrap();
+        assert_eq!(page.items.len(), 1_000);
+        assert!(page.offset.is_some());
+        assert!(!page.truncated);
+        // Nor past the response's byte ceiling (~10 rows)
+        let page = db
+            .get_bsos(get_bsos(Some(1_000), Some(10_000)))
+            .await
+            .unwrap();
+        assert!(page.items.len() <= 10, "{}", page.items.len());
+        assert!(page.truncated);
+        assert_eq!(page.offset, Some(page.items.len().to_string()));
+        let all = db.get_bsos(get_bsos(None, None)).await.unwrap();
+        assert_eq!(all.items.len(), ROWS + 1);
+        assert!(all.offset.is_none());
+
+        let mut stream = db
+            .sql(&format!(
+                "SELECT bso_id, payload FROM bsos WHERE {}",
+                filter
+            ))
+            .await
+            .unwrap()
+            .execute_async(&db.conn)
+            .unwrap();
+        let mut rows = 0;
+        while let Some(row) = stream.next_async().await {
+            row.unwrap();
+            rows += 1;
+        }
+        assert_eq!(rows, ROWS + 1);
+        assert!(stream.peak_rows() < ROWS / 10, "{}", stream.peak_rows());
+        db.commit().await.unwrap();
+
+        let db = pool.get_sync().unwrap();
+        db.delete_storage(hid(uid)).await.unwrap();
+        db.commit().await.unwrap();
+    }
+
+    /// Concurrently creating the same new collection allocates it a single
+    /// id
+    #[actix_rt::test]
+    async fn create_collection_race() {
+        const TASKS: u32 = 20;
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            // A connection per task (acquired synchronously)
+            database_pool_max_size: Some(TASKS + 1),
+            database_pool_min_idle: Some(1),
+            database_use_test_transactions: false,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let coll = format!("race{}", uid());
+
+        let tasks = (0..TASKS).map(|_| {
+            let db = pool.get_sync().unwrap();
+            let coll = coll.clone();
+            async move {
+                // Creates the collection (when missing)
+                db.lock_for_write(params::LockCollection {
+                    user_id: hid(uid()),
+                    collection: coll.clone(),
+                })
+                .await
+                .unwrap();
+                db.commit().await.unwrap();
+                db.get_collection_id(coll).await.unwrap()
+            }
+        });
+        let ids = join_all(tasks).await;
+        assert!(ids.iter().all(|&id| id == ids[0]));
+
+        let db = pool.get_sync().unwrap();
+        db.begin(true).await.unwrap();
+        let mut sqlparams = HashMap::new();
+        sqlparams.insert("name".to_owned(), as_value(coll.clone()));
+        let mut streaming = db
+            .sql(
+                "SELECT collection_id
+                   FROM collections
+                  WHERE name = @name",
+            )
+            .await
+            .unwrap()
+            .params(sqlparams.clone())
+            .execute_async(&db.conn)
+            .unwrap();
+        let mut rows = 0;
+        while let Some(row) = streaming.next_async().await {
+            assert_eq!(row.unwrap()[0].get_string_value(), ids[0].to_string());
+            rows += 1;
+        }
+        assert_eq!(rows, 1);
+
+        db.sql("DELETE FROM collections WHERE name = @name")
+            .await
+            .unwrap()
+            .params(sqlparams)
+            .execute_dml_async(&db.conn)
+            .await
+            .unwrap();
+        db.commit().await.unwrap();
+    }
+
+    /// Writes record their commit's timestamp: rapidly sequential writes'
+    /// timestamps still strictly increase, and are the values read back
+    #[actix_rt::test]
+    async fn commit_timestamps() {
+        const WRITES: usize = 10;
+        let settings = match Backend::Spanner.settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+        let settings = Settings {
+            database_use_test_transactions: false,
+            ..settings
+        };
+        let pool = SpannerDbPool::new_without_migrations(&settings, &Metrics::noop()).unwrap();
+        let uid = uid();
+        let coll = "clients";
+
+        let mut timestamps = vec![];
+        while timestamps.len() < WRITES {
+            let db = pool.get_sync().unwrap();
+            let locked = db
+                .lock_for_write(params::LockCollection {
+                    user_id: hid(uid),
+                    collection: coll.to_owned(),
+                })
+                .await;
+            match locked {
+                Ok(()) => (),
+                // Within the same 10 milliseconds as the previous write
+                Err(e) if e.is_conflict() => {
+                    db.rollback().await.unwrap();
+                    continue;
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+            let locked_at = Db::timestamp(&db);
+            let collection_id = db.get_collection_id_async(coll).await.unwrap();
+            db.touch_collection_mutation_async(&hid(uid), collection_id)
+                .await
+                .unwrap();
+            db.commit().await.unwrap();
+            let modified = Db::timestamp(&db);
+            assert!(modified >= locked_at);
+
+            let db = pool.get_sync().unwrap();
+            let read = db
+                .get_collection_timestamp(params::GetCollectionTimestamp {
+                    user_id: hid(uid),
+                    collection: coll.to_owned(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(read, modified);
+            timestamps.push(modified);
+        }
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let db = pool.get_sync().unwrap();
+        db.delete_storage(hid(uid)).await.unwrap();
+        db.commit().await.unwrap();
     }
 }
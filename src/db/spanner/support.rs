@@ -2,22 +2,29 @@ use std::{
     collections::{HashMap, VecDeque},
     mem,
     result::Result as StdResult,
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
 };
 
 use futures::stream::{StreamExt, StreamFuture};
 use googleapis_raw::spanner::v1::{
+    mutation::{Mutation, Mutation_Write},
     result_set::{PartialResultSet, ResultSetMetadata, ResultSetStats},
-    spanner::ExecuteSqlRequest,
+    spanner::{ExecuteSqlRequest, ExecuteSqlRequest_QueryMode},
     type_pb::{StructType_Field, Type, TypeCode},
 };
 use grpcio::ClientSStreamReceiver;
 use protobuf::{
-    well_known_types::{ListValue, NullValue, Struct, Value},
+    well_known_types::{ListValue, NullValue, Struct, Timestamp, Value},
     RepeatedField,
 };
 
-use super::models::{Conn, Result};
+use super::{
+    manager::{check_session, record_rpc},
+    models::{Conn, Result},
+};
 use crate::db::{results, util::SyncTimestamp, DbError, DbErrorKind};
+use crate::server::metrics::Metrics;
 
 use crate::{
     db::{params, spanner::models::DEFAULT_BSO_TTL, util::to_rfc3339},
@@ -102,31 +109,91 @@ impl ExecuteSqlRequestBuilder {
 
     /// Execute a SQL read statement but return a non-blocking streaming result
     pub fn execute_async(self, conn: &Conn) -> Result<StreamedResultSetAsync> {
+        let request = self.prepare_request(conn);
         let stream = conn
             .client
-            .execute_streaming_sql(&self.prepare_request(conn))?;
-        Ok(StreamedResultSetAsync::new(stream))
+            .execute_streaming_sql_opt(&request, conn.call_option()?)?;
+        let mut rs =
+            StreamedResultSetAsync::new(stream, Arc::clone(&conn.lost), conn.metrics.clone());
+        rs.profiled = profiled_query(&request);
+        Ok(rs)
     }
 
     /// Execute a DML statement, returning the exact count of modified rows
     pub async fn execute_dml_async(self, conn: &Conn) -> Result<i64> {
+        let request = self.prepare_request(conn);
         let rs = conn
-            .client
-            .execute_sql_async(&self.prepare_request(conn))?
+            .call("ExecuteSql", |client, opt| {
+                client.execute_sql_async_opt(&request, opt)
+            })
             .await?;
+        if let Some(query) = profiled_query(&request) {
+            log_query_plan(query, rs.get_stats());
+        }
         Ok(rs.get_stats().get_row_count_exact())
     }
 }
 
+/// Describe the request's statement and bound parameters when it was sampled
+/// for profiling (see `SpannerDb::sql_request`).
+///
+/// Only the parameters' names and types are described: their values may be
+/// user data (ids, payloads).
+fn profiled_query(request: &ExecuteSqlRequest) -> Option<String> {
+    if request.get_query_mode() != ExecuteSqlRequest_QueryMode::PROFILE {
+        return None;
+    }
+    let mut params: Vec<_> = request
+        .get_params()
+        .get_fields()
+        .keys()
+        .map(|name| {
+            // Untyped params are bound as strings
+            let code = request
+                .get_param_types()
+                .get(name)
+                .map_or(TypeCode::STRING, Type::get_code);
+            format!("{}: {:?}", name, code)
+        })
+        .collect();
+    params.sort();
+    Some(format!(
+        "{} -- params: {}",
+        request.get_sql(),
+        params.join(", ")
+    ))
+}
+
+fn log_query_plan(query: String, stats: &ResultSetStats) {
+    info!(
+        "Query plan";
+        "query" => query,
+        "plan" => format!("{:?}", stats.get_query_plan()),
+        "stats" => format!("{:?}", stats.get_query_stats())
+    );
+}
+
 pub struct StreamedResultSetAsync {
     /// Stream from execute_streaming_sql
     stream: Option<StreamFuture<ClientSStreamReceiver<PartialResultSet>>>,
 
     metadata: Option<ResultSetMetadata>,
     stats: Option<ResultSetStats>,
-
-    /// Fully-processed rows
+    /// Description of the query when it was sampled for profiling
+    profiled: Option<String>,
+    /// Flags the executing session as lost when the stream fails due to it
+    session_lost: Arc<AtomicBool>,
+    metrics: Metrics,
+    /// When the stream was opened (until its RPC's latency is recorded, once
+    /// it's finished or failed)
+    started: Option<Instant>,
+
+    /// Fully-processed rows (of the latest PartialResultSet: they're
+    /// consumed before the next is pulled from the stream)
     rows: VecDeque<Vec<Value>>,
+    /// The most `rows` buffered at once
+    #[cfg(test)]
+    peak_rows: usize,
     /// Accumulated values for incomplete row
     current_row: Vec<Value>,
     /// Incomplete value
@@ -134,12 +201,22 @@ pub struct StreamedResultSetAsync {
 }
 
 impl StreamedResultSetAsync {
-    pub fn new(stream: ClientSStreamReceiver<PartialResultSet>) -> Self {
+    pub fn new(
+        stream: ClientSStreamReceiver<PartialResultSet>,
+        session_lost: Arc<AtomicBool>,
+        metrics: Metrics,
+    ) -> Self {
         Self {
             stream: Some(stream.into_future()),
             metadata: None,
             stats: None,
+            profiled: None,
+            session_lost,
+            metrics,
+            started: Some(Instant::now()),
             rows: Default::default(),
+            #[cfg(test)]
+            peak_rows: 0,
             current_row: vec![],
             pending_chunk: None,
         }
@@ -155,6 +232,22 @@ impl StreamedResultSetAsync {
         self.stats.as_ref()
     }
 
+    #[cfg(test)]
+    pub fn peak_rows(&self) -> usize {
+        self.peak_rows
+    }
+
+    /// Stop streaming once the caller has read enough: the remainder of the
+    /// result set is discarded (and no longer sent by Spanner)
+    pub fn cancel(&mut self) {
+        if let Some(mut stream) = self.stream.take().and_then(StreamFuture::into_inner) {
+            stream.cancel();
+        }
+        self.rows.clear();
+        self.current_row.clear();
+        self.pending_chunk = None;
+    }
+
     pub fn fields(&self) -> &[StructType_Field] {
         match self.metadata {
             Some(ref metadata) => metadata.get_row_type().get_fields(),
@@ -183,20 +276,26 @@ impl StreamedResultSetAsync {
 
     /// Pull and process the next values from the Stream
     ///
-    /// Returns false when the stream is finished
+    /// Returns false when the stream is finished (or was cancelled)
     async fn consume_next(&mut self) -> Result<bool> {
-        let (result, stream) = self
-            .stream
-            .take()
-            .expect("Could not get next stream element")
-            .await;
+        let (result, stream) = match self.stream.take() {
+            Some(stream) => stream.await,
+            None => return Ok(false),
+        };
 
         self.stream = Some(stream.into_future());
-        let mut partial_rs = if let Some(result) = result {
-            result?
-        } else {
-            // Stream finished
-            return Ok(false);
+        let mut partial_rs = match result {
+            Some(result) => {
+                if let Err(ref e) = result {
+                    self.record_rpc(Some(e));
+                }
+                check_session(&self.session_lost, result)?
+            }
+            None => {
+                // Stream finished
+                self.record_rpc(None);
+                return Ok(false);
+            }
         };
 
         if self.metadata.is_none() && partial_rs.has_metadata() {
@@ -205,7 +304,11 @@ impl StreamedResultSetAsync {
         }
         if partial_rs.has_stats() {
             // last response
-            self.stats = Some(partial_rs.take_stats());
+            let stats = partial_rs.take_stats();
+            if let Some(query) = self.profiled.take() {
+                log_query_plan(query, &stats);
+            }
+            self.stats = Some(stats);
         }
 
         let mut values = partial_rs.take_values().into_vec();
@@ -233,6 +336,17 @@ impl StreamedResultSetAsync {
         Ok(true)
     }
 
+    fn record_rpc(&mut self, error: Option<&grpcio::Error>) {
+        if let Some(started) = self.started.take() {
+            record_rpc(
+                &self.metrics,
+                "ExecuteStreamingSql",
+                started.elapsed(),
+                error,
+            );
+        }
+    }
+
     fn consume_values(&mut self, values: Vec<Value>) {
         let width = self.fields().len();
         for value in values {
@@ -242,6 +356,10 @@ impl StreamedResultSetAsync {
                 self.rows.push_back(current_row);
             }
         }
+        #[cfg(test)]
+        {
+            self.peak_rows = self.peak_rows.max(self.rows.len());
+        }
     }
 
     // We could implement Stream::poll_next instead of this, but
@@ -382,6 +500,131 @@ pub fn bso_to_update_row(
     Ok((columns, row))
 }
 
+/// Spanner's limit on the mutations in a single commit: each column written
+/// to each row (and each secondary index entry) counts as one
+pub const MAX_MUTATIONS_PER_COMMIT: usize = 20_000;
+
+/// Mutations left to each commit's other writes (e.g. the user_collections
+/// row, deleting the batch)
+pub const RESERVED_MUTATIONS: usize = 100;
+
+/// The bsos columns written by an insert
+pub const BSO_COLUMNS: [&str; 8] = [
+    "fxa_uid",
+    "fxa_kid",
+    "collection_id",
+    "bso_id",
+    "sortindex",
+    "payload",
+    "modified",
+    "expiry",
+];
+
+/// The bsos table's secondary indexes (BsoModified, BsoExpiry), each
+/// written along with its rows
+pub const BSO_INDEXES: usize = 2;
+
+/// The mutations deleting a bsos row counts for (the row and its index
+/// entries)
+pub const BSO_DELETE_MUTATIONS: usize = 1 + BSO_INDEXES;
+
+/// A BSO's write to the bsos table
+pub enum BsoWrite {
+    Insert(ListValue),
+    Update(Vec<&'static str>, ListValue),
+}
+
+impl BsoWrite {
+    /// The number of mutations Spanner counts for the write
+    pub fn mutations(&self) -> usize {
+        let columns = match self {
+            BsoWrite::Insert(_) => BSO_COLUMNS.len(),
+            BsoWrite::Update(columns, _) => columns.len(),
+        };
+        columns + BSO_INDEXES
+    }
+}
+
+/// Split `items` into consecutive chunks of at most `max` mutations each,
+/// failing when a single item exceeds `max` on its own
+pub fn chunk_by_mutations<T>(
+    items: Vec<T>,
+    mutations: impl Fn(&T) -> usize,
+    max: usize,
+) -> Result<Vec<Vec<T>>> {
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut chunk_mutations = 0;
+    for item in items {
+        let item_mutations = mutations(&item);
+        if item_mutations > max {
+            Err(DbErrorKind::SpannerTooLarge(format!(
+                "A single write of {} mutations exceeds the limit of {} per commit",
+                item_mutations, max
+            )))?
+        }
+        if chunk_mutations + item_mutations > max {
+            chunks.push(mem::take(&mut chunk));
+            chunk_mutations = 0;
+        }
+        chunk_mutations += item_mutations;
+        chunk.push(item);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+/// The value a mutation writes to a commit timestamp column (one with the
+/// `allow_commit_timestamp` option) to record the commit's timestamp
+pub const COMMIT_TIMESTAMP: &str = "spanner.commit_timestamp()";
+
+/// Have the mutations' writes of `modified` columns record the commit's
+/// timestamp (in place of the transaction's own, read before committing),
+/// returning whether any did
+///
+/// Spanner's commit timestamps always follow the order of the commits.
+pub fn stamp_commit_timestamp(mutations: &mut [Mutation]) -> bool {
+    let mut stamped = false;
+    for write in mutations.iter_mut().filter_map(mutation_write) {
+        let column = match write
+            .get_columns()
+            .iter()
+            .position(|column| column == "modified")
+        {
+            Some(column) => column,
+            None => continue,
+        };
+        for row in write.mut_values().iter_mut() {
+            row.mut_values()[column] = as_value(COMMIT_TIMESTAMP.to_owned());
+        }
+        stamped = true;
+    }
+    stamped
+}
+
+/// The rows a mutation inserts or updates (None for deletes)
+fn mutation_write(mutation: &mut Mutation) -> Option<&mut Mutation_Write> {
+    if mutation.has_insert() {
+        Some(mutation.mut_insert())
+    } else if mutation.has_update() {
+        Some(mutation.mut_update())
+    } else if mutation.has_insert_or_update() {
+        Some(mutation.mut_insert_or_update())
+    } else if mutation.has_replace() {
+        Some(mutation.mut_replace())
+    } else {
+        None
+    }
+}
+
+/// Convert a timestamp returned by Spanner (e.g. a commit's)
+pub fn sync_timestamp(timestamp: &Timestamp) -> Result<SyncTimestamp> {
+    let millis = timestamp.seconds * 1000 + i64::from(timestamp.nanos) / 1_000_000;
+    SyncTimestamp::from_i64(millis)
+}
+
 #[derive(Clone)]
 pub struct MapAndThenIterator<I, F> {
     iter: I,
@@ -418,3 +661,118 @@ pub trait MapAndThenTrait {
 }
 
 impl<I, T, E> MapAndThenTrait for I where I: Sized + Iterator<Item = StdResult<T, E>> {}
+
+#[cfg(test)]
+mod tests {
+    use googleapis_raw::spanner::v1::{
+        mutation::{Mutation, Mutation_Delete, Mutation_Write},
+        spanner::{ExecuteSqlRequest, ExecuteSqlRequest_QueryMode},
+        type_pb::TypeCode,
+    };
+    use protobuf::{
+        well_known_types::{ListValue, Struct, Timestamp},
+        RepeatedField,
+    };
+
+    use super::{
+        as_type, as_value, chunk_by_mutations, profiled_query, stamp_commit_timestamp,
+        sync_timestamp, BsoWrite, COMMIT_TIMESTAMP, MAX_MUTATIONS_PER_COMMIT, RESERVED_MUTATIONS,
+    };
+    use crate::db::util::SyncTimestamp;
+
+    #[test]
+    fn chunks_by_mutations() {
+        let chunks = chunk_by_mutations(vec![3, 4, 2, 5, 1], |&n| n, 7).unwrap();
+        assert_eq!(chunks, vec![vec![3, 4], vec![2, 5], vec![1]]);
+        assert!(chunk_by_mutations(Vec::<usize>::new(), |&n| n, 7)
+            .unwrap()
+            .is_empty());
+        // A single item over the limit can't be committed at all
+        assert!(chunk_by_mutations(vec![1, 9, 1], |&n| n, 7).is_err());
+    }
+
+    #[test]
+    fn batch_over_mutation_limit() {
+        let max = MAX_MUTATIONS_PER_COMMIT - RESERVED_MUTATIONS;
+        let insert = || BsoWrite::Insert(ListValue::new());
+        let count = max / insert().mutations() + 1;
+        let writes: Vec<_> = (0..count).map(|_| insert()).collect();
+        let chunks = chunk_by_mutations(writes, BsoWrite::mutations, max).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), count - 1);
+        assert_eq!(chunks[1].len(), 1);
+    }
+
+    #[test]
+    fn stamps_commit_timestamps() {
+        let write = |table: &str, columns: &[&str]| {
+            let mut write = Mutation_Write::new();
+            write.set_table(table.to_owned());
+            write.set_columns(RepeatedField::from_vec(
+                columns.iter().map(|&column| column.to_owned()).collect(),
+            ));
+            let mut row = ListValue::new();
+            row.set_values(RepeatedField::from_vec(
+                columns.iter().map(|_| as_value("x".to_owned())).collect(),
+            ));
+            write.set_values(RepeatedField::from_vec(vec![row]));
+            write
+        };
+        let mut touch = Mutation::new();
+        touch.set_insert_or_update(write("user_collections", &["fxa_uid", "modified"]));
+        let mut delete = Mutation::new();
+        delete.set_delete(Mutation_Delete::new());
+
+        let mut mutations = vec![delete.clone()];
+        assert!(!stamp_commit_timestamp(&mut mutations));
+
+        let mut batch = Mutation::new();
+        batch.set_insert(write("batches", &["batch_id", "expiry"]));
+        let mut mutations = vec![touch, delete, batch];
+        assert!(stamp_commit_timestamp(&mut mutations));
+        let values = mutations[0].get_insert_or_update().get_values()[0].get_values();
+        assert_eq!(values[0].get_string_value(), "x");
+        assert_eq!(values[1].get_string_value(), COMMIT_TIMESTAMP);
+        // Writes without a modified column are left alone
+        let values = mutations[2].get_insert().get_values()[0].get_values();
+        assert!(values.iter().all(|value| value.get_string_value() == "x"));
+    }
+
+    #[test]
+    fn commit_timestamp_precision() {
+        let mut timestamp = Timestamp::new();
+        timestamp.set_seconds(1_594_684_800);
+        timestamp.set_nanos(123_456_789);
+        assert_eq!(
+            sync_timestamp(&timestamp).unwrap(),
+            SyncTimestamp::from_milliseconds(1_594_684_800_120)
+        );
+    }
+
+    #[test]
+    fn profiled_query_omits_values() {
+        let mut request = ExecuteSqlRequest::new();
+        request.set_sql("SELECT 1".to_owned());
+        let mut params = Struct::new();
+        params
+            .mut_fields()
+            .insert("fxa_uid".to_owned(), as_value("secret".to_owned()));
+        params
+            .mut_fields()
+            .insert("modified".to_owned(), as_value("secret".to_owned()));
+        request.set_params(params);
+        request
+            .mut_param_types()
+            .insert("modified".to_owned(), as_type(TypeCode::TIMESTAMP));
+        // Only described when sampled for profiling
+        assert_eq!(profiled_query(&request), None);
+
+        request.set_query_mode(ExecuteSqlRequest_QueryMode::PROFILE);
+        let query = profiled_query(&request).unwrap();
+        assert_eq!(
+            query,
+            "SELECT 1 -- params: fxa_uid: STRING, modified: TIMESTAMP"
+        );
+        assert!(!query.contains("secret"));
+    }
+}
@@ -0,0 +1,211 @@
+//! In-process serialization of writes to the same collection.
+//!
+//! Concurrent write transactions on one user's collection contend over the
+//! same user_collections row, with Spanner aborting all but one of them.
+//! Queueing writes to the same (user, collection) on an instance trades a
+//! little latency for fewer of those aborts (writes via other instances may
+//! still contend).
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::channel::oneshot;
+
+use super::models::Result;
+use crate::db::error::DbError;
+use crate::web::extractors::HawkIdentifier;
+
+pub const DEFAULT_WRITE_QUEUE_MAX_ENTRIES: usize = 10_000;
+
+type Key = (HawkIdentifier, i32);
+
+/// Sent by a writer abandoned while still queued: its predecessor's release
+/// signal, for its successor to wait on instead
+#[derive(Debug)]
+struct Handoff(oneshot::Receiver<Handoff>);
+
+/// A queued writer yet to take its turn
+struct Waiter {
+    release: Option<oneshot::Sender<Handoff>>,
+    previous: Option<oneshot::Receiver<Handoff>>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // Abandoned while still queued: the successor waits on our
+        // predecessor instead of proceeding early
+        if let (Some(release), Some(previous)) = (self.release.take(), self.previous.take()) {
+            let _ = release.send(Handoff(previous));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteQueue {
+    /// Maximum number of collections queued at once
+    max_entries: usize,
+    /// Release signal of each collection's last queued writer (with its
+    /// ticket)
+    tails: Mutex<HashMap<Key, (u64, oneshot::Receiver<Handoff>)>>,
+    next_ticket: AtomicU64,
+}
+
+impl WriteQueue {
+    /// A queue of at most `max_entries` collections (disabled when 0)
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            tails: Mutex::new(HashMap::new()),
+            next_ticket: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for the collection's previously queued writers, returning a
+    /// guard releasing the next one when dropped.
+    ///
+    /// Returns `None` (the write proceeds unserialized) when the queue is
+    /// disabled or full.
+    pub async fn enqueue(self: Arc<Self>, key: Key) -> Result<Option<WriteQueueGuard>> {
+        let (release, released) = oneshot::channel();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let previous = {
+            let mut tails = self
+                .tails
+                .lock()
+                .map_err(|_| DbError::internal("write_queue lock"))?;
+            if !tails.contains_key(&key) && tails.len() >= self.max_entries {
+                // Drop entries of writers since released
+                tails.retain(|_, (_, released)| loop {
+                    match released.try_recv() {
+                        Ok(None) => break true,
+                        Ok(Some(Handoff(previous))) => *released = previous,
+                        Err(_) => break false,
+                    }
+                });
+                if tails.len() >= self.max_entries {
+                    return Ok(None);
+                }
+            }
+            tails.insert(key.clone(), (ticket, released))
+        };
+        let mut waiter = Waiter {
+            release: Some(release),
+            previous: previous.map(|(_, previous)| previous),
+        };
+        while let Some(previous) = waiter.previous.as_mut() {
+            // A release drops the sender (Canceled)
+            waiter.previous = previous.await.ok().map(|Handoff(previous)| previous);
+        }
+        Ok(Some(WriteQueueGuard {
+            queue: self,
+            key,
+            ticket,
+            _release: waiter.release.take(),
+        }))
+    }
+
+    /// The number of collections currently queued
+    pub fn queued(&self) -> usize {
+        self.tails.lock().map(|tails| tails.len()).unwrap_or(0)
+    }
+}
+
+/// A collection's turn to write, released on drop
+#[derive(Debug)]
+pub struct WriteQueueGuard {
+    queue: Arc<WriteQueue>,
+    key: Key,
+    ticket: u64,
+    _release: Option<oneshot::Sender<Handoff>>,
+}
+
+impl Drop for WriteQueueGuard {
+    fn drop(&mut self) {
+        if let Ok(mut tails) = self.queue.tails.lock() {
+            // Nobody queued behind us: free the entry
+            if tails.get(&self.key).map(|(ticket, _)| *ticket) == Some(self.ticket) {
+                tails.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::{executor::block_on, future::FutureExt};
+
+    use super::WriteQueue;
+    use crate::web::extractors::HawkIdentifier;
+
+    fn key(uid: u64, collection_id: i32) -> (HawkIdentifier, i32) {
+        (HawkIdentifier::new_legacy(uid), collection_id)
+    }
+
+    #[test]
+    fn serializes_per_collection() {
+        let queue = Arc::new(WriteQueue::new(10));
+        let first = block_on(Arc::clone(&queue).enqueue(key(1, 1)))
+            .unwrap()
+            .unwrap();
+        // Other collections (and users) don't wait
+        let other = block_on(Arc::clone(&queue).enqueue(key(1, 2)))
+            .unwrap()
+            .unwrap();
+        let other_user = block_on(Arc::clone(&queue).enqueue(key(2, 1)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(queue.queued(), 3);
+
+        let mut second = Arc::clone(&queue).enqueue(key(1, 1)).boxed_local();
+        assert!(second.as_mut().now_or_never().is_none());
+        drop(first);
+        let second = block_on(second).unwrap().unwrap();
+
+        drop((second, other, other_user));
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[test]
+    fn abandoned_waiter() {
+        let queue = Arc::new(WriteQueue::new(10));
+        let first = block_on(Arc::clone(&queue).enqueue(key(1, 1)))
+            .unwrap()
+            .unwrap();
+        let mut second = Arc::clone(&queue).enqueue(key(1, 1)).boxed_local();
+        assert!(second.as_mut().now_or_never().is_none());
+        let mut third = Arc::clone(&queue).enqueue(key(1, 1)).boxed_local();
+        assert!(third.as_mut().now_or_never().is_none());
+
+        // The third still waits on the first
+        drop(second);
+        assert!(third.as_mut().now_or_never().is_none());
+        drop(first);
+        let third = block_on(third).unwrap().unwrap();
+
+        drop(third);
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[test]
+    fn bounded() {
+        let queue = Arc::new(WriteQueue::new(1));
+        let first = block_on(Arc::clone(&queue).enqueue(key(1, 1))).unwrap();
+        assert!(first.is_some());
+        assert!(block_on(Arc::clone(&queue).enqueue(key(1, 2)))
+            .unwrap()
+            .is_none());
+        drop(first);
+        assert!(block_on(Arc::clone(&queue).enqueue(key(1, 2)))
+            .unwrap()
+            .is_some());
+
+        let disabled = Arc::new(WriteQueue::new(0));
+        assert!(block_on(disabled.enqueue(key(1, 1))).unwrap().is_none());
+    }
+}
@@ -1,10 +1,10 @@
-use futures_await_test::async_test;
 use log::debug;
 
-use super::support::{db, gbso, hid, postbso, Result};
+use super::support::{committing_db, db, gbso, hid, pbso, postbso, uid, Result};
 use crate::{
-    db::{error::DbErrorKind, params, util::SyncTimestamp, BATCH_LIFETIME},
+    db::{error::DbErrorKind, params, results, util::SyncTimestamp, BATCH_LIFETIME},
     error::ApiErrorKind,
+    settings::Settings,
 };
 
 fn cb(user_id: u32, coll: &str, bsos: Vec<params::PostCollectionBso>) -> params::CreateBatch {
@@ -45,11 +45,10 @@ fn gb(user_id: u32, coll: &str, id: String) -> params::GetBatch {
     }
 }
 
-#[async_test]
-async fn create_delete() -> Result<()> {
-    let db = db().await?;
+async fn create_delete(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 1;
+    let uid = uid();
     let coll = "clients";
     let id = db.create_batch(cb(uid, coll, vec![])).await?;
     assert!(db.validate_batch(vb(uid, coll, id.clone())).await?);
@@ -64,11 +63,10 @@ async fn create_delete() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn expiry() -> Result<()> {
-    let db = db().await?;
+async fn expiry(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 1;
+    let uid = uid();
     let coll = "clients";
     let id = with_delta!(db, -(BATCH_LIFETIME + 11), {
         db.create_batch(cb(uid, coll, vec![])).await
@@ -90,16 +88,67 @@ async fn expiry() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn update() -> Result<()> {
-    let db = db().await?;
+async fn purge_expired(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 1;
+    let uid = uid();
+    let coll = "clients";
+    let bsos = || vec![postbso("b0", Some("payload 0"), Some(10), None)];
+    // Batch ids are derived from the timestamp: create each at a different one
+    for i in 0..3 {
+        with_delta!(db, -(BATCH_LIFETIME + 1000 + i), {
+            db.create_batch(cb(uid, coll, bsos())).await
+        })?;
+    }
+    let live = db.create_batch(cb(uid, coll, bsos())).await?;
+
+    let purge = |limit, dry_run| params::PurgeExpired {
+        user_id: Some(hid(uid)),
+        collection: Some(coll.to_owned()),
+        limit,
+        dry_run,
+    };
+    assert_eq!(db.purge_expired_batches(purge(10, true)).await?, 3);
+    assert_eq!(db.purge_expired_batches(purge(2, false)).await?, 2);
+    assert_eq!(db.purge_expired_batches(purge(10, false)).await?, 1);
+    assert_eq!(db.purge_expired_batches(purge(10, false)).await?, 0);
+    assert!(db.validate_batch(vb(uid, coll, live)).await?);
+    Ok(())
+}
+
+async fn count_batches(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let all = || params::CountBatches { user_id: None };
+    // Counts every user's batches
+    let before = db.count_batches(all()).await?;
+    with_delta!(db, -(BATCH_LIFETIME + 11), {
+        db.create_batch(cb(uid, coll, vec![])).await
+    })?;
+    db.create_batch(cb(uid, coll, vec![])).await?;
+    let after = db.count_batches(all()).await?;
+    assert_eq!(after.open, before.open + 2);
+    assert_eq!(after.stale, before.stale + 1);
+
+    let user = db
+        .count_batches(params::CountBatches {
+            user_id: Some(hid(uid)),
+        })
+        .await?;
+    assert_eq!(user, results::CountBatches { open: 2, stale: 1 });
+    Ok(())
+}
+
+async fn update(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "clients";
     let id = db.create_batch(cb(uid, coll, vec![])).await?;
-    assert!(db.get_batch(gb(uid, coll, id.clone())).await?.is_some());
-    // XXX: now bogus under spanner
-    //assert_eq!(batch.bsos, "".to_owned());
+    let batch = db.get_batch(gb(uid, coll, id.clone())).await?.unwrap();
+    assert!(batch.bsos.is_empty());
 
     let bsos = vec![
         postbso("b0", Some("payload 0"), Some(10), None),
@@ -107,17 +156,40 @@ async fn update() -> Result<()> {
     ];
     db.append_to_batch(ab(uid, coll, id.clone(), bsos)).await?;
 
-    assert!(db.get_batch(gb(uid, coll, id)).await?.is_some());
-    // XXX: now bogus under spanner
-    //assert_ne!(batch.bsos, "".to_owned());
+    let batch = db.get_batch(gb(uid, coll, id)).await?.unwrap();
+    let ids: Vec<_> = batch.bsos.iter().map(|bso| bso.id.as_str()).collect();
+    assert_eq!(ids, vec!["b0", "b1"]);
     Ok(())
 }
 
-#[async_test]
-async fn append_commit() -> Result<()> {
-    let db = db().await?;
+async fn get_batch_bsos(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 1;
+    let uid = uid();
+    let coll = "clients";
+    let bsos1 = vec![
+        postbso("b1", Some("payload 1"), Some(10), None),
+        postbso("b0", None, None, Some(100)),
+    ];
+    let id = db.create_batch(cb(uid, coll, bsos1)).await?;
+    let bsos2 = vec![postbso("b2", Some("payload 2"), None, None)];
+    db.append_to_batch(ab(uid, coll, id.clone(), bsos2)).await?;
+
+    let bsos = db.get_batch_bsos(gb(uid, coll, id)).await?;
+    let ids: Vec<_> = bsos.iter().map(|bso| bso.id.as_str()).collect();
+    assert_eq!(ids, vec!["b0", "b1", "b2"]);
+    assert_eq!(bsos[0].payload, None);
+    assert_eq!(bsos[0].ttl, Some(100));
+    assert_eq!(bsos[1].payload, Some("payload 1".to_owned()));
+    assert_eq!(bsos[1].sortindex, Some(10));
+    assert_eq!(bsos[2].sortindex, None);
+    Ok(())
+}
+
+async fn append_commit(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "clients";
     let bsos1 = vec![
         postbso("b0", Some("payload 0"), Some(10), None),
@@ -154,3 +226,130 @@ async fn append_commit() -> Result<()> {
     assert_eq!(bso.payload, "payload 1");
     Ok(())
 }
+
+async fn append_commit_committed(settings: Settings) -> Result<()> {
+    let db = committing_db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let lock = || params::LockCollection {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    };
+    db.lock_for_write(lock()).await?;
+    let bsos1 = vec![postbso("b0", Some("payload 0"), Some(10), None)];
+    let id = db.create_batch(cb(uid, coll, bsos1)).await?;
+    let bsos2 = vec![postbso("b1", Some("payload 1"), None, Some(1000))];
+    db.append_to_batch(ab(uid, coll, id.clone(), bsos2)).await?;
+    db.commit().await?;
+
+    db.lock_for_write(lock()).await?;
+    let batch = db.get_batch(gb(uid, coll, id)).await?.unwrap();
+    let result = db
+        .commit_batch(params::CommitBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            batch,
+        })
+        .await?;
+    db.commit().await?;
+    // Spanner's writes are stamped with their commit timestamp
+    let modified = db.timestamp();
+
+    db.begin(false).await?;
+    let b0 = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    let b1 = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    let ts = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    db.commit().await?;
+
+    db.begin(true).await?;
+    db.delete_storage(hid(uid)).await?;
+    db.commit().await?;
+
+    assert_eq!(b0.payload, "payload 0");
+    assert_eq!(b0.sortindex, Some(10));
+    assert_eq!(b1.payload, "payload 1");
+    assert!(result.modified <= modified);
+    assert_eq!(b0.modified, modified);
+    assert_eq!(b1.modified, modified);
+    assert_eq!(ts, modified);
+    Ok(())
+}
+
+async fn commit_updates_supplied_fields(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "b0", Some("payload 0"), Some(1), None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some("payload 1"), Some(1), None))
+        .await?;
+
+    let id = db
+        .create_batch(cb(uid, coll, vec![postbso("b0", None, Some(2), None)]))
+        .await?;
+    let bsos = vec![postbso("b1", Some("updated 1"), None, None)];
+    db.append_to_batch(ab(uid, coll, id.clone(), bsos)).await?;
+    let batch = db.get_batch(gb(uid, coll, id)).await?.unwrap();
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, "payload 0");
+    assert_eq!(bso.sortindex, Some(2));
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.payload, "updated 1");
+    assert_eq!(bso.sortindex, Some(1));
+    Ok(())
+}
+
+async fn deleted_with_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let bsos = || vec![postbso("b0", Some("payload 0"), None, None)];
+    let id = db.create_batch(cb(uid, coll, bsos())).await?;
+    db.delete_collection(params::DeleteCollection {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    })
+    .await?;
+    assert!(!db.validate_batch(vb(uid, coll, id.clone())).await?);
+    assert!(db.get_batch(gb(uid, coll, id)).await?.is_none());
+
+    let id = db.create_batch(cb(uid, coll, bsos())).await?;
+    db.delete_storage(hid(uid)).await?;
+    assert!(!db.validate_batch(vb(uid, coll, id.clone())).await?);
+    assert!(db.get_batch(gb(uid, coll, id)).await?.is_none());
+
+    // Unknown collections have no batches
+    assert!(
+        !db.validate_batch(vb(uid, "nonexistent", "0".to_owned()))
+            .await?
+    );
+    Ok(())
+}
+
+db_test_suite! {
+    create_delete,
+    expiry,
+    purge_expired,
+    count_batches,
+    update,
+    get_batch_bsos,
+    append_commit,
+    append_commit_committed,
+    commit_updates_supplied_fields,
+    deleted_with_collection,
+}
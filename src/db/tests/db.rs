@@ -1,26 +1,32 @@
 #![allow(clippy::cognitive_complexity)]
-use std::collections::HashMap;
+use std::{collections::HashMap, thread, time::Duration};
 
-use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-use futures_await_test::async_test;
-
-use super::support::{db, dbso, dbsos, gbso, gbsos, hid, pbso, postbso, Result};
-use crate::db::{mysql::models::DEFAULT_BSO_TTL, params, util::SyncTimestamp, Sorting};
+use super::support::{
+    committing_db, committing_pool, db, dbso, dbsos, gbso, gbsos, gcounts, hid, pbso, postbso, uid,
+    Result,
+};
+use crate::db::{
+    cache::CollectionCache,
+    error::DbErrorKind,
+    migrate,
+    mysql::models::DEFAULT_BSO_TTL,
+    params, results, standard_collections,
+    util::{response_size, SyncTimestamp},
+    Db, Sorting, FIRST_CUSTOM_COLLECTION_ID,
+};
+use crate::error::{ApiError, ApiErrorKind};
+use crate::settings::Settings;
+use crate::web::extractors::HawkIdentifier;
 
 // distant future (year 2099) timestamp for tests
 const MAX_TIMESTAMP: u64 = 4_070_937_600_000;
 
-lazy_static! {
-    static ref UID: u32 = thread_rng().gen_range(0, 10000);
-}
-
-#[async_test]
-async fn bso_successfully_updates_single_values() -> Result<()> {
-    let db = db().await?;
+async fn bso_successfully_updates_single_values(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let bid = "testBSO";
     let sortindex = 1;
@@ -56,11 +62,10 @@ async fn bso_successfully_updates_single_values() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn bso_modified_not_changed_on_ttl_touch() -> Result<()> {
-    let db = db().await?;
+async fn bso_modified_not_changed_on_ttl_touch(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let bid = "testBSO";
     let timestamp = db.timestamp().as_i64();
@@ -78,11 +83,10 @@ async fn bso_modified_not_changed_on_ttl_touch() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn put_bso_updates() -> Result<()> {
-    let db = db().await?;
+async fn put_bso_updates(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let bid = "1";
     let bso1 = pbso(uid, coll, bid, Some("initial"), None, None);
@@ -100,11 +104,79 @@ async fn put_bso_updates() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_bsos_limit_offset() -> Result<()> {
-    let db = db().await?;
+async fn put_bso_modified_matches_get(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let bid = "1";
+    let modified = db
+        .put_bso(pbso(uid, coll, bid, Some("x"), None, None))
+        .await?;
+    assert_eq!(modified, db.timestamp());
+
+    // What's echoed back to clients is byte identical to later reads
+    let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+    assert_eq!(bso.modified.as_header(), modified.as_header());
+    assert_eq!(
+        serde_json::to_string(&bso.modified).unwrap(),
+        serde_json::to_string(&modified).unwrap()
+    );
+    let ts = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    assert_eq!(ts.as_header(), modified.as_header());
+    Ok(())
+}
+
+async fn default_sortindex(settings: Settings) -> Result<()> {
+    // Without a configured default, omitted sortindexes are stored as NULL
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "1", Some("x"), None, None))
+        .await?;
+    let bso = db.get_bso(gbso(uid, coll, "1")).await?.unwrap();
+    assert_eq!(bso.sortindex, None);
+
+    let db = db(&Settings {
+        default_sortindex: Some(0),
+        ..settings
+    })
+    .await?;
+    db.put_bso(pbso(uid, coll, "2", Some("x"), None, None))
+        .await?;
+    db.post_bsos(params::PostBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        bsos: vec![postbso("3", Some("x"), None, None)],
+        failed: Default::default(),
+        breakdown: false,
+    })
+    .await?;
+    for bid in &["2", "3"] {
+        let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+        assert_eq!(bso.sortindex, Some(0));
+    }
+
+    // Updates never apply the default
+    db.put_bso(pbso(uid, coll, "2", None, Some(5), None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "2", Some("y"), None, None))
+        .await?;
+    let bso = db.get_bso(gbso(uid, coll, "2")).await?.unwrap();
+    assert_eq!(bso.sortindex, Some(5));
+    Ok(())
+}
+
+async fn get_bsos_limit_offset(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let size = 12;
     for i in 0..size {
@@ -220,11 +292,66 @@ async fn get_bsos_limit_offset() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_bsos_newer() -> Result<()> {
-    let db = db().await?;
+async fn count_bsos(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    for i in 0..5 {
+        let bso = pbso(uid, coll, &i.to_string(), Some("payload"), Some(i), None);
+        with_delta!(&db, i64::from(i) * 10, { db.put_bso(bso).await })?;
+    }
+    let ts = db.timestamp().as_i64() as u64;
+
+    // Ignores the limit and offset
+    let count = db
+        .count_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            2,
+            "2",
+        ))
+        .await?;
+    assert_eq!(count, 5);
+
+    let count = db
+        .count_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            ts + 10,
+            Sorting::None,
+            1,
+            "0",
+        ))
+        .await?;
+    assert_eq!(count, 3);
+
+    let count = db
+        .count_bsos(gbsos(
+            uid,
+            coll,
+            &["0", "4", "9"],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::None,
+            1,
+            "0",
+        ))
+        .await?;
+    assert_eq!(count, 2);
+    Ok(())
+}
+
+async fn get_bsos_newer(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let timestamp = db.timestamp().as_i64();
 
@@ -304,11 +431,94 @@ async fn get_bsos_newer() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_bsos_sort() -> Result<()> {
-    let db = db().await?;
+async fn get_bsos_inclusive_bounds(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let timestamp = db.timestamp().as_i64();
+
+    // b1 and b2 share a modified timestamp
+    for (bid, delta) in &[("b0", 0), ("b1", -10), ("b2", -10), ("b3", -20)] {
+        let pbso = pbso(uid, coll, bid, Some("a"), Some(1), Some(DEFAULT_BSO_TTL));
+        with_delta!(&db, *delta, { db.put_bso(pbso).await })?;
+    }
+    let shared = SyncTimestamp::_from_i64(timestamp - 10)?;
+    let query = |newer_eq, older_eq| {
+        let mut params = gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Newest,
+            10,
+            &"0".to_owned(),
+        );
+        params.params.newer_eq = newer_eq;
+        params.params.older_eq = older_eq;
+        params
+    };
+    let ids = |bsos: Vec<results::GetBso>| -> Vec<String> {
+        let mut ids: Vec<_> = bsos.into_iter().map(|bso| bso.id).collect();
+        ids.sort();
+        ids
+    };
+
+    let bsos = db.get_bsos(query(Some(shared), None)).await?;
+    assert_eq!(ids(bsos.items), vec!["b0", "b1", "b2"]);
+
+    let bsos = db.get_bsos(query(None, Some(shared))).await?;
+    assert_eq!(ids(bsos.items), vec!["b1", "b2", "b3"]);
+
+    let bsos = db.get_bsos(query(Some(shared), Some(shared))).await?;
+    assert_eq!(ids(bsos.items), vec!["b1", "b2"]);
+
+    // The exclusive bounds still skip the shared timestamp
+    let mut params = query(None, None);
+    params.params.newer = Some(shared);
+    let bsos = db.get_bsos(params).await?;
+    assert_eq!(ids(bsos.items), vec!["b0"]);
+    Ok(())
+}
+
+async fn get_bsos_expiring_before(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let timestamp = db.timestamp().as_i64();
+    for (bid, ttl) in &[("b0", 10), ("b1", 100), ("b2", DEFAULT_BSO_TTL)] {
+        db.put_bso(pbso(uid, coll, bid, Some("a"), Some(1), Some(*ttl)))
+            .await?;
+    }
+
+    let mut params = gbsos(
+        uid,
+        coll,
+        &[],
+        MAX_TIMESTAMP,
+        0,
+        Sorting::Newest,
+        10,
+        &"0".to_owned(),
+    );
+    params.params.expiring_before = Some(SyncTimestamp::_from_i64(timestamp + 50_000)?);
+    let bsos = db.get_bsos(params.clone()).await?;
+    assert_eq!(bsos.items.len(), 1);
+    assert_eq!(bsos.items[0].id, "b0");
+
+    params.params.expiring_before = Some(SyncTimestamp::_from_i64(timestamp + 500_000)?);
+    let mut ids = db.get_bso_ids(params).await?.items;
+    ids.sort();
+    assert_eq!(ids, vec!["b0", "b1"]);
+    Ok(())
+}
+
+async fn get_bsos_sort(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
 
     for (revi, sortindex) in [1, 0, 2].iter().enumerate().rev() {
@@ -376,11 +586,10 @@ async fn get_bsos_sort() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn delete_bsos_in_correct_collection() -> Result<()> {
-    let db = db().await?;
+async fn delete_bsos_in_correct_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let payload = "data";
     db.put_bso(pbso(uid, "clients", "b1", Some(payload), None, None))
         .await?;
@@ -392,11 +601,10 @@ async fn delete_bsos_in_correct_collection() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_storage_timestamp() -> Result<()> {
-    let db = db().await?;
+async fn get_storage_timestamp(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     db.create_collection("col1".to_owned()).await?;
     let col2 = db.create_collection("col2".to_owned()).await?;
     db.create_collection("col3".to_owned()).await?;
@@ -413,28 +621,28 @@ async fn get_storage_timestamp() -> Result<()> {
     })
 }
 
-#[async_test]
-async fn get_collection_id() -> Result<()> {
-    let db = db().await?;
+async fn get_collection_id(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
     db.get_collection_id("bookmarks".to_owned()).await?;
     Ok(())
 }
 
-#[async_test]
-async fn create_collection() -> Result<()> {
-    let db = db().await?;
+async fn create_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
     let name = "NewCollection";
     let cid = db.create_collection(name.to_owned()).await?;
     assert_ne!(cid, 0);
     let cid2 = db.get_collection_id(name.to_owned()).await?;
     assert_eq!(cid2, cid);
+    // Standard collections are never allocated a custom id
+    assert!(db.create_collection("bookmarks".to_owned()).await.is_err());
+    assert_eq!(db.get_collection_id("bookmarks".to_owned()).await?, 7);
     Ok(())
 }
 
-#[async_test]
-async fn touch_collection() -> Result<()> {
-    let db = db().await?;
+async fn touch_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
     let cid = db.create_collection("test".to_owned()).await?;
     db.touch_collection(params::TouchCollection {
@@ -445,11 +653,10 @@ async fn touch_collection() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn delete_collection() -> Result<()> {
-    let db = db().await?;
+async fn delete_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "NewCollection";
     for bid in 1..=3 {
         db.put_bso(pbso(uid, coll, &bid.to_string(), Some("test"), None, None))
@@ -480,11 +687,40 @@ async fn delete_collection() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn delete_collection_tombstone() -> Result<()> {
-    let db = db().await?;
+async fn collection_exists(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let other_uid = uid();
+    let uid = uid();
+    let coll = "NewCollection";
+    let exists = |uid, collection: &str| {
+        db.collection_exists(params::CollectionExists {
+            user_id: hid(uid),
+            collection: collection.to_owned(),
+        })
+    };
+    // Unknown to the db, then known but not the user's
+    assert!(!exists(uid, "nonexistent").await?);
+    db.put_bso(pbso(other_uid, coll, "b0", Some("test"), None, None))
+        .await?;
+    assert!(!exists(uid, coll).await?);
+
+    db.put_bso(pbso(uid, coll, "b0", Some("test"), None, None))
+        .await?;
+    assert!(exists(uid, coll).await?);
+    db.delete_collection(params::DeleteCollection {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    })
+    .await?;
+    assert!(!exists(uid, coll).await?);
+    Ok(())
+}
+
+async fn delete_collection_tombstone(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "test";
     let bid1 = "b0";
     let coll2 = "test2";
@@ -539,11 +775,10 @@ async fn delete_collection_tombstone() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_collection_timestamps() -> Result<()> {
-    let db = db().await?;
+async fn get_collection_timestamps(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "test";
     let cid = db.create_collection(coll.to_owned()).await?;
     db.touch_collection(params::TouchCollection {
@@ -565,11 +800,10 @@ async fn get_collection_timestamps() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_collection_timestamps_tombstone() -> Result<()> {
-    let db = db().await?;
+async fn get_collection_timestamps_tombstone(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "test";
     let cid = db.create_collection(coll.to_owned()).await?;
     db.touch_collection(params::TouchCollection {
@@ -588,11 +822,10 @@ async fn get_collection_timestamps_tombstone() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_collection_usage() -> Result<()> {
-    let db = db().await?;
+async fn get_collection_usage(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 5;
+    let uid = uid();
     let mut expected = HashMap::new();
     let mut rng = thread_rng();
 
@@ -623,11 +856,342 @@ async fn get_collection_usage() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_collection_counts() -> Result<()> {
-    let db = db().await?;
+async fn usage_of_reference_lookalikes(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    // Without offloading, payloads resembling a blob reference are stored
+    // (and counted) as is
+    let uid = uid();
+    let coll = "bookmarks";
+    let lookalike = "blob:x:0";
+    db.put_bso(pbso(uid, coll, "b0", Some(lookalike), None, None))
+        .await?;
+    let usage = lookalike.len() as u64;
+    assert_eq!(db.get_storage_usage(hid(uid)).await?, usage);
+    let usages = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(usages.get(coll).copied(), Some(usage as i64));
+    assert_eq!(
+        db.get_bso(gbso(uid, coll, "b0")).await?.unwrap().payload,
+        lookalike
+    );
+    Ok(())
+}
+
+async fn usage_excludes_expired(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "bookmarks";
+    db.put_bso(pbso(uid, coll, "b0", Some("live"), None, None))
+        .await?;
+    // Expired 10 seconds ago (but not yet purged): as unreadable, it's not
+    // counted
+    let bso = pbso(uid, coll, "b1", Some("expired"), None, Some(10));
+    with_delta!(db, -20_000, { db.put_bso(bso).await })?;
+    let bso = pbso(uid, "history", "b0", Some("expired"), None, Some(10));
+    with_delta!(db, -20_000, { db.put_bso(bso).await })?;
+
+    let expected: HashMap<_, _> = vec![(coll.to_owned(), 4)].into_iter().collect();
+    assert_eq!(db.get_collection_usage(hid(uid)).await?, expected);
+    assert_eq!(db.get_storage_usage(hid(uid)).await?, 4);
+    let expected: HashMap<_, _> = vec![(coll.to_owned(), 1)].into_iter().collect();
+    assert_eq!(db.get_collection_counts(gcounts(uid)).await?, expected);
+    Ok(())
+}
+
+async fn utf8mb4_payloads(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    // Emoji and other astral plane characters are 4 bytes in UTF-8
+    let payload = "\u{1F98A} bookmark \u{1F4DA}\u{1D11E}\u{10348} \u{E9}\u{20AC}";
+    db.put_bso(pbso(uid, coll, "b0", Some(payload), None, None))
+        .await?;
+    let result = db
+        .post_bsos(params::PostBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![postbso("b1", Some(payload), None, None)],
+            failed: Default::default(),
+            breakdown: false,
+        })
+        .await?;
+    assert!(result.failed.is_empty());
+
+    for bid in &["b0", "b1"] {
+        let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+        assert_eq!(bso.payload, payload);
+    }
+    // Usage is measured in bytes, not characters
+    let total = db.get_storage_usage(hid(uid)).await?;
+    assert_eq!(total, 2 * payload.len() as u64);
+    Ok(())
+}
+
+async fn payload_codec(settings: Settings) -> Result<()> {
+    let db = db(&Settings {
+        payload_codec: "base64".to_owned(),
+        ..settings
+    })
+    .await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let payload = "\u{1F98A} payload";
+    db.put_bso(pbso(uid, coll, "b0", Some(payload), None, None))
+        .await?;
+    db.post_bsos(params::PostBsos {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        bsos: vec![postbso("b1", Some(payload), Some(1), None)],
+        failed: Default::default(),
+        breakdown: false,
+    })
+    .await?;
+    let id = db
+        .create_batch(params::CreateBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![postbso("b2", Some(payload), None, None)],
+        })
+        .await?;
+    let batch_bsos = db
+        .get_batch_bsos(params::GetBatchBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: id.clone(),
+        })
+        .await?;
+    assert_eq!(batch_bsos[0].payload.as_deref(), Some(payload));
+    let batch = db
+        .get_batch(params::GetBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id,
+        })
+        .await?
+        .unwrap();
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+
+    for bid in &["b0", "b1", "b2"] {
+        let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+        assert_eq!(bso.payload, payload);
+    }
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            10,
+            "0",
+        ))
+        .await?;
+    assert_eq!(bsos.items.len(), 3);
+    assert!(bsos.items.iter().all(|bso| bso.payload == payload));
+    // Usage reflects the stored (encoded) size
+    let stored = base64::encode(payload).len() as u64;
+    assert_eq!(db.get_storage_usage(hid(uid)).await?, 3 * stored);
+    Ok(())
+}
+
+async fn payload_blob_store(settings: Settings) -> Result<()> {
+    let root = std::env::temp_dir().join(format!("blobs-{}", uuid::Uuid::new_v4()));
+    let db = db(&Settings {
+        payload_blob_store: Some(format!("file://{}", root.display())),
+        payload_blob_threshold: 16,
+        ..settings
+    })
+    .await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let small = "small";
+    let large = "x".repeat(1_000);
+    db.put_bso(pbso(uid, coll, "b0", Some(small), None, None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some(&large), None, None))
+        .await?;
+
+    assert_eq!(
+        db.get_bso(gbso(uid, coll, "b0")).await?.unwrap().payload,
+        small
+    );
+    assert_eq!(
+        db.get_bso(gbso(uid, coll, "b1")).await?.unwrap().payload,
+        large
+    );
+    // Only a reference to the large payload's stored inline, but usage
+    // still reflects its size
+    let usage = (small.len() + large.len()) as u64;
+    assert_eq!(db.get_storage_usage(hid(uid)).await?, usage);
+    let usages = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(usages.get(coll).copied(), Some(usage as i64));
+    assert!(root.read_dir().unwrap().next().is_some());
+
+    let references = db.get_blob_references().await?;
+    let key = ring::digest::digest(&ring::digest::SHA256, large.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    assert!(references.contains(&key));
+    std::fs::remove_dir_all(root).unwrap();
+    Ok(())
+}
+
+async fn migrate_user(settings: Settings) -> Result<()> {
+    // Copied between two pools' (committing) dbs, to a second user
+    let source = committing_pool(&settings)?;
+    let dest = committing_pool(&settings)?;
+    // Distinct users on either backend (Spanner identifies them by fxa_uid)
+    let user = |uid: u32| HawkIdentifier {
+        legacy_id: u64::from(uid),
+        fxa_uid: format!("migrate{}", uid),
+        fxa_kid: format!("migrate{}", uid),
+    };
+    let (user_id, dest_user_id) = (user(uid()), user(uid()));
+    let lock = |user_id: &HawkIdentifier, coll: &str| params::LockCollection {
+        user_id: user_id.clone(),
+        collection: coll.to_owned(),
+    };
+
+    let db = source.get().await?;
+    for (coll, bid) in &[("clients", "b0"), ("clients", "b1"), ("tabs", "b0")] {
+        // Distinct timestamps (and outside the same 10ms)
+        thread::sleep(Duration::from_millis(20));
+        db.lock_for_write(lock(&user_id, coll)).await?;
+        db.put_bso(params::PutBso {
+            user_id: user_id.clone(),
+            ..pbso(0, coll, bid, Some(bid), Some(1), Some(3600))
+        })
+        .await?;
+        db.commit().await?;
+    }
+    // The pools hold a single connection each
+    drop(db);
+    // Removed from the destination, being absent from the source
+    let dest_db = dest.get().await?;
+    dest_db
+        .lock_for_write(lock(&dest_user_id, "clients"))
+        .await?;
+    dest_db
+        .put_bso(params::PutBso {
+            user_id: dest_user_id.clone(),
+            ..pbso(0, "clients", "stale", Some("x"), None, None)
+        })
+        .await?;
+    dest_db.commit().await?;
+    drop(dest_db);
+
+    let migration = migrate::migrate_user(&*source, &*dest, &user_id, &dest_user_id).await?;
+    let rerun = migrate::migrate_user(&*source, &*dest, &user_id, &dest_user_id).await?;
+
+    let timestamps = |db: Box<dyn Db>, user_id: HawkIdentifier| async move {
+        db.begin(false).await?;
+        let timestamps = db.get_collection_timestamps(user_id).await?;
+        db.commit().await?;
+        Ok::<_, ApiError>(timestamps)
+    };
+    let source_timestamps = timestamps(source.get().await?, user_id.clone()).await?;
+    let dest_timestamps = timestamps(dest.get().await?, dest_user_id.clone()).await?;
+    let bsos = |db: Box<dyn Db>, user_id: HawkIdentifier| async move {
+        db.begin(false).await?;
+        let bsos = db
+            .get_bsos_after(params::GetBsosAfter {
+                user_id,
+                collection: "clients".to_owned(),
+                after: None,
+                sort: Sorting::Oldest,
+                limit: 10,
+            })
+            .await?;
+        db.commit().await?;
+        Ok::<_, ApiError>(bsos.items)
+    };
+    let source_bsos = bsos(source.get().await?, user_id.clone()).await?;
+    let dest_bsos = bsos(dest.get().await?, dest_user_id.clone()).await?;
+
+    // Refused while the user has an open batch
+    let db = source.get().await?;
+    db.lock_for_write(lock(&user_id, "clients")).await?;
+    db.create_batch(params::CreateBatch {
+        user_id: user_id.clone(),
+        collection: "clients".to_owned(),
+        bsos: vec![],
+    })
+    .await?;
+    db.commit().await?;
+    drop(db);
+    let refused = migrate::migrate_user(&*source, &*dest, &user_id, &dest_user_id).await;
+
+    for (pool, user_id) in &[(&source, &user_id), (&dest, &dest_user_id)] {
+        let db = pool.get().await?;
+        db.begin(true).await?;
+        db.delete_storage((*user_id).clone()).await?;
+        db.commit().await?;
+    }
+
+    assert_eq!(migration.migrated, vec!["clients", "tabs"]);
+    assert_eq!(migration.bsos, 3);
+    assert_eq!(rerun.skipped, vec!["clients", "tabs"]);
+    assert!(rerun.migrated.is_empty());
+    assert_eq!(dest_timestamps, source_timestamps);
+    assert_eq!(dest_bsos.len(), 2);
+    for (dest_bso, source_bso) in dest_bsos.iter().zip(&source_bsos) {
+        assert_eq!(dest_bso.id, source_bso.id);
+        assert_eq!(dest_bso.modified, source_bso.modified);
+        assert_eq!(dest_bso.payload, source_bso.payload);
+        assert_eq!(dest_bso.sortindex, source_bso.sortindex);
+    }
+    assert!(refused.is_err());
+    Ok(())
+}
+
+async fn export_user(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    db.put_bso(pbso(uid, "tabs", "b0", Some("t0"), Some(1), None))
+        .await?;
+    for bid in &["b0", "b1", "b2"] {
+        db.put_bso(pbso(uid, "bookmarks", bid, Some("x"), None, None))
+            .await?;
+    }
+
+    let mut out = vec![];
+    let export = crate::db::export::export_user(&*db, &hid(uid), &mut out).await?;
+    assert_eq!(export.collections, 2);
+    assert_eq!(export.bsos, 4);
+    let lines: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0]["collection"], "bookmarks");
+    let tabs = &lines[3];
+    assert_eq!(tabs["collection"], "tabs");
+    assert_eq!(tabs["id"], "b0");
+    assert_eq!(tabs["payload"], "t0");
+    assert_eq!(tabs["sortindex"], 1);
+    assert!(tabs["modified"].is_number());
+    assert!(tabs["expiry"].as_i64().unwrap() > 0);
+    Ok(())
+}
+
+async fn get_collection_counts(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 4;
+    let uid = uid();
     let mut expected = HashMap::new();
     let mut rng = thread_rng();
 
@@ -640,16 +1204,62 @@ async fn get_collection_counts() -> Result<()> {
         }
     }
 
-    let counts = db.get_collection_counts(hid(uid)).await?;
+    let counts = db.get_collection_counts(gcounts(uid)).await?;
     assert_eq!(counts, expected);
     Ok(())
 }
 
-#[async_test]
-async fn put_bso() -> Result<()> {
-    let db = db().await?;
+async fn get_collection_counts_newer(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
+    with_delta!(&db, -100, {
+        db.put_bso(pbso(uid, "bookmarks", "b0", Some("x"), None, None))
+            .await
+    })?;
+    for bid in &["b0", "b1"] {
+        db.put_bso(pbso(uid, "history", bid, Some("x"), None, None))
+            .await?;
+    }
+
+    let newer = SyncTimestamp::_from_i64(db.timestamp().as_i64() - 50)?;
+    let counts = db
+        .get_collection_counts(params::GetCollectionCounts {
+            user_id: hid(uid),
+            newer: Some(newer),
+        })
+        .await?;
+    let expected: HashMap<_, _> = vec![("history".to_owned(), 2)].into_iter().collect();
+    assert_eq!(counts, expected);
+    assert_eq!(db.get_collection_counts(gcounts(uid)).await?.len(), 2);
+    Ok(())
+}
+
+async fn get_collection_names(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    assert!(db.get_collection_names(hid(uid)).await?.is_empty());
+
+    for &coll in ["prefs", "bookmarks", "history"].iter() {
+        db.put_bso(pbso(uid, coll, "b0", Some("x"), None, None))
+            .await?;
+    }
+    db.delete_collection(params::DeleteCollection {
+        user_id: hid(uid),
+        collection: "history".to_owned(),
+    })
+    .await?;
+
+    let names = db.get_collection_names(hid(uid)).await?;
+    assert_eq!(names, vec!["bookmarks", "prefs"]);
+    Ok(())
+}
+
+async fn put_bso(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "NewCollection";
     let bid = "b0";
     let bso1 = pbso(uid, coll, bid, Some("foo"), Some(1), Some(DEFAULT_BSO_TTL));
@@ -684,11 +1294,35 @@ async fn put_bso() -> Result<()> {
     })
 }
 
-#[async_test]
-async fn post_bsos() -> Result<()> {
-    let db = db().await?;
+async fn put_bso_create_only(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let create = |bid: &str, payload: &str| params::PutBso {
+        create_only: true,
+        ..pbso(uid, coll, bid, Some(payload), None, None)
+    };
+    db.put_bso(create("b0", "first")).await?;
+    let err = db.put_bso(create("b0", "second")).await.unwrap_err();
+    assert!(match err.kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::BsoExists),
+        _ => false,
+    });
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, "first");
+
+    // Expired BSOs may be recreated (expired 10 seconds ago)
+    let bso = pbso(uid, coll, "b1", Some("x"), None, Some(10));
+    with_delta!(db, -20_000, { db.put_bso(bso).await })?;
+    db.put_bso(create("b1", "third")).await?;
+    Ok(())
+}
+
+async fn post_bsos(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "NewCollection";
     let result = db
         .post_bsos(params::PostBsos {
@@ -700,6 +1334,7 @@ async fn post_bsos() -> Result<()> {
                 postbso("b2", Some("payload 2"), Some(100), None),
             ],
             failed: Default::default(),
+            breakdown: false,
         })
         .await?;
 
@@ -727,6 +1362,7 @@ async fn post_bsos() -> Result<()> {
                 postbso("b2", Some("updated 2"), Some(22), Some(10000)),
             ],
             failed: Default::default(),
+            breakdown: false,
         })
         .await?;
 
@@ -752,13 +1388,81 @@ async fn post_bsos() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_bso() -> Result<()> {
-    let db = db().await?;
+async fn post_bsos_mixed_fields(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
-    let bid = "b0";
+    db.put_bso(pbso(uid, coll, "b0", Some("payload 0"), Some(1), None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some("payload 1"), Some(1), None))
+        .await?;
+
+    // Updates and inserts supplying different fields in one POST
+    let result = db
+        .post_bsos(params::PostBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![
+                postbso("b0", None, Some(2), None),
+                postbso("b2", Some("payload 2"), None, None),
+                postbso("b1", Some("updated 1"), None, Some(100)),
+                postbso("b3", Some("payload 3"), Some(3), None),
+            ],
+            failed: Default::default(),
+            breakdown: false,
+        })
+        .await?;
+    assert_eq!(result.success.len(), 4);
+    assert!(result.failed.is_empty());
+    assert_eq!(result.created, None);
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, "payload 0");
+    assert_eq!(bso.sortindex, Some(2));
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.payload, "updated 1");
+    assert_eq!(bso.sortindex, Some(1));
+    let bso = db.get_bso(gbso(uid, coll, "b2")).await?.unwrap();
+    assert_eq!(bso.payload, "payload 2");
+    assert_eq!(bso.sortindex, None);
+    let bso = db.get_bso(gbso(uid, coll, "b3")).await?.unwrap();
+    assert_eq!(bso.payload, "payload 3");
+    assert_eq!(bso.sortindex, Some(3));
+    Ok(())
+}
+
+async fn post_bsos_breakdown(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "b0", Some("payload 0"), None, None))
+        .await?;
+    let result = db
+        .post_bsos(params::PostBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![
+                postbso("b0", Some("updated 0"), None, None),
+                postbso("b1", Some("payload 1"), None, None),
+                postbso("b2", Some("payload 2"), None, None),
+            ],
+            failed: Default::default(),
+            breakdown: true,
+        })
+        .await?;
+    assert_eq!(result.success.len(), 3);
+    assert_eq!(result.created, Some(vec!["b1".to_owned(), "b2".to_owned()]));
+    Ok(())
+}
+
+async fn get_bso(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let bid = "b0";
     let payload = "a";
     db.put_bso(pbso(uid, coll, bid, Some(payload), None, None))
         .await?;
@@ -772,11 +1476,98 @@ async fn get_bso() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn get_bsos() -> Result<()> {
-    let db = db().await?;
+async fn newer_than_returned_modified(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = 2;
+    let uid = uid();
+    let coll = "clients";
+    let modified = db
+        .put_bso(pbso(uid, coll, "b0", Some("x"), None, None))
+        .await?;
+    // The timestamp the client was sent, round tripped through the wire
+    // format
+    let sent = SyncTimestamp::from_header(&modified.as_header()).unwrap();
+    assert_eq!(sent, modified);
+    let newer = |newer: u64| gbsos(uid, coll, &[], MAX_TIMESTAMP, newer, Sorting::None, 10, "0");
+
+    let bsos = db.get_bsos(newer(sent.as_i64() as u64)).await?;
+    assert!(bsos.items.is_empty());
+    let bsos = db.get_bsos(newer(sent.as_i64() as u64 - 10)).await?;
+    assert_eq!(bsos.items.len(), 1);
+    assert_eq!(bsos.items[0].modified, modified);
+    Ok(())
+}
+
+async fn newer_older_committed(settings: Settings) -> Result<()> {
+    // Spanner's writes record their commit's timestamp once committed
+    let db = committing_db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let lock = || params::LockCollection {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    };
+    let mut modified = vec![];
+    for bid in &["b0", "b1", "b2"] {
+        // Distinct timestamps (and outside the same 10ms)
+        thread::sleep(Duration::from_millis(20));
+        db.lock_for_write(lock()).await?;
+        db.put_bso(pbso(uid, coll, bid, Some("x"), None, None))
+            .await?;
+        modified.push(db.commit_and_get_timestamp().await?);
+        db.commit().await?;
+    }
+    thread::sleep(Duration::from_millis(20));
+    db.lock_for_write(lock()).await?;
+    db.delete_bso(dbso(uid, coll, "b2")).await?;
+    let deleted = db.commit_and_get_timestamp().await?;
+    db.commit().await?;
+
+    let query =
+        |older: u64, newer: u64| gbsos(uid, coll, &[], older, newer, Sorting::Oldest, 10, "0");
+    let as_u64 = |ts: SyncTimestamp| ts.as_i64() as u64;
+    db.lock_for_read(params::LockCollectionForRead {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        strong: true,
+    })
+    .await?;
+    let collection = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    let b1 = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    let newer = db
+        .get_bsos(query(MAX_TIMESTAMP, as_u64(modified[0])))
+        .await?;
+    let newest = db
+        .get_bsos(query(MAX_TIMESTAMP, as_u64(modified[1])))
+        .await?;
+    let older = db.get_bsos(query(as_u64(modified[1]), 0)).await?;
+    db.commit().await?;
+
+    db.begin(true).await?;
+    db.delete_storage(hid(uid)).await?;
+    db.commit().await?;
+
+    assert!(modified[0] < modified[1] && modified[1] < modified[2] && modified[2] < deleted);
+    assert_eq!(collection, deleted);
+    assert_eq!(b1.modified, modified[1]);
+    assert_eq!(newer.items.len(), 1);
+    assert_eq!(newer.items[0].id, "b1");
+    assert!(newest.items.is_empty());
+    assert_eq!(older.items.len(), 1);
+    assert_eq!(older.items[0].id, "b0");
+    Ok(())
+}
+
+async fn get_bsos(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "clients";
     let sortindexes = vec![1, 3, 4, 2, 0];
     for (i, (revi, sortindex)) in sortindexes.iter().enumerate().rev().enumerate() {
@@ -836,17 +1627,163 @@ async fn get_bsos() -> Result<()> {
         ))
         .await?;
     assert_eq!(bsos.items.len(), 2);
-    assert_eq!(bsos.offset, Some("2".to_string()));
     assert_eq!(bsos.items[0].id, "b2");
     assert_eq!(bsos.items[1].id, "b1");
+
+    // The offset's opaque (numeric, or a keyset on MySQL): it resumes after
+    // the page, whatever its form
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            2,
+            &bsos.offset.expect("No offset"),
+        ))
+        .await?;
+    assert_eq!(bsos.items.len(), 2);
+    assert_eq!(bsos.items[0].id, "b3");
+    assert_eq!(bsos.items[1].id, "b0");
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            2,
+            &bsos.offset.expect("No offset"),
+        ))
+        .await?;
+    assert_eq!(bsos.items.len(), 1);
+    assert_eq!(bsos.items[0].id, "b4");
+    assert_eq!(bsos.offset, None);
+    Ok(())
+}
+
+async fn get_bsos_max_bytes(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    for i in 0..5 {
+        let bso = pbso(uid, coll, &format!("b{}", i), Some("x"), Some(i), None);
+        db.put_bso(bso).await?;
+    }
+    let params = |offset: &str| gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, Sorting::Index, 10, offset);
+    let all = db.get_bsos(params("0")).await?;
+    assert!(!all.truncated);
+    // A ceiling fitting two of the records per page
+    let max_bytes = Some(response_size(&all.items[0]) + response_size(&all.items[1]));
+
+    let mut ids = vec![];
+    let mut offset = "0".to_owned();
+    let mut pages = vec![];
+    loop {
+        let mut page_params = params(&offset);
+        page_params.params.max_bytes = max_bytes;
+        let page = db.get_bsos(page_params).await?;
+        pages.push((page.items.len(), page.truncated));
+        ids.extend(page.items.into_iter().map(|bso| bso.id));
+        match page.offset {
+            Some(next) => offset = next,
+            None => break,
+        }
+    }
+    assert_eq!(pages, vec![(2, true), (2, true), (1, false)]);
+    assert_eq!(ids, vec!["b4", "b3", "b2", "b1", "b0"]);
     Ok(())
 }
 
-#[async_test]
-async fn get_bso_timestamp() -> Result<()> {
-    let db = db().await?;
+async fn get_bsos_after(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
+    let coll = "clients";
+    // Runs of equal modified timestamps: most pages end amid one
+    for (i, delta) in [-30, -20, -10].iter().enumerate() {
+        for id in &["a", "b", "c"] {
+            let bso = pbso(uid, coll, &format!("{}{}", id, i), Some("x"), None, None);
+            with_delta!(&db, *delta, { db.put_bso(bso).await })?;
+        }
+    }
+    let existing: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .flat_map(|id| (0..3).map(move |i| format!("{}{}", id, i)))
+        .collect();
+
+    for &sort in &[Sorting::Newest, Sorting::Oldest] {
+        let mut ids = vec![];
+        let mut after = None;
+        let mut page = 0;
+        loop {
+            let result = db
+                .get_bsos_after(params::GetBsosAfter {
+                    user_id: hid(uid),
+                    collection: coll.to_owned(),
+                    after: after.clone(),
+                    sort,
+                    limit: 2,
+                })
+                .await?;
+            assert!(result.items.len() <= 2);
+            ids.extend(result.items.iter().map(|bso| bso.id.clone()));
+            // Inserted between pages: sorting before (Newest) or after
+            // (Oldest) the position, amid a run of the existing timestamps
+            let bso = pbso(
+                uid,
+                coll,
+                &format!("new{:?}{}", sort, page),
+                Some("x"),
+                None,
+                None,
+            );
+            with_delta!(&db, -20, { db.put_bso(bso).await })?;
+            page += 1;
+            if !result.more {
+                break;
+            }
+            after = result
+                .items
+                .last()
+                .map(|bso| (bso.modified, bso.id.clone()));
+        }
+
+        // Every existing BSO exactly once, in the sort order
+        let mut seen = ids.clone();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), ids.len(), "{:?}: duplicates {:?}", sort, ids);
+        for id in &existing {
+            assert!(ids.contains(id), "{:?}: skipped {} {:?}", sort, id, ids);
+        }
+        let expected_first = if sort == Sorting::Newest { "c2" } else { "a0" };
+        assert_eq!(ids[0], expected_first);
+    }
+
+    // A limit of 0 still makes progress
+    let result = db
+        .get_bsos_after(params::GetBsosAfter {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            after: None,
+            sort: Sorting::Oldest,
+            limit: 0,
+        })
+        .await?;
+    assert_eq!(result.items.len(), 1);
+    assert!(result.more);
+    Ok(())
+}
+
+async fn get_bso_timestamp(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "clients";
     let bid = "b0";
     let bso = pbso(uid, coll, bid, Some("a"), None, None);
@@ -862,11 +1799,10 @@ async fn get_bso_timestamp() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn delete_bso() -> Result<()> {
-    let db = db().await?;
+async fn delete_bso(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let bid = "b0";
     db.put_bso(pbso(uid, coll, bid, Some("a"), None, None))
@@ -877,11 +1813,10 @@ async fn delete_bso() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn delete_bsos() -> Result<()> {
-    let db = db().await?;
+async fn delete_bsos(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     let bids = (0..=2).map(|i| format!("b{}", i));
     for bid in bids.clone() {
@@ -897,12 +1832,15 @@ async fn delete_bsos() -> Result<()> {
     }
     db.delete_bso(dbso(uid, coll, "b0")).await?;
     // deleting non existant bid errors
-    assert!(db
-        .delete_bso(dbso(uid, coll, "bxi0"))
-        .await
-        .unwrap_err()
-        .is_bso_not_found());
-    db.delete_bsos(dbsos(uid, coll, &["b1", "b2"])).await?;
+    let err = db.delete_bso(dbso(uid, coll, "bxi0")).await.unwrap_err();
+    assert!(err.is_bso_not_found());
+    // labeled with its originating operation
+    assert_eq!(err.db_labels().map(|(_, op)| op), Some("delete_bso"));
+    // nonexistent ids are ignored (and not counted)
+    let result = db
+        .delete_bsos(dbsos(uid, coll, &["b1", "b2", "bxi0"]))
+        .await?;
+    assert_eq!(result.deleted, 2);
     for bid in bids {
         let bso = db.get_bso(gbso(uid, coll, &bid)).await?;
         assert!(bso.is_none());
@@ -910,31 +1848,226 @@ async fn delete_bsos() -> Result<()> {
     Ok(())
 }
 
-/*
-#[async_test]
-async fn usage_stats() -> Result<()> {
-    let db = db().await?;
+async fn bulk_set_ttl(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    let timestamp = db.timestamp().as_i64();
+    for bid in &["b0", "b1"] {
+        let bso = pbso(uid, coll, bid, Some("payload"), Some(1), Some(10));
+        with_delta!(db, -100, { db.put_bso(bso).await })?;
+    }
+    let bso = pbso(uid, coll, "b2", Some("payload"), Some(1), Some(1));
+    with_delta!(db, -100_000, { db.put_bso(bso).await })?;
+
+    // nonexistent (and expired) ids are ignored
+    let modified = db
+        .bulk_set_ttl(params::BulkSetTtl {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            ids: vec!["b0".to_owned(), "b2".to_owned(), "bxi0".to_owned()],
+            ttl: 1000,
+        })
+        .await?;
+    assert_eq!(modified.as_i64(), timestamp);
+    assert_eq!(
+        db.get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?,
+        modified
+    );
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.modified, modified);
+    assert_eq!(bso.expiry, timestamp + 1000 * 1000);
+    assert_eq!(bso.payload, "payload");
+    assert_eq!(bso.sortindex, Some(1));
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.modified.as_i64(), timestamp - 100);
+    assert_eq!(bso.expiry, timestamp - 100 + 10 * 1000);
+    assert!(db.get_bso(gbso(uid, coll, "b2")).await?.is_none());
     Ok(())
 }
 
-#[async_test]
-async fn purge_expired() -> Result<()> {
-    let db = db().await?;
+/*
+async fn usage_stats(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
     Ok(())
 }
 
-#[async_test]
-async fn optimize() -> Result<()> {
-    let db = db().await?;
+async fn optimize(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
     Ok(())
 }
 */
 
-#[async_test]
-async fn delete_storage() -> Result<()> {
-    let db = db().await?;
+async fn reset_collection(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "clients";
+    for bid in &["b0", "b1"] {
+        let bso = pbso(uid, coll, bid, Some("old"), Some(1), None);
+        with_delta!(db, -1000, { db.put_bso(bso).await })?;
+    }
+
+    let result = db
+        .reset_collection(params::ResetCollection {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![
+                postbso("b1", Some("new 1"), None, None),
+                postbso("b2", Some("new 2"), None, None),
+            ],
+            failed: Default::default(),
+            breakdown: false,
+        })
+        .await?;
+    assert_eq!(result.modified, db.timestamp());
+    assert_eq!(result.success, vec!["b1".to_owned(), "b2".to_owned()]);
+
+    assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_none());
+    // Replaced outright: unsupplied fields aren't carried over
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.payload, "new 1");
+    assert_eq!(bso.sortindex, None);
+    assert_eq!(bso.modified, db.timestamp());
+    assert!(db.get_bso(gbso(uid, coll, "b2")).await?.is_some());
+    let counts = db.get_collection_counts(gcounts(uid)).await?;
+    assert_eq!(counts.get(coll), Some(&2));
+    let ts = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    assert_eq!(ts, result.modified);
+    Ok(())
+}
+
+async fn large_user_id(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    // Beyond both i32 and u32
+    let user_id = HawkIdentifier::new_legacy((1 << 40) + u64::from(uid()));
+    let truncated = HawkIdentifier::new_legacy(u64::from(user_id.legacy_id as u32));
+    let coll = "clients";
+    let modified = db
+        .put_bso(params::PutBso {
+            user_id: user_id.clone(),
+            collection: coll.to_owned(),
+            id: "b0".to_owned(),
+            sortindex: Some(1),
+            payload: Some("x".to_owned()),
+            ttl: None,
+            create_only: false,
+        })
+        .await?;
+    let get_bso = |user_id: &HawkIdentifier| params::GetBso {
+        user_id: user_id.clone(),
+        collection: coll.to_owned(),
+        id: "b0".to_owned(),
+    };
+    let bso = db.get_bso(get_bso(&user_id)).await?.unwrap();
+    assert_eq!(bso.payload, "x");
+    assert!(db.get_bso(get_bso(&truncated)).await?.is_none());
+
+    let timestamps = db.get_collection_timestamps(user_id.clone()).await?;
+    assert_eq!(timestamps.get(coll), Some(&modified));
+    assert!(db
+        .get_collection_timestamps(truncated)
+        .await?
+        .get(coll)
+        .is_none());
+
+    db.delete_collection(params::DeleteCollection {
+        user_id: user_id.clone(),
+        collection: coll.to_owned(),
+    })
+    .await?;
+    assert!(db.get_bso(get_bso(&user_id)).await?.is_none());
+    Ok(())
+}
+
+async fn max_collections_per_user(settings: Settings) -> Result<()> {
+    let db = db(&Settings {
+        max_collections_per_user: Some(2),
+        ..settings
+    })
+    .await?;
+
+    let uid = uid();
+    for coll in &["custom0", "custom1"] {
+        db.put_bso(pbso(uid, coll, "b0", Some("x"), None, None))
+            .await?;
+    }
+    let err = db
+        .put_bso(pbso(uid, "custom2", "b0", Some("x"), None, None))
+        .await
+        .unwrap_err();
+    assert!(match err.kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::TooManyCollections),
+        _ => false,
+    });
+
+    // Existing custom and standard collections remain writable
+    db.put_bso(pbso(uid, "custom1", "b1", Some("x"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "clients", "b0", Some("x"), None, None))
+        .await?;
+    // The limit is per user
+    db.put_bso(pbso(uid(), "custom2", "b0", Some("x"), None, None))
+        .await?;
+    Ok(())
+}
 
-    let uid = *UID;
+async fn purge_expired(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let other = uid();
+    let coll = "clients";
+    // Expired 10 seconds ago
+    for bid in &["1", "2", "3"] {
+        let bso = pbso(uid, coll, bid, Some("x"), None, Some(10));
+        with_delta!(db, -20_000, { db.put_bso(bso).await })?;
+    }
+    let bso = pbso(other, coll, "1", Some("x"), None, Some(10));
+    with_delta!(db, -20_000, { db.put_bso(bso).await })?;
+    db.put_bso(pbso(uid, coll, "4", Some("x"), None, Some(3600)))
+        .await?;
+
+    let purge = |uid, limit, dry_run| params::PurgeExpired {
+        user_id: Some(hid(uid)),
+        collection: Some(coll.to_owned()),
+        limit,
+        dry_run,
+    };
+    assert_eq!(db.purge_expired_bsos(purge(uid, 10, true)).await?, 3);
+    assert_eq!(db.purge_expired_bsos(purge(uid, 2, false)).await?, 2);
+    assert_eq!(db.purge_expired_bsos(purge(uid, 10, false)).await?, 1);
+    assert_eq!(db.purge_expired_bsos(purge(uid, 10, false)).await?, 0);
+    assert!(db.get_bso(gbso(uid, coll, "4")).await?.is_some());
+    // Other users' are untouched
+    assert_eq!(db.purge_expired_bsos(purge(other, 10, true)).await?, 1);
+    // Nor is there anything to purge of an unknown collection
+    let result = db
+        .purge_expired_bsos(params::PurgeExpired {
+            collection: Some("NewCollection".to_owned()),
+            ..purge(uid, 10, false)
+        })
+        .await?;
+    assert_eq!(result, 0);
+    Ok(())
+}
+
+async fn delete_storage(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let bid = "test";
     let coll = "my_collection";
     let cid = db.create_collection(coll.to_owned()).await?;
@@ -949,17 +2082,120 @@ async fn delete_storage() -> Result<()> {
     let cid2 = db.get_collection_id("my_collection".to_owned()).await?;
     assert_eq!(cid2, cid);
 
-    let collections = db.get_collection_counts(hid(uid)).await?;
+    let collections = db.get_collection_counts(gcounts(uid)).await?;
     assert!(collections == HashMap::<String, i64>::new());
 
     Ok(())
 }
 
-#[async_test]
-async fn collection_cache() -> Result<()> {
-    let db = db().await?;
+async fn delete_storage_many_collections(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
+    let colls: Vec<_> = (0..20).map(|i| format!("coll{}", i)).collect();
+    for coll in &colls {
+        db.post_bsos(params::PostBsos {
+            user_id: hid(uid),
+            collection: coll.clone(),
+            bsos: (0..5)
+                .map(|i| postbso(&i.to_string(), Some("x"), None, None))
+                .collect(),
+            failed: Default::default(),
+            breakdown: false,
+        })
+        .await?;
+    }
+    // Including a collection only holding a pending batch
+    db.create_collection("pending".to_owned()).await?;
+    let id = db
+        .create_batch(params::CreateBatch {
+            user_id: hid(uid),
+            collection: "pending".to_owned(),
+            bsos: vec![postbso("b0", Some("x"), None, None)],
+        })
+        .await?;
+    assert_eq!(
+        db.get_collection_counts(gcounts(uid)).await?.len(),
+        colls.len()
+    );
+
+    // One collection at a time, then the rest
+    let ts = db
+        .delete_collection(params::DeleteCollection {
+            user_id: hid(uid),
+            collection: colls[0].clone(),
+        })
+        .await?;
+    assert_eq!(db.get_storage_timestamp(hid(uid)).await?, ts);
+    let counts = db.get_collection_counts(gcounts(uid)).await?;
+    assert_eq!(counts.len(), colls.len() - 1);
+    assert!(!counts.contains_key(&colls[0]));
+    assert_eq!(counts.get(&colls[1]), Some(&5));
+
+    // Retries converge
+    for _ in 0..2 {
+        db.delete_storage(hid(uid)).await?;
+        assert!(db.get_collection_counts(gcounts(uid)).await?.is_empty());
+        assert_eq!(db.get_storage_usage(hid(uid)).await?, 0);
+        assert!(db.get_collection_timestamps(hid(uid)).await?.is_empty());
+    }
+    let batch = db
+        .get_batch(params::GetBatch {
+            user_id: hid(uid),
+            collection: "pending".to_owned(),
+            id,
+        })
+        .await?;
+    assert!(batch.is_none());
+    Ok(())
+}
+
+async fn pinned_standard_collections(settings: Settings) -> Result<()> {
+    let db = db(&Settings {
+        standard_collections: vec![("containers".to_owned(), 14)].into_iter().collect(),
+        ..settings
+    })
+    .await?;
+
+    assert_eq!(db.get_collection_id("containers".to_owned()).await?, 14);
+    let id = db.create_collection("custom".to_owned()).await?;
+    assert!(id >= FIRST_CUSTOM_COLLECTION_ID);
+    Ok(())
+}
+
+#[test]
+fn standard_collections_validation() {
+    let settings = |colls: &[(&str, i32)]| Settings {
+        standard_collections: colls
+            .iter()
+            .map(|(name, id)| ((*name).to_owned(), *id))
+            .collect(),
+        ..Default::default()
+    };
+    let invalid = |colls: &[(&str, i32)]| match standard_collections(&settings(colls)) {
+        Err(e) => matches!(e.kind(), DbErrorKind::InvalidStandardCollections(_)),
+        Ok(_) => false,
+    };
+
+    let colls = standard_collections(&settings(&[("containers", 14), ("work", 99)])).unwrap();
+    assert!(colls.contains(&(1, "clients".to_owned())));
+    assert!(colls.contains(&(14, "containers".to_owned())));
+    assert!(colls.contains(&(99, "work".to_owned())));
+
+    // Built-in ids/names
+    assert!(invalid(&[("containers", 13)]));
+    assert!(invalid(&[("clients", 14)]));
+    // Outside of the reserved range
+    assert!(invalid(&[("containers", 100)]));
+    assert!(invalid(&[("containers", FIRST_CUSTOM_COLLECTION_ID)]));
+    // Duplicate ids
+    assert!(invalid(&[("containers", 14), ("work", 14)]));
+}
+
+async fn collection_cache(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "test";
     let cid = db.create_collection(coll.to_owned()).await?;
     db.touch_collection(params::TouchCollection {
@@ -974,15 +2210,113 @@ async fn collection_cache() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn lock_for_read() -> Result<()> {
-    let db = db().await?;
+async fn warm_collection_cache(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let names: Vec<_> = (0..3).map(|i| format!("warm{}", i)).collect();
+    let mut ids = vec![];
+    for name in &names {
+        ids.push(db.create_collection(name.clone()).await?);
+    }
+
+    // Small batches to exercise the paging
+    let cache = CollectionCache::new(&[]);
+    let count = cache.warm(&*db, 2).await?;
+    assert!(count >= names.len());
+    for (name, id) in names.iter().zip(&ids) {
+        assert_eq!(cache.get_id(name)?, Some(*id));
+        assert_eq!(cache.get_name(*id)?.as_ref(), Some(name));
+    }
+
+    let page = db
+        .get_collections(params::GetCollections {
+            after_id: ids[0],
+            limit: 1,
+        })
+        .await?;
+    assert_eq!(page, vec![(ids[1], names[1].clone())]);
+    Ok(())
+}
+
+async fn collection_cache_skips_uncommitted(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
+    let coll = "uncommitted";
+    db.begin(true).await?;
+    db.put_bso(pbso(uid, coll, "b0", Some("x"), None, None))
+        .await?;
+    // Visible within the transaction, but not cached
+    db.get_collection_id(coll.to_owned()).await?;
+    db.rollback().await?;
 
-    let uid = *UID;
+    let result = db.get_collection_id(coll.to_owned()).await;
+    assert!(result.unwrap_err().is_collection_not_found());
+    Ok(())
+}
+
+async fn get_collection_id_cached(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    // Cache hit: the standard collections are prepopulated
+    assert_eq!(db.cached_collection_id("bookmarks"), Some(7));
+    assert_eq!(db.get_collection_id("bookmarks".to_owned()).await?, 7);
+
+    // Db hit: read then cached
+    let coll = "cached";
+    let cid = db.create_collection(coll.to_owned()).await?;
+    db.clear_coll_cache();
+    assert_eq!(db.cached_collection_id(coll), None);
+    assert_eq!(db.get_collection_id(coll.to_owned()).await?, cid);
+    assert_eq!(db.cached_collection_id(coll), Some(cid));
+    Ok(())
+}
+
+async fn get_or_create_collection_id(settings: Settings) -> Result<()> {
+    let db = db(&Settings {
+        max_collections_per_user: Some(1),
+        ..settings
+    })
+    .await?;
+
+    let uid = uid();
+    let coll = "created";
+    let result = db.get_collection_id(coll.to_owned()).await;
+    assert!(result.unwrap_err().is_collection_not_found());
+
+    let get_or_create = |uid, coll: &str| {
+        db.get_or_create_collection_id(params::GetOrCreateCollectionId {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+    };
+    let cid = get_or_create(uid, coll).await?;
+    assert!(cid >= FIRST_CUSTOM_COLLECTION_ID);
+    assert_eq!(db.get_collection_id(coll.to_owned()).await?, cid);
+    assert_eq!(get_or_create(uid, coll).await?, cid);
+
+    // Writing to another custom collection would exceed the user's limit
+    db.put_bso(pbso(uid, coll, "b0", Some("x"), None, None))
+        .await?;
+    let err = get_or_create(uid, "created2").await.unwrap_err();
+    assert!(match err.kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::TooManyCollections),
+        _ => false,
+    });
+    assert_eq!(get_or_create(uid, coll).await?, cid);
+    assert_eq!(get_or_create(uid, "clients").await?, 1);
+    Ok(())
+}
+
+async fn lock_for_read(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let uid = uid();
     let coll = "clients";
-    db.lock_for_read(params::LockCollection {
+    db.lock_for_read(params::LockCollectionForRead {
         user_id: hid(uid),
         collection: coll.to_owned(),
+        strong: false,
     })
     .await?;
     let result = db.get_collection_id("NewCollection".to_owned()).await;
@@ -991,11 +2325,10 @@ async fn lock_for_read() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn lock_for_write() -> Result<()> {
-    let db = db().await?;
+async fn lock_for_write(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
-    let uid = *UID;
+    let uid = uid();
     let coll = "clients";
     db.lock_for_write(params::LockCollection {
         user_id: hid(uid),
@@ -1008,10 +2341,112 @@ async fn lock_for_write() -> Result<()> {
     Ok(())
 }
 
-#[async_test]
-async fn heartbeat() -> Result<()> {
-    let db = db().await?;
+async fn heartbeat(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
 
     assert!(db.check().await?);
     Ok(())
 }
+
+async fn health_detail(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let detail = db.health_detail().await?;
+    assert!(detail.last_success_ms.unwrap() > 0);
+    // This Db's connection is in use
+    assert!(detail.connections > detail.idle_connections);
+    assert!(detail.saturation > 0.0 && detail.saturation <= 1.0);
+    // The pool was warmed up as it was built
+    assert!(!detail.warming_up);
+    Ok(())
+}
+
+async fn try_acquire_maintenance_lock(settings: Settings) -> Result<()> {
+    let db = db(&settings).await?;
+
+    let lock = |name: &str, ttl| params::TryAcquireMaintenanceLock {
+        name: name.to_owned(),
+        ttl,
+    };
+    assert!(db.try_acquire_maintenance_lock(lock("purge", 60)).await?);
+    // Held until its lease expires
+    assert!(!db.try_acquire_maintenance_lock(lock("purge", 60)).await?);
+    // Locks are independent of one another
+    assert!(db.try_acquire_maintenance_lock(lock("reap", 0)).await?);
+    // An expired lease may be taken over
+    assert!(db.try_acquire_maintenance_lock(lock("reap", 60)).await?);
+    assert!(!db.try_acquire_maintenance_lock(lock("reap", 60)).await?);
+    Ok(())
+}
+
+db_test_suite! {
+    bso_successfully_updates_single_values,
+    bso_modified_not_changed_on_ttl_touch,
+    put_bso_updates,
+    put_bso_modified_matches_get,
+    default_sortindex,
+    get_bsos_limit_offset,
+    get_bsos_max_bytes,
+    count_bsos,
+    get_bsos_newer,
+    get_bsos_inclusive_bounds,
+    get_bsos_expiring_before,
+    get_bsos_sort,
+    delete_bsos_in_correct_collection,
+    get_storage_timestamp,
+    get_collection_id,
+    create_collection,
+    touch_collection,
+    delete_collection,
+    delete_collection_tombstone,
+    collection_exists,
+    get_collection_timestamps,
+    get_collection_timestamps_tombstone,
+    get_collection_usage,
+    usage_excludes_expired,
+    usage_of_reference_lookalikes,
+    utf8mb4_payloads,
+    payload_codec,
+    payload_blob_store,
+    export_user,
+    migrate_user,
+    get_collection_counts,
+    get_collection_counts_newer,
+    get_collection_names,
+    put_bso,
+    put_bso_create_only,
+    post_bsos,
+    post_bsos_mixed_fields,
+    post_bsos_breakdown,
+    get_bso,
+    get_bsos,
+    newer_than_returned_modified,
+    newer_older_committed,
+    get_bsos_after,
+    get_bso_timestamp,
+    delete_bso,
+    delete_bsos,
+    bulk_set_ttl,
+    reset_collection,
+    max_collections_per_user,
+    purge_expired,
+    delete_storage,
+    delete_storage_many_collections,
+    pinned_standard_collections,
+    collection_cache,
+    warm_collection_cache,
+    collection_cache_skips_uncommitted,
+    get_collection_id_cached,
+    get_or_create_collection_id,
+    lock_for_read,
+    lock_for_write,
+    heartbeat,
+    health_detail,
+    try_acquire_maintenance_lock,
+}
+
+// Spanner identifies users by their fxa_uid (not their legacy id)
+db_test_suite!(mysql_only, Mysql; large_user_id);
+
+// MockDb only stubs out the Db trait with default values
+db_test_suite!(mock, Mock; heartbeat);
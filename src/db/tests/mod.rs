@@ -1,6 +1,6 @@
 #[cfg(test)]
 #[macro_use]
-mod support;
+pub(super) mod support;
 
 #[cfg(test)]
 mod batch;
@@ -1,7 +1,24 @@
-use std::str::FromStr;
+use std::{
+    env,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Once,
+    },
+};
+
+use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
 
 use crate::{
-    db::{params, pool_from_settings, util::SyncTimestamp, Db, Sorting},
+    db::{
+        mock::MockDbPool,
+        params, pool_from_settings,
+        spanner::{emulator, manager::emulator_host, migrations},
+        util::SyncTimestamp,
+        Db, DbPool, Sorting,
+    },
     error::ApiError,
     server::metrics,
     settings::{Secrets, ServerLimits, Settings},
@@ -10,24 +27,106 @@ use crate::{
 
 pub type Result<T> = std::result::Result<T, ApiError>;
 
-pub async fn db() -> Result<Box<dyn Db>> {
-    let _ = env_logger::try_init();
-    // inherit SYNC_DATABASE_URL from the env
-    let settings = Settings::with_env_and_config_file(&None).unwrap();
-    let settings = Settings {
-        debug: true,
-        port: 8000,
-        host: settings.host,
-        database_url: settings.database_url,
-        database_pool_max_size: Some(1),
-        database_use_test_transactions: true,
-        limits: ServerLimits::default(),
-        master_secret: Secrets::default(),
-        ..Default::default()
-    };
+const MOCK_DATABASE_URL: &str = "mock://";
+
+/// The Spanner emulator's test database (when `TEST_SPANNER_URL` isn't set)
+const EMULATOR_DATABASE_URL: &str =
+    "spanner://projects/test-project/instances/test-instance/databases/syncstorage";
 
+lazy_static! {
+    static ref UID_BASE: u32 = thread_rng().gen_range(0, 1_000_000) * 1000;
+}
+
+/// The backends the shared db test suite runs against.
+#[derive(Clone, Copy, Debug)]
+pub enum Backend {
+    Mysql,
+    Spanner,
+    Mock,
+}
+
+impl Backend {
+    /// The env var naming this backend's test database
+    fn env_var(self) -> Option<&'static str> {
+        match self {
+            Backend::Mysql => Some("TEST_MYSQL_URL"),
+            Backend::Spanner => Some("TEST_SPANNER_URL"),
+            Backend::Mock => None,
+        }
+    }
+
+    /// Test settings for this backend, or `None` when its env var isn't set
+    /// (nor, for Spanner, `SPANNER_EMULATOR_HOST`)
+    pub fn settings(self) -> Option<Settings> {
+        let database_url = match self.env_var() {
+            Some(var) => match (self, env::var(var)) {
+                (_, Ok(database_url)) => database_url,
+                (Backend::Spanner, Err(_)) if emulator_host().is_some() => {
+                    EMULATOR_DATABASE_URL.to_owned()
+                }
+                _ => return None,
+            },
+            None => MOCK_DATABASE_URL.to_owned(),
+        };
+        let settings = Settings::with_env_and_config_file(&None).unwrap();
+        let settings = Settings {
+            debug: true,
+            port: 8000,
+            host: settings.host,
+            database_url,
+            database_pool_max_size: Some(1),
+            database_use_test_transactions: true,
+            limits: ServerLimits::default(),
+            master_secret: Secrets::default(),
+            ..Default::default()
+        };
+        if let Backend::Spanner = self {
+            if emulator_host().is_some() {
+                bootstrap_emulator(&settings);
+            }
+        }
+        Some(settings)
+    }
+}
+
+/// Create the emulator's test database and its schema (once per test run:
+/// the emulator's state is lost whenever it restarts)
+fn bootstrap_emulator(settings: &Settings) {
+    static BOOTSTRAP: Once = Once::new();
+    BOOTSTRAP.call_once(|| {
+        emulator::create_database(settings).expect("Couldn't create the emulator's database");
+        migrations::run_migrations(settings).expect("Couldn't migrate the emulator's database");
+    });
+}
+
+/// Run a test from the shared suite against `backend`, skipping it when the
+/// backend isn't configured
+pub async fn run<F, Fut>(backend: Backend, test: F) -> Result<()>
+where
+    F: FnOnce(Settings) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    match backend.settings() {
+        Some(settings) => test(settings).await,
+        None => {
+            eprintln!(
+                "Skipping {:?} db test: {} (or SPANNER_EMULATOR_HOST) not set",
+                backend,
+                backend.env_var().unwrap_or_default()
+            );
+            Ok(())
+        }
+    }
+}
+
+pub async fn db(settings: &Settings) -> Result<Box<dyn Db>> {
+    let _ = env_logger::try_init();
     let metrics = metrics::Metrics::noop();
-    let pool = pool_from_settings(&settings, &metrics)?;
+    let pool: Box<dyn DbPool> = if settings.database_url == MOCK_DATABASE_URL {
+        Box::new(MockDbPool::new())
+    } else {
+        pool_from_settings(settings, &metrics)?
+    };
     let db = pool.get().await?;
     // Spanner won't have a timestamp until lock_for_xxx are called: fill one
     // in for it
@@ -35,6 +134,59 @@ pub async fn db() -> Result<Box<dyn Db>> {
     Ok(db)
 }
 
+/// A db whose transactions are committed (unlike `db`'s test transactions),
+/// running the backends' production write paths (e.g. Spanner's mutations,
+/// only applied once committed).
+///
+/// Its writes persist: tests using it must delete their user's data.
+pub async fn committing_db(settings: &Settings) -> Result<Box<dyn Db>> {
+    Ok(committing_pool(settings)?.get().await?)
+}
+
+/// A pool of `committing_db`s
+pub fn committing_pool(settings: &Settings) -> Result<Box<dyn DbPool>> {
+    let settings = Settings {
+        database_use_test_transactions: false,
+        ..settings.clone()
+    };
+    let _ = env_logger::try_init();
+    let metrics = metrics::Metrics::noop();
+    Ok(if settings.database_url == MOCK_DATABASE_URL {
+        Box::new(MockDbPool::new())
+    } else {
+        pool_from_settings(&settings, &metrics)?
+    })
+}
+
+/// A user id unused by any other test in this run, so tests (and suites)
+/// may run in parallel without observing each other's data
+pub fn uid() -> u32 {
+    static NEXT_UID: AtomicU32 = AtomicU32::new(0);
+    *UID_BASE + NEXT_UID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Instantiate the shared db test suite: each named `async fn(Settings) ->
+/// Result<()>` in the calling module becomes a test per backend, within a
+/// `mysql`/`spanner` (or the given) submodule.
+macro_rules! db_test_suite {
+    ($($name:ident),+ $(,)?) => {
+        db_test_suite!(mysql, Mysql; $($name),+);
+        db_test_suite!(spanner, Spanner; $($name),+);
+    };
+    ($module:ident, $backend:ident; $($name:ident),+ $(,)?) => {
+        mod $module {
+            use $crate::db::tests::support::{run, Backend, Result};
+
+            $(
+                #[futures_await_test::async_test]
+                async fn $name() -> Result<()> {
+                    run(Backend::$backend, super::$name).await
+                }
+            )+
+        }
+    };
+}
+
 macro_rules! with_delta {
     ($db:expr, $delta:expr, $body:block) => {{
         let ts = $db.timestamp().as_i64();
@@ -60,6 +212,7 @@ pub fn pbso(
         payload: payload.map(|payload| payload.to_owned()),
         sortindex,
         ttl,
+        create_only: false,
     }
 }
 
@@ -103,10 +256,15 @@ pub fn gbsos(
             ids: bids.iter().map(|id| id.to_owned().into()).collect(),
             older: Some(SyncTimestamp::from_milliseconds(older)),
             newer: Some(SyncTimestamp::from_milliseconds(newer)),
+            newer_eq: None,
+            older_eq: None,
+            expiring_before: None,
             sort,
             limit: Some(limit as u32),
             offset: Some(Offset::from_str(offset).unwrap_or_default()),
             full: true,
+            breakdown: false,
+            max_bytes: None,
         },
     }
 }
@@ -127,6 +285,13 @@ pub fn dbsos(user_id: u32, coll: &str, bids: &[&str]) -> params::DeleteBsos {
     }
 }
 
+pub fn gcounts(user_id: u32) -> params::GetCollectionCounts {
+    params::GetCollectionCounts {
+        user_id: hid(user_id),
+        newer: None,
+    }
+}
+
 pub fn hid(user_id: u32) -> HawkIdentifier {
     HawkIdentifier::new_legacy(u64::from(user_id))
 }
@@ -1,4 +1,13 @@
-use std::{convert::TryInto, u64};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+    u64,
+};
 
 use chrono::{
     offset::{FixedOffset, TimeZone, Utc},
@@ -7,17 +16,68 @@ use chrono::{
 use diesel::{
     backend::Backend,
     deserialize::{self, FromSql},
+    r2d2::{self, ManageConnection, Pool, PooledConnection},
     sql_types::BigInt,
 };
 use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::{DbError, DbErrorKind};
+use super::{results, DbError, DbErrorKind};
+use crate::server::metrics::Metrics;
+
+/// Default for how far into the future client supplied timestamps may be
+pub const DEFAULT_TIMESTAMP_SLACK_SECS: u64 = 24 * 60 * 60;
+
+/// Default threshold beyond which waiting for a pooled connection is logged
+pub const DEFAULT_POOL_ACQUIRE_WARN_MS: u64 = 1000;
 
 /// Get the time since the UNIX epoch in milliseconds
 pub fn ms_since_epoch() -> i64 {
     Utc::now().timestamp_millis()
 }
 
+/// A record's share of a get_collection response's size: its JSON
+/// serialization plus a separator (a comma or newline)
+pub fn response_size<T: Serialize>(item: &T) -> usize {
+    serde_json::to_vec(item).map_or(0, |v| v.len()) + 1
+}
+
+/// Tracks the size of a get_collection response read so far against its
+/// `max_bytes` ceiling (see `BsoQueryParams::max_bytes`)
+pub struct ResponseCeiling {
+    max_bytes: Option<usize>,
+    size: usize,
+    read: usize,
+    reached: bool,
+}
+
+impl ResponseCeiling {
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            max_bytes,
+            size: 0,
+            read: 0,
+            reached: false,
+        }
+    }
+
+    /// Add a record read, returning whether it fits beneath the ceiling (the
+    /// first always does). The page's truncated before the first that
+    /// doesn't: no more need be read
+    pub fn fits<T: Serialize>(&mut self, item: &T) -> bool {
+        if let Some(max_bytes) = self.max_bytes {
+            self.size += response_size(item);
+            self.read += 1;
+            self.reached = self.size > max_bytes && self.read > 1;
+        }
+        !self.reached
+    }
+
+    /// Whether the page was truncated at the ceiling
+    pub fn reached(&self) -> bool {
+        self.reached
+    }
+}
+
 /// Sync Timestamp
 ///
 /// Internally represents a Sync timestamp as a u64 representing milliseconds since the epoch.
@@ -34,9 +94,24 @@ impl SyncTimestamp {
         format_ts(self.0)
     }
 
-    /// Create a `SyncTimestamp` from a string header
+    /// Create a Sync Timestamp header value with millisecond (three decimal
+    /// places) precision
+    ///
+    /// For clients opting into higher precision (see
+    /// `X-Weave-Timestamp-Precision`). Note that timestamps are currently
+    /// truncated to 10 milliseconds.
+    pub fn as_header_millis(self) -> String {
+        format!("{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+
+    /// Create a `SyncTimestamp` from a client supplied string header (or
+    /// query parameter)
     ///
     /// Assumes the string represents the seconds since epoch with two decimal places of precision.
+    ///
+    /// Only checks the value's well formed, not how far it is from the
+    /// clock: client timestamps are bounded separately (see
+    /// `within_slack`), so this also parses the server's own headers.
     pub fn from_header(val: &str) -> Result<Self, &'static str> {
         val.parse::<f64>()
             .map_err(|_| "Invalid value")
@@ -47,20 +122,24 @@ impl SyncTimestamp {
                     Ok(v)
                 }
             })
-            .map(|v: f64| (v * 1_000f64) as u64)
-            .map(SyncTimestamp::from_milliseconds)
+            .map(|v| SyncTimestamp::from_milliseconds(seconds_to_ms(v)))
+    }
+
+    /// Reject a client supplied timestamp more than `slack_secs` into the
+    /// future
+    pub fn within_slack(self, slack_secs: u64) -> Result<Self, &'static str> {
+        let max = (ms_since_epoch() as u64).saturating_add(slack_secs.saturating_mul(1000));
+        if self.0 > max {
+            return Err("Invalid value (too far in the future)");
+        }
+        Ok(self)
     }
 
     /// Create a `SyncTimestamp` from an i64
     ///
     /// Only called from the db module
     pub(super) fn from_i64(val: i64) -> Result<Self, DbError> {
-        if val < 0 {
-            Err(DbErrorKind::Integrity(
-                "Invalid modified i64 (< 0)".to_owned(),
-            ))?;
-        }
-        Ok(SyncTimestamp::from_milliseconds(val as u64))
+        SyncTimestamp::try_from(val)
     }
 
     /// Exposed separately for db tests
@@ -76,8 +155,7 @@ impl SyncTimestamp {
 
     /// Create a `SyncTimestamp` from seconds since epoch
     pub fn from_seconds(val: f64) -> Self {
-        let val = (val * 1000f64) as u64;
-        SyncTimestamp(val - (val % 10))
+        SyncTimestamp::from_milliseconds(seconds_to_ms(val))
     }
 
     /// Create a `SyncTimestamp` from an RFC 3339 and ISO 8601 date and time
@@ -102,6 +180,17 @@ impl SyncTimestamp {
         self.0 as i64
     }
 
+    /// Return the least storage value truncating to a later timestamp
+    ///
+    /// Stored values read back truncated to 10 milliseconds, so range queries
+    /// over them compare against these boundaries (rather than e.g.
+    /// `as_i64() + 1`): a stray millisecond in a stored value can then
+    /// never place a record on the other side of a timestamp the client was
+    /// sent.
+    pub fn storage_next(self) -> i64 {
+        self.as_i64() + 10
+    }
+
     /// Return the timestamp as an f64 seconds since epoch
     pub fn as_seconds(self) -> f64 {
         self.0 as f64 / 1000.0
@@ -120,6 +209,20 @@ impl Default for SyncTimestamp {
     }
 }
 
+/// Convert from the i64 milliseconds since epoch storage representation
+impl TryFrom<i64> for SyncTimestamp {
+    type Error = DbError;
+
+    fn try_from(val: i64) -> Result<Self, Self::Error> {
+        if val < 0 {
+            Err(DbErrorKind::Integrity(
+                "Invalid modified i64 (< 0)".to_owned(),
+            ))?;
+        }
+        Ok(SyncTimestamp::from_milliseconds(val as u64))
+    }
+}
+
 impl From<SyncTimestamp> for i64 {
     fn from(val: SyncTimestamp) -> i64 {
         val.0 as i64
@@ -139,21 +242,29 @@ where
 {
     fn from_sql(value: Option<&<DB as Backend>::RawValue>) -> deserialize::Result<Self> {
         let i64_value = <i64 as FromSql<BigInt, DB>>::from_sql(value)?;
-        SyncTimestamp::from_i64(i64_value)
+        SyncTimestamp::try_from(i64_value)
             .map_err(|e| format!("Invalid SyncTimestamp i64 {}", e).into())
     }
 }
 
 /// Format a timestamp as second since epoch with two decimal places of precision.
 fn format_ts(val: u64) -> String {
-    format!("{:.*}", 2, val as f64 / 1000.0)
+    // Round to the nearest hundredth of a second, avoiding float formatting
+    let centis = val / 10 + u64::from(val % 10 >= 5);
+    format!("{}.{:02}", centis / 100, centis % 100)
+}
+
+/// Convert seconds since epoch to milliseconds, rounding away the float
+/// representation error (e.g. 0.29 * 1000 == 289.99999999999994)
+fn seconds_to_ms(val: f64) -> u64 {
+    (val * 1_000f64).round() as u64
 }
 
 pub fn deserialize_ts<'de, D>(d: D) -> Result<u64, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Deserialize::deserialize(d).map(|result: f64| (result * 1_000f64) as u64)
+    Deserialize::deserialize(d).map(|result: f64| SyncTimestamp::from_seconds(result).0)
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -169,6 +280,172 @@ where
     precise.serialize(s)
 }
 
+/// Rate limits the logging of query plans to at most one per interval (never,
+/// when no interval's configured)
+#[derive(Debug, Default)]
+pub struct QueryPlanSampler {
+    interval: Option<Duration>,
+    last: Mutex<Option<Instant>>,
+}
+
+impl QueryPlanSampler {
+    pub fn new(interval_secs: Option<u64>) -> Self {
+        Self {
+            interval: interval_secs.map(Duration::from_secs),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Whether the plan of the query about to run should be logged
+    pub fn sample(&self) -> bool {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        // Skip rather than wait on a concurrent sample
+        let mut last = match self.last.try_lock() {
+            Ok(last) => last,
+            Err(_) => return false,
+        };
+        let now = Instant::now();
+        if last.map_or(false, |last| now.duration_since(last) < interval) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+}
+
+/// Health of a pool's connections, shared by its `Db`s
+pub struct PoolHealth<M: ManageConnection> {
+    pool: Pool<M>,
+    /// When a query last succeeded, in milliseconds since the epoch (0 when
+    /// none have)
+    last_success: AtomicU64,
+    /// Whether the pool's established its initial connections
+    warm: AtomicBool,
+    /// Whether the db's initialized (e.g. its standard collections created,
+    /// which may be retried in the background)
+    initialized: AtomicBool,
+}
+
+impl<M: ManageConnection> PoolHealth<M> {
+    pub fn new(pool: Pool<M>) -> Self {
+        Self {
+            pool,
+            last_success: AtomicU64::new(0),
+            warm: AtomicBool::new(false),
+            initialized: AtomicBool::new(true),
+        }
+    }
+
+    /// Note whether the db's initialized: the pool's warming up until then
+    pub fn set_initialized(&self, initialized: bool) {
+        self.initialized.store(initialized, Ordering::Relaxed);
+    }
+
+    /// Whether the pool's established its `min_idle` connections (remaining
+    /// so once it has, as connections are later closed and replaced)
+    pub fn is_warm(&self) -> bool {
+        if self.warm.load(Ordering::Relaxed) {
+            return true;
+        }
+        let min_idle = self.pool.min_idle().unwrap_or_else(|| self.pool.max_size());
+        let warm = self.pool.state().connections >= min_idle;
+        if warm {
+            self.warm.store(true, Ordering::Relaxed);
+        }
+        warm
+    }
+
+    /// Record a successful query
+    pub fn record_success(&self) {
+        self.last_success
+            .store(ms_since_epoch() as u64, Ordering::Relaxed);
+    }
+
+    /// When a query last succeeded, in milliseconds since the epoch
+    pub fn last_success(&self) -> Option<u64> {
+        match self.last_success.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    /// Diagnostics including the `latency` of a successful health check
+    /// query (which should already have been recorded via
+    /// `record_success`)
+    pub fn detail(&self, latency: Duration) -> results::HealthDetail {
+        let state = self.pool.state();
+        let max_connections = self.pool.max_size();
+        let in_use = state.connections - state.idle_connections;
+        results::HealthDetail {
+            latency_ms: latency.as_millis() as u64,
+            last_success_ms: self.last_success(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_connections,
+            saturation: f64::from(in_use) / f64::from(max_connections.max(1)),
+            warming_up: !self.is_warm() || !self.initialized.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<M: ManageConnection> fmt::Debug for PoolHealth<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolHealth")
+            .field("last_success", &self.last_success)
+            .finish()
+    }
+}
+
+/// Get a connection from `pool`, recording how long was spent waiting for
+/// one (as opposed to running queries on it)
+///
+/// Waits exceeding `warn_threshold` are also logged: they indicate an
+/// undersized pool rather than a slow database.
+pub fn acquire_conn<M>(
+    pool: &Pool<M>,
+    metrics: &Metrics,
+    warn_threshold: Duration,
+) -> Result<PooledConnection<M>, r2d2::PoolError>
+where
+    M: ManageConnection,
+{
+    let start = Instant::now();
+    let result = pool.get();
+    let elapsed = start.elapsed();
+    metrics.timing("db.pool.acquire.timing", elapsed.as_millis() as u64);
+    if elapsed > warn_threshold {
+        let state = pool.state();
+        warn!(
+            "⚠️ Slow db connection acquisition";
+            "elapsed_ms" => elapsed.as_millis() as u64,
+            "connections" => state.connections,
+            "idle_connections" => state.idle_connections
+        );
+    }
+    result
+}
+
+/// Build the pool, establishing its `min_idle` connections up front when
+/// `warmup`'s set (r2d2 waits up to its connection timeout for them, failing
+/// otherwise) or in the background (see `PoolHealth::is_warm`)
+pub fn build_pool<M>(
+    builder: r2d2::Builder<M>,
+    manager: M,
+    warmup: bool,
+) -> Result<Pool<M>, r2d2::PoolError>
+where
+    M: ManageConnection,
+{
+    if warmup {
+        builder.build(manager)
+    } else {
+        Ok(builder.build_unchecked(manager))
+    }
+}
+
 /// Render a timestamp (as an i64 milliseconds since epoch) as an RFC 3339 and ISO 8601
 /// date and time string such as 1996-12-19T16:39:57-08:00
 pub fn to_rfc3339(val: i64) -> Result<String, DbError> {
@@ -180,3 +457,86 @@ pub fn to_rfc3339(val: i64) -> Result<String, DbError> {
         .timestamp(secs, nsecs)
         .to_rfc3339_opts(SecondsFormat::Nanos, true))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use rand::{thread_rng, Rng};
+
+    use super::{ms_since_epoch, QueryPlanSampler, SyncTimestamp, DEFAULT_TIMESTAMP_SLACK_SECS};
+
+    #[test]
+    fn query_plan_sampler() {
+        assert!(!QueryPlanSampler::default().sample());
+        let sampler = QueryPlanSampler::new(Some(3600));
+        assert!(sampler.sample());
+        assert!(!sampler.sample());
+        let sampler = QueryPlanSampler::new(Some(0));
+        assert!(sampler.sample());
+        assert!(sampler.sample());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut rng = thread_rng();
+        let now = ms_since_epoch() as u64;
+        for _ in 0..10_000 {
+            let ms = rng.gen_range(0, now);
+            let ts = SyncTimestamp::from_milliseconds(ms);
+            let header = ts.as_header();
+            let (_, decimals) = header.split_at(header.find('.').unwrap());
+            assert_eq!(decimals.len(), 3, "{}", header);
+            assert_eq!(SyncTimestamp::from_header(&header), Ok(ts));
+            assert_eq!(SyncTimestamp::from_seconds(ts.as_seconds()), ts);
+            assert_eq!(SyncTimestamp::try_from(i64::from(ts)).unwrap(), ts);
+            assert_eq!(ms - u64::from(ts), ms % 10);
+        }
+    }
+
+    #[test]
+    fn header_formatting() {
+        assert_eq!(SyncTimestamp::from_milliseconds(0).as_header(), "0.00");
+        assert_eq!(SyncTimestamp::from_milliseconds(1_000).as_header(), "1.00");
+        assert_eq!(SyncTimestamp::from_milliseconds(1_009).as_header(), "1.00");
+        assert_eq!(SyncTimestamp::from_milliseconds(1_010).as_header(), "1.01");
+        assert_eq!(
+            SyncTimestamp::from_header("1589484283.29").map(|ts| ts.as_header()),
+            Ok("1589484283.29".to_owned())
+        );
+    }
+
+    #[test]
+    fn header_millis_formatting() {
+        assert_eq!(
+            SyncTimestamp::from_milliseconds(0).as_header_millis(),
+            "0.000"
+        );
+        assert_eq!(
+            SyncTimestamp::from_milliseconds(1_589_484_283_296).as_header_millis(),
+            "1589484283.290"
+        );
+        let ts = SyncTimestamp::from_milliseconds(1_589_484_283_010);
+        assert_eq!(SyncTimestamp::from_header(&ts.as_header_millis()), Ok(ts));
+    }
+
+    #[test]
+    fn reject_far_future() {
+        let now = ms_since_epoch() as u64;
+        let slack = DEFAULT_TIMESTAMP_SLACK_SECS * 1000;
+        let ts = |ms| SyncTimestamp::from_milliseconds(ms);
+        assert!(ts(now + slack / 2)
+            .within_slack(DEFAULT_TIMESTAMP_SLACK_SECS)
+            .is_ok());
+        assert!(ts(now + slack * 2)
+            .within_slack(DEFAULT_TIMESTAMP_SLACK_SECS)
+            .is_err());
+        assert!(ts(now + slack * 2)
+            .within_slack(DEFAULT_TIMESTAMP_SLACK_SECS * 3)
+            .is_ok());
+        let decades = ts(now + 30 * 365 * 24 * 60 * 60 * 1000);
+        let parsed = SyncTimestamp::from_header(&decades.as_header()).unwrap();
+        assert!(parsed.within_slack(DEFAULT_TIMESTAMP_SLACK_SECS).is_err());
+        assert!(SyncTimestamp::try_from(-1).is_err());
+    }
+}
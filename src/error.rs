@@ -28,8 +28,8 @@ use crate::web::extractors::RequestErrorLocation;
 /// Legacy Sync 1.1 error codes, which Sync 1.5 also returns by replacing the descriptive JSON
 /// information and replacing it with one of these error codes.
 #[allow(dead_code)]
-#[derive(Serialize)]
-enum WeaveError {
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum WeaveError {
     /// Unknown error
     UnknownError = 0,
     /// Illegal method/protocol
@@ -50,6 +50,10 @@ pub type ApiResult<T> = Result<T, ApiError>;
 /// How long the client should wait before retrying a conflicting write.
 pub const RETRY_AFTER: u8 = 10;
 
+/// The fraction of transient Spanner errors reported to Sentry: they're
+/// capacity signals (tracked by metrics), not bugs
+const TRANSIENT_REPORT_RATE: f64 = 0.01;
+
 /// Top-level error type.
 #[derive(Debug)]
 pub struct ApiError {
@@ -115,11 +119,36 @@ impl ApiError {
         false
     }
 
+    /// Whether this error's a transient failure of the database (a 503 the
+    /// client should retry later)
+    pub fn is_transient(&self) -> bool {
+        match self.kind() {
+            ApiErrorKind::Db(dbe) => dbe.transient_status().is_some(),
+            _ => false,
+        }
+    }
+
+    /// The backend and operation of a labeled db error
+    pub fn db_labels(&self) -> Option<(&'static str, &'static str)> {
+        match self.kind() {
+            ApiErrorKind::Db(dbe) => match (dbe.backend, dbe.operation) {
+                (Some(backend), Some(operation)) => Some((backend, operation)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn is_reportable(&self) -> bool {
         // Should we report this error to sentry?
         match self.kind() {
             ApiErrorKind::Db(dbe) => match dbe.kind() {
-                DbErrorKind::Conflict => return false,
+                DbErrorKind::Conflict
+                | DbErrorKind::TooManyCollections
+                | DbErrorKind::BsoExists => return false,
+                _ if dbe.transient_status().is_some() => {
+                    return rand::random::<f64>() < TRANSIENT_REPORT_RATE
+                }
                 _ => (),
             },
             _ => (),
@@ -155,6 +184,12 @@ impl ApiError {
                     }
                 }
             },
+            ApiErrorKind::Db(dbe) => match dbe.kind() {
+                // The Python server's 403 for exceeding a user's limits
+                DbErrorKind::TooManyCollections => WeaveError::OverQuota,
+                DbErrorKind::SpannerTooLarge(_) => WeaveError::SizeLimitExceeded,
+                _ => WeaveError::UnknownError,
+            },
             _ => WeaveError::UnknownError,
         }
     }
@@ -223,7 +258,7 @@ impl ResponseError for ApiError {
         //
         // So instead we translate our error to a backwards compatible one
         HttpResponse::build(self.status)
-            .if_true(self.is_conflict(), |resp| {
+            .if_true(self.is_conflict() || self.is_transient(), |resp| {
                 resp.header("Retry-After", RETRY_AFTER.to_string());
             })
             .json(self.weave_error_code() as i32)
@@ -323,3 +358,123 @@ macro_rules! from_error {
 from_error!(DbError, ApiError, ApiErrorKind::Db);
 from_error!(HawkError, ApiError, ApiErrorKind::Hawk);
 from_error!(ValidationError, ApiError, ApiErrorKind::Validation);
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::Body, error::ResponseError, http::StatusCode};
+
+    use super::{ApiError, ApiErrorKind};
+    use crate::db::error::DbErrorKind;
+    use crate::web::error::{HawkErrorKind, ValidationErrorKind};
+    use crate::web::extractors::RequestErrorLocation;
+
+    /// The error's response status and (Weave error code) body
+    fn weave_response(error: ApiError) -> (StatusCode, String) {
+        let response = error.error_response();
+        let body = match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => panic!("Expected a bytes body"),
+        };
+        (response.status(), body)
+    }
+
+    fn validation_error(description: &str, location: RequestErrorLocation, name: &str) -> ApiError {
+        ValidationErrorKind::FromDetails(
+            description.to_owned(),
+            location,
+            Some(name.to_owned()),
+            None,
+        )
+        .into()
+    }
+
+    #[test]
+    fn weave_error_bodies() {
+        let cases: Vec<(ApiError, StatusCode, &str)> = vec![
+            (
+                validation_error(
+                    "Invalid JSON in request body",
+                    RequestErrorLocation::Body,
+                    "bsos",
+                ),
+                StatusCode::BAD_REQUEST,
+                "8",
+            ),
+            (
+                validation_error("size-limit-exceeded", RequestErrorLocation::Body, "bso"),
+                StatusCode::BAD_REQUEST,
+                "17",
+            ),
+            (
+                validation_error(
+                    "Invalid offset",
+                    RequestErrorLocation::QueryString,
+                    "offset",
+                ),
+                StatusCode::BAD_REQUEST,
+                "0",
+            ),
+            (
+                ApiErrorKind::Db(DbErrorKind::SpannerTooLarge("1".to_owned()).into()).into(),
+                StatusCode::BAD_REQUEST,
+                "17",
+            ),
+            (
+                ApiErrorKind::Hawk(HawkErrorKind::Expired.into()).into(),
+                StatusCode::UNAUTHORIZED,
+                "0",
+            ),
+            (
+                ApiErrorKind::Db(DbErrorKind::TooManyCollections.into()).into(),
+                StatusCode::FORBIDDEN,
+                "14",
+            ),
+            (
+                ApiErrorKind::Db(DbErrorKind::BsoExists.into()).into(),
+                StatusCode::PRECONDITION_FAILED,
+                "0",
+            ),
+            (
+                ApiErrorKind::Db(DbErrorKind::Conflict.into()).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "0",
+            ),
+            (
+                ApiErrorKind::Db(DbErrorKind::BatchNotFound.into()).into(),
+                StatusCode::BAD_REQUEST,
+                "0",
+            ),
+            (
+                ApiErrorKind::Internal("oops".to_owned()).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "0",
+            ),
+        ];
+        for (error, status, body) in cases {
+            let description = error.to_string();
+            assert_eq!(
+                weave_response(error),
+                (status, body.to_owned()),
+                "{}",
+                description
+            );
+        }
+    }
+
+    #[test]
+    fn conflict_retry_after() {
+        let error: ApiError = ApiErrorKind::Db(DbErrorKind::Conflict.into()).into();
+        let response = error.error_response();
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "10");
+    }
+
+    #[test]
+    fn deadline_exceeded_retry_after() {
+        let status = grpcio::RpcStatus::new(grpcio::RpcStatusCode::DEADLINE_EXCEEDED, None);
+        let error: ApiError = ApiErrorKind::Db(grpcio::Error::RpcFailure(status).into()).into();
+        assert!(error.is_transient());
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "10");
+    }
+}
@@ -1,39 +1,54 @@
-use std::io;
+use std::{
+    io, panic,
+    sync::{Mutex, Once, RwLock},
+    thread,
+};
 
 use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::Settings;
 
+use lazy_static::lazy_static;
 use mozsvc_common::{aws::get_ec2_instance_id, get_hostname};
 use slog::{self, slog_o, Drain};
 use slog_mozlog_json::MozLogJson;
 
-pub fn init_logging(json: bool) -> ApiResult<()> {
-    let logger = if json {
-        let hostname = get_ec2_instance_id()
-            .map(&str::to_owned)
-            .or_else(get_hostname)
-            .ok_or_else(|| "Couldn't get_hostname")
-            .map_err(|e| ApiErrorKind::Internal(e.to_owned()))?;
-
-        let drain = MozLogJson::new(io::stdout())
-            .logger_name(format!(
-                "{}-{}",
-                env!("CARGO_PKG_NAME"),
-                env!("CARGO_PKG_VERSION")
-            ))
-            .msg_type(format!("{}:log", env!("CARGO_PKG_NAME")))
-            .hostname(hostname)
-            .build()
-            .fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+lazy_static! {
+    /// Logs panics synchronously: the global logger's asynchronous drain
+    /// may not be flushed before a panicking process dies
+    static ref PANIC_LOGGER: RwLock<Option<slog::Logger>> = RwLock::new(None);
+}
+
+static LOG_PANICS: Once = Once::new();
+
+type BoxDrain = Box<dyn Drain<Ok = (), Err = slog::Never> + Send>;
+
+/// Initialize the global logger: line delimited MozLog JSON, or readable
+/// (colored) output given `human_logs`, filtered by the `log_level`
+/// (otherwise the `RUST_LOG` environment variable)
+pub fn init_logging(settings: &Settings) -> ApiResult<()> {
+    let hostname = if settings.human_logs {
+        None
     } else {
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        Some(
+            get_ec2_instance_id()
+                .map(&str::to_owned)
+                .or_else(get_hostname)
+                .ok_or_else(|| "Couldn't get_hostname")
+                .map_err(|e| ApiErrorKind::Internal(e.to_owned()))?,
+        )
     };
+    let level = settings.log_level.as_deref();
+
+    let async_drain = slog_async::Async::new(filter(drain(hostname.clone()), level))
+        .build()
+        .fuse();
+    let logger = slog::Logger::root(async_drain, slog_o!());
+    // (Ignoring a poisoned lock rather than panicking within the panic hook)
+    let panic_drain = Mutex::new(filter(drain(hostname), level)).ignore_res();
+    *PANIC_LOGGER.write().expect("Panic logger poisoned") =
+        Some(slog::Logger::root(panic_drain, slog_o!()));
+    LOG_PANICS.call_once(log_panics);
+
     // XXX: cancel slog_scope's NoGlobalLoggerSet for now, it's difficult to
     // prevent it from potentially panicing during tests. reset_logging resets
     // the global logger during shutdown anyway:
@@ -46,4 +61,70 @@ pub fn init_logging(json: bool) -> ApiResult<()> {
 pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, slog_o!());
     slog_scope::set_global_logger(logger).cancel_reset();
+    if let Ok(mut panic_logger) = PANIC_LOGGER.write() {
+        panic_logger.take();
+    }
+}
+
+/// MozLog JSON records (given the `hostname` they're tagged with),
+/// otherwise human readable output
+fn drain(hostname: Option<String>) -> BoxDrain {
+    match hostname {
+        Some(hostname) => Box::new(
+            MozLogJson::new(io::stdout())
+                .logger_name(format!(
+                    "{}-{}",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .msg_type(format!("{}:log", env!("CARGO_PKG_NAME")))
+                .hostname(hostname)
+                .build()
+                .fuse(),
+        ),
+        None => {
+            let decorator = slog_term::TermDecorator::new().build();
+            Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+        }
+    }
+}
+
+/// Filter by `level` (directives in the form of `RUST_LOG`'s, e.g.
+/// "syncstorage=debug,info"), otherwise by `RUST_LOG` itself
+fn filter(drain: BoxDrain, level: Option<&str>) -> slog_envlogger::EnvLogger<BoxDrain> {
+    match level {
+        Some(level) => slog_envlogger::LogBuilder::new(drain).parse(level).build(),
+        None => slog_envlogger::new(drain),
+    }
+}
+
+/// Log panics (ahead of the previous panic hook, e.g. the default one
+/// printing them to stderr)
+fn log_panics() {
+    let next = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<Any>");
+        let location = info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()));
+        let thread = thread::current();
+        // Never panic within the hook: skip the record when the logger's
+        // unavailable
+        if let Ok(logger) = PANIC_LOGGER.try_read() {
+            if let Some(logger) = logger.as_ref() {
+                slog::crit!(
+                    logger,
+                    "Panic: {}", msg;
+                    "location" => location,
+                    "thread" => thread.name()
+                );
+            }
+        }
+        next(info);
+    }));
 }
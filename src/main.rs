@@ -5,22 +5,32 @@ extern crate slog_scope;
 use std::error::Error;
 
 use docopt::Docopt;
+use mozsvc_common::get_hostname;
+use sentry::internals::{ClientInitGuard, Dsn};
 use serde_derive::Deserialize;
 
 use logging::init_logging;
-use syncstorage::{logging, server, settings};
+use syncstorage::{db, logging, server, settings};
 
 const USAGE: &str = "
-Usage: syncstorage [options]
+Usage: syncstorage [options] [--set=SETTING]...
 
 Options:
     -h, --help               Show this message.
     --config=CONFIGFILE      Syncstorage configuration file path.
+    --set=SETTING            Override a setting (of the configuration file and
+                             environment) as KEY=VALUE, e.g. --set port=8080.
+    --dump-config            Print the effective configuration (with secrets
+                             redacted) and exit.
+    --migrations-only        Apply the database's pending migrations and exit.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_set: Vec<String>,
+    flag_dump_config: bool,
+    flag_migrations_only: bool,
 }
 
 #[actix_rt::main]
@@ -28,10 +38,57 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
-    let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
-    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    let settings = settings::Settings::with_overrides(&args.flag_config, &args.flag_set)?;
+    if args.flag_dump_config {
+        println!("{}", settings.dump()?);
+        return Ok(());
+    }
+    init_logging(&settings).expect("Logging failed to initialize");
+    if args.flag_migrations_only {
+        let result = db::run_migrations(&settings);
+        match &result {
+            Ok(_) => info!("Migrations complete"),
+            Err(e) => error!("Migrations failed: {}", e),
+        }
+        logging::reset_logging();
+        result.map_err(|e| e.to_string())?;
+        return Ok(());
+    }
     debug!("Starting up...");
-    // Set SENTRY_DSN environment variable to enable Sentry.
+    let _sentry = match init_sentry(&settings) {
+        Ok(sentry) => sentry,
+        Err(e) => {
+            error!("Failed to start: {}", e);
+            logging::reset_logging();
+            return Err(e.into());
+        }
+    };
+
+    // Setup and run the server
+    let banner = settings.banner();
+    let server = match server::Server::with_settings(settings) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to start: {}", e);
+            logging::reset_logging();
+            return Err(e.to_string().into());
+        }
+    };
+    info!("Server running on {}", banner);
+    let result = server.run().await;
+    if let Err(ref e) = result {
+        error!("Shutdown failed: {}", e);
+    }
+    // The db pools' connections are closed as the runtime's dropped
+    info!("Server closing");
+    logging::reset_logging();
+    result.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Initialize Sentry per the settings (a disabled client when it's disabled
+/// or lacks a DSN), returning the guard flushing its events when dropped
+fn init_sentry(settings: &settings::Settings) -> Result<ClientInitGuard, String> {
     // Avoid its default reqwest transport for now due to issues w/
     // likely grpcio's boringssl
     let curl_transport_factory = |options: &sentry::ClientOptions| {
@@ -39,22 +96,45 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Box::new(sentry::transports::CurlHttpTransport::new(&options))
             as Box<dyn sentry::internals::Transport>
     };
-    let sentry = sentry::init(sentry::ClientOptions {
+    let mut options = sentry::ClientOptions {
         transport: Box::new(curl_transport_factory),
-        release: sentry::release_name!(),
+        release: Some(settings.sentry_release().into()),
         ..sentry::ClientOptions::default()
-    });
+    };
+    if let Some(hostname) = get_hostname() {
+        options.server_name = Some(hostname.into());
+    }
+    if let Some(environment) = &settings.sentry_environment {
+        options.environment = Some(environment.clone().into());
+    }
+    // Otherwise read from the SENTRY_DSN environment variable
+    if let Some(dsn) = &settings.sentry_dsn {
+        let dsn = dsn
+            .parse::<Dsn>()
+            .map_err(|e| format!("Invalid sentry_dsn: {}", e))?;
+        options.dsn = Some(dsn);
+    }
+    if !settings.sentry_enabled {
+        options.dsn = None;
+    }
+
+    let tags = (
+        options.release.clone(),
+        options.environment.clone(),
+        options.server_name.clone(),
+    );
+    let sentry = sentry::init(options);
     if sentry.is_enabled() {
         sentry::integrations::panic::register_panic_handler();
+        let (release, environment, server_name) = tags;
+        info!(
+            "Sentry enabled";
+            "release" => release.as_deref(),
+            "environment" => environment.as_deref(),
+            "server_name" => server_name.as_deref()
+        );
+    } else {
+        info!("Sentry disabled");
     }
-
-    // Setup and run the server
-    let banner = settings.banner();
-    let server = server::Server::with_settings(settings).unwrap();
-    info!("Server running on {}", banner);
-    server.await?;
-    info!("Server closing");
-    logging::reset_logging();
-
-    Ok(())
+    Ok(sentry)
 }
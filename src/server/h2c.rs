@@ -0,0 +1,193 @@
+//! Cleartext HTTP/2 (h2c, "with prior knowledge") served alongside HTTP/1.1.
+//!
+//! actix-web's `HttpServer` only negotiates HTTP/2 via TLS (ALPN). TLS is
+//! terminated by the proxies/load balancers in front of us, which may speak
+//! HTTP/2 to their backends (multiplexing many client requests over a few
+//! long lived connections) given prior knowledge that the backend does.
+use std::{fmt, io, net, time::Duration};
+
+use actix_http::{
+    body::MessageBody,
+    error::DispatchError,
+    http::{header, HeaderValue},
+    HttpService, Protocol, Request,
+};
+use actix_rt::net::TcpStream;
+use actix_service::{
+    apply_fn_factory, fn_service, map_config, pipeline_factory, IntoServiceFactory,
+};
+use actix_web::dev::{AppConfig, Server, Service, ServiceFactory};
+use actix_web::{Error, HttpResponse};
+
+/// The first bytes sent on an HTTP/2 connection
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How often a partially received preface is checked for the remainder
+const PREFACE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Connection settings of the server (see the `server_*` settings)
+pub struct H2cConfig {
+    pub workers: Option<usize>,
+    pub keep_alive: Option<usize>,
+    pub client_timeout_ms: u64,
+    pub client_shutdown_ms: u64,
+    pub shutdown_timeout_secs: u64,
+    /// The host of requests naming none (the listening address by default)
+    pub host: Option<String>,
+}
+
+/// Serve the apps created by `factory` on `addr`, over HTTP/1.1 or HTTP/2
+/// (per connection)
+pub fn serve<F, I, S, B>(addr: &str, factory: F, config: H2cConfig) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<HttpResponse<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let H2cConfig {
+        workers,
+        keep_alive,
+        client_timeout_ms,
+        client_shutdown_ms,
+        shutdown_timeout_secs,
+        host,
+    } = config;
+    let host = host.unwrap_or_else(|| local_addr.to_string());
+    let host =
+        HeaderValue::from_str(&host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let preface_timeout = Duration::from_millis(client_timeout_ms);
+
+    let mut builder = Server::build()
+        // Signals are handled by `Server::run`
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout_secs);
+    if let Some(workers) = workers {
+        builder = builder.workers(workers);
+    }
+    Ok(builder
+        .listen("syncstorage-h2c", listener, move || {
+            let factory = factory.clone();
+            let host = host.clone();
+            pipeline_factory(fn_service(move |mut io: TcpStream| async move {
+                let peer_addr = io.peer_addr().ok();
+                let protocol = sniff_protocol(&mut io, preface_timeout)
+                    .await
+                    .map_err(DispatchError::Io)?;
+                Ok::<_, DispatchError>((io, protocol, peer_addr))
+            }))
+            .and_then(
+                HttpService::build()
+                    .keep_alive(keep_alive)
+                    .client_timeout(client_timeout_ms)
+                    .client_disconnect(client_shutdown_ms)
+                    .local_addr(local_addr)
+                    .finish(apply_fn_factory(
+                        map_config(factory(), |_| AppConfig::default()),
+                        move |mut req: Request, app| {
+                            // The app's config (whose constructor actix-web
+                            // keeps private) is the default, naming
+                            // "localhost:8080": requests naming no host are
+                            // given ours instead
+                            if !req.headers().contains_key(header::HOST)
+                                && req.uri().authority().is_none()
+                            {
+                                req.headers_mut().insert(header::HOST, host.clone());
+                            }
+                            app.call(req)
+                        },
+                    )),
+            )
+        })?
+        .run())
+}
+
+/// The protocol a new connection speaks: HTTP/2 when it begins with the
+/// HTTP/2 preface (never a valid HTTP/1.1 request line), otherwise HTTP/1.1.
+///
+/// Connections sending neither the preface nor anything else within
+/// `timeout` (idle, or stalled partway through the preface) are closed.
+async fn sniff_protocol(io: &mut TcpStream, timeout: Duration) -> io::Result<Protocol> {
+    actix_rt::time::timeout(timeout, peek_protocol(io))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out awaiting the request",
+            ))
+        })
+}
+
+/// Peek at the connection's first bytes until they determine its protocol
+async fn peek_protocol(io: &mut TcpStream) -> io::Result<Protocol> {
+    let mut buf = [0; PREFACE.len()];
+    loop {
+        let read = io.peek(&mut buf).await?;
+        if read == 0 || buf[..read] != PREFACE[..read] {
+            return Ok(Protocol::Http1);
+        }
+        if read == PREFACE.len() {
+            return Ok(Protocol::Http2);
+        }
+        // Peeking returns immediately while the received bytes are unread
+        actix_rt::time::delay_for(PREFACE_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        net,
+        time::Duration,
+    };
+
+    use actix_http::Protocol;
+    use actix_rt::net::TcpStream;
+
+    use super::{sniff_protocol, PREFACE};
+
+    /// A connected pair of (client, server) sockets
+    fn connect() -> (net::TcpStream, TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        (client, TcpStream::from_std(server).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn sniffs_protocol() {
+        let timeout = Duration::from_secs(5);
+        let (mut client, mut server) = connect();
+        client.write_all(PREFACE).unwrap();
+        let protocol = sniff_protocol(&mut server, timeout).await.unwrap();
+        assert!(matches!(protocol, Protocol::Http2));
+
+        let (mut client, mut server) = connect();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let protocol = sniff_protocol(&mut server, timeout).await.unwrap();
+        assert!(matches!(protocol, Protocol::Http1));
+    }
+
+    #[actix_rt::test]
+    async fn idle_connections_time_out() {
+        let timeout = Duration::from_millis(50);
+        // Sending nothing at all
+        let (_client, mut server) = connect();
+        let err = sniff_protocol(&mut server, timeout).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Stalled partway through the preface
+        let (mut client, mut server) = connect();
+        client.write_all(&PREFACE[..4]).unwrap();
+        let err = sniff_protocol(&mut server, timeout).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
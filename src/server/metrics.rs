@@ -1,16 +1,44 @@
+use std::collections::HashMap;
+use std::fs;
+use std::iter;
 use std::net::UdpSocket;
-use std::time::Instant;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix_web::{error::ErrorInternalServerError, web::Data, Error, HttpRequest};
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Gauged, Metric, MetricBuilder, MetricResult, NopMetricSink,
+    QueuingMetricSink, StatsdClient, Timed,
 };
+use mozsvc_common::get_hostname;
 
-use crate::error::ApiError;
-use crate::server::ServerState;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::{shutdown::Shutdown, ServerState};
 use crate::settings::Settings;
 use crate::web::tags::Tags;
 
+/// A `StatsdClient` along with the configuration applying to every metric
+/// recorded through it (see `metrics_from_opts`)
+#[derive(Debug, Clone)]
+pub struct MetricsClient {
+    client: StatsdClient,
+    /// Tags included in every metric (the `statsd_tags` and optionally the
+    /// hostname)
+    tags: Arc<HashMap<String, String>>,
+    /// Whether metrics are sent at all: without a `statsd_host` recording
+    /// them is skipped entirely (rather than formatted for a no-op sink)
+    enabled: bool,
+}
+
+impl Deref for MetricsClient {
+    type Target = StatsdClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricTimer {
     pub label: String,
@@ -20,61 +48,61 @@ pub struct MetricTimer {
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
-    client: Option<StatsdClient>,
+    client: Option<MetricsClient>,
     tags: Option<Tags>,
     timer: Option<MetricTimer>,
 }
 
 impl Drop for Metrics {
     fn drop(&mut self) {
-        let tags = self.tags.clone().unwrap_or_default();
-        if let Some(client) = self.client.as_ref() {
-            if let Some(timer) = self.timer.as_ref() {
-                let lapse = (Instant::now() - timer.start).as_millis() as u64;
-                trace!("⌚ Ending timer at nanos: {:?} : {:?}", &timer.label, lapse; &tags);
-                let mut tagged = client.time_with_tags(&timer.label, lapse);
-                // Include any "hard coded" tags.
-                // tagged = tagged.with_tag("version", env!("CARGO_PKG_VERSION"));
-                let tags = timer.tags.tags.clone();
-                let keys = tags.keys();
-                for tag in keys {
-                    tagged = tagged.with_tag(tag, &tags.get(tag).unwrap())
-                }
-                match tagged.try_send() {
-                    Err(e) => {
-                        // eat the metric, but log the error
-                        warn!("⚠️ Metric {} error: {:?} ", &timer.label, e);
-                    }
-                    Ok(v) => {
-                        trace!("⌚ {:?}", v.as_metric_str());
-                    }
-                }
-            }
+        if let Some(timer) = self.timer.take() {
+            let lapse = (Instant::now() - timer.start).as_millis() as u64;
+            trace!("⌚ Ending timer at nanos: {:?} : {:?}", &timer.label, lapse; &timer.tags);
+            self.timing_with_tags(&timer.label, lapse, Some(timer.tags));
+        }
+    }
+}
+
+/// Records the time until it's dropped as a timing metric (see
+/// `Metrics::timer`)
+pub struct TimerGuard {
+    metrics: Option<Metrics>,
+    label: String,
+    start: Instant,
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            let lapse = self.start.elapsed().as_millis() as u64;
+            metrics.timing(&self.label, lapse);
         }
     }
 }
 
 impl From<&HttpRequest> for Metrics {
     fn from(req: &HttpRequest) -> Self {
+        let client = match req.app_data::<Data<ServerState>>() {
+            Some(v) if v.metrics.enabled => *v.metrics.clone(),
+            Some(_) => return Self::noop(),
+            None => {
+                warn!("⚠️ metric error: No App State");
+                return Self::noop();
+            }
+        };
         let exts = req.extensions();
         let def_tags = Tags::from_request_head(req.head());
         let tags = exts.get::<Tags>().unwrap_or_else(|| &def_tags);
         Metrics {
-            client: match req.app_data::<Data<ServerState>>() {
-                Some(v) => Some(*v.metrics.clone()),
-                None => {
-                    warn!("⚠️ metric error: No App State");
-                    None
-                }
-            },
+            client: Some(client),
             tags: Some(tags.clone()),
             timer: None,
         }
     }
 }
 
-impl From<&StatsdClient> for Metrics {
-    fn from(client: &StatsdClient) -> Self {
+impl From<&MetricsClient> for Metrics {
+    fn from(client: &MetricsClient) -> Self {
         Metrics {
             client: Some(client.clone()),
             tags: None,
@@ -94,19 +122,49 @@ impl From<&actix_web::web::Data<ServerState>> for Metrics {
 }
 
 impl Metrics {
-    pub fn sink() -> StatsdClient {
-        StatsdClient::builder("", NopMetricSink).build()
+    pub fn sink() -> MetricsClient {
+        MetricsClient {
+            client: StatsdClient::builder("", NopMetricSink).build(),
+            tags: Default::default(),
+            enabled: true,
+        }
     }
 
+    /// Metrics that are never sent
     pub fn noop() -> Self {
         Self {
-            client: Some(Self::sink()),
+            client: None,
             timer: None,
             tags: None,
         }
     }
 
+    /// Tag these metrics with the `collection` (see
+    /// `Tags::insert_collection`)
+    pub fn with_collection(mut self, collection: &str) -> Self {
+        if self.client().is_some() {
+            self.tags
+                .get_or_insert_with(Tags::default)
+                .insert_collection(collection);
+        }
+        self
+    }
+
+    /// The client metrics are sent to, unless they're disabled
+    fn client(&self) -> Option<&MetricsClient> {
+        self.client.as_ref().filter(|client| client.enabled)
+    }
+
+    /// Whether metrics are sent at all (so callers may skip building their
+    /// tags when they're not)
+    pub fn is_enabled(&self) -> bool {
+        self.client().is_some()
+    }
+
     pub fn start_timer(&mut self, label: &str, tags: Option<Tags>) {
+        if self.client().is_none() {
+            return;
+        }
         let mut mtags = self.tags.clone().unwrap_or_default();
         if let Some(t) = tags {
             mtags.extend(t.tags)
@@ -120,37 +178,123 @@ impl Metrics {
         });
     }
 
+    /// Time the current scope: the returned guard records the timing when
+    /// it's dropped, e.g. `let _t = metrics.timer("db.post_bsos");`
+    pub fn timer(&self, label: &str) -> TimerGuard {
+        TimerGuard {
+            // (Without any timer of these metrics, reported by their drop)
+            metrics: self.client().map(|_| Metrics {
+                client: self.client.clone(),
+                tags: self.tags.clone(),
+                timer: None,
+            }),
+            label: label.to_owned(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Record a timing (in milliseconds) with no tags data
+    pub fn timing(&self, label: &str, lapse: u64) {
+        self.timing_with_tags(label, lapse, None)
+    }
+
+    /// Record a timing (in milliseconds)
+    pub fn timing_with_tags(&self, label: &str, lapse: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client() {
+            let tags = self.all_tags(client, tags.as_ref());
+            let result = with_tags(client.time_with_tags(label, lapse), &tags).try_send();
+            log_result(label, result, &tags);
+        }
+    }
+
+    /// Record a gauge's value with no tags data
+    pub fn gauge(&self, label: &str, value: u64) {
+        self.gauge_with_tags(label, value, None)
+    }
+
+    /// Record a gauge's value
+    pub fn gauge_with_tags(&self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client() {
+            let tags = self.all_tags(client, tags.as_ref());
+            let result = with_tags(client.gauge_with_tags(label, value), &tags).try_send();
+            log_result(label, result, &tags);
+        }
+    }
+
+    /// Add `count` to a counter with no tags data
+    pub fn count(&self, label: &str, count: i64) {
+        self.count_with_tags(label, count, None)
+    }
+
+    /// Add `count` to a counter
+    pub fn count_with_tags(&self, label: &str, count: i64, tags: Option<Tags>) {
+        if let Some(client) = self.client() {
+            let tags = self.all_tags(client, tags.as_ref());
+            let result = with_tags(client.count_with_tags(label, count), &tags).try_send();
+            log_result(label, result, &tags);
+        }
+    }
+
     // increment a counter with no tags data.
     pub fn incr(self, label: &str) {
         self.incr_with_tags(label, None)
     }
 
     pub fn incr_with_tags(self, label: &str, tags: Option<Tags>) {
-        if let Some(client) = self.client.as_ref() {
-            let mut tagged = client.incr_with_tags(label);
-            let mut mtags = self.tags.clone().unwrap_or_default();
-            if let Some(tags) = tags {
-                mtags.extend(tags.tags);
-            }
-            for key in mtags.tags.keys().clone() {
-                if let Some(val) = mtags.tags.get(key) {
-                    tagged = tagged.with_tag(&key, val.as_ref());
-                }
-            }
-            // Include any "hard coded" tags.
-            // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
-            match tagged.try_send() {
-                Err(e) => {
-                    // eat the metric, but log the error
-                    warn!("⚠️ Metric {} error: {:?} ", label, e; mtags);
-                }
-                Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
-            }
+        if let Some(client) = self.client() {
+            let tags = self.all_tags(client, tags.as_ref());
+            let result = with_tags(client.incr_with_tags(label), &tags).try_send();
+            log_result(label, result, &tags);
         }
     }
+
+    /// The client's tags, then these `Metrics`' own, then `tags` (each
+    /// overriding the former), borrowed rather than copied
+    fn all_tags<'a>(
+        &'a self,
+        client: &'a MetricsClient,
+        tags: Option<&'a Tags>,
+    ) -> HashMap<&'a str, &'a str> {
+        let mut all = HashMap::new();
+        let sources = iter::once(client.tags.as_ref())
+            .chain(self.tags.as_ref().map(|own| &own.tags))
+            .chain(tags.map(|tags| &tags.tags));
+        for source in sources {
+            all.extend(source.iter().map(|(key, val)| (key.as_str(), val.as_str())));
+        }
+        all
+    }
 }
 
-pub fn metrics_from_req(req: &HttpRequest) -> Result<Box<StatsdClient>, Error> {
+fn with_tags<'m, 'c, T>(
+    mut builder: MetricBuilder<'m, 'c, T>,
+    tags: &'m HashMap<&str, &str>,
+) -> MetricBuilder<'m, 'c, T>
+where
+    T: Metric + From<String>,
+{
+    for (key, val) in tags {
+        builder = builder.with_tag(key, val);
+    }
+    builder
+}
+
+/// Log a metric's failure to send (eating the error)
+fn log_result<T: Metric>(label: &str, result: MetricResult<T>, tags: &HashMap<&str, &str>) {
+    match result {
+        Err(e) => {
+            let tags = Tags::with_tags(
+                tags.iter()
+                    .map(|(key, val)| ((*key).to_owned(), (*val).to_owned()))
+                    .collect(),
+            );
+            warn!("⚠️ Metric {} error: {:?} ", label, e; &tags)
+        }
+        Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
+    }
+}
+
+pub fn metrics_from_req(req: &HttpRequest) -> Result<Box<MetricsClient>, Error> {
     Ok(req
         .app_data::<Data<ServerState>>()
         .ok_or_else(|| ErrorInternalServerError("Could not get state"))
@@ -159,8 +303,17 @@ pub fn metrics_from_req(req: &HttpRequest) -> Result<Box<StatsdClient>, Error> {
         .clone())
 }
 
-/// Create a cadence StatsdClient from the given options
-pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
+/// Create a cadence StatsdClient from the given options, along with the tags
+/// of every metric recorded through it (disabling them entirely without a
+/// `statsd_host`)
+pub fn metrics_from_opts(opts: &Settings) -> Result<MetricsClient, ApiError> {
+    let mut tags = opts.statsd_tags.clone();
+    if opts.statsd_hostname_tag {
+        if let Some(hostname) = get_hostname() {
+            tags.insert("hostname".to_owned(), hostname);
+        }
+    }
+
     let builder = if let Some(statsd_host) = opts.statsd_host.as_ref() {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_nonblocking(true)?;
@@ -172,11 +325,70 @@ pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
     } else {
         StatsdClient::builder(opts.statsd_label.as_ref(), NopMetricSink)
     };
-    Ok(builder
-        .with_error_handler(|err| {
-            warn!("⚠️ Metric send error:  {:?}", err);
-        })
-        .build())
+    Ok(MetricsClient {
+        client: builder
+            .with_error_handler(|err| {
+                warn!("⚠️ Metric send error:  {:?}", err);
+            })
+            .build(),
+        tags: Arc::new(tags),
+        enabled: opts.statsd_host.is_some(),
+    })
+}
+
+/// The process's memory and file descriptor usage
+#[derive(Debug)]
+pub struct ProcessStats {
+    /// Resident set size, in bytes
+    pub rss: u64,
+    pub open_fds: u64,
+}
+
+/// Read the process's `ProcessStats` from procfs (`None` without it)
+pub fn process_stats() -> Option<ProcessStats> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let rss_kb: u64 = status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+    // (Including the descriptor listing them)
+    let open_fds = fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    Some(ProcessStats {
+        rss: rss_kb * 1024,
+        open_fds,
+    })
+}
+
+/// Emit gauges of the process's `ProcessStats` periodically, until shut
+/// down
+pub fn spawn_process_periodic_reporter(
+    interval: Duration,
+    metrics: MetricsClient,
+    shutdown: Shutdown,
+) -> Result<(), ApiError> {
+    let hostname =
+        get_hostname().ok_or_else(|| ApiErrorKind::Internal("Couldn't get_hostname".to_owned()))?;
+    actix_rt::spawn(async move {
+        loop {
+            if let Some(ProcessStats { rss, open_fds }) = process_stats() {
+                metrics
+                    .gauge_with_tags("process.memory.rss", rss)
+                    .with_tag("hostname", &hostname)
+                    .send();
+                metrics
+                    .gauge_with_tags("process.open_fds", open_fds)
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            if shutdown.sleep(interval).await {
+                break;
+            }
+        }
+    });
+    Ok(())
 }
 
 #[cfg(test)]
@@ -229,4 +441,53 @@ mod tests {
         assert!(!tags.tags.contains_key("ua.os.ver"));
         println!("{:?}", tags);
     }
+
+    #[test]
+    fn clients_keep_their_own_config() {
+        let tagged = metrics_from_opts(&Settings {
+            statsd_host: Some("127.0.0.1".to_owned()),
+            statsd_tags: vec![("env".to_owned(), "test".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Settings::default()
+        })
+        .unwrap();
+        // Creating another client doesn't reconfigure the first
+        let disabled = metrics_from_opts(&Settings::default()).unwrap();
+        assert!(!disabled.enabled);
+        assert!(Metrics::from(&disabled).client().is_none());
+        assert!(tagged.enabled);
+
+        let metrics = Metrics::from(&tagged);
+        let client = metrics.client().unwrap();
+        assert_eq!(metrics.all_tags(client, None).get("env"), Some(&"test"));
+        let tags = Tags::with_tags(
+            vec![("env".to_owned(), "override".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            metrics.all_tags(client, Some(&tags)).get("env"),
+            Some(&"override")
+        );
+    }
+
+    #[test]
+    fn noop_records_nothing() {
+        let metrics = Metrics::noop();
+        assert!(metrics.client().is_none());
+        assert!(metrics.timer("db.post_bsos").metrics.is_none());
+        let mut metrics = metrics;
+        metrics.start_timer("db.post_bsos", None);
+        assert!(metrics.timer.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_process_stats() {
+        let stats = process_stats().expect("No procfs");
+        assert!(stats.rss > 0);
+        // At least the listing's own descriptor
+        assert!(stats.open_fds > 0, "{:?}", stats);
+    }
 }
@@ -1,25 +1,58 @@
 //! Main application server
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    io,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
 
-use crate::db::{pool_from_settings, spawn_pool_periodic_reporter, DbPool};
-use crate::error::ApiError;
-use crate::server::metrics::Metrics;
-use crate::settings::{Secrets, ServerLimits, Settings};
-use crate::web::{handlers, middleware, tokenserver};
+use crate::db::{
+    cache::WARM_BATCH_SIZE, pool_from_settings, read_pool_from_settings,
+    spawn_batch_periodic_reporter, spawn_pool_periodic_reporter, DbPool,
+};
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::{
+    metrics::{spawn_process_periodic_reporter, Metrics, MetricsClient},
+    shutdown::ShutdownController,
+};
+use crate::settings::{Listen, Secrets, ServerLimits, Settings};
+use crate::web::{
+    client_ip::TrustedProxies, dockerflow::DockerflowEndpoints, handlers, middleware,
+    schema::PayloadSchemas, tokenserver,
+};
 use actix_cors::Cors;
+use actix_rt::signal::unix::{signal, Signal, SignalKind};
 use actix_web::{
-    dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpRequest,
-    HttpResponse, HttpServer,
+    dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpServer,
+};
+use futures::{
+    executor::block_on,
+    future::{self, Either, FutureExt},
 };
-use cadence::StatsdClient;
+use url::Url;
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
 const SYNC_VERSION_PATH: &str = "1.5";
 
+mod h2c;
 pub mod metrics;
+pub mod shutdown;
 #[cfg(test)]
 mod test;
 pub mod user_agent;
@@ -29,6 +62,9 @@ pub mod user_agent;
 pub struct ServerState {
     pub db_pool: Box<dyn DbPool>,
 
+    /// Read replica serving reads (GET/HEAD requests), when configured
+    pub db_read_pool: Option<Box<dyn DbPool>>,
+
     /// Server-enforced limits for request payloads.
     pub limits: Arc<ServerLimits>,
 
@@ -36,28 +72,77 @@ pub struct ServerState {
     pub secrets: Arc<Secrets>,
 
     /// Metric reporting
-    pub metrics: Box<StatsdClient>,
+    pub metrics: Box<MetricsClient>,
 
     pub port: u16,
+
+    /// The URL clients reach the server at, whose host and port Hawk
+    /// authorization headers are validated against when set
+    pub public_url: Option<Arc<Url>>,
+
+    /// Path prefix the API's served under (e.g. "/sync"), empty for none
+    pub url_prefix: String,
+
+    /// Read-only maintenance mode: writes are rejected with a 503
+    pub read_only: Arc<AtomicBool>,
+
+    /// Set once a shutdown's signaled: `__lbheartbeat__` responds with a 503
+    pub shutting_down: Arc<AtomicBool>,
+
+    /// Whether clients may request millisecond precision timestamp headers
+    pub allow_millisecond_timestamps: bool,
+
+    /// Whether BSOs may be written with an empty payload
+    pub allow_empty_payload: bool,
+
+    /// How far into the future client supplied timestamps may be before
+    /// they're rejected
+    pub timestamp_slack_secs: u64,
+
+    /// JSON schemas that payloads of certain collections must conform to
+    pub payload_schemas: Arc<PayloadSchemas>,
+
+    /// Dockerflow endpoints, exempt from Hawk authentication
+    pub dockerflow_endpoints: Arc<DockerflowEndpoints>,
+
+    /// Proxies trusted to report the client's IP via forwarding headers
+    pub trusted_proxies: Arc<TrustedProxies>,
 }
 
-pub fn cfg_path(path: &str) -> String {
+/// The route of an API `path` (served under the `url_prefix`)
+pub fn cfg_path(url_prefix: &str, path: &str) -> String {
     let path = path
         .replace(
             "{collection}",
             &format!("{{collection:{}}}", COLLECTION_ID_REGEX),
         )
         .replace("{bso}", &format!("{{bso:{}}}", BSO_ID_REGEX));
-    format!("/{}/{{uid:{}}}{}", SYNC_VERSION_PATH, MYSQL_UID_REGEX, path)
+    format!(
+        "{}/{}/{{uid:{}}}{}",
+        url_prefix, SYNC_VERSION_PATH, MYSQL_UID_REGEX, path
+    )
 }
 
-pub struct Server;
+/// A running server, shut down by SIGTERM (gracefully) or SIGINT/SIGQUIT
+pub struct Server {
+    server: dev::Server,
+    shutting_down: Arc<AtomicBool>,
+    /// Background tasks, signaled to finish on shutdown
+    tasks: ShutdownController,
+    lb_delay: Duration,
+    drain_timeout: Duration,
+    /// The Unix domain socket listened on (if any), removed on shutdown
+    socket_path: Option<PathBuf>,
+}
 
 #[macro_export]
 macro_rules! build_app {
-    ($state: expr, $limits: expr) => {
+    ($state: expr, $limits: expr) => {{
+        let state: ServerState = $state;
+        let dockerflow_endpoints = Arc::clone(&state.dockerflow_endpoints);
+        let url_prefix = state.url_prefix.clone();
         App::new()
-            .data($state)
+            .data(state)
             // Middleware is applied LIFO
             // These will wrap all outbound responses with matching status codes.
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
@@ -70,30 +155,41 @@ macro_rules! build_app {
             // Followed by the "official middleware" so they run first.
             .wrap(Cors::default())
             .service(
-                web::resource(&cfg_path("/info/collections"))
+                web::resource(&cfg_path(&url_prefix, "/info/collections"))
                     .route(web::get().to(handlers::get_collections)),
             )
             .service(
-                web::resource(&cfg_path("/info/collection_counts"))
+                web::resource(&cfg_path(&url_prefix, "/info/collection_names"))
+                    .route(web::get().to(handlers::get_collection_names)),
+            )
+            .service(
+                web::resource(&cfg_path(&url_prefix, "/info/collection_counts"))
                     .route(web::get().to(handlers::get_collection_counts)),
             )
             .service(
-                web::resource(&cfg_path("/info/collection_usage"))
+                web::resource(&cfg_path(&url_prefix, "/info/collection_usage"))
                     .route(web::get().to(handlers::get_collection_usage)),
             )
             .service(
-                web::resource(&cfg_path("/info/configuration"))
+                web::resource(&cfg_path(&url_prefix, "/info/configuration"))
                     .route(web::get().to(handlers::get_configuration)),
             )
             .service(
-                web::resource(&cfg_path("/info/quota")).route(web::get().to(handlers::get_quota)),
+                web::resource(&cfg_path(&url_prefix, "/info/quota"))
+                    .route(web::get().to(handlers::get_quota)),
+            )
+            .service(
+                web::resource(&cfg_path(&url_prefix, ""))
+                    .route(web::delete().to(handlers::delete_all)),
             )
-            .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
             .service(
-                web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
+                web::resource(&cfg_path(&url_prefix, "/storage"))
+                    .app_data(web::PayloadConfig::new($limits.max_request_bytes as usize))
+                    .route(web::delete().to(handlers::delete_all))
+                    .route(web::post().to(handlers::post_storage)),
             )
             .service(
-                web::resource(&cfg_path("/storage/{collection}"))
+                web::resource(&cfg_path(&url_prefix, "/storage/{collection}"))
                     .app_data(
                         // Declare the payload limit for "normal" collections.
                         web::PayloadConfig::new($limits.max_request_bytes as usize),
@@ -107,10 +203,11 @@ macro_rules! build_app {
                     )
                     .route(web::delete().to(handlers::delete_collection))
                     .route(web::get().to(handlers::get_collection))
+                    .route(web::patch().to(handlers::patch_collection))
                     .route(web::post().to(handlers::post_collection)),
             )
             .service(
-                web::resource(&cfg_path("/storage/{collection}/{bso}"))
+                web::resource(&cfg_path(&url_prefix, "/storage/{collection}/{bso}"))
                     .app_data(web::PayloadConfig::new($limits.max_request_bytes as usize))
                     .app_data(
                         web::JsonConfig::default()
@@ -123,58 +220,319 @@ macro_rules! build_app {
             )
             // Tokenserver
             .service(
-                web::resource(&cfg_path("/1.0/sync/1.5")).route(web::get().to(tokenserver::get)),
+                web::resource(&cfg_path(&url_prefix, "/1.0/sync/1.5"))
+                    .route(web::get().to(tokenserver::get)),
             )
-            // Dockerflow
-            // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
+            // Dockerflow (at the root, regardless of the url_prefix)
+            // Remember to update .::web::DOCKER_FLOW_ENDPOINTS
             // when applying changes to endpoint names.
             .service(web::resource("/__heartbeat__").route(web::get().to(handlers::heartbeat)))
-            .service(
-                web::resource("/__lbheartbeat__").route(web::get().to(|_: HttpRequest| {
-                    // used by the load balancers, just return OK.
-                    HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body("{}")
-                })),
-            )
-            .service(
-                web::resource("/__version__").route(web::get().to(|_: HttpRequest| {
-                    // return the contents of the version.json file created by circleci
-                    // and stored in the docker root
-                    HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body(include_str!("../../version.json"))
-                })),
-            )
+            .service(web::resource("/__lbheartbeat__").route(web::get().to(handlers::lbheartbeat)))
+            .service(web::resource("/__version__").route(web::get().to(handlers::version)))
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
-    };
+            // Additional (configured) Dockerflow endpoints
+            .configure(|cfg| dockerflow_endpoints.configure(cfg))
+    }};
 }
 
 impl Server {
-    pub fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
+    pub fn with_settings(settings: Settings) -> Result<Self, ApiError> {
         let metrics = metrics::metrics_from_opts(&settings)?;
         let db_pool = pool_from_settings(&settings, &Metrics::from(&metrics))?;
+        let db_read_pool = read_pool_from_settings(&settings, &Metrics::from(&metrics))?;
+        if settings.database_warm_collection_cache {
+            for pool in std::iter::once(&db_pool).chain(db_read_pool.as_ref()) {
+                let count = warm_collection_cache(pool.as_ref())?;
+                info!("Warmed the collection cache"; "collections" => count);
+            }
+        }
+        let listen = settings
+            .listen()
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid listen `{}`", e)))?;
+        let socket_mode = settings.listen_socket_mode().ok_or_else(|| {
+            ApiErrorKind::Internal(format!(
+                "Invalid listen_socket_mode `{}`",
+                settings.listen_socket_mode
+            ))
+        })?;
+        let public_url = settings
+            .public_url()
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid public_url `{}`", e)))?
+            .map(Arc::new);
+        // The host of requests naming none
+        let server_hostname = public_url.as_ref().and_then(|url| {
+            url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            })
+        });
         let limits = Arc::new(settings.limits);
         let secrets = Arc::new(settings.master_secret);
         let port = settings.port;
+        let url_prefix = settings.url_prefix.clone();
+        let read_only = Arc::new(AtomicBool::new(settings.read_only));
+        let allow_millisecond_timestamps = settings.allow_millisecond_timestamps;
+        let allow_empty_payload = settings.allow_empty_payload;
+        let timestamp_slack_secs = settings.timestamp_slack_secs;
+        let payload_schemas = Arc::new(PayloadSchemas::from_paths(&settings.payload_schemas)?);
+        let dockerflow_endpoints = Arc::new(DockerflowEndpoints::from_settings(
+            &settings.dockerflow_endpoints,
+        )?);
+        let trusted_proxies = Arc::new(TrustedProxies::from_settings(&settings.trusted_proxies)?);
+
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let tasks = ShutdownController::new();
 
-        spawn_pool_periodic_reporter(Duration::from_secs(10), metrics.clone(), db_pool.clone())?;
+        // Gauges are pointless without a statsd_host to send them to
+        if settings.statsd_host.is_some() {
+            let interval = Duration::from_secs(settings.statsd_gauges_interval_secs);
+            spawn_pool_periodic_reporter(
+                interval,
+                metrics.clone(),
+                db_pool.clone(),
+                tasks.handle(),
+            )?;
+            spawn_process_periodic_reporter(interval, metrics.clone(), tasks.handle())?;
+            if let Some(interval) = settings.database_batch_metrics_interval {
+                spawn_batch_periodic_reporter(
+                    Duration::from_secs(interval),
+                    metrics.clone(),
+                    db_pool.clone(),
+                    tasks.handle(),
+                );
+            }
+        }
+        spawn_read_only_signal_handlers(&read_only)?;
+        let state_shutting_down = Arc::clone(&shutting_down);
 
-        let server = HttpServer::new(move || {
+        let keep_alive = Some(settings.server_keep_alive_secs as usize).filter(|secs| *secs > 0);
+
+        let app = move || {
             // Setup the server state
             let state = ServerState {
                 db_pool: db_pool.clone(),
+                db_read_pool: db_read_pool.clone(),
                 limits: Arc::clone(&limits),
                 secrets: Arc::clone(&secrets),
                 metrics: Box::new(metrics.clone()),
                 port,
+                public_url: public_url.clone(),
+                url_prefix: url_prefix.clone(),
+                read_only: Arc::clone(&read_only),
+                shutting_down: Arc::clone(&state_shutting_down),
+                allow_millisecond_timestamps,
+                allow_empty_payload,
+                timestamp_slack_secs,
+                payload_schemas: Arc::clone(&payload_schemas),
+                dockerflow_endpoints: Arc::clone(&dockerflow_endpoints),
+                trusted_proxies: Arc::clone(&trusted_proxies),
             };
 
             build_app!(state, limits)
+        };
+        let mut socket_path = None;
+        let server = match listen {
+            Listen::Tcp(addr) if settings.server_http2 => h2c::serve(
+                &addr,
+                app,
+                h2c::H2cConfig {
+                    workers: settings.server_workers,
+                    keep_alive,
+                    client_timeout_ms: settings.server_client_request_timeout_ms,
+                    client_shutdown_ms: settings.server_client_disconnect_timeout_ms,
+                    shutdown_timeout_secs: settings.shutdown_drain_timeout_secs,
+                    host: server_hostname,
+                },
+            )?,
+            listen => {
+                let mut server = HttpServer::new(app)
+                    .keep_alive(keep_alive)
+                    .client_timeout(settings.server_client_request_timeout_ms)
+                    .client_shutdown(settings.server_client_disconnect_timeout_ms);
+                if let Some(workers) = settings.server_workers {
+                    server = server.workers(workers);
+                }
+                if let Some(host) = server_hostname {
+                    server = server.server_hostname(host);
+                }
+                server = match listen {
+                    Listen::Tcp(addr) => server.bind(addr)?,
+                    #[cfg(unix)]
+                    Listen::Unix(path) => {
+                        let listener = bind_unix_socket(&path, socket_mode)?;
+                        socket_path = Some(path);
+                        server.listen_uds(listener)?
+                    }
+                    #[cfg(not(unix))]
+                    Listen::Unix(_) => Err(ApiErrorKind::Internal(
+                        "Unix domain sockets are unsupported on this platform".to_owned(),
+                    ))?,
+                };
+                server
+                    // Signals are handled by `run`
+                    .disable_signals()
+                    .shutdown_timeout(settings.shutdown_drain_timeout_secs)
+                    .run()
+            }
+        };
+        Ok(Self {
+            server,
+            shutting_down,
+            tasks,
+            lb_delay: Duration::from_secs(settings.shutdown_lb_delay_secs),
+            drain_timeout: Duration::from_secs(settings.shutdown_drain_timeout_secs),
+            socket_path,
         })
-        .bind(format!("{}:{}", settings.host, settings.port))
-        .expect("Could not get Server in Server::with_settings")
-        .run();
-        Ok(server)
     }
+
+    /// Serve until shut down.
+    ///
+    /// On SIGTERM `__lbheartbeat__` immediately begins responding with a
+    /// 503 and the background tasks are signaled to finish. After the
+    /// `shutdown_lb_delay_secs` new connections are refused and in-flight
+    /// requests are given up to the `shutdown_drain_timeout_secs` to
+    /// complete (those that don't are aborted, rolling back their
+    /// transactions). SIGINT/SIGQUIT (or a second signal) skip the delay and
+    /// drain.
+    pub async fn run(self) -> Result<(), ApiError> {
+        let Self {
+            server,
+            shutting_down,
+            mut tasks,
+            lb_delay,
+            drain_timeout,
+            socket_path,
+        } = self;
+        let mut signals = ShutdownSignals::new()?;
+
+        let graceful = signals.next().await;
+        info!("Shutting down"; "graceful" => graceful);
+        shutting_down.store(true, Ordering::Relaxed);
+        tasks.signal();
+        if graceful {
+            let drain = async {
+                actix_rt::time::delay_for(lb_delay).await;
+                info!("Draining requests"; "timeout" => drain_timeout.as_secs());
+                server.stop(true).await;
+            };
+            if let Either::Right(_) =
+                future::select(drain.boxed_local(), signals.next().boxed_local()).await
+            {
+                info!("Shutting down immediately");
+                server.stop(false).await;
+            }
+        } else {
+            server.stop(false).await;
+        }
+        finish_shutdown(tasks, drain_timeout, socket_path).await;
+        Ok(())
+    }
+
+    /// Shut down immediately, aborting in-flight requests
+    pub async fn stop(self) {
+        self.server.stop(false).await;
+        finish_shutdown(self.tasks, self.drain_timeout, self.socket_path).await;
+    }
+}
+
+/// Warm the pool's collection cache, on a dedicated thread: the server's
+/// started from within the (actix) runtime, which blocking on the warming
+/// queries would stall
+fn warm_collection_cache(pool: &dyn DbPool) -> Result<usize, ApiError> {
+    let pool = pool.box_clone();
+    thread::spawn(move || block_on(pool.warm_collection_cache(WARM_BATCH_SIZE)))
+        .join()
+        .map_err(|_| ApiErrorKind::Internal("Warming the collection cache panicked".to_owned()))?
+}
+
+/// Await the background tasks (up to the `drain_timeout`) of a stopped
+/// server, then remove its Unix domain socket (if any)
+async fn finish_shutdown(
+    tasks: ShutdownController,
+    drain_timeout: Duration,
+    socket_path: Option<PathBuf>,
+) {
+    if !tasks.shutdown(drain_timeout).await {
+        warn!("Background tasks didn't finish before the drain timeout");
+    }
+    if let Some(path) = socket_path {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Couldn't remove the socket"; "path" => path.display().to_string(), "error" => e.to_string());
+        }
+    }
+}
+
+/// Bind a Unix domain socket at `path` with the `mode` permissions,
+/// removing a stale socket (one no process is listening on) left there by
+/// a previous process
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path, mode: u32) -> Result<UnixListener, ApiError> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(
+                    ApiErrorKind::Internal(format!("Socket {} is in use", path.display())).into(),
+                );
+            }
+            info!("Removing a stale socket"; "path" => path.display().to_string());
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(
+                ApiErrorKind::Internal(format!("{} isn't a socket", path.display())).into(),
+            );
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// The signals shutting down the server
+struct ShutdownSignals {
+    term: Signal,
+    int: Signal,
+    quit: Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> Result<Self, ApiError> {
+        Ok(Self {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+            quit: signal(SignalKind::quit())?,
+        })
+    }
+
+    /// Wait for the next signal, returning whether it requests a graceful
+    /// shutdown (SIGTERM)
+    async fn next(&mut self) -> bool {
+        let (graceful, _, _) = future::select_all(vec![
+            self.term.recv().map(|_| true).boxed_local(),
+            self.int.recv().map(|_| false).boxed_local(),
+            self.quit.recv().map(|_| false).boxed_local(),
+        ])
+        .await;
+        graceful
+    }
+}
+
+/// Enter read-only maintenance mode on SIGUSR1, leave it on SIGUSR2
+fn spawn_read_only_signal_handlers(read_only: &Arc<AtomicBool>) -> Result<(), ApiError> {
+    for &(kind, enabled) in &[
+        (SignalKind::user_defined1(), true),
+        (SignalKind::user_defined2(), false),
+    ] {
+        let mut signals = signal(kind)?;
+        let read_only = Arc::clone(read_only);
+        actix_rt::spawn(async move {
+            while signals.recv().await.is_some() {
+                read_only.store(enabled, Ordering::Relaxed);
+                info!("Read-only maintenance mode"; "enabled" => enabled);
+            }
+        });
+    }
+    Ok(())
 }
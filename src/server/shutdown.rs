@@ -0,0 +1,125 @@
+//! Graceful shutdown of the server's background tasks.
+use std::time::Duration;
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either, FutureExt, Shared},
+    StreamExt,
+};
+
+/// Held by a background task: signaled when the server's shutting down,
+/// after which the task should finish its current iteration and exit
+/// (dropping its handle)
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: Shared<oneshot::Receiver<()>>,
+    /// Never sent on: only dropped once the task exits
+    _running: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Whether the shutdown's been signaled
+    pub fn is_signaled(&self) -> bool {
+        self.signal.peek().is_some()
+    }
+
+    /// Sleep for `duration`, waking early when the shutdown's signaled.
+    /// Returns whether it was.
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        let delay = actix_rt::time::delay_for(duration);
+        match future::select(delay, self.signal.clone()).await {
+            Either::Left(_) => self.is_signaled(),
+            Either::Right(_) => true,
+        }
+    }
+}
+
+/// Hands out the `Shutdown` handles of background tasks, signaling them
+/// (and awaiting their exit) on shutdown
+pub struct ShutdownController {
+    trigger: Option<oneshot::Sender<()>>,
+    handle: Option<Shutdown>,
+    exited: mpsc::Receiver<()>,
+}
+
+impl ShutdownController {
+    pub fn new() -> Self {
+        let (trigger, signal) = oneshot::channel();
+        let (running, exited) = mpsc::channel(0);
+        Self {
+            trigger: Some(trigger),
+            handle: Some(Shutdown {
+                signal: signal.shared(),
+                _running: running,
+            }),
+            exited,
+        }
+    }
+
+    /// A handle for a new background task
+    pub fn handle(&self) -> Shutdown {
+        self.handle
+            .clone()
+            .expect("Background task started after the shutdown")
+    }
+
+    /// Signal the background tasks to finish
+    pub fn signal(&mut self) {
+        if let Some(trigger) = self.trigger.take() {
+            let _ = trigger.send(());
+        }
+        self.handle.take();
+    }
+
+    /// Signal the background tasks, then wait up to `timeout` for them to
+    /// exit. Returns whether they all did.
+    pub async fn shutdown(mut self, timeout: Duration) -> bool {
+        self.signal();
+        // Resolves once every handle's dropped
+        let exited = self.exited.next();
+        let delay = actix_rt::time::delay_for(timeout);
+        match future::select(exited, delay).await {
+            Either::Left(_) => true,
+            Either::Right(_) => false,
+        }
+    }
+}
+
+impl Default for ShutdownController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::ShutdownController;
+
+    #[actix_rt::test]
+    async fn tasks_finish_on_shutdown() {
+        let controller = ShutdownController::new();
+        let shutdown = controller.handle();
+        let iterations = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&iterations);
+        actix_rt::spawn(async move {
+            loop {
+                counter.fetch_add(1, Ordering::SeqCst);
+                if shutdown.sleep(Duration::from_secs(60)).await {
+                    break;
+                }
+            }
+        });
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        // Woken from its sleep (rather than awaited for a minute)
+        assert!(controller.shutdown(Duration::from_secs(5)).await);
+        assert_eq!(iterations.load(Ordering::SeqCst), 1);
+    }
+}
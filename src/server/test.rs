@@ -20,13 +20,14 @@ use std::str::FromStr;
 
 use super::*;
 use crate::build_app;
+use crate::db::mock::MockDbPool;
 use crate::db::params;
 use crate::db::pool_from_settings;
-use crate::db::results::{DeleteBso, GetBso, PostBsos, PutBso};
+use crate::db::results::{DeleteBso, DeleteBsos, GetBso, PostBsos, PutBso};
 use crate::db::util::SyncTimestamp;
 use crate::settings::{Secrets, ServerLimits};
 use crate::web::auth::HawkPayload;
-use crate::web::extractors::BsoBody;
+use crate::web::{extractors::BsoBody, X_LAST_MODIFIED, X_WEAVE_ALERT, X_WEAVE_RECORDS};
 
 lazy_static! {
     static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -61,6 +62,7 @@ fn get_test_settings() -> Settings {
         database_use_test_transactions: true,
         limits: ServerLimits::default(),
         master_secret: Secrets::default(),
+        human_logs: true,
         ..Default::default()
     }
 }
@@ -70,22 +72,65 @@ fn get_test_state(settings: &Settings) -> ServerState {
     ServerState {
         db_pool: pool_from_settings(&settings, &Metrics::from(&metrics))
             .expect("Could not get db_pool in get_test_state"),
+        db_read_pool: None,
         limits: Arc::clone(&SERVER_LIMITS),
         secrets: Arc::clone(&SECRETS),
         metrics: Box::new(metrics),
         port: settings.port,
+        public_url: settings
+            .public_url()
+            .expect("Invalid public_url in get_test_state")
+            .map(Arc::new),
+        url_prefix: settings.url_prefix.clone(),
+        read_only: Arc::new(AtomicBool::new(settings.read_only)),
+        shutting_down: Default::default(),
+        allow_millisecond_timestamps: settings.allow_millisecond_timestamps,
+        allow_empty_payload: settings.allow_empty_payload,
+        timestamp_slack_secs: settings.timestamp_slack_secs,
+        payload_schemas: Arc::new(
+            PayloadSchemas::from_paths(&settings.payload_schemas)
+                .expect("Could not load payload_schemas in get_test_state"),
+        ),
+        dockerflow_endpoints: Arc::new(
+            DockerflowEndpoints::from_settings(&settings.dockerflow_endpoints)
+                .expect("Could not load dockerflow_endpoints in get_test_state"),
+        ),
+        trusted_proxies: Arc::new(
+            TrustedProxies::from_settings(&settings.trusted_proxies)
+                .expect("Could not load trusted_proxies in get_test_state"),
+        ),
     }
 }
 
 macro_rules! init_app {
     () => {{
-        crate::logging::init_logging(false).unwrap();
         let settings = get_test_settings();
+        crate::logging::init_logging(&settings).unwrap();
         let limits = Arc::new(settings.limits.clone());
         test::init_service(build_app!(get_test_state(&settings), limits))
     }};
 }
 
+/// Initialize an app with a single db connection, sharing its test
+/// transaction between requests (so each sees the previous' writes)
+macro_rules! init_single_conn_app {
+    ($settings:expr) => {
+        init_single_conn_app!($settings, ServerLimits::default())
+    };
+    ($settings:expr, $limits:expr) => {{
+        let settings = Settings {
+            database_pool_max_size: Some(1),
+            ..$settings
+        };
+        let limits = Arc::new(settings.limits.clone());
+        let state = ServerState {
+            limits: Arc::new($limits),
+            ..get_test_state(&settings)
+        };
+        test::init_service(build_app!(state, limits))
+    }};
+}
+
 fn create_request(
     method: http::Method,
     path: &str,
@@ -245,6 +290,16 @@ fn collections() {
     );
 }
 
+#[test]
+fn collection_names() {
+    test_endpoint(
+        http::Method::GET,
+        "/1.5/42/info/collection_names",
+        None,
+        Some("[]"),
+    );
+}
+
 #[test]
 fn collection_counts() {
     test_endpoint(
@@ -255,6 +310,16 @@ fn collection_counts() {
     );
 }
 
+#[test]
+fn collection_counts_newer() {
+    test_endpoint(
+        http::Method::GET,
+        "/1.5/42/info/collection_counts?newer=1234.56",
+        None,
+        Some("{}"),
+    );
+}
+
 #[test]
 fn collection_usage() {
     test_endpoint(
@@ -310,21 +375,22 @@ fn delete_collection() {
     test_endpoint_with_response(
         http::Method::DELETE,
         "/1.5/42/storage/bookmarks?ids=1,",
-        &move |result: DeleteBso| {
+        &move |result: DeleteBsos| {
             assert!(
-                result > start,
-                format!("Bad Bookmarks ids {:?} < {:?}", result, start)
+                result.modified > start,
+                format!("Bad Bookmarks ids {:?} < {:?}", result.modified, start)
             );
         },
     );
     test_endpoint_with_response(
         http::Method::DELETE,
         "/1.5/42/storage/bookmarks?ids=1,2,3",
-        &move |result: DeleteBso| {
+        &move |result: DeleteBsos| {
             assert!(
-                result > start,
-                format!("Bad Bookmarks ids, m {:?} < {:?}", result, start)
+                result.modified > start,
+                format!("Bad Bookmarks ids, m {:?} < {:?}", result.modified, start)
             );
+            assert_eq!(result.deleted, 0);
         },
     );
 }
@@ -347,6 +413,34 @@ fn get_collection() {
     );
 }
 
+#[test]
+fn get_collection_never_created() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = block_on(test::init_service(build_app!(
+        get_test_state(&settings),
+        limits
+    )));
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/nevercreated?offset=5",
+        None,
+        None,
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in get_collection_never_created");
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers.get("X-Weave-Records").unwrap(), "0");
+    assert_eq!(headers.get("X-Weave-Total-Records").unwrap(), "0");
+    assert_eq!(headers.get("X-Last-Modified").unwrap(), "0.00");
+    assert!(!headers.contains_key("X-Weave-Next-Offset"));
+    let body = block_on(test::read_body(response));
+    assert_eq!(body, "[]");
+}
+
 #[test]
 fn post_collection() {
     let start = SyncTimestamp::default();
@@ -364,6 +458,270 @@ fn post_collection() {
     assert_eq!(result.failed.len(), 0);
 }
 
+#[test]
+fn patch_collection() {
+    let path = "/1.5/42/storage/bookmarks";
+    let bytes = test_endpoint_with_body(
+        http::Method::PATCH,
+        path,
+        json!({"ids": ["foo", "bar"], "ttl": 31_536_000}),
+    );
+    let result: serde_json::Value =
+        serde_json::from_slice(&bytes).expect("Could not get result in patch_collection");
+    assert!(result["modified"].is_number());
+
+    let mut app = block_on(init_app!());
+    let ids: Vec<_> = (0..=100).map(|i| format!("b{}", i)).collect();
+    for body in vec![
+        json!({"ids": ids, "ttl": 60}),
+        json!({"ids": ["foo"], "ttl": 1_000_000_000}),
+        json!({"ids": ["foo"], "ttl": 60, "payload": "bar"}),
+        json!({"ids": ["foo"]}),
+    ] {
+        let req = create_request(http::Method::PATCH, path, None, Some(body)).to_request();
+        let response = block_on(app.call(req)).expect("Could not get response in patch_collection");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[test]
+fn post_storage() {
+    let start = SyncTimestamp::default();
+    let body = json!({
+        "bookmarks": [{"id": "b0", "payload": "x"}],
+        "history": [
+            {"id": "h0", "payload": "x", "sortindex": 1},
+            {"id": "h1", "payload": "x", "ttl": "never"},
+        ],
+    });
+    let bytes = test_endpoint_with_body(http::Method::POST, "/1.5/42/storage", body);
+    let result: serde_json::Value =
+        serde_json::from_slice(&bytes.to_vec()).expect("Could not get result in post_storage");
+    let modified: SyncTimestamp = serde_json::from_value(result["modified"].clone())
+        .expect("Could not get modified in post_storage");
+    assert!(modified >= start);
+    let collections = &result["collections"];
+    assert_eq!(collections["bookmarks"]["success"], json!(["b0"]));
+    assert_eq!(collections["history"]["success"], json!(["h0"]));
+    assert!(collections["history"]["failed"]["h1"].is_string());
+}
+
+#[test]
+fn post_storage_combined_limits() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let state = ServerState {
+        limits: Arc::new(ServerLimits {
+            max_post_records: 3,
+            ..ServerLimits::default()
+        }),
+        ..get_test_state(&settings)
+    };
+    let mut app = block_on(test::init_service(build_app!(state, limits)));
+
+    // The limits apply across collections (ordered by name)
+    let body = json!({
+        "history": [{"id": "h0", "payload": "x"}, {"id": "h1", "payload": "x"}],
+        "bookmarks": [{"id": "b0", "payload": "x"}, {"id": "b1", "payload": "x"}],
+    });
+    let req = create_request(http::Method::POST, "/1.5/42/storage", None, Some(body)).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in post_storage_combined_limits");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let result: serde_json::Value = serde_json::from_slice(&body)
+        .expect("Could not get result in post_storage_combined_limits");
+    let collections = &result["collections"];
+    assert_eq!(collections["bookmarks"]["success"], json!(["b0", "b1"]));
+    assert_eq!(collections["history"]["success"], json!(["h0"]));
+    assert_eq!(collections["history"]["failed"]["h1"], json!("retry bso"));
+
+    // Invalid collection names are rejected
+    let body = json!({"not a collection!": [{"id": "b0", "payload": "x"}]});
+    let req = create_request(http::Method::POST, "/1.5/42/storage", None, Some(body)).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response2 in post_storage_combined_limits");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn post_collection_if_unmodified_since() {
+    let mut app = block_on(init_single_conn_app!(get_test_settings()));
+    let path = "/1.5/42/storage/bookmarks";
+    let post = |id: &str, headers| {
+        create_request(
+            http::Method::POST,
+            path,
+            headers,
+            Some(json!([{"id": id, "payload": "x"}])),
+        )
+        .to_request()
+    };
+
+    // The client's read
+    let response = block_on(app.call(post("a", None)))
+        .expect("Could not get response in post_collection_if_unmodified_since");
+    assert_eq!(response.status(), StatusCode::OK);
+    let read_ts = response
+        .headers()
+        .get(X_LAST_MODIFIED)
+        .expect("Could not get X-Last-Modified in post_collection_if_unmodified_since")
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // Another client modifies the collection
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let response = block_on(app.call(post("b", None)))
+        .expect("Could not get response2 in post_collection_if_unmodified_since");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut headers = HashMap::new();
+    headers.insert("X-If-Unmodified-Since", read_ts);
+    let response = block_on(app.call(post("c", Some(headers))))
+        .expect("Could not get response3 in post_collection_if_unmodified_since");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    // With the Weave error body of any other 412
+    assert_eq!(block_on(test::read_body(response)), "0");
+
+    // None of the POST was written
+    let req = create_request(http::Method::GET, &format!("{}/c", path), None, None).to_request();
+    let response = block_on(app.call(req))
+        .expect("Could not get response4 in post_collection_if_unmodified_since");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn post_collection_if_match() {
+    let mut app = block_on(init_single_conn_app!(get_test_settings()));
+    let path = "/1.5/42/storage/addresses";
+    let post = |id: &str, headers| {
+        create_request(
+            http::Method::POST,
+            path,
+            headers,
+            Some(json!([{"id": id, "payload": "x"}])),
+        )
+        .to_request()
+    };
+    let get = || create_request(http::Method::GET, path, None, None).to_request();
+    let etag_of = |response: dev::ServiceResponse| {
+        assert_eq!(response.status(), StatusCode::OK);
+        response
+            .headers()
+            .get(http::header::ETAG)
+            .expect("Could not get ETag in post_collection_if_match")
+            .to_str()
+            .unwrap()
+            .to_owned()
+    };
+
+    let response = block_on(app.call(post("a", None)))
+        .expect("Could not get response in post_collection_if_match");
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag =
+        etag_of(block_on(app.call(get())).expect("Could not get etag in post_collection_if_match"));
+
+    let mut headers = HashMap::new();
+    headers.insert("If-Match", etag.clone());
+    let response = block_on(app.call(post("b", Some(headers.clone()))))
+        .expect("Could not get response2 in post_collection_if_match");
+    assert_eq!(response.status(), StatusCode::OK);
+    // The write changed the ETag
+    let etag2 = etag_of(
+        block_on(app.call(get())).expect("Could not get etag2 in post_collection_if_match"),
+    );
+    assert_ne!(etag2, etag);
+
+    let response = block_on(app.call(post("c", Some(headers))))
+        .expect("Could not get response3 in post_collection_if_match");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let settings = Settings {
+        read_only: true,
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = block_on(test::init_service(build_app!(
+        get_test_state(&settings),
+        limits
+    )));
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(json!({"payload": "x"})),
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in read_only_rejects_writes");
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("Retry-After"));
+    assert!(response.headers().contains_key(X_WEAVE_ALERT));
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response2 in read_only_rejects_writes");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn read_replica_stale_falls_back() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    // A replica that's seen none of the primary's writes (storage timestamp 0)
+    let state = ServerState {
+        db_read_pool: Some(Box::new(MockDbPool::new())),
+        ..get_test_state(&settings)
+    };
+    let mut app = block_on(test::init_service(build_app!(state, limits)));
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(json!({"payload": "x"})),
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in read_replica_stale_falls_back");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Reads are routed to the replica
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response2 in read_replica_stale_falls_back");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let result: serde_json::Value = serde_json::from_slice(&body)
+        .expect("Could not get result in read_replica_stale_falls_back");
+    assert_eq!(result, json!({}));
+
+    // Unless it's behind the timestamp the client's already seen
+    let mut headers = HashMap::new();
+    headers.insert("X-If-Modified-Since", "1.00".to_owned());
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/info/collections",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response3 in read_replica_stale_falls_back");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let result: serde_json::Value = serde_json::from_slice(&body)
+        .expect("Could not get result2 in read_replica_stale_falls_back");
+    assert!(result.get("bookmarks").is_some());
+}
+
 #[test]
 fn delete_bso() {
     test_endpoint(
@@ -396,6 +754,240 @@ fn put_bso() {
     assert!(result >= start);
 }
 
+#[test]
+fn put_bso_if_none_match() {
+    let mut app = block_on(init_single_conn_app!(get_test_settings()));
+    let path = "/1.5/42/storage/bookmarks/claimed";
+    let put = |payload: &str| {
+        let mut headers = HashMap::new();
+        headers.insert("If-None-Match", "*".to_owned());
+        create_request(
+            http::Method::PUT,
+            path,
+            Some(headers),
+            Some(json!({ "payload": payload })),
+        )
+        .to_request()
+    };
+
+    // Created when absent
+    let response =
+        block_on(app.call(put("first"))).expect("Could not get response in put_bso_if_none_match");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Never overwritten
+    let response = block_on(app.call(put("second")))
+        .expect("Could not get response2 in put_bso_if_none_match");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let req = create_request(http::Method::GET, path, None, None).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response3 in put_bso_if_none_match");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let bso: serde_json::Value =
+        serde_json::from_slice(&body).expect("Could not get bso in put_bso_if_none_match");
+    assert_eq!(bso["payload"], "first");
+}
+
+#[test]
+fn get_collection_limit_clamped() {
+    let mut app = block_on(init_single_conn_app!(
+        get_test_settings(),
+        ServerLimits {
+            max_request_records: 2,
+            ..ServerLimits::default()
+        }
+    ));
+
+    let bsos = json!([
+        {"id": "b0", "payload": "x"},
+        {"id": "b1", "payload": "x"},
+        {"id": "b2", "payload": "x"},
+    ]);
+    let req =
+        create_request(http::Method::POST, "/1.5/42/storage/tabs", None, Some(bsos)).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in get_collection_limit_clamped");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?limit=10&sort=index",
+        None,
+        None,
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response2 in get_collection_limit_clamped");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("X-Weave-Next-Offset"));
+    let body = block_on(test::read_body(response));
+    let ids: Vec<String> =
+        serde_json::from_slice(&body).expect("Could not get ids in get_collection_limit_clamped");
+    assert_eq!(ids.len(), 2);
+
+    // As is an absent limit
+    let req = create_request(http::Method::GET, "/1.5/42/storage/tabs", None, None).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response3 in get_collection_limit_clamped");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("X-Weave-Next-Offset"));
+    let body = block_on(test::read_body(response));
+    let ids: Vec<String> =
+        serde_json::from_slice(&body).expect("Could not get ids in get_collection_limit_clamped");
+    assert_eq!(ids.len(), 2);
+}
+
+#[test]
+fn get_collection_max_response_bytes() {
+    let mut app = block_on(init_single_conn_app!(
+        get_test_settings(),
+        ServerLimits {
+            // Fits two of the (serialized) ids
+            max_response_bytes: Some(10),
+            ..ServerLimits::default()
+        }
+    ));
+
+    let bsos = json!([
+        {"id": "b0", "payload": "x"},
+        {"id": "b1", "payload": "x"},
+        {"id": "b2", "payload": "x"},
+    ]);
+    let req =
+        create_request(http::Method::POST, "/1.5/42/storage/tabs", None, Some(bsos)).to_request();
+    let response = block_on(app.call(req))
+        .expect("Could not get response in get_collection_max_response_bytes");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?sort=index",
+        None,
+        None,
+    )
+    .to_request();
+    let response = block_on(app.call(req))
+        .expect("Could not get response2 in get_collection_max_response_bytes");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("Warning"));
+    assert_eq!(response.headers()["X-Weave-Total-Records"], "3");
+    // The backend's usual offset (e.g. a keyset on MySQL), resuming after
+    // the records kept
+    let offset = response.headers()["X-Weave-Next-Offset"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(offset.starts_with('2'));
+    let body = block_on(test::read_body(response));
+    let ids: Vec<String> = serde_json::from_slice(&body)
+        .expect("Could not get ids in get_collection_max_response_bytes");
+    assert_eq!(ids.len(), 2);
+
+    let req = create_request(
+        http::Method::GET,
+        &format!("/1.5/42/storage/tabs?sort=index&offset={}", offset),
+        None,
+        None,
+    )
+    .to_request();
+    let response = block_on(app.call(req))
+        .expect("Could not get response3 in get_collection_max_response_bytes");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("Warning"));
+    assert!(!response.headers().contains_key("X-Weave-Next-Offset"));
+    let body = block_on(test::read_body(response));
+    let ids: Vec<String> = serde_json::from_slice(&body)
+        .expect("Could not get ids2 in get_collection_max_response_bytes");
+    assert_eq!(ids.len(), 1);
+}
+
+#[test]
+fn get_collection_max_offset() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let state = ServerState {
+        limits: Arc::new(ServerLimits {
+            max_offset: Some(100),
+            ..ServerLimits::default()
+        }),
+        ..get_test_state(&settings)
+    };
+    let mut app = block_on(test::init_service(build_app!(state, limits)));
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?offset=100",
+        None,
+        None,
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response in get_collection_max_offset");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?offset=101",
+        None,
+        None,
+    )
+    .to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response2 in get_collection_max_offset");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/configuration", None, None).to_request();
+    let response =
+        block_on(app.call(req)).expect("Could not get response3 in get_collection_max_offset");
+    let body = block_on(test::read_body(response));
+    let limits: serde_json::Value =
+        serde_json::from_slice(&body).expect("Could not get limits in get_collection_max_offset");
+    assert_eq!(limits["max_offset"], 100);
+}
+
+#[test]
+fn delete_bsos_chunked() {
+    let mut app = block_on(init_single_conn_app!(
+        get_test_settings(),
+        ServerLimits {
+            max_delete_ids: 250,
+            delete_ids_chunk_size: 7,
+            ..ServerLimits::default()
+        }
+    ));
+
+    let bsos = json!([
+        {"id": "b0", "payload": "x"},
+        {"id": "b1", "payload": "x"},
+        {"id": "b2", "payload": "x"},
+    ]);
+    let req =
+        create_request(http::Method::POST, "/1.5/42/storage/tabs", None, Some(bsos)).to_request();
+    let response = block_on(app.call(req)).expect("Could not get response in delete_bsos_chunked");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Beyond the usual 100 ids, the existing ones in separate chunks
+    let mut ids: Vec<_> = (0..250).map(|i| format!("b{}", i * 10)).collect();
+    ids[8] = "b1".to_owned();
+    ids[200] = "b2".to_owned();
+    let path = format!("/1.5/42/storage/tabs?ids={}", ids.join(","));
+    let req = create_request(http::Method::DELETE, &path, None, None).to_request();
+    let response = block_on(app.call(req)).expect("Could not get response2 in delete_bsos_chunked");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let result: DeleteBsos =
+        serde_json::from_slice(&body).expect("Could not get result in delete_bsos_chunked");
+    assert_eq!(result.deleted, 3);
+
+    let path = format!("/1.5/42/storage/tabs?ids={},b1", ids.join(","));
+    let req = create_request(http::Method::DELETE, &path, None, None).to_request();
+    let response = block_on(app.call(req)).expect("Could not get response3 in delete_bsos_chunked");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[test]
 fn bsos_can_have_a_collection_field() {
     let start = SyncTimestamp::default();
@@ -488,6 +1080,280 @@ fn invalid_batch_post() {
     assert_eq!(body, "0");
 }
 
+#[async_test]
+async fn batch_deleted_with_collection() {
+    let mut app = init_single_conn_app!(get_test_settings()).await;
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs?batch=true",
+        None,
+        Some(json!([{"id": "123", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage/tabs", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::POST,
+        &format!("/1.5/42/storage/tabs?batch={}&commit=true", batch),
+        None,
+        Some(json!([{"id": "456", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[async_test]
+async fn batch_commit_if_unmodified_since() {
+    let mut app = init_single_conn_app!(get_test_settings()).await;
+    let path = "/1.5/42/storage/tabs";
+    let post = |uri: &str, id: &str, headers| {
+        create_request(
+            http::Method::POST,
+            uri,
+            headers,
+            Some(json!([{"id": id, "payload": "xxx"}])),
+        )
+        .to_request()
+    };
+    let last_modified = |response: &dev::ServiceResponse| {
+        response
+            .headers()
+            .get(X_LAST_MODIFIED)
+            .expect("Could not get X-Last-Modified in batch_commit_if_unmodified_since")
+            .to_str()
+            .unwrap()
+            .to_owned()
+    };
+
+    // The client's read
+    let response = app.call(post(path, "a", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let read_ts = last_modified(&response);
+
+    let response = app
+        .call(post(&format!("{}?batch=true", path), "b", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+
+    // Another client modifies the collection
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let response = app.call(post(path, "c", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let modified = last_modified(&response);
+
+    // The commit's a 412 (which the client may retry after reading the
+    // changes), not the 400 of a missing batch
+    let mut headers = HashMap::new();
+    headers.insert("X-If-Unmodified-Since", read_ts);
+    let response = app
+        .call(post(
+            &format!("{}?batch={}&commit=true", path, batch),
+            "d",
+            Some(headers),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(last_modified(&response), modified);
+    assert_eq!(test::read_body(response).await, "0");
+}
+
+#[async_test]
+async fn invalid_batch_get() {
+    let mut app = init_app!().await;
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?batch=sammich",
+        None,
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[async_test]
+async fn additional_dockerflow_endpoint() {
+    let settings = Settings {
+        dockerflow_endpoints: vec![("/__ready__".to_owned(), "/__lbheartbeat__".to_owned())]
+            .into_iter()
+            .collect(),
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings), limits)).await;
+    // No Hawk credentials required
+    let req = test::TestRequest::with_uri("/__ready__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[async_test]
+async fn lbheartbeat_shutting_down() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let state = get_test_state(&settings);
+    let shutting_down = Arc::clone(&state.shutting_down);
+    let mut app = test::init_service(build_app!(state, limits)).await;
+    let lbheartbeat = || test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let response = app.call(lbheartbeat()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    shutting_down.store(true, Ordering::Relaxed);
+    let response = app.call(lbheartbeat()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[async_test]
+async fn replace_batch_post() {
+    let mut app = init_app!().await;
+    let mut headers = HashMap::new();
+    headers.insert("X-Weave-Replace", "true".to_owned());
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs?batch=true",
+        Some(headers),
+        Some(json!([{"id": "123", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[async_test]
+async fn replace_post() {
+    let mut app = init_single_conn_app!(get_test_settings()).await;
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs",
+        None,
+        Some(json!([{"id": "a", "payload": "xxx"}, {"id": "b", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut headers = HashMap::new();
+    headers.insert("X-Weave-Replace", "true".to_owned());
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs",
+        Some(headers),
+        Some(json!([{"id": "b", "payload": "yyy"}, {"id": "c", "payload": "yyy"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: PostBsos = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let mut success = body.success;
+    success.sort();
+    assert_eq!(success, vec!["b", "c"]);
+
+    // Only the replacement records remain
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/tabs?full=1&sort=index",
+        None,
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let mut bsos: Vec<_> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bso| {
+            (
+                bso["id"].as_str().unwrap(),
+                bso["payload"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    bsos.sort();
+    assert_eq!(bsos, vec![("b", "yyy"), ("c", "yyy")]);
+}
+
+#[async_test]
+async fn batch_get() {
+    let mut app = init_single_conn_app!(get_test_settings()).await;
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs?batch=true",
+        None,
+        Some(json!([{"id": "123", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+    let req = create_request(
+        http::Method::POST,
+        &format!("/1.5/42/storage/tabs?batch={}", batch),
+        None,
+        Some(json!([{"id": "456", "payload": "yyy"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    // The ids appended so far
+    let req = create_request(
+        http::Method::GET,
+        &format!("/1.5/42/storage/tabs?batch={}", batch),
+        None,
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(X_WEAVE_RECORDS).unwrap(), "2");
+    let mut ids: Vec<String> = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    ids.sort();
+    assert_eq!(ids, vec!["123", "456"]);
+
+    // Or the full records
+    let req = create_request(
+        http::Method::GET,
+        &format!("/1.5/42/storage/tabs?batch={}&full=1", batch),
+        None,
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let payloads: HashMap<_, _> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bso| {
+            (
+                bso["id"].as_str().unwrap(),
+                bso["payload"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(payloads.get("123"), Some(&"xxx"));
+    assert_eq!(payloads.get("456"), Some(&"yyy"));
+}
+
 #[async_test]
 async fn accept_new_or_dev_ios() {
     let mut app = init_app!().await;
@@ -575,3 +1441,161 @@ async fn reject_old_ios() {
     let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
     assert_eq!(body, "0");
 }
+
+#[test]
+fn url_prefix() {
+    let mut app = block_on(init_single_conn_app!(Settings {
+        url_prefix: "/sync".to_owned(),
+        ..get_test_settings()
+    }));
+
+    // Hawk headers sign the (prefixed) path
+    let req = create_request(
+        http::Method::PUT,
+        "/sync/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(json!({"payload": "x"})),
+    )
+    .to_request();
+    let response = block_on(app.call(req)).expect("Could not get response in url_prefix");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::GET,
+        "/sync/1.5/42/storage/bookmarks?full=1&limit=1",
+        None,
+        None,
+    )
+    .to_request();
+    let response = block_on(app.call(req)).expect("Could not get response2 in url_prefix");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = block_on(test::read_body(response));
+    let bsos: Vec<serde_json::Value> =
+        serde_json::from_slice(&body).expect("Could not get bsos in url_prefix");
+    assert_eq!(bsos.len(), 1);
+    assert_eq!(bsos[0]["id"], "wibble");
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = block_on(app.call(req)).expect("Could not get response3 in url_prefix");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Dockerflow endpoints remain at the root
+    let req = test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let response = block_on(app.call(req)).expect("Could not get response4 in url_prefix");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Make a (raw HTTP/1.1) GET request of `path` over `stream`, returning the
+/// response
+fn raw_get<S: std::io::Read + std::io::Write>(
+    mut stream: S,
+    path: &str,
+    authorization: Option<String>,
+) -> std::io::Result<String> {
+    let authorization = authorization
+        .map(|header| format!("Authorization: {}\r\n", header))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        path, TEST_HOST, authorization
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[actix_rt::test]
+async fn listen_tcp() {
+    // A free port, released for the server
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .port();
+    let settings = Settings {
+        listen: Some(format!("127.0.0.1:{}", port)),
+        database_pool_max_size: Some(1),
+        ..get_test_settings()
+    };
+    let server = Server::with_settings(settings).unwrap();
+    let response = web::block(move || {
+        let stream = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        raw_get(stream, "/__lbheartbeat__", None)
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    server.stop().await;
+}
+
+#[cfg(unix)]
+#[actix_rt::test]
+async fn listen_unix_socket() {
+    // Hawk headers are validated against the public_url, rather than the
+    // (socket's meaningless) host and port
+    let path = std::env::temp_dir().join(format!("syncstorage-{}.sock", uuid::Uuid::new_v4()));
+    // A stale socket (no longer listened on) is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let settings = Settings {
+        listen: Some(format!("unix:{}", path.display())),
+        listen_socket_mode: "600".to_owned(),
+        public_url: Some(format!("http://{}:{}", TEST_HOST, TEST_PORT)),
+        database_pool_max_size: Some(1),
+        ..get_test_settings()
+    };
+    let server = Server::with_settings(settings).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let socket_path = path.clone();
+    let response = web::block(move || {
+        let uri = "/1.5/42/info/collections";
+        let stream = std::os::unix::net::UnixStream::connect(&socket_path)?;
+        raw_get(stream, uri, Some(create_hawk_header("GET", TEST_PORT, uri)))
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    server.stop().await;
+    assert!(!path.exists());
+}
+
+#[actix_rt::test]
+async fn http2_cleartext() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .port();
+    let settings = Settings {
+        listen: Some(format!("127.0.0.1:{}", port)),
+        server_http2: true,
+        database_pool_max_size: Some(1),
+        ..get_test_settings()
+    };
+    let server = Server::with_settings(settings).unwrap();
+    // HTTP/1.1 is still served
+    let response = web::block(move || {
+        let stream = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        raw_get(stream, "/__lbheartbeat__", None)
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // Connections beginning with the preface (and the client's SETTINGS
+    // frame) are answered with the server's SETTINGS frame
+    let frame_header = web::block(move || {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")?;
+        stream.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0])?;
+        let mut frame_header = [0; 9];
+        stream.read_exact(&mut frame_header)?;
+        Ok::<_, std::io::Error>(frame_header)
+    })
+    .await
+    .unwrap();
+    // The frame type
+    assert_eq!(frame_header[3], 0x4);
+    server.stop().await;
+}
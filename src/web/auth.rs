@@ -15,6 +15,7 @@ use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use time::Duration;
+use url::Url;
 
 use actix_web::dev::ConnectionInfo;
 use actix_web::http::Uri;
@@ -158,29 +159,41 @@ impl HawkPayload {
 }
 
 impl HawkPayload {
+    /// Validate a request's Hawk `header`, signed for the host and port of
+    /// the `public_url` when given, otherwise of the request itself
     pub fn extrude(
         header: &str,
         method: &str,
         secrets: &Secrets,
         ci: &ConnectionInfo,
+        public_url: Option<&Url>,
         uri: &Uri,
         tags: Option<Tags>,
     ) -> ApiResult<Self> {
-        let host_port: Vec<_> = ci.host().splitn(2, ':').collect();
-        let host = host_port[0];
-        let port = if host_port.len() == 2 {
-            host_port[1].parse().map_err(|_| {
-                ValidationErrorKind::FromDetails(
-                    "Invalid port (hostname:port) specified".to_owned(),
-                    RequestErrorLocation::Header,
-                    None,
-                    tags,
-                )
-            })?
-        } else if ci.scheme() == "https" {
-            443
-        } else {
-            80
+        let (host, port) = match public_url {
+            // (validated as an http(s) URL with a host)
+            Some(url) => (
+                url.host_str().unwrap_or_default(),
+                url.port_or_known_default().unwrap_or(80),
+            ),
+            None => {
+                let host_port: Vec<_> = ci.host().splitn(2, ':').collect();
+                let port = if host_port.len() == 2 {
+                    host_port[1].parse().map_err(|_| {
+                        ValidationErrorKind::FromDetails(
+                            "Invalid port (hostname:port) specified".to_owned(),
+                            RequestErrorLocation::Header,
+                            None,
+                            tags,
+                        )
+                    })?
+                } else if ci.scheme() == "https" {
+                    443
+                } else {
+                    80
+                };
+                (host_port[0], port)
+            }
         };
         let path = uri.path_and_query().ok_or(HawkErrorKind::MissingPath)?;
         let expiry = if path.path().ends_with("/info/collections") {
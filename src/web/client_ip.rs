@@ -0,0 +1,300 @@
+//! The client's IP address, resolved through the forwarding headers
+//! (`Forwarded`, else `X-Forwarded-For`) of trusted proxies.
+//!
+//! The headers are only believed when the connection's peer is a trusted
+//! proxy, and only as far back as the chain of proxies remains trusted: any
+//! earlier hop may have been forged by the client.
+use std::net::IpAddr;
+
+use actix_web::{dev::ServiceRequest, http::header::HeaderMap, HttpMessage};
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::ServerState;
+
+const FORWARDED: &str = "forwarded";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The resolved client IP address, available from the request extensions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// The request's client IP as a log field ("unknown" when unresolved)
+    pub fn log_value(sreq: &ServiceRequest) -> String {
+        sreq.extensions()
+            .get::<ClientIp>()
+            .map_or_else(|| "unknown".to_owned(), |ip| ip.0.to_string())
+    }
+}
+
+/// Proxies (e.g. load balancers) whose forwarding headers are trusted
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Parse the `trusted_proxies` setting: comma separated IP addresses
+    /// and/or CIDR networks
+    pub fn from_settings(trusted_proxies: &str) -> Result<Self, ApiError> {
+        let networks = trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                Network::parse(entry).ok_or_else(|| {
+                    ApiErrorKind::Internal(format!("Invalid trusted_proxies entry: {}", entry))
+                        .into()
+                })
+            })
+            .collect::<Result<_, ApiError>>()?;
+        Ok(Self { networks })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The IP address of the client whose request (with `headers`) arrived
+    /// from `peer`
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = normalize(peer);
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        self.forwarded_ip(headers).unwrap_or(peer)
+    }
+
+    /// The IP address of the client whose request (with `headers`) was
+    /// forwarded by a trusted peer, when the headers name one
+    pub fn forwarded_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = None;
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // Nothing further back is attributable (e.g. `for=unknown`)
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Resolve the request's client IP
+///
+/// A peer without an IP address (i.e. connected via the `listen` Unix
+/// domain socket) is a local proxy, so trusted: the client's then only
+/// known from its forwarding headers.
+pub fn client_ip(sreq: &ServiceRequest) -> Option<IpAddr> {
+    let state = sreq.app_data::<ServerState>();
+    let default = TrustedProxies::default();
+    let proxies = state
+        .as_ref()
+        .map_or(&default, |state| &*state.trusted_proxies);
+    match sreq.peer_addr() {
+        Some(peer) => Some(proxies.client_ip(peer.ip(), sreq.headers())),
+        None => proxies.forwarded_ip(sreq.headers()),
+    }
+}
+
+/// An IP network: an address and prefix length
+#[derive(Debug)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Parse an IP address or CIDR network (e.g. `10.0.0.0/8`)
+    fn parse(entry: &str) -> Option<Self> {
+        let (addr, prefix) = match entry.find('/') {
+            Some(i) => (&entry[..i], Some(&entry[i + 1..])),
+            None => (entry, None),
+        };
+        let addr = normalize(addr.parse().ok()?);
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (
+                u128::from(u32::from(network)),
+                u128::from(u32::from(ip)),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = u32::from(bits - self.prefix);
+        network >> shift == ip >> shift
+    }
+}
+
+/// The hops (oldest first) listed by the forwarding headers: `None` for
+/// those not identified by an IP address
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let elements = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+    let forwarded = elements(FORWARDED);
+    if forwarded.is_empty() {
+        return elements(X_FORWARDED_FOR)
+            .iter()
+            .map(|node| parse_node(node))
+            .collect();
+    }
+    forwarded
+        .iter()
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| {
+                    let i = pair.find('=')?;
+                    Some((pair[..i].trim(), pair[i + 1..].trim()))
+                })
+                .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node))
+        })
+        .collect()
+}
+
+/// Parse a forwarded node: an IP address, optionally quoted, bracketed
+/// (IPv6) and/or with a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    let addr = if node.starts_with('[') {
+        &node[1..node.find(']')?]
+    } else if node.matches(':').count() == 1 {
+        // IPv4 with a port
+        &node[..node.find(':')?]
+    } else {
+        node
+    };
+    addr.parse().ok().map(normalize)
+}
+
+/// Treat IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) as IPv4
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => v6.to_ipv4().map_or(ip, IpAddr::V4),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::TrustedProxies;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn header_map(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peers_headers_ignored() {
+        let proxies = TrustedProxies::from_settings("10.0.0.0/8").unwrap();
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(proxies.client_ip(ip("5.6.7.8"), &headers), ip("5.6.7.8"));
+
+        let proxies = TrustedProxies::default();
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn x_forwarded_for() {
+        let proxies = TrustedProxies::from_settings("10.0.0.0/8, 192.168.1.1").unwrap();
+        // The client may forge earlier hops: only trust as far as the proxies
+        let headers = header_map(&[("x-forwarded-for", "9.9.9.9, 1.2.3.4, 192.168.1.1")]);
+        assert_eq!(proxies.client_ip(ip("10.1.2.3"), &headers), ip("1.2.3.4"));
+        // Multiple headers are one list
+        let headers = header_map(&[
+            ("x-forwarded-for", "9.9.9.9"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(proxies.client_ip(ip("10.1.2.3"), &headers), ip("1.2.3.4"));
+        // An IPv4-mapped peer
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4:5678")]);
+        assert_eq!(
+            proxies.client_ip(ip("::ffff:10.1.2.3"), &headers),
+            ip("1.2.3.4")
+        );
+        // All hops trusted
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(proxies.client_ip(ip("10.1.2.3"), &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded() {
+        let proxies = TrustedProxies::from_settings("10.0.0.0/8,fd00::/8").unwrap();
+        let headers = header_map(&[
+            (
+                "forwarded",
+                r#"for=1.2.3.4;proto=https, For="[2001:db8:cafe::17]:4711";by=10.0.0.1"#,
+            ),
+            ("x-forwarded-for", "5.6.7.8"),
+        ]);
+        assert_eq!(
+            proxies.client_ip(ip("fd00::1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+        // Unknown hops stop the search at the last trusted proxy
+        let headers = header_map(&[("forwarded", "for=1.2.3.4, for=unknown, for=10.0.0.9")]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.9"));
+    }
+
+    #[test]
+    fn socket_peer_trusted() {
+        let proxies = TrustedProxies::from_settings("10.0.0.0/8").unwrap();
+        let headers = header_map(&[("x-forwarded-for", "9.9.9.9, 1.2.3.4, 10.0.0.2")]);
+        assert_eq!(proxies.forwarded_ip(&headers), Some(ip("1.2.3.4")));
+        // Without trusted proxies, only the socket's proxy is believed
+        let proxies = TrustedProxies::default();
+        assert_eq!(proxies.forwarded_ip(&headers), Some(ip("10.0.0.2")));
+        assert_eq!(proxies.forwarded_ip(&header_map(&[])), None);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for setting in &["10.0.0.0/33", "not-an-ip", "::/129", "10.0.0.0/"] {
+            assert!(TrustedProxies::from_settings(setting).is_err());
+        }
+        assert!(TrustedProxies::from_settings(" , ").is_ok());
+    }
+}
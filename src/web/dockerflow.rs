@@ -0,0 +1,115 @@
+//! Dockerflow (Ops) endpoints, exempt from Hawk authentication
+use std::collections::HashMap;
+
+use actix_web::{dev::ServiceRequest, web};
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::ServerState;
+use crate::web::{handlers, DOCKER_FLOW_ENDPOINTS};
+
+/// Additional Dockerflow endpoints (e.g. Kubernetes readiness probes), each
+/// responding as one of the built-in `DOCKER_FLOW_ENDPOINTS`
+#[derive(Debug, Default)]
+pub struct DockerflowEndpoints {
+    /// Built-in endpoint paths keyed by the (lower case) additional path
+    aliases: HashMap<String, String>,
+}
+
+impl DockerflowEndpoints {
+    /// Validate the configured endpoints: paths mapped to built-in endpoints
+    pub fn from_settings(endpoints: &HashMap<String, String>) -> Result<Self, ApiError> {
+        let mut aliases = HashMap::new();
+        for (path, builtin) in endpoints {
+            let error = |msg: &str| -> ApiError {
+                ApiErrorKind::Internal(format!(
+                    "Invalid dockerflow_endpoints {} ({}): {}",
+                    path, builtin, msg
+                ))
+                .into()
+            };
+            let path = path.to_lowercase();
+            let builtin = builtin.to_lowercase();
+            if !path.starts_with("/__") || !path.ends_with("__") || path.contains('{') {
+                return Err(error("paths must be of the form /__name__"));
+            }
+            if DOCKER_FLOW_ENDPOINTS.contains(&path.as_str()) {
+                return Err(error("already a built-in endpoint"));
+            }
+            if !DOCKER_FLOW_ENDPOINTS.contains(&builtin.as_str()) {
+                return Err(error("not a built-in endpoint"));
+            }
+            aliases.insert(path, builtin);
+        }
+        Ok(Self { aliases })
+    }
+
+    /// Whether the path is a (built-in or additional) Dockerflow endpoint,
+    /// case-insensitively
+    pub fn contains(&self, path: &str) -> bool {
+        let path = path.to_lowercase();
+        DOCKER_FLOW_ENDPOINTS.contains(&path.as_str()) || self.aliases.contains_key(&path)
+    }
+
+    /// Route the additional endpoints to their built-in's handler
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        for (path, builtin) in &self.aliases {
+            let route = match builtin.as_str() {
+                "/__heartbeat__" => web::get().to(handlers::heartbeat),
+                "/__lbheartbeat__" => web::get().to(handlers::lbheartbeat),
+                "/__version__" => web::get().to(handlers::version),
+                _ => web::get().to(handlers::test_error),
+            };
+            cfg.service(web::resource(path).route(route));
+        }
+    }
+}
+
+/// Whether the request is for a Dockerflow endpoint
+pub fn is_dockerflow_request(sreq: &ServiceRequest) -> bool {
+    let path = sreq.uri().path();
+    match sreq.app_data::<ServerState>() {
+        Some(state) => state.dockerflow_endpoints.contains(path),
+        None => DOCKER_FLOW_ENDPOINTS.contains(&path.to_lowercase().as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::DockerflowEndpoints;
+
+    fn endpoints(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(path, builtin)| ((*path).to_owned(), (*builtin).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn additional_endpoints() {
+        let dockerflow = DockerflowEndpoints::from_settings(&endpoints(&[
+            ("/__ready__", "/__heartbeat__"),
+            ("/__Alive__", "/__LBHEARTBEAT__"),
+        ]))
+        .unwrap();
+        assert!(dockerflow.contains("/__ready__"));
+        assert!(dockerflow.contains("/__READY__"));
+        assert!(dockerflow.contains("/__alive__"));
+        assert!(dockerflow.contains("/__Heartbeat__"));
+        assert!(!dockerflow.contains("/__started__"));
+        assert!(!dockerflow.contains("/1.5/42/info/collections"));
+    }
+
+    #[test]
+    fn invalid_endpoints() {
+        for pairs in &[
+            [("/ready", "/__heartbeat__")],
+            [("/1.5/__ready__", "/__heartbeat__")],
+            [("/__heartbeat__", "/__lbheartbeat__")],
+            [("/__ready__", "/__ready__")],
+        ] {
+            assert!(DockerflowEndpoints::from_settings(&endpoints(pairs)).is_err());
+        }
+    }
+}
@@ -2,14 +2,20 @@
 //!
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
-use std::{self, collections::HashMap, num::ParseIntError, str::FromStr};
+use std::{
+    self,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    str::FromStr,
+    sync::Arc,
+};
 
 use actix_web::{
     dev::{ConnectionInfo, Extensions, Payload, RequestHead},
     error::ErrorInternalServerError,
     http::{
-        header::{qitem, Accept, ContentType, Header, HeaderMap},
-        Uri,
+        header::{self, qitem, Accept, ContentType, Header, HeaderMap},
+        Method, Uri,
     },
     web::{Data, Json, Query},
     Error, FromRequest, HttpMessage, HttpRequest,
@@ -25,9 +31,13 @@ use serde::{
     Deserialize, Serialize,
 };
 use serde_json::Value;
+use url::Url;
 use validator::{Validate, ValidationError};
 
-use crate::db::{util::SyncTimestamp, Db, Sorting};
+use crate::db::{
+    util::{SyncTimestamp, DEFAULT_TIMESTAMP_SLACK_SECS},
+    Db, Sorting,
+};
 use crate::error::ApiError;
 use crate::server::{metrics, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX};
 use crate::settings::{Secrets, ServerLimits};
@@ -35,15 +45,19 @@ use crate::web::{
     auth::HawkPayload,
     error::{HawkErrorKind, ValidationErrorKind},
     tags::Tags,
-    X_WEAVE_RECORDS,
+    X_WEAVE_RECORDS, X_WEAVE_REPLACE, X_WEAVE_TOTAL_RECORDS,
 };
 
 const BATCH_MAX_IDS: usize = 100;
 
 // BSO const restrictions
 const BSO_MAX_TTL: u32 = 999_999_999;
+// Sortindexes may be negative: the range is symmetric (as in the Python
+// server)
 const BSO_MAX_SORTINDEX_VALUE: i32 = 999_999_999;
 const BSO_MIN_SORTINDEX_VALUE: i32 = -999_999_999;
+const BSO_SORTINDEX_RANGE_ERROR: &str =
+    "sortindex must be an integer between -999999999 and 999999999";
 
 const ACCEPTED_CONTENT_TYPES: [&str; 3] =
     ["application/json", "text/plain", "application/newlines"];
@@ -67,6 +81,7 @@ pub struct UidParam {
 pub struct BatchBsoBody {
     #[validate(custom = "validate_body_bso_id")]
     pub id: String,
+    #[serde(default, deserialize_with = "deserialize_sortindex")]
     #[validate(custom = "validate_body_bso_sortindex")]
     pub sortindex: Option<i32>,
     pub payload: Option<String>,
@@ -93,7 +108,7 @@ impl BatchBsoBody {
             }
         }
         serde_json::from_value(val.clone())
-            .map_err(|_| "invalid json".to_string())
+            .map_err(|e| format!("invalid bso: {}", e))
             .and_then(|v: BatchBsoBody| match v.validate() {
                 Ok(()) => Ok(v),
                 Err(e) => Err(format!("invalid bso: {}", e)),
@@ -136,6 +151,102 @@ pub struct BsoBodies {
     pub invalid: HashMap<String, String>,
 }
 
+/// The error for request bodies that aren't JSON BSO(s)
+fn make_error(tags: Option<Tags>) -> Error {
+    ValidationErrorKind::FromDetails(
+        "Invalid JSON in request body".to_owned(),
+        RequestErrorLocation::Body,
+        Some("bsos".to_owned()),
+        tags,
+    )
+    .into()
+}
+
+impl BsoBodies {
+    /// Validate the raw BSOs, sorting them into valid and invalid ones
+    ///
+    /// `total_payload_size` accumulates the size of the payloads (checked
+    /// against `max_post_bytes`) across calls.
+    fn from_values(
+        bsos: Vec<Value>,
+        max_payload_size: usize,
+        max_post_bytes: usize,
+        total_payload_size: &mut usize,
+    ) -> Result<Self, Error> {
+        // Validate all the BSO's, move invalid to our other list. Assume they'll all make
+        // it with our pre-allocation
+        let mut valid: Vec<BatchBsoBody> = Vec::with_capacity(bsos.len());
+
+        // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
+        // per the way the Python version works.
+        let mut invalid: HashMap<String, String> = HashMap::new();
+
+        // Temporarily track the bso id's for dupe detection
+        let mut bso_ids: Vec<String> = Vec::with_capacity(bsos.len());
+
+        for bso in bsos {
+            // Error out if its not a JSON mapping type
+            if !bso.is_object() {
+                return Err(make_error(None));
+            }
+            // Save all id's we get, check for missing id, or duplicate.
+            let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
+                let id = id.to_string();
+                if bso_ids.contains(&id) {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Input BSO has duplicate ID".to_owned(),
+                        RequestErrorLocation::Body,
+                        Some("bsos".to_owned()),
+                        None,
+                    )
+                    .into());
+                } else {
+                    bso_ids.push(id.clone());
+                    id
+                }
+            } else {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Input BSO has no ID".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bsos".to_owned()),
+                    None,
+                )
+                .into());
+            };
+            match BatchBsoBody::from_raw_bso(&bso) {
+                Ok(b) => {
+                    // Is this record too large? Deny if it is.
+                    let payload_size = b
+                        .payload
+                        .as_ref()
+                        .map(std::string::String::len)
+                        .unwrap_or_default();
+                    *total_payload_size += payload_size;
+                    if payload_size <= max_payload_size && *total_payload_size <= max_post_bytes {
+                        valid.push(b);
+                    } else {
+                        invalid.insert(b.id, "retry bytes".to_string());
+                    }
+                }
+                Err(e) => {
+                    invalid.insert(bso_id, e);
+                }
+            }
+        }
+        Ok(BsoBodies { valid, invalid })
+    }
+
+    /// Move the valid BSOs beyond the first `max_records` to invalid (to be
+    /// retried)
+    fn truncate(&mut self, max_records: usize) {
+        while self.valid.len() > max_records {
+            if let Some(last) = self.valid.pop() {
+                self.invalid.insert(last.id, "retry bso".to_string());
+            }
+        }
+    }
+}
+
 impl FromRequest for BsoBodies {
     type Config = ();
     type Error = Error;
@@ -193,17 +304,6 @@ impl FromRequest for BsoBodies {
             .into()
         });
 
-        // Avoid duplicating by defining our error func now, doesn't need the box wrapper
-        fn make_error(tags: Option<Tags>) -> Error {
-            ValidationErrorKind::FromDetails(
-                "Invalid JSON in request body".to_owned(),
-                RequestErrorLocation::Body,
-                Some("bsos".to_owned()),
-                tags,
-            )
-            .into()
-        }
-
         // Define a new bool to check from a static closure to release the reference on the
         // content_type header
         let newlines: bool = content_type == "application/newlines";
@@ -248,75 +348,13 @@ impl FromRequest for BsoBodies {
                 return future::err(make_error(None));
             };
 
-            // Validate all the BSO's, move invalid to our other list. Assume they'll all make
-            // it with our pre-allocation
-            let mut valid: Vec<BatchBsoBody> = Vec::with_capacity(bsos.len());
-
-            // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
-            // per the way the Python version works.
-            let mut invalid: HashMap<String, String> = HashMap::new();
-
-            // Keep track of our total payload size
             let mut total_payload_size = 0;
-
-            // Temporarily track the bso id's for dupe detection
-            let mut bso_ids: Vec<String> = Vec::with_capacity(bsos.len());
-
-            for bso in bsos {
-                // Error out if its not a JSON mapping type
-                if !bso.is_object() {
-                    return future::err(make_error(None));
-                }
-                // Save all id's we get, check for missing id, or duplicate.
-                let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
-                    let id = id.to_string();
-                    if bso_ids.contains(&id) {
-                        return future::err(
-                            ValidationErrorKind::FromDetails(
-                                "Input BSO has duplicate ID".to_owned(),
-                                RequestErrorLocation::Body,
-                                Some("bsos".to_owned()),
-                                None,
-                            )
-                            .into(),
-                        );
-                    } else {
-                        bso_ids.push(id.clone());
-                        id
-                    }
-                } else {
-                    return future::err(
-                        ValidationErrorKind::FromDetails(
-                            "Input BSO has no ID".to_owned(),
-                            RequestErrorLocation::Body,
-                            Some("bsos".to_owned()),
-                            None,
-                        )
-                        .into(),
-                    );
-                };
-                match BatchBsoBody::from_raw_bso(&bso) {
-                    Ok(b) => {
-                        // Is this record too large? Deny if it is.
-                        let payload_size = b
-                            .payload
-                            .as_ref()
-                            .map(std::string::String::len)
-                            .unwrap_or_default();
-                        total_payload_size += payload_size;
-                        if payload_size <= max_payload_size && total_payload_size <= max_post_bytes
-                        {
-                            valid.push(b);
-                        } else {
-                            invalid.insert(b.id, "retry bytes".to_string());
-                        }
-                    }
-                    Err(e) => {
-                        invalid.insert(bso_id, e);
-                    }
-                }
-            }
-            future::ok(BsoBodies { valid, invalid })
+            future::ready(BsoBodies::from_values(
+                bsos,
+                max_payload_size,
+                max_post_bytes,
+                &mut total_payload_size,
+            ))
         });
 
         Box::pin(fut)
@@ -328,6 +366,7 @@ impl FromRequest for BsoBodies {
 pub struct BsoBody {
     #[validate(custom = "validate_body_bso_id")]
     pub id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_sortindex")]
     #[validate(custom = "validate_body_bso_sortindex")]
     pub sortindex: Option<i32>,
     pub payload: Option<String>,
@@ -444,11 +483,58 @@ pub struct BsoParam {
     pub bso: String,
 }
 
+/// The segments of a request's path following the server's `url_prefix`,
+/// e.g. `["", "1.5", "{uid}", "storage", "{collection}"]`
+fn path_elements<'a>(uri: &'a Uri, url_prefix: &str) -> Vec<&'a str> {
+    let path = uri.path();
+    let path = match path.get(url_prefix.len()..) {
+        Some(rest) if path.starts_with(url_prefix) && rest.starts_with('/') => rest,
+        _ => path,
+    };
+    path.split('/').collect()
+}
+
+/// The `url_prefix` of the request's server (empty without a `ServerState`)
+pub fn url_prefix(state: Option<&ServerState>) -> String {
+    state
+        .map(|state| state.url_prefix.clone())
+        .unwrap_or_default()
+}
+
+/// How far into the future the request's server accepts client supplied
+/// timestamps (the default without a `ServerState`)
+pub fn timestamp_slack(state: Option<&ServerState>) -> u64 {
+    state.map_or(DEFAULT_TIMESTAMP_SLACK_SECS, |state| {
+        state.timestamp_slack_secs
+    })
+}
+
+/// Reject client supplied query string timestamps too far into the future
+fn validate_query_timestamps(
+    timestamps: &[(&str, Option<SyncTimestamp>)],
+    slack_secs: u64,
+    tags: &Tags,
+) -> Result<(), Error> {
+    for (name, timestamp) in timestamps {
+        if let Some(timestamp) = timestamp {
+            timestamp.within_slack(slack_secs).map_err(|e| {
+                ValidationErrorKind::FromDetails(
+                    e.to_owned(),
+                    RequestErrorLocation::QueryString,
+                    Some((*name).to_owned()),
+                    Some(tags.clone()),
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
 impl BsoParam {
-    pub fn bsoparam_from_path(uri: &Uri, tags: &Tags) -> Result<Self, Error> {
+    pub fn bsoparam_from_path(uri: &Uri, url_prefix: &str, tags: &Tags) -> Result<Self, Error> {
         // TODO: replace with proper path parser
-        // path: "/1.5/{uid}/storage/{collection}/{bso}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        // path: "{url_prefix}/1.5/{uid}/storage/{collection}/{bso}"
+        let elements = path_elements(uri, url_prefix);
         let elem = elements.get(3);
         if elem.is_none() || elem != Some(&"storage") || elements.len() != 6 {
             warn!("⚠️ Unexpected BSO URI: {:?}", uri.path(); tags);
@@ -481,13 +567,17 @@ impl BsoParam {
         }
     }
 
-    pub fn extrude(head: &RequestHead, extensions: &mut Extensions) -> Result<Self, Error> {
+    pub fn extrude(
+        head: &RequestHead,
+        extensions: &mut Extensions,
+        url_prefix: &str,
+    ) -> Result<Self, Error> {
         let uri = head.uri.clone();
         let tags = Tags::from_request_head(head);
         if let Some(bso) = extensions.get::<BsoParam>() {
             return Ok(bso.clone());
         }
-        let bso = Self::bsoparam_from_path(&uri, &tags)?;
+        let bso = Self::bsoparam_from_path(&uri, url_prefix, &tags)?;
         bso.validate().map_err(|e| {
             ValidationErrorKind::FromValidationErrors(
                 e,
@@ -506,7 +596,12 @@ impl FromRequest for BsoParam {
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        future::ready(Self::extrude(req.head(), &mut req.extensions_mut()))
+        let url_prefix = url_prefix(req.app_data::<Data<ServerState>>().map(Data::get_ref));
+        future::ready(Self::extrude(
+            req.head(),
+            &mut req.extensions_mut(),
+            &url_prefix,
+        ))
     }
 }
 
@@ -518,10 +613,14 @@ pub struct CollectionParam {
 }
 
 impl CollectionParam {
-    fn col_from_path(uri: &Uri, tags: &Tags) -> Result<Option<CollectionParam>, Error> {
+    fn col_from_path(
+        uri: &Uri,
+        url_prefix: &str,
+        tags: &Tags,
+    ) -> Result<Option<CollectionParam>, Error> {
         // TODO: replace with proper path parser.
-        // path: "/1.5/{uid}/storage/{collection}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        // path: "{url_prefix}/1.5/{uid}/storage/{collection}"
+        let elements = path_elements(uri, url_prefix);
         let elem = elements.get(3);
         if elem.is_none() || elem != Some(&"storage") || !(5..=6).contains(&elements.len()) {
             return Ok(None);
@@ -549,13 +648,14 @@ impl CollectionParam {
     pub fn extrude(
         uri: &Uri,
         extensions: &mut Extensions,
+        url_prefix: &str,
         tags: &Tags,
     ) -> Result<Option<Self>, Error> {
         if let Some(collection) = extensions.get::<Option<Self>>() {
             return Ok(collection.clone());
         }
 
-        let collection = Self::col_from_path(&uri, tags)?;
+        let collection = Self::col_from_path(&uri, url_prefix, tags)?;
         let result = if let Some(collection) = collection {
             collection.validate().map_err(|e| {
                 ValidationErrorKind::FromValidationErrors(
@@ -584,7 +684,10 @@ impl FromRequest for CollectionParam {
         let req = req.clone();
         Box::pin(async move {
             let tags = fut.await?;
-            if let Some(collection) = Self::extrude(&req.uri(), &mut req.extensions_mut(), &tags)? {
+            let url_prefix = url_prefix(req.app_data::<Data<ServerState>>().map(Data::get_ref));
+            if let Some(collection) =
+                Self::extrude(&req.uri(), &mut req.extensions_mut(), &url_prefix, &tags)?
+            {
                 Ok(collection)
             } else {
                 Err(ValidationErrorKind::FromDetails(
@@ -640,7 +743,7 @@ impl FromRequest for MetaRequest {
 }
 
 /// Desired reply format for a Collection Get request
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ReplyFormat {
     Json,
     Newlines,
@@ -654,7 +757,14 @@ pub struct CollectionRequest {
     pub db: Box<dyn Db>,
     pub user_id: HawkIdentifier,
     pub query: BsoQueryParams,
+    /// A pending batch whose contents are requested (GET only)
+    pub batch: Option<String>,
     pub reply: ReplyFormat,
+    /// How many of the `ids` are deleted per database call (DELETE only)
+    pub delete_chunk_size: usize,
+    /// Records beyond this many (serialized) bytes are left to the next page
+    /// (GET only)
+    pub max_response_bytes: Option<usize>,
     pub metrics: metrics::Metrics,
     pub tags: Option<Tags>,
 }
@@ -671,6 +781,10 @@ impl FromRequest for CollectionRequest {
             let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
             let db = <Box<dyn Db>>::from_request(&req, &mut payload).await?;
             let query = BsoQueryParams::from_request(&req, &mut payload).await?;
+            let batch = Query::<BatchParams>::from_request(&req, &mut payload)
+                .await
+                .ok()
+                .and_then(|params| params.into_inner().batch);
             let collection = CollectionParam::from_request(&req, &mut payload)
                 .await?
                 .collection;
@@ -682,10 +796,12 @@ impl FromRequest for CollectionRequest {
                 }
             };
 
+            // A missing Accept (or */*) defaults to JSON, anything else we
+            // can't produce is a 406
             let accept = get_accepted(&req, &ACCEPTED_CONTENT_TYPES, "application/json");
             let reply = match accept.as_str() {
                 "application/newlines" => ReplyFormat::Newlines,
-                "application/json" | "" => ReplyFormat::Json,
+                "application/json" | "text/plain" => ReplyFormat::Json,
                 _ => {
                     return Err(ValidationErrorKind::FromDetails(
                         "Invalid accept".to_string(),
@@ -697,13 +813,29 @@ impl FromRequest for CollectionRequest {
                 }
             };
 
+            let delete_chunk_size = req
+                .app_data::<Data<ServerState>>()
+                .map_or(BATCH_MAX_IDS, |state| {
+                    state.limits.delete_ids_chunk_size as usize
+                })
+                .max(1)
+                .min(BATCH_MAX_IDS);
+            let max_response_bytes = req
+                .app_data::<Data<ServerState>>()
+                .and_then(|state| state.limits.max_response_bytes)
+                .map(|max| max as usize);
+
+            let metrics = metrics::Metrics::from(&req).with_collection(&collection);
             Ok(CollectionRequest {
                 collection,
                 db,
                 user_id,
                 query,
+                batch,
                 reply,
-                metrics: metrics::Metrics::from(&req),
+                delete_chunk_size,
+                max_response_bytes,
+                metrics,
                 tags: Some(tags),
             })
         }
@@ -721,6 +853,8 @@ pub struct CollectionPostRequest {
     pub query: BsoQueryParams,
     pub bsos: BsoBodies,
     pub batch: Option<BatchRequest>,
+    /// Replace all of the collection's BSOs (`X-Weave-Replace: true`)
+    pub replace: bool,
     pub metrics: metrics::Metrics,
 }
 
@@ -757,7 +891,7 @@ impl FromRequest for CollectionPostRequest {
                 }
             };
 
-            let max_post_records = i64::from(state.limits.max_post_records);
+            let max_post_records = state.limits.max_post_records as usize;
             let (user_id, db, collection, query, mut bsos) =
                 <(
                     HawkIdentifier,
@@ -768,35 +902,26 @@ impl FromRequest for CollectionPostRequest {
                 )>::from_request(&req, &mut payload)
                 .await?;
             let collection = collection.collection;
-            if collection == "crypto" {
-                // Verify the client didn't mess up the crypto if we have a payload
-                for bso in &bsos.valid {
-                    if let Some(ref data) = bso.payload {
-                        if KNOWN_BAD_PAYLOAD_REGEX.is_match(data) {
-                            return Err(ValidationErrorKind::FromDetails(
-                                "Known-bad BSO payload".to_owned(),
-                                RequestErrorLocation::Body,
-                                Some("bsos".to_owned()),
-                                Some(tags),
-                            )
-                            .into());
-                        }
-                    }
-                }
-            }
+            validate_collection_bsos(state, &collection, &bsos, &tags)?;
 
             // Trim the excess BSO's to be under the batch size
-            let overage: i64 = (bsos.valid.len() as i64) - max_post_records;
-            if overage > 0 {
-                for _ in 1..=overage {
-                    if let Some(last) = bsos.valid.pop() {
-                        bsos.invalid.insert(last.id, "retry bso".to_string());
-                    }
-                }
-            }
+            bsos.truncate(max_post_records);
 
             // XXX: let's not use extract here (maybe convert to extrude?)
             let batch = BatchRequestOpt::extract(&req).await?;
+            let replace = req.headers().get(X_WEAVE_REPLACE).map_or(false, |replace| {
+                replace.as_bytes().eq_ignore_ascii_case(b"true")
+            });
+            if replace && batch.opt.is_some() {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Replacing a collection via a batch upload is unsupported".to_owned(),
+                    RequestErrorLocation::Header,
+                    Some(X_WEAVE_REPLACE.to_owned()),
+                    Some(tags),
+                )
+                .into());
+            }
+            let metrics = metrics::Metrics::from(&req).with_collection(&collection);
             Ok(CollectionPostRequest {
                 collection,
                 db,
@@ -804,6 +929,230 @@ impl FromRequest for CollectionPostRequest {
                 query,
                 bsos,
                 batch: batch.opt,
+                replace,
+                metrics,
+            })
+        })
+    }
+}
+
+/// Verify the collection's BSOs' payloads: known bad crypto and those not
+/// conforming to the collection's payload schema are rejected
+fn validate_collection_bsos(
+    state: &ServerState,
+    collection: &str,
+    bsos: &BsoBodies,
+    tags: &Tags,
+) -> Result<(), Error> {
+    if collection == "crypto" {
+        // Verify the client didn't mess up the crypto if we have a payload
+        for bso in &bsos.valid {
+            if let Some(ref data) = bso.payload {
+                if KNOWN_BAD_PAYLOAD_REGEX.is_match(data) {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Known-bad BSO payload".to_owned(),
+                        RequestErrorLocation::Body,
+                        Some("bsos".to_owned()),
+                        Some(tags.clone()),
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+    for bso in &bsos.valid {
+        if let Some(ref data) = bso.payload {
+            if data.is_empty() && !state.allow_empty_payload {
+                return Err(ValidationErrorKind::FromDetails(
+                    format!("Empty BSO {} payload", bso.id),
+                    RequestErrorLocation::Body,
+                    Some("bsos".to_owned()),
+                    Some(tags.clone()),
+                )
+                .into());
+            }
+            if let Err(e) = state.payload_schemas.validate(collection, data) {
+                return Err(ValidationErrorKind::FromDetails(
+                    format!("Invalid BSO {} payload: {}", bso.id, e),
+                    RequestErrorLocation::Body,
+                    Some("bsos".to_owned()),
+                    Some(tags.clone()),
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The body of a collection PATCH: the ids of BSOs whose ttl is reset
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TtlBody {
+    #[validate(custom = "validate_body_ttl_ids")]
+    pub ids: Vec<String>,
+    #[validate(custom = "validate_body_bso_ttl")]
+    pub ttl: u32,
+}
+
+/// Collection Patch extractor
+///
+/// Extracts/validates information needed for collection PATCH requests,
+/// which extend the lifetime of BSOs without re-uploading their payloads.
+pub struct CollectionPatchRequest {
+    pub collection: String,
+    pub db: Box<dyn Db>,
+    pub user_id: HawkIdentifier,
+    pub body: TtlBody,
+    pub metrics: metrics::Metrics,
+}
+
+impl FromRequest for CollectionPatchRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<CollectionPatchRequest, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = payload.take();
+        Box::pin(async move {
+            let tags = match req.extensions().get::<Tags>() {
+                Some(t) => t.clone(),
+                None => Tags::from_request_head(req.head()),
+            };
+            let (user_id, db, collection) =
+                <(HawkIdentifier, Box<dyn Db>, CollectionParam)>::from_request(&req, &mut payload)
+                    .await?;
+            let body = <Json<TtlBody>>::from_request(&req, &mut payload)
+                .await
+                .map_err(|e| {
+                    warn!("⚠️ Could not parse TTL Body: {:?}", e);
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::Body,
+                        Some("ttl".to_owned()),
+                        Some(tags.clone()),
+                    )
+                })?
+                .into_inner();
+            body.validate().map_err(|e| {
+                ValidationErrorKind::FromValidationErrors(e, RequestErrorLocation::Body, Some(tags))
+            })?;
+            let metrics = metrics::Metrics::from(&req).with_collection(&collection.collection);
+            Ok(CollectionPatchRequest {
+                collection: collection.collection,
+                db,
+                user_id,
+                body,
+                metrics,
+            })
+        })
+    }
+}
+
+/// Storage Post extractor
+///
+/// Extracts/validates information needed for multi-collection POST requests,
+/// whose body maps collection names to their BSOs (as in a collection POST).
+/// The request's limits (`max_post_records`, `max_post_bytes`) apply to all
+/// of its BSOs combined.
+pub struct StoragePostRequest {
+    pub db: Box<dyn Db>,
+    pub user_id: HawkIdentifier,
+    /// Each collection's BSOs, ordered by collection name
+    pub collections: Vec<(String, BsoBodies)>,
+    pub metrics: metrics::Metrics,
+}
+
+impl FromRequest for StoragePostRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<StoragePostRequest, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = payload.take();
+        Box::pin(async move {
+            let tags = match req.extensions().get::<Tags>() {
+                Some(t) => t.clone(),
+                None => Tags::from_request_head(req.head()),
+            };
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        Some(tags),
+                    )
+                    .into());
+                }
+            };
+
+            let content_type = ContentType::parse(&req)
+                .map(|ctype| format!("{}/{}", ctype.type_(), ctype.subtype()))
+                .unwrap_or_default();
+            if !["application/json", "text/plain"].contains(&content_type.as_str()) {
+                return Err(ValidationErrorKind::FromDetails(
+                    format!("Invalid Content-Type {:?}", content_type),
+                    RequestErrorLocation::Header,
+                    Some("Content-Type".to_owned()),
+                    Some(tags),
+                )
+                .into());
+            }
+
+            let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
+            let db = <Box<dyn Db>>::from_request(&req, &mut payload).await?;
+            let body = <String>::from_request(&req, &mut payload)
+                .await
+                .map_err(|e| {
+                    warn!("⚠️ Payload read error: {:?}", e);
+                    ValidationErrorKind::FromDetails(
+                        "Mimetype/encoding/content-length error".to_owned(),
+                        RequestErrorLocation::Header,
+                        None,
+                        None,
+                    )
+                })?;
+            let raw: BTreeMap<String, Vec<Value>> =
+                serde_json::from_str(&body).map_err(|_| make_error(Some(tags.clone())))?;
+
+            let max_payload_size = state.limits.max_record_payload_bytes as usize;
+            let max_post_bytes = state.limits.max_post_bytes as usize;
+            let mut remaining_records = state.limits.max_post_records as usize;
+            let mut total_payload_size = 0;
+            let mut collections = Vec::with_capacity(raw.len());
+            for (collection, bsos) in raw {
+                CollectionParam {
+                    collection: collection.clone(),
+                }
+                .validate()
+                .map_err(|e| {
+                    ValidationErrorKind::FromValidationErrors(
+                        e,
+                        RequestErrorLocation::Body,
+                        Some(tags.clone()),
+                    )
+                })?;
+                let mut bsos = BsoBodies::from_values(
+                    bsos,
+                    max_payload_size,
+                    max_post_bytes,
+                    &mut total_payload_size,
+                )?;
+                validate_collection_bsos(state, &collection, &bsos, &tags)?;
+                bsos.truncate(remaining_records);
+                remaining_records -= bsos.valid.len();
+                collections.push((collection, bsos));
+            }
+
+            Ok(StoragePostRequest {
+                db,
+                user_id,
+                collections,
                 metrics: metrics::Metrics::from(&req),
             })
         })
@@ -840,13 +1189,14 @@ impl FromRequest for BsoRequest {
                 .collection;
             let bso = BsoParam::from_request(&req, &mut payload).await?;
 
+            let metrics = metrics::Metrics::from(&req).with_collection(&collection);
             Ok(BsoRequest {
                 collection,
                 db,
                 user_id,
                 query,
                 bso: bso.bso,
-                metrics: metrics::Metrics::from(&req),
+                metrics,
             })
         })
     }
@@ -863,6 +1213,8 @@ pub struct BsoPutRequest {
     pub bso: String,
     pub body: BsoBody,
     pub metrics: metrics::Metrics,
+    /// Only create the BSO, never overwrite it (`If-None-Match: *`)
+    pub create_only: bool,
 }
 
 impl FromRequest for BsoPutRequest {
@@ -872,6 +1224,13 @@ impl FromRequest for BsoPutRequest {
 
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         let metrics = metrics::Metrics::from(req);
+        let payload_schemas = req
+            .app_data::<Data<ServerState>>()
+            .map(|state| Arc::clone(&state.payload_schemas));
+        let allow_empty_payload = req
+            .app_data::<Data<ServerState>>()
+            .map_or(true, |state| state.allow_empty_payload);
+        let create_only = if_none_match_any(req.headers());
         let fut = <(
             HawkIdentifier,
             Box<dyn Db>,
@@ -899,6 +1258,31 @@ impl FromRequest for BsoPutRequest {
                     }
                 }
             }
+            if !allow_empty_payload && body.payload.as_deref() == Some("") {
+                return future::err(
+                    ValidationErrorKind::FromDetails(
+                        "Empty BSO payload".to_owned(),
+                        RequestErrorLocation::Body,
+                        Some("bso".to_owned()),
+                        Some(tags),
+                    )
+                    .into(),
+                );
+            }
+            if let (Some(schemas), Some(data)) = (payload_schemas, body.payload.as_ref()) {
+                if let Err(e) = schemas.validate(&collection, data) {
+                    return future::err(
+                        ValidationErrorKind::FromDetails(
+                            format!("Invalid BSO payload: {}", e),
+                            RequestErrorLocation::Body,
+                            Some("bso".to_owned()),
+                            Some(tags),
+                        )
+                        .into(),
+                    );
+                }
+            }
+            let metrics = metrics.with_collection(&collection);
             future::ok(BsoPutRequest {
                 collection,
                 db,
@@ -907,6 +1291,7 @@ impl FromRequest for BsoPutRequest {
                 bso: bso.bso,
                 body,
                 metrics,
+                create_only,
             })
         });
         Box::pin(fut)
@@ -957,6 +1342,11 @@ impl FromRequest for ConfigRequest {
                 max_request_bytes: data.max_request_bytes,
                 max_total_bytes: data.max_total_bytes,
                 max_total_records: data.max_total_records,
+                max_request_records: data.max_request_records,
+                max_delete_ids: data.max_delete_ids,
+                max_offset: data.max_offset,
+                delete_ids_chunk_size: data.delete_ids_chunk_size,
+                max_response_bytes: data.max_response_bytes,
             },
         }))
     }
@@ -1066,21 +1456,26 @@ impl HawkIdentifier {
         }
     }
 
-    fn uid_from_path(uri: &Uri, tags: Option<Tags>) -> Result<u64, Error> {
+    fn uid_from_path(uri: &Uri, url_prefix: &str, tags: Option<Tags>) -> Result<u64, Error> {
         // TODO: replace with proper path parser.
-        // path: "/1.5/{uid}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        // path: "{url_prefix}/1.5/{uid}"
+        let elements = path_elements(uri, url_prefix);
         if let Some(v) = elements.get(2) {
-            u64::from_str(v).map_err(|e| {
-                warn!("⚠️ HawkIdentifier Error invalid UID {:?} {:?}", v, e);
-                ValidationErrorKind::FromDetails(
-                    "Invalid UID".to_owned(),
-                    RequestErrorLocation::Path,
-                    Some("uid".to_owned()),
-                    tags.clone(),
-                )
-                .into()
-            })
+            // Beyond i64::MAX is out of range of MySQL's (signed) BIGINT
+            // uid columns
+            u64::from_str(v)
+                .ok()
+                .filter(|uid| *uid <= i64::max_value() as u64)
+                .ok_or_else(|| {
+                    warn!("⚠️ HawkIdentifier Error invalid UID {:?}", v);
+                    ValidationErrorKind::FromDetails(
+                        "Invalid UID".to_owned(),
+                        RequestErrorLocation::Path,
+                        Some("uid".to_owned()),
+                        tags.clone(),
+                    )
+                    .into()
+                })
         } else {
             warn!("⚠️ HawkIdentifier Error missing UID {:?}", uri);
             Err(ValidationErrorKind::FromDetails(
@@ -1113,7 +1508,16 @@ impl HawkIdentifier {
             .ok_or_else(|| -> ApiError { HawkErrorKind::MissingHeader.into() })?
             .to_str()
             .map_err(|e| -> ApiError { HawkErrorKind::Header(e).into() })?;
-        let identifier = Self::generate(&state.secrets, method, auth_header, ci, uri, tags)?;
+        let identifier = Self::generate(
+            &state.secrets,
+            method,
+            auth_header,
+            ci,
+            state.public_url.as_deref(),
+            &state.url_prefix,
+            uri,
+            tags,
+        )?;
         msg.extensions_mut().insert(identifier.clone());
         Ok(identifier)
     }
@@ -1123,12 +1527,21 @@ impl HawkIdentifier {
         method: &str,
         header: &str,
         connection_info: &ConnectionInfo,
+        public_url: Option<&Url>,
+        url_prefix: &str,
         uri: &Uri,
         tags: Option<Tags>,
     ) -> Result<Self, Error> {
-        let payload =
-            HawkPayload::extrude(header, method, secrets, connection_info, uri, tags.clone())?;
-        let puid = Self::uid_from_path(&uri, tags.clone())?;
+        let payload = HawkPayload::extrude(
+            header,
+            method,
+            secrets,
+            connection_info,
+            public_url,
+            uri,
+            tags.clone(),
+        )?;
+        let puid = Self::uid_from_path(&uri, url_prefix, tags.clone())?;
         if payload.user_id != puid {
             warn!("⚠️ Hawk UID not in URI: {:?} {:?}", payload.user_id, uri);
             Err(ValidationErrorKind::FromDetails(
@@ -1212,26 +1625,72 @@ impl FromRequest for Box<dyn Db> {
 #[serde(default)]
 pub struct Offset {
     pub timestamp: Option<SyncTimestamp>,
+    /// The number of records preceding the page
     pub offset: u64,
+    /// Position of the previous page's last record, for backends supporting
+    /// keyset pagination (others fall back to the numeric offset)
+    pub keyset: Option<Keyset>,
+}
+
+/// The sort key and id of the last record of a page: the next page begins
+/// with the record following it in the (id tie broken) sort order
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct Keyset {
+    /// The record's modified timestamp or sortindex (per the sort order),
+    /// `None` for a NULL sortindex or unsorted queries
+    pub sort_key: Option<i64>,
+    pub id: String,
 }
 
 impl ToString for Offset {
     fn to_string(&self) -> String {
-        match self.timestamp {
-            None => format!("{}", self.offset),
-            Some(ts) => format!("{}:{}", ts.as_i64(), self.offset),
+        match (&self.keyset, self.timestamp) {
+            // The id is encoded as it may contain any printable character
+            (Some(keyset), _) => format!(
+                "{}:{}:{}",
+                self.offset,
+                keyset
+                    .sort_key
+                    .map(|sort_key| sort_key.to_string())
+                    .unwrap_or_default(),
+                base64::encode_config(&keyset.id, base64::URL_SAFE_NO_PAD)
+            ),
+            (None, None) => format!("{}", self.offset),
+            (None, Some(ts)) => format!("{}:{}", ts.as_i64(), self.offset),
         }
     }
 }
 
 impl FromStr for Offset {
-    type Err = ParseIntError;
+    type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
+        // issue559: Disable "timestamp:offset" support for now: parse either
+        // a numeric offset or "offset:sort_key:id" keyset
+        let mut parts = s.splitn(3, ':');
+        let offset = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<u64>()
+            .map_err(|e| e.to_string())?;
+        let keyset = match (parts.next(), parts.next()) {
+            (None, _) => None,
+            (Some(sort_key), Some(id)) => Some(Keyset {
+                sort_key: if sort_key.is_empty() {
+                    None
+                } else {
+                    Some(sort_key.parse::<i64>().map_err(|e| e.to_string())?)
+                },
+                id: base64::decode_config(id, base64::URL_SAFE_NO_PAD)
+                    .ok()
+                    .and_then(|id| String::from_utf8(id).ok())
+                    .ok_or_else(|| "Invalid offset id".to_owned())?,
+            }),
+            (Some(_), None) => return Err("Invalid offset".to_owned()),
+        };
         let result = Offset {
             timestamp: None,
-            offset: s.parse::<u64>()?,
+            offset,
+            keyset,
         };
         /*
         let result = match s.chars().position(|c| c == ':') {
@@ -1270,6 +1729,19 @@ pub struct BsoQueryParams {
     #[serde(deserialize_with = "deserialize_sync_timestamp")]
     pub older: Option<SyncTimestamp>,
 
+    /// inclusive lower-bound on last-modified time
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    pub newer_eq: Option<SyncTimestamp>,
+
+    /// inclusive upper-bound on last-modified time
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    pub older_eq: Option<SyncTimestamp>,
+
+    /// upper-bound on expiry time (already expired items are always
+    /// excluded)
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    pub expiring_before: Option<SyncTimestamp>,
+
     /// order in which to return results (string)
     #[serde(default)]
     pub sort: Sorting,
@@ -1289,6 +1761,17 @@ pub struct BsoQueryParams {
     // flag, whether to include full bodies (bool)
     #[serde(deserialize_with = "deserialize_present_value")]
     pub full: bool,
+
+    // flag, whether to report which posted BSOs were created vs updated
+    // (bool)
+    #[serde(deserialize_with = "deserialize_present_value")]
+    pub breakdown: bool,
+
+    /// Not a query param: the response's byte ceiling
+    /// (`limits.max_response_bytes`), beyond which backends truncate the page
+    /// (returning their usual offset of the record following it)
+    #[serde(skip)]
+    pub max_bytes: Option<usize>,
 }
 
 impl FromRequest for BsoQueryParams {
@@ -1303,7 +1786,7 @@ impl FromRequest for BsoQueryParams {
         Box::pin(async move {
             let tags = Tags::from_request(&req, &mut payload).await?;
 
-            let params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
+            let mut params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
                 .map_err(|e| {
                     ValidationErrorKind::FromDetails(
                         e.to_string(),
@@ -1321,6 +1804,57 @@ impl FromRequest for BsoQueryParams {
                     Some(tags.clone()),
                 )
             })?;
+            validate_query_timestamps(
+                &[
+                    ("newer", params.newer),
+                    ("older", params.older),
+                    ("newer_eq", params.newer_eq),
+                    ("older_eq", params.older_eq),
+                    ("expiring_before", params.expiring_before),
+                ],
+                timestamp_slack(req.app_data::<Data<ServerState>>().map(Data::get_ref)),
+                &tags,
+            )?;
+            // DELETEs may exceed the usual cap on ids (deleting them in
+            // chunks)
+            let max_ids = match req.app_data::<Data<ServerState>>() {
+                Some(state) if req.method() == Method::DELETE => {
+                    state.limits.max_delete_ids as usize
+                }
+                _ => BATCH_MAX_IDS,
+            };
+            if params.ids.len() > max_ids {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Too many ids provided".to_owned(),
+                    RequestErrorLocation::QueryString,
+                    Some("ids".to_owned()),
+                    Some(tags),
+                )
+                .into());
+            }
+            // Clamp the limit rather than rejecting it (applying it when
+            // absent): the db layer still returns an offset to the remaining
+            // records
+            if let Some(state) = req.app_data::<Data<ServerState>>() {
+                let max = state.limits.max_request_records;
+                params.limit = Some(params.limit.map_or(max, |limit| limit.min(max)));
+
+                // Deep numeric offsets have the db skip over every preceding
+                // record
+                let too_deep = match (state.limits.max_offset, &params.offset) {
+                    (Some(max), Some(offset)) => offset.keyset.is_none() && offset.offset > max,
+                    _ => false,
+                };
+                if too_deep {
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Offset too large".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        Some("offset".to_owned()),
+                        Some(tags),
+                    )
+                    .into());
+                }
+            }
             // issue559: Dead code (timestamp always None)
             /*
                if params.sort != Sorting::Index {
@@ -1356,6 +1890,46 @@ impl FromRequest for BsoQueryParams {
     }
 }
 
+/// The info/collection_counts query params
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct CollectionCountsQueryParams {
+    /// only count collections modified since (a timestamp)
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    pub newer: Option<SyncTimestamp>,
+}
+
+impl FromRequest for CollectionCountsQueryParams {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = Payload::None;
+        Box::pin(async move {
+            let tags = Tags::from_request(&req, &mut payload).await?;
+            let params = Query::<CollectionCountsQueryParams>::from_request(&req, &mut payload)
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        Some("newer".to_owned()),
+                        Some(tags.clone()),
+                    )
+                })
+                .await?
+                .into_inner();
+            validate_query_timestamps(
+                &[("newer", params.newer)],
+                timestamp_slack(req.app_data::<Data<ServerState>>().map(Data::get_ref)),
+                &tags,
+            )?;
+            Ok(params)
+        })
+    }
+}
+
 #[derive(Debug, Default, Clone, Deserialize, Validate)]
 #[serde(default)]
 pub struct BatchParams {
@@ -1417,7 +1991,7 @@ impl FromRequest for BatchRequestOpt {
             let checks = [
                 (X_WEAVE_RECORDS, limits.max_post_records),
                 ("X-Weave-Bytes", limits.max_post_bytes),
-                ("X-Weave-Total-Records", limits.max_total_records),
+                (X_WEAVE_TOTAL_RECORDS, limits.max_total_records),
                 ("X-Weave-Total-Bytes", limits.max_total_bytes),
             ];
             for (header, limit) in &checks {
@@ -1507,6 +2081,15 @@ impl FromRequest for BatchRequestOpt {
     }
 }
 
+/// Whether the request has an `If-None-Match: *` header: when PUTting a BSO,
+/// only create it (never overwrite an existing one)
+pub fn if_none_match_any(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.trim() == "*")
+}
+
 /// PreCondition Header
 ///
 /// It's valid to include a X-If-Modified-Since or X-If-Unmodified-Since header but not
@@ -1526,7 +2109,11 @@ pub struct PreConditionHeaderOpt {
 }
 
 impl PreConditionHeaderOpt {
-    pub fn extrude(headers: &HeaderMap, tags: Option<Tags>) -> Result<Self, Error> {
+    pub fn extrude(
+        headers: &HeaderMap,
+        slack_secs: u64,
+        tags: Option<Tags>,
+    ) -> Result<Self, Error> {
         let modified = headers.get("X-If-Modified-Since");
         let unmodified = headers.get("X-If-Unmodified-Since");
         if modified.is_some() && unmodified.is_some() {
@@ -1574,15 +2161,17 @@ impl PreConditionHeaderOpt {
                 .into()
             })
             .and_then(|v| {
-                SyncTimestamp::from_header(v).map_err(|e| {
-                    ValidationErrorKind::FromDetails(
-                        e.to_string(),
-                        RequestErrorLocation::Header,
-                        Some(field_name.to_owned()),
-                        tags.clone(),
-                    )
-                    .into()
-                })
+                SyncTimestamp::from_header(v)
+                    .and_then(|ts| ts.within_slack(slack_secs))
+                    .map_err(|e| {
+                        ValidationErrorKind::FromDetails(
+                            e.to_string(),
+                            RequestErrorLocation::Header,
+                            Some(field_name.to_owned()),
+                            tags.clone(),
+                        )
+                        .into()
+                    })
             })
             .map(|v| {
                 let header = if field_name == "X-If-Modified-Since" {
@@ -1606,7 +2195,9 @@ impl FromRequest for PreConditionHeaderOpt {
         let mut payload = Payload::None;
         Box::pin(async move {
             let tags = Tags::from_request(&req, &mut payload).await?;
-            Self::extrude(req.headers(), Some(tags)).map_err(Into::into)
+            let slack_secs =
+                timestamp_slack(req.app_data::<Data<ServerState>>().map(Data::get_ref));
+            Self::extrude(req.headers(), slack_secs, Some(tags)).map_err(Into::into)
         })
     }
 }
@@ -1632,19 +2223,33 @@ fn request_error(message: &'static str, location: RequestErrorLocation) -> Valid
     err
 }
 
-/// Verifies that the list of id's is not too long and that the ids are valid
+/// Verifies that the ids are valid (their count is checked by the
+/// `BsoQueryParams` extractor, as DELETEs allow more of them)
 fn validate_qs_ids(ids: &[String]) -> Result<(), ValidationError> {
+    for id in ids {
+        if !VALID_ID_REGEX.is_match(&id) {
+            return Err(request_error(
+                "Invalid id in ids",
+                RequestErrorLocation::QueryString,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the ids of a TTL body are valid and few enough
+fn validate_body_ttl_ids(ids: &[String]) -> Result<(), ValidationError> {
     if ids.len() > BATCH_MAX_IDS {
         return Err(request_error(
             "Too many ids provided",
-            RequestErrorLocation::QueryString,
+            RequestErrorLocation::Body,
         ));
     }
     for id in ids {
         if !VALID_ID_REGEX.is_match(&id) {
             return Err(request_error(
                 "Invalid id in ids",
-                RequestErrorLocation::QueryString,
+                RequestErrorLocation::Body,
             ));
         }
     }
@@ -1667,7 +2272,10 @@ fn validate_body_bso_sortindex(sort: i32) -> Result<(), ValidationError> {
     if BSO_MIN_SORTINDEX_VALUE <= sort && sort <= BSO_MAX_SORTINDEX_VALUE {
         Ok(())
     } else {
-        Err(request_error("invalid value", RequestErrorLocation::Body))
+        Err(request_error(
+            BSO_SORTINDEX_RANGE_ERROR,
+            RequestErrorLocation::Body,
+        ))
     }
 }
 
@@ -1732,6 +2340,21 @@ where
     }
 }
 
+/// Deserialize a sortindex, rejecting values beyond an i32 with the same
+/// message as the range validation (rather than serde's generic one)
+fn deserialize_sortindex<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let maybe_sortindex: Option<i64> = Deserialize::deserialize(deserializer)
+        .map_err(|_| SerdeError::custom(BSO_SORTINDEX_RANGE_ERROR))?;
+    maybe_sortindex
+        .map(|sortindex| {
+            i32::try_from(sortindex).map_err(|_| SerdeError::custom(BSO_SORTINDEX_RANGE_ERROR))
+        })
+        .transpose()
+}
+
 fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<Offset>, D::Error>
 where
     D: Deserializer<'de>,
@@ -1812,10 +2435,21 @@ mod tests {
         let settings = Settings::default();
         ServerState {
             db_pool: Box::new(MockDbPool::new()),
+            db_read_pool: None,
             limits: Arc::clone(&SERVER_LIMITS),
             secrets: Arc::clone(&SECRETS),
             port: 8000,
+            public_url: None,
+            url_prefix: "".to_owned(),
             metrics: Box::new(metrics::metrics_from_opts(&settings).unwrap()),
+            read_only: Default::default(),
+            shutting_down: Default::default(),
+            allow_millisecond_timestamps: false,
+            allow_empty_payload: true,
+            timestamp_slack_secs: DEFAULT_TIMESTAMP_SLACK_SECS,
+            payload_schemas: Default::default(),
+            dockerflow_endpoints: Default::default(),
+            trusted_proxies: Default::default(),
         }
     }
 
@@ -1964,6 +2598,55 @@ mod tests {
         assert_eq!(result.sort, Sorting::Index);
         assert_eq!(result.older.unwrap(), SyncTimestamp::from_seconds(2.43));
         assert_eq!(result.full, true);
+        assert_eq!(result.breakdown, false);
+    }
+
+    #[test]
+    fn test_offset_round_trip() {
+        let offset = Offset::from_str("20").unwrap();
+        assert_eq!(offset.offset, 20);
+        assert_eq!(offset.keyset, None);
+        assert_eq!(offset.to_string(), "20");
+
+        for &sort_key in &[Some(1_590_000_000_000), Some(-5), None] {
+            let offset = Offset {
+                timestamp: None,
+                offset: 100,
+                keyset: Some(Keyset {
+                    sort_key,
+                    id: "a:b&c=d/{e}".to_owned(),
+                }),
+            };
+            let parsed = Offset::from_str(&offset.to_string()).unwrap();
+            assert_eq!(parsed.offset, 100);
+            assert_eq!(parsed.keyset, offset.keyset);
+        }
+        assert!(Offset::from_str("100:5").is_err());
+        assert!(Offset::from_str("100:x:YQ").is_err());
+    }
+
+    #[test]
+    fn test_valid_inclusive_query_args() {
+        let req = TestRequest::with_uri("/?newer_eq=1.5&older_eq=2.43")
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.newer, None);
+        assert_eq!(result.older, None);
+        assert_eq!(result.newer_eq.unwrap(), SyncTimestamp::from_seconds(1.5));
+        assert_eq!(result.older_eq.unwrap(), SyncTimestamp::from_seconds(2.43));
+    }
+
+    #[test]
+    fn test_valid_expiring_before_query_arg() {
+        let req = TestRequest::with_uri("/?expiring_before=1234.5")
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(
+            result.expiring_before.unwrap(),
+            SyncTimestamp::from_seconds(1234.5)
+        );
     }
 
     #[test]
@@ -2090,6 +2773,41 @@ mod tests {
         */
     }
 
+    #[test]
+    fn test_empty_bso_payload() {
+        let put = |state: ServerState| {
+            let payload = HawkPayload::test_default(*USER_ID);
+            let uri = format!("/1.5/{}/storage/tabs/asdf", *USER_ID);
+            let header =
+                create_valid_hawk_header(&payload, &state, "PUT", &uri, TEST_HOST, TEST_PORT);
+            let req = TestRequest::with_uri(&uri)
+                .data(state)
+                .header("authorization", header)
+                .header("content-type", "application/json")
+                .method(Method::PUT)
+                .set_payload(json!({"payload": ""}).to_string())
+                .param("uid", &USER_ID_STR)
+                .param("collection", "tabs")
+                .param("bso", "asdf")
+                .to_http_request();
+            req.extensions_mut().insert(make_db());
+            block_on(BsoPutRequest::extract(&req))
+        };
+
+        let result = put(make_state()).expect("Could not get result in test_empty_bso_payload");
+        assert_eq!(result.body.payload, Some("".to_owned()));
+
+        let state = ServerState {
+            allow_empty_payload: false,
+            ..make_state()
+        };
+        let response: HttpResponse = put(state)
+            .err()
+            .expect("Could not get response in test_empty_bso_payload")
+            .into();
+        assert_eq!(response.status(), 400);
+    }
+
     #[test]
     fn test_valid_collection_request() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2111,6 +2829,46 @@ mod tests {
         assert_eq!(&result.collection, "tabs");
     }
 
+    fn collection_request_with_accept(accept: Option<&str>) -> Result<CollectionRequest, Error> {
+        let payload = HawkPayload::test_default(*USER_ID);
+        let state = make_state();
+        let uri = format!("/1.5/{}/storage/tabs", *USER_ID);
+        let header = create_valid_hawk_header(&payload, &state, "GET", &uri, TEST_HOST, TEST_PORT);
+        let mut req = TestRequest::with_uri(&uri)
+            .data(state)
+            .header("authorization", header)
+            .method(Method::GET)
+            .param("uid", &USER_ID_STR)
+            .param("collection", "tabs");
+        if let Some(accept) = accept {
+            req = req.header("accept", accept);
+        }
+        let req = req.to_http_request();
+        req.extensions_mut().insert(make_db());
+        block_on(CollectionRequest::extract(&req))
+    }
+
+    #[test]
+    fn test_collection_request_accept() {
+        for accept in &[
+            None,
+            Some("*/*"),
+            Some("application/json"),
+            Some("text/plain"),
+        ] {
+            let result = collection_request_with_accept(*accept)
+                .expect("Could not get result in test_collection_request_accept");
+            assert_eq!(result.reply, ReplyFormat::Json);
+        }
+        let result = collection_request_with_accept(Some("application/newlines"))
+            .expect("Could not get result in test_collection_request_accept");
+        assert_eq!(result.reply, ReplyFormat::Newlines);
+
+        let result = collection_request_with_accept(Some("application/xml"));
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 406);
+    }
+
     #[test]
     fn test_invalid_collection_request() {
         let hawk_payload = HawkPayload::test_default(*USER_ID);
@@ -2177,6 +2935,29 @@ mod tests {
         assert_eq!(result.bsos.invalid.len(), 2);
     }
 
+    #[actix_rt::test]
+    async fn test_collection_post_sortindex_range() {
+        let bso_body = json!([
+            {"id": "max", "sortindex": 999_999_999},
+            {"id": "min", "sortindex": -999_999_999},
+            {"id": "over", "sortindex": 1_000_000_000},
+            {"id": "under", "sortindex": -1_000_000_000},
+            {"id": "i32_over", "sortindex": i64::from(i32::max_value()) + 1},
+            {"id": "i32_under", "sortindex": i64::from(i32::min_value()) - 1},
+            {"id": "float", "sortindex": 1.5}
+        ]);
+        let result = post_collection("", &bso_body)
+            .await
+            .expect("Could not get result in test_collection_post_sortindex_range");
+        let mut valid: Vec<_> = result.bsos.valid.iter().map(|bso| &bso.id).collect();
+        valid.sort();
+        assert_eq!(valid, vec!["max", "min"]);
+        assert_eq!(result.bsos.invalid.len(), 5);
+        for reason in result.bsos.invalid.values() {
+            assert!(reason.contains(BSO_SORTINDEX_RANGE_ERROR), "{}", reason);
+        }
+    }
+
     #[actix_rt::test]
     async fn test_valid_collection_batch_post_request() {
         // If the "batch" parameter is has no value or has a value of "true"
@@ -2241,7 +3022,11 @@ mod tests {
                 Some(t) => t.clone(),
                 None => Tags::from_request_head(req.head()),
             };
-            let result = PreConditionHeaderOpt::extrude(&req.headers(), Some(tags));
+            let result = PreConditionHeaderOpt::extrude(
+                &req.headers(),
+                DEFAULT_TIMESTAMP_SLACK_SECS,
+                Some(tags),
+            );
             assert!(result.is_err());
             let response: HttpResponse = result.err().unwrap().into();
             assert_eq!(response.status(), 400);
@@ -2281,10 +3066,11 @@ mod tests {
             .data(make_state())
             .header("X-If-Modified-Since", "32.1")
             .to_http_request();
-        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
-            .unwrap()
-            .opt
-            .unwrap();
+        let result =
+            PreConditionHeaderOpt::extrude(&req.headers(), DEFAULT_TIMESTAMP_SLACK_SECS, None)
+                .unwrap()
+                .opt
+                .unwrap();
         assert_eq!(
             result,
             PreConditionHeader::IfModifiedSince(SyncTimestamp::from_seconds(32.1))
@@ -2293,10 +3079,11 @@ mod tests {
             .data(make_state())
             .header("X-If-Unmodified-Since", "32.14")
             .to_http_request();
-        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
-            .unwrap()
-            .opt
-            .unwrap();
+        let result =
+            PreConditionHeaderOpt::extrude(&req.headers(), DEFAULT_TIMESTAMP_SLACK_SECS, None)
+                .unwrap()
+                .opt
+                .unwrap();
         assert_eq!(
             result,
             PreConditionHeader::IfUnmodifiedSince(SyncTimestamp::from_seconds(32.14))
@@ -2355,6 +3142,27 @@ mod tests {
         */
     }
 
+    #[test]
+    fn valid_header_with_out_of_range_uid() {
+        // MySQL's uid columns are signed 64-bit
+        let uid = i64::max_value() as u64 + 1;
+        let hawk_payload = HawkPayload::test_default(uid);
+        let state = make_state();
+        let uri = format!("/1.5/{}/storage/col2", uid);
+        let header =
+            create_valid_hawk_header(&hawk_payload, &state, "GET", &uri, TEST_HOST, TEST_PORT);
+        let req = TestRequest::with_uri(&uri)
+            .data(state)
+            .header("authorization", header)
+            .method(Method::GET)
+            .param("uid", &uid.to_string())
+            .to_http_request();
+        let result = block_on(HawkIdentifier::extract(&req));
+        assert!(result.is_err());
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 400);
+    }
+
     #[actix_rt::test]
     async fn test_max_ttl() {
         let bso_body = json!([
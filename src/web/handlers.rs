@@ -1,21 +1,36 @@
 //! API Handlers
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::atomic::Ordering};
 
-use actix_web::{http::StatusCode, Error, HttpRequest, HttpResponse};
+use actix_web::{
+    http::{header::WARNING, StatusCode},
+    web::Data,
+    Error, HttpRequest, HttpResponse,
+};
 use futures::future::{self, Either, Future, FutureExt, LocalBoxFuture, TryFutureExt};
 use serde::Serialize;
 use serde_json::{json, Value};
 
-use crate::db::{params, results::Paginated, util::SyncTimestamp, DbError, DbErrorKind};
+use crate::db::{
+    params,
+    results::{self, Paginated},
+    util::SyncTimestamp,
+    DbError, DbErrorKind,
+};
 use crate::error::{ApiError, ApiErrorKind};
+use crate::server::{metrics::Metrics, ServerState};
 use crate::web::extractors::{
-    BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, ConfigRequest,
-    HeartbeatRequest, MetaRequest, ReplyFormat, TestErrorRequest,
+    BsoPutRequest, BsoQueryParams, BsoRequest, CollectionCountsQueryParams, CollectionPatchRequest,
+    CollectionPostRequest, CollectionRequest, ConfigRequest, HeartbeatRequest, MetaRequest,
+    ReplyFormat, StoragePostRequest, TestErrorRequest,
 };
-use crate::web::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS};
+use crate::web::tags::Tags;
+use crate::web::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS, X_WEAVE_TOTAL_RECORDS};
 
 pub const ONE_KB: f64 = 1024.0;
 
+/// Warns of a get_collection response truncated by `limits.max_response_bytes`
+const TRUNCATED_WARNING: &str = "199 - \"Response truncated, see X-Weave-Next-Offset\"";
+
 pub fn get_collections(meta: MetaRequest) -> impl Future<Output = Result<HttpResponse, Error>> {
     meta.metrics.incr("request.get_collections");
     meta.db
@@ -28,12 +43,30 @@ pub fn get_collections(meta: MetaRequest) -> impl Future<Output = Result<HttpRes
         })
 }
 
+pub fn get_collection_names(
+    meta: MetaRequest,
+) -> impl Future<Output = Result<HttpResponse, Error>> {
+    meta.metrics.incr("request.get_collection_names");
+    meta.db
+        .get_collection_names(meta.user_id)
+        .map_err(From::from)
+        .map_ok(|result| {
+            HttpResponse::build(StatusCode::OK)
+                .header(X_WEAVE_RECORDS, result.len().to_string())
+                .json(result)
+        })
+}
+
 pub fn get_collection_counts(
     meta: MetaRequest,
+    query: CollectionCountsQueryParams,
 ) -> impl Future<Output = Result<HttpResponse, Error>> {
     meta.metrics.incr("request.get_collection_counts");
     meta.db
-        .get_collection_counts(meta.user_id)
+        .get_collection_counts(params::GetCollectionCounts {
+            user_id: meta.user_id,
+            newer: query.newer,
+        })
         .map_err(From::from)
         .map_ok(|result| {
             HttpResponse::build(StatusCode::OK)
@@ -77,60 +110,140 @@ pub async fn delete_all(meta: MetaRequest) -> Result<HttpResponse, Error> {
     Ok(HttpResponse::Ok().json(db.delete_storage(meta.user_id).await?))
 }
 
-pub fn delete_collection(
-    coll: CollectionRequest,
-) -> impl Future<Output = Result<HttpResponse, Error>> {
-    let delete_bsos = !coll.query.ids.is_empty();
-    let metrics = coll.metrics.clone();
-    let fut = if delete_bsos {
-        metrics.incr("request.delete_bsos");
-        coll.db.delete_bsos(params::DeleteBsos {
-            user_id: coll.user_id.clone(),
-            collection: coll.collection.clone(),
-            ids: coll.query.ids.clone(),
-        })
-    } else {
-        metrics.incr("request.delete_collection");
-        coll.db.delete_collection(params::DeleteCollection {
+pub async fn delete_collection(coll: CollectionRequest) -> Result<HttpResponse, Error> {
+    if !coll.query.ids.is_empty() {
+        coll.metrics.clone().incr("request.delete_bsos");
+        let exists = coll
+            .db
+            .collection_exists(params::CollectionExists {
+                user_id: coll.user_id.clone(),
+                collection: coll.collection.clone(),
+            })
+            .await?;
+        // Nothing to delete when the collection doesn't exist
+        let ids: &[String] = if exists { &coll.query.ids } else { &[] };
+        // Many ids are deleted in chunks, all within the request's
+        // transaction (sharing its timestamp)
+        let mut result: Option<results::DeleteBsos> = None;
+        for ids in ids.chunks(coll.delete_chunk_size) {
+            let chunk = coll
+                .db
+                .delete_bsos(params::DeleteBsos {
+                    user_id: coll.user_id.clone(),
+                    collection: coll.collection.clone(),
+                    ids: ids.to_vec(),
+                })
+                .await?;
+            let deleted = result.as_ref().map_or(0, |result| result.deleted);
+            result = Some(results::DeleteBsos {
+                modified: chunk.modified,
+                deleted: deleted + chunk.deleted,
+            });
+        }
+        let result = match result {
+            Some(result) => results::DeleteBsos {
+                modified: coll.db.commit_and_get_timestamp().await?,
+                ..result
+            },
+            None => results::DeleteBsos {
+                modified: coll.db.get_storage_timestamp(coll.user_id).await?,
+                deleted: 0,
+            },
+        };
+        return Ok(HttpResponse::Ok()
+            .header(X_LAST_MODIFIED, result.modified.as_header())
+            .json(result));
+    }
+
+    coll.metrics.clone().incr("request.delete_collection");
+    let result = match coll
+        .db
+        .delete_collection(params::DeleteCollection {
             user_id: coll.user_id.clone(),
             collection: coll.collection.clone(),
         })
-    };
-
-    fut.or_else(move |e| {
-        if e.is_collection_not_found() || e.is_bso_not_found() {
-            coll.db.get_storage_timestamp(coll.user_id)
-        } else {
-            Box::pin(future::err(e))
+        .await
+    {
+        // The request's own timestamp when it deleted anything (otherwise
+        // the storage's)
+        Ok(result) if result == coll.db.timestamp() => coll.db.commit_and_get_timestamp().await?,
+        Ok(result) => result,
+        Err(e) if e.is_collection_not_found() || e.is_bso_not_found() => {
+            coll.db.get_storage_timestamp(coll.user_id).await?
         }
-    })
-    .map_err(From::from)
-    .map_ok(move |result| {
-        HttpResponse::Ok()
-            .if_true(delete_bsos, |resp| {
-                resp.header(X_LAST_MODIFIED, result.as_header());
-            })
-            .json(result)
-    })
+        Err(e) => return Err(e.into()),
+    };
+    Ok(HttpResponse::Ok()
+        .header(X_LAST_MODIFIED, result.as_header())
+        .json(result))
 }
 
 pub fn get_collection(
     coll: CollectionRequest,
-) -> impl Future<Output = Result<HttpResponse, Error>> {
+) -> LocalBoxFuture<'static, Result<HttpResponse, Error>> {
+    if let Some(id) = coll.batch.clone() {
+        return Box::pin(get_batch_bsos(coll, id));
+    }
     coll.metrics.clone().incr("request.get_collection");
     let params = params::GetBsos {
         user_id: coll.user_id.clone(),
-        params: coll.query.clone(),
+        params: BsoQueryParams {
+            max_bytes: coll.max_response_bytes,
+            ..coll.query.clone()
+        },
         collection: coll.collection.clone(),
     };
     if coll.query.full {
         let fut = coll.db.get_bsos(params);
-        Either::Left(finish_get_collection(coll, fut))
+        finish_get_collection(coll, fut)
     } else {
         // Changed to be a Paginated list of BSOs, need to extract IDs from them.
         let fut = coll.db.get_bso_ids(params);
-        Either::Right(finish_get_collection(coll, fut))
+        finish_get_collection(coll, fut)
+    }
+}
+
+/// The ids (or full records) appended to a pending batch so far, allowing an
+/// interrupted batch upload to resume
+async fn get_batch_bsos(coll: CollectionRequest, id: String) -> Result<HttpResponse, Error> {
+    coll.metrics.clone().incr("request.get_batch");
+    // Invalid, unknown or expired batches are all 404s
+    if coll.db.validate_batch_id(id.clone()).is_err() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let batch = coll
+        .db
+        .get_batch(params::GetBatch {
+            user_id: coll.user_id.clone(),
+            collection: coll.collection.clone(),
+            id: id.clone(),
+        })
+        .or_else(|e| {
+            if e.is_collection_not_found() {
+                future::ok(None)
+            } else {
+                future::err(e)
+            }
+        })
+        .await?;
+    if batch.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
     }
+    let bsos = coll
+        .db
+        .get_batch_bsos(params::GetBatchBsos {
+            user_id: coll.user_id,
+            collection: coll.collection,
+            id,
+        })
+        .await?;
+    let mut resp = HttpResponse::build(StatusCode::OK);
+    resp.header(X_WEAVE_RECORDS, bsos.len().to_string());
+    Ok(if coll.query.full {
+        resp.json(bsos)
+    } else {
+        resp.json(bsos.into_iter().map(|bso| bso.id).collect::<Vec<_>>())
+    })
 }
 
 fn finish_get_collection<F, T>(
@@ -143,49 +256,90 @@ where
 {
     let reply_format = coll.reply;
     Box::pin(
-        fut.or_else(move |e| {
-            if e.is_collection_not_found() {
-                // For b/w compat, non-existent collections must return an
-                // empty list
-                future::ok(Paginated::default())
-            } else {
-                future::err(e)
-            }
-        })
-        .map_err(From::from)
-        .and_then(|result| {
-            coll.db
-                .extract_resource(coll.user_id, Some(coll.collection), None)
-                .map_err(From::from)
-                .map_ok(move |ts| (result, ts))
-        })
-        .map_ok(move |(result, ts): (Paginated<T>, SyncTimestamp)| {
-            let mut builder = HttpResponse::build(StatusCode::OK);
-            let resp = builder
-                .header(X_LAST_MODIFIED, ts.as_header())
-                .header(X_WEAVE_RECORDS, result.items.len().to_string())
-                .if_some(result.offset, |offset, resp| {
-                    resp.header(X_WEAVE_NEXT_OFFSET, offset);
-                });
-            match reply_format {
-                ReplyFormat::Json => resp.json(result.items),
-                ReplyFormat::Newlines => {
-                    let items: String = result
-                        .items
-                        .into_iter()
-                        .map(|v| serde_json::to_string(&v).unwrap_or_else(|_| "".to_string()))
-                        .filter(|v| !v.is_empty())
-                        .map(|v| v.replace("\n", "\\u000a") + "\n")
-                        .collect();
-                    resp.header("Content-Type", "application/newlines")
-                        .header("Content-Length", format!("{}", items.len()))
-                        .body(items)
+        fut.map_ok(Some)
+            .or_else(move |e| {
+                if e.is_collection_not_found() {
+                    future::ok(None)
+                } else {
+                    future::err(e)
                 }
-            }
-        }),
+            })
+            .and_then(move |result| match result {
+                // (Possibly truncated by the db at the response's byte
+                // ceiling: its offset is then the backend's usual one)
+                Some(result) => {
+                    Either::Left(total_records(&coll, &result).and_then(move |total| {
+                        coll.db
+                            .extract_resource(coll.user_id, Some(coll.collection), None)
+                            .map_ok(move |ts| (result, total, ts))
+                    }))
+                }
+                // For b/w compat, non-existent collections must return an
+                // empty list (that's never been modified)
+                None => Either::Right(future::ok((
+                    Paginated::default(),
+                    0,
+                    SyncTimestamp::from_seconds(0f64),
+                ))),
+            })
+            .map_err(From::from)
+            .map_ok(
+                move |(result, total, ts): (Paginated<T>, u64, SyncTimestamp)| {
+                    let mut builder = HttpResponse::build(StatusCode::OK);
+                    let resp = builder
+                        .header(X_LAST_MODIFIED, ts.as_header())
+                        .header(X_WEAVE_RECORDS, result.items.len().to_string())
+                        .header(X_WEAVE_TOTAL_RECORDS, total.to_string())
+                        .if_some(result.offset, |offset, resp| {
+                            resp.header(X_WEAVE_NEXT_OFFSET, offset);
+                        })
+                        .if_true(result.truncated, |resp| {
+                            resp.header(WARNING, TRUNCATED_WARNING);
+                        });
+                    match reply_format {
+                        ReplyFormat::Json => resp.json(result.items),
+                        ReplyFormat::Newlines => {
+                            let items: String = result
+                                .items
+                                .into_iter()
+                                .map(|v| {
+                                    serde_json::to_string(&v).unwrap_or_else(|_| "".to_string())
+                                })
+                                .filter(|v| !v.is_empty())
+                                .map(|v| v.replace("\n", "\\u000a") + "\n")
+                                .collect();
+                            resp.header("Content-Type", "application/newlines")
+                                .header("Content-Length", format!("{}", items.len()))
+                                .body(items)
+                        }
+                    }
+                },
+            ),
     )
 }
 
+/// The total number of records matching a get_collection request, regardless
+/// of paging
+///
+/// Only requires a count query when `page` isn't the final one.
+fn total_records<T>(
+    coll: &CollectionRequest,
+    page: &Paginated<T>,
+) -> LocalBoxFuture<'static, Result<u64, ApiError>>
+where
+    T: Serialize,
+{
+    if page.offset.is_none() {
+        let offset = coll.query.offset.as_ref().map_or(0, |offset| offset.offset);
+        return Box::pin(future::ok(offset + page.items.len() as u64));
+    }
+    coll.db.count_bsos(params::CountBsos {
+        user_id: coll.user_id.clone(),
+        collection: coll.collection.clone(),
+        params: coll.query.clone(),
+    })
+}
+
 pub fn post_collection(
     coll: CollectionPostRequest,
 ) -> impl Future<Output = Result<HttpResponse, Error>> {
@@ -193,21 +347,97 @@ pub fn post_collection(
     if coll.batch.is_some() {
         return Either::Left(post_collection_batch(coll));
     }
-    Either::Right(
-        coll.db
+    let params = params::PostBsos {
+        user_id: coll.user_id,
+        collection: coll.collection,
+        bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
+        failed: coll.bsos.invalid,
+        breakdown: coll.query.breakdown,
+    };
+    let fut = if coll.replace {
+        coll.metrics.clone().incr("request.reset_collection");
+        coll.db.reset_collection(params)
+    } else {
+        coll.db.post_bsos(params)
+    };
+    let db = coll.db;
+    Either::Right(async move {
+        let mut result = fut.await?;
+        result.modified = db.commit_and_get_timestamp().await?;
+        Ok::<_, Error>(
+            HttpResponse::build(StatusCode::OK)
+                .header(X_LAST_MODIFIED, result.modified.as_header())
+                .json(result),
+        )
+    })
+}
+
+/// Refresh the ttls of a collection's BSOs (by id) without rewriting them
+pub async fn patch_collection(coll: CollectionPatchRequest) -> Result<HttpResponse, Error> {
+    coll.metrics.incr("request.bulk_set_ttl");
+    coll.db
+        .bulk_set_ttl(params::BulkSetTtl {
+            user_id: coll.user_id,
+            collection: coll.collection,
+            ids: coll.body.ids,
+            ttl: coll.body.ttl,
+        })
+        .await?;
+    let result = coll.db.commit_and_get_timestamp().await?;
+    Ok(HttpResponse::Ok()
+        .header(X_LAST_MODIFIED, result.as_header())
+        .json(json!({ "modified": result })))
+}
+
+/// POST to multiple collections at once: each collection's BSOs are posted
+/// (as by `post_collection`) within the request's transaction
+pub async fn post_storage(sreq: StoragePostRequest) -> Result<HttpResponse, Error> {
+    sreq.metrics.clone().incr("request.post_storage");
+    let db = sreq.db;
+    // The db middleware can't lock the collections (absent from the path):
+    // lock them all (in name order) before writing to any, so they share a
+    // timestamp
+    for (collection, _) in &sreq.collections {
+        db.lock_for_write(params::LockCollection {
+            user_id: sreq.user_id.clone(),
+            collection: collection.clone(),
+        })
+        .await?;
+    }
+
+    let mut written = false;
+    let mut collections = serde_json::Map::new();
+    for (collection, bsos) in sreq.collections {
+        let result = db
             .post_bsos(params::PostBsos {
-                user_id: coll.user_id,
-                collection: coll.collection,
-                bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
-                failed: coll.bsos.invalid,
+                user_id: sreq.user_id.clone(),
+                collection: collection.clone(),
+                bsos: bsos.valid.into_iter().map(From::from).collect(),
+                failed: bsos.invalid,
+                breakdown: false,
             })
-            .map_err(From::from)
-            .map_ok(|result| {
-                HttpResponse::build(StatusCode::OK)
-                    .header(X_LAST_MODIFIED, result.modified.as_header())
-                    .json(result)
+            .await?;
+        written = true;
+        collections.insert(
+            collection,
+            json!({
+                "success": result.success,
+                "failed": result.failed,
             }),
-    )
+        );
+    }
+    let modified = if written {
+        db.commit_and_get_timestamp().await?
+    } else {
+        // Nothing was written
+        db.get_storage_timestamp(sreq.user_id).await?
+    };
+    Ok(HttpResponse::build(StatusCode::OK)
+        .header(X_LAST_MODIFIED, modified.as_header())
+        .json(json!({
+            "modified": modified,
+            "collections": collections,
+        })))
 }
 
 pub fn post_collection_batch(
@@ -254,6 +484,7 @@ pub fn post_collection_batch(
     let db = coll.db.clone();
     let user_id = coll.user_id.clone();
     let collection = coll.collection.clone();
+    let metrics = coll.metrics.clone();
 
     Either::Right(
         fut.and_then(move |id| {
@@ -286,6 +517,7 @@ pub fn post_collection_batch(
                                 })
                                 .collect(),
                             failed: Default::default(),
+                            breakdown: false,
                         })
                         .and_then(|_| future::ok(())),
                 )
@@ -320,6 +552,7 @@ pub fn post_collection_batch(
                 return Either::Left(future::ok(HttpResponse::Accepted().json(resp)));
             }
 
+            let db2 = db.clone();
             let fut = db
                 .get_batch(params::GetBatch {
                     user_id: user_id.clone(),
@@ -341,20 +574,47 @@ pub fn post_collection_batch(
                     }
                 })
                 .map_err(From::from)
-                .map_ok(|result| {
-                    resp["modified"] = json!(result.modified);
-                    HttpResponse::build(StatusCode::OK)
-                        .header(X_LAST_MODIFIED, result.modified.as_header())
-                        .json(resp)
+                .and_then(move |_| async move {
+                    let modified = db2.commit_and_get_timestamp().await?;
+                    record_partial_failure(&metrics, &failed);
+                    resp["modified"] = json!(modified);
+                    Ok::<_, Error>(
+                        HttpResponse::build(StatusCode::OK)
+                            .header(X_LAST_MODIFIED, modified.as_header())
+                            .json(resp),
+                    )
                 });
             Either::Right(fut)
         }),
     )
 }
 
+/// Count the records that failed to be written by a committed batch, per
+/// failure reason
+fn record_partial_failure(metrics: &Metrics, failed: &HashMap<String, String>) {
+    let mut reasons: HashMap<&str, i64> = HashMap::new();
+    for reason in failed.values() {
+        *reasons.entry(failure_reason(reason)).or_default() += 1;
+    }
+    for (reason, count) in reasons {
+        let mut tags = Tags::default();
+        tags.tags.insert("reason".to_owned(), reason.to_owned());
+        metrics.count_with_tags("batch.commit.partial_failure", count, Some(tags));
+    }
+}
+
+/// A record's failure reason stripped of its details (e.g. "invalid bso: ..."
+/// becomes "invalid bso"), bounding the metric's tag values
+fn failure_reason(reason: &str) -> &str {
+    if reason.starts_with("unknown field") {
+        return "unknown field";
+    }
+    reason.split(':').next().unwrap_or(reason).trim()
+}
+
 pub async fn delete_bso(bso_req: BsoRequest) -> Result<HttpResponse, Error> {
     bso_req.metrics.incr("request.delete_bso");
-    let result = bso_req
+    bso_req
         .db
         .delete_bso(params::DeleteBso {
             user_id: bso_req.user_id,
@@ -362,11 +622,24 @@ pub async fn delete_bso(bso_req: BsoRequest) -> Result<HttpResponse, Error> {
             id: bso_req.bso,
         })
         .await?;
-    Ok(HttpResponse::Ok().json(json!({ "modified": result })))
+    let result = bso_req.db.commit_and_get_timestamp().await?;
+    Ok(HttpResponse::Ok()
+        .header(X_LAST_MODIFIED, result.as_header())
+        .json(json!({ "modified": result })))
 }
 
 pub async fn get_bso(bso_req: BsoRequest) -> Result<HttpResponse, Error> {
     bso_req.metrics.incr("request.get_bso");
+    let exists = bso_req
+        .db
+        .collection_exists(params::CollectionExists {
+            user_id: bso_req.user_id.clone(),
+            collection: bso_req.collection.clone(),
+        })
+        .await?;
+    if !exists {
+        return Ok(HttpResponse::NotFound().finish());
+    }
     let result = bso_req
         .db
         .get_bso(params::GetBso {
@@ -384,7 +657,7 @@ pub async fn get_bso(bso_req: BsoRequest) -> Result<HttpResponse, Error> {
 
 pub async fn put_bso(bso_req: BsoPutRequest) -> Result<HttpResponse, Error> {
     bso_req.metrics.incr("request.put_bso");
-    let result = bso_req
+    bso_req
         .db
         .put_bso(params::PutBso {
             user_id: bso_req.user_id,
@@ -393,8 +666,10 @@ pub async fn put_bso(bso_req: BsoPutRequest) -> Result<HttpResponse, Error> {
             sortindex: bso_req.body.sortindex,
             payload: bso_req.body.payload,
             ttl: bso_req.body.ttl,
+            create_only: bso_req.create_only,
         })
         .await?;
+    let result = bso_req.db.commit_and_get_timestamp().await?;
 
     Ok(HttpResponse::build(StatusCode::OK)
         .header(X_LAST_MODIFIED, result.as_header())
@@ -415,30 +690,65 @@ pub async fn heartbeat(hb: HeartbeatRequest) -> HttpResponse {
         Value::String(env!("CARGO_PKG_VERSION").to_owned()),
     );
 
-    match hb.db.check().await {
-        Ok(result) => {
-            if result {
-                checklist.insert("database".to_owned(), Value::from("Ok"));
-            } else {
-                checklist.insert("database".to_owned(), Value::from("Err"));
-                checklist.insert(
-                    "database_msg".to_owned(),
-                    Value::from("check failed without error"),
-                );
-            };
-            let status = if result { "Ok" } else { "Err" };
-            checklist.insert("status".to_owned(), Value::from(status));
+    match hb.db.health_detail().await {
+        // Not ready until the pool's established its initial connections
+        // (and initialized the db, e.g. created the standard collections)
+        Ok(detail) if detail.warming_up => {
+            checklist.insert("status".to_owned(), Value::from("Err"));
+            checklist.insert("database".to_owned(), Value::from("Warming up"));
+            checklist.insert(
+                "database_detail".to_owned(),
+                serde_json::to_value(detail).unwrap_or_default(),
+            );
+            HttpResponse::ServiceUnavailable().json(checklist)
+        }
+        Ok(detail) => {
+            checklist.insert("database".to_owned(), Value::from("Ok"));
+            checklist.insert(
+                "database_detail".to_owned(),
+                serde_json::to_value(detail).unwrap_or_default(),
+            );
+            checklist.insert("status".to_owned(), Value::from("Ok"));
             HttpResponse::Ok().json(checklist)
         }
         Err(e) => {
             error!("Heartbeat error: {:?}", e);
             checklist.insert("status".to_owned(), Value::from("Err"));
             checklist.insert("database".to_owned(), Value::from("Unknown"));
+            // When the db was last reachable, to tell a blip from an outage
+            checklist.insert(
+                "database_detail".to_owned(),
+                json!({ "last_success_ms": hb.db.last_success() }),
+            );
             HttpResponse::ServiceUnavailable().json(checklist)
         }
     }
 }
 
+/// Used by the load balancers, just return OK (or a 503 once shutting down,
+/// so they stop routing to us).
+pub async fn lbheartbeat(req: HttpRequest) -> HttpResponse {
+    let shutting_down = req
+        .app_data::<Data<ServerState>>()
+        .map_or(false, |state| state.shutting_down.load(Ordering::Relaxed));
+    if shutting_down {
+        return HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .body(r#"{"status": "shutting down"}"#);
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body("{}")
+}
+
+/// Return the contents of the version.json file created by circleci and
+/// stored in the docker root
+pub async fn version(_: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(include_str!("../../version.json"))
+}
+
 // try returning an API error
 pub async fn test_error(
     _req: HttpRequest,
@@ -446,19 +756,33 @@ pub async fn test_error(
 ) -> Result<HttpResponse, ApiError> {
     // generate an error for sentry.
 
-    /*  The various error log macros only can take a string.
-        Content of Tags struct can be logged as KV (key value) pairs after a `;`.
-        e.g.
-        ```
-        error!("Something Bad {:?}", err; wtags)
-        ```
-
-        TODO: find some way to transform Tags into error::KV
-    */
-    error!("Test Error: {:?}", &ter.tags);
+    // Tags are logged as key values (fields of MozLog records)
+    error!("Test Error"; &ter.tags);
 
     // ApiError will call the middleware layer to auto-append the tags.
     let err = ApiError::from(ApiErrorKind::Internal("Oh Noes!".to_owned()));
 
     Err(err)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::failure_reason;
+
+    #[test]
+    fn failure_reasons_bucketed() {
+        assert_eq!(
+            failure_reason("invalid bso: ttl: Value is out of range"),
+            "invalid bso"
+        );
+        assert_eq!(
+            failure_reason("invalid bso: payload: Value is too long"),
+            "invalid bso"
+        );
+        assert_eq!(failure_reason("unknown field foo"), "unknown field");
+        assert_eq!(failure_reason("unknown field bar: baz"), "unknown field");
+        assert_eq!(failure_reason("retry bytes"), "retry bytes");
+        assert_eq!(failure_reason("retry bso"), "retry bso");
+        assert_eq!(failure_reason(""), "");
+    }
+}
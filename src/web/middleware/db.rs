@@ -1,22 +1,31 @@
 use std::task::Context;
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, sync::atomic::Ordering};
 
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
     http::{header::HeaderValue, Method},
+    web::Data,
     Error, HttpMessage, HttpResponse,
 };
 use futures::future::{self, Either, LocalBoxFuture, Ready, TryFutureExt};
 use std::task::Poll;
 
-use crate::db::params;
-use crate::error::{ApiError, ApiErrorKind};
+use crate::db::{params, util::SyncTimestamp, Db};
+use crate::error::{ApiError, ApiErrorKind, RETRY_AFTER};
 use crate::server::{metrics, ServerState};
-use crate::web::middleware::sentry::{queue_report, report};
+use crate::web::middleware::sentry::{event_from_error, queue_report, report};
 use crate::web::{
-    extractors::CollectionParam, middleware::SyncServerRequest, tags::Tags, DOCKER_FLOW_ENDPOINTS,
+    client_ip::ClientIp,
+    dockerflow::is_dockerflow_request,
+    extractors::{CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt},
+    middleware::SyncServerRequest,
+    tags::Tags,
+    X_WEAVE_ALERT,
 };
 
+/// The X-Weave-Alert sent with writes rejected in read-only maintenance mode
+const READ_ONLY_ALERT: &str = "Sync is in read-only mode for maintenance, please try again later";
+
 pub struct DbTransaction;
 
 impl DbTransaction {
@@ -81,7 +90,7 @@ where
             .to_str()
             .unwrap_or("NONE");
         info!(">>> testing db middleware"; "user_agent" => useragent);
-        if DOCKER_FLOW_ENDPOINTS.contains(&sreq.uri().path().to_lowercase().as_str()) {
+        if is_dockerflow_request(&sreq) {
             let mut service = Rc::clone(&self.service);
             return Box::pin(service.call(sreq));
         }
@@ -90,7 +99,6 @@ where
             Some(t) => t.clone(),
             None => Tags::from_request_head(sreq.head()),
         };
-        let col_result = CollectionParam::extrude(&sreq.uri(), &mut sreq.extensions_mut(), &tags);
         let state = match &sreq.app_data::<ServerState>() {
             Some(v) => v.clone(),
             None => {
@@ -106,6 +114,27 @@ where
                 ));
             }
         };
+        let col_result = CollectionParam::extrude(
+            &sreq.uri(),
+            &mut sreq.extensions_mut(),
+            &state.url_prefix,
+            &tags,
+        );
+        if state.read_only.load(Ordering::Relaxed)
+            && !matches!(*sreq.method(), Method::GET | Method::HEAD)
+        {
+            debug!("Rejecting write in read-only mode");
+            return Box::pin(future::ok(
+                sreq.into_response(
+                    HttpResponse::ServiceUnavailable()
+                        .header("Retry-After", RETRY_AFTER.to_string())
+                        .header(X_WEAVE_ALERT, READ_ONLY_ALERT)
+                        .content_type("application/json")
+                        .body("0".to_owned())
+                        .into_body(),
+                ),
+            ));
+        }
         let collection = match col_result {
             Ok(v) => v,
             Err(e) => {
@@ -127,7 +156,11 @@ where
         let hawk_user_id = match sreq.get_hawk_id() {
             Ok(v) => v,
             Err(e) => {
-                warn!("⚠️ Bad Hawk Id: {:?}", e; "user_agent"=> useragent);
+                warn!(
+                    "⚠️ Bad Hawk Id: {:?}", e;
+                    "user_agent" => useragent,
+                    "client_ip" => ClientIp::log_value(&sreq)
+                );
                 queue_report(sreq.extensions_mut(), &e);
                 return Box::pin(future::ok(
                     sreq.into_response(
@@ -139,19 +172,43 @@ where
                 ));
             }
         };
+        // Invalid headers are rejected later by the PreConditionCheck
+        let precondition =
+            PreConditionHeaderOpt::extrude(sreq.headers(), state.timestamp_slack_secs, None)
+                .ok()
+                .and_then(|header| header.opt);
+        let since = match precondition {
+            Some(PreConditionHeader::IfModifiedSince(since)) => Some(since),
+            _ => None,
+        };
+        // Preconditions must be evaluated against the latest data, never a
+        // stale snapshot
+        let strong = matches!(
+            precondition,
+            Some(PreConditionHeader::IfModifiedSince(_))
+                | Some(PreConditionHeader::IfUnmodifiedSince(_))
+        );
+        let read = matches!(method, Method::GET | Method::HEAD);
         let mut service = Rc::clone(&self.service);
-        let fut = state.db_pool.get().map_err(Into::into).and_then(move |db| {
+        let db_fut = get_db(state, read, hawk_user_id.clone(), since);
+        let fut = db_fut.map_err(Into::into).and_then(move |mut db| {
+            if let Some(collection) = &collection {
+                db.set_metrics_collection(&collection.collection);
+            }
             sreq.extensions_mut().insert(db.clone());
             let db2 = db.clone();
 
             if let Some(collection) = collection {
-                let lc = params::LockCollection {
-                    user_id: hawk_user_id,
-                    collection: collection.collection,
-                };
                 Either::Left(match method {
-                    Method::GET | Method::HEAD => db.lock_for_read(lc),
-                    _ => db.lock_for_write(lc),
+                    Method::GET | Method::HEAD => db.lock_for_read(params::LockCollectionForRead {
+                        user_id: hawk_user_id,
+                        collection: collection.collection,
+                        strong,
+                    }),
+                    _ => db.lock_for_write(params::LockCollection {
+                        user_id: hawk_user_id,
+                        collection: collection.collection,
+                    }),
                 })
             } else {
                 Either::Right(future::ok(()))
@@ -173,7 +230,7 @@ where
                         // we can't queue_report here (no access to extensions)
                         // so just report it immediately with tags on hand
                         if apie.is_reportable() {
-                            report(&tags, sentry::integrations::failure::event_from_fail(&apie));
+                            report(&tags, event_from_error(&apie));
                         } else {
                             debug!("Not reporting error: {:?}", apie);
                         }
@@ -186,3 +243,29 @@ where
         Box::pin(fut)
     }
 }
+
+/// Get a `Db` for the request
+///
+/// Reads are served by the read replica, when one's configured, unless it
+/// lags behind the `X-If-Modified-Since` timestamp the client has already
+/// seen: the primary serves those instead.
+async fn get_db(
+    state: Data<ServerState>,
+    read: bool,
+    user_id: HawkIdentifier,
+    since: Option<SyncTimestamp>,
+) -> Result<Box<dyn Db>, ApiError> {
+    let read_pool = match &state.db_read_pool {
+        Some(read_pool) if read => read_pool,
+        _ => return state.db_pool.get().await,
+    };
+    let db = read_pool.get().await?;
+    if let Some(since) = since {
+        if db.get_storage_timestamp(user_id).await? < since {
+            debug!("Read replica is stale, using the primary");
+            metrics::Metrics::from(&state).incr("db.read_replica.stale");
+            return state.db_pool.get().await;
+        }
+    }
+    Ok(db)
+}
@@ -13,7 +13,7 @@ use actix_web::{dev::ServiceRequest, Error};
 use crate::db::util::SyncTimestamp;
 use crate::error::{ApiError, ApiErrorKind};
 use crate::server::ServerState;
-use crate::web::{extractors::HawkIdentifier, tags::Tags, DOCKER_FLOW_ENDPOINTS};
+use crate::web::{dockerflow::is_dockerflow_request, extractors::HawkIdentifier, tags::Tags};
 
 /// The resource in question's Timestamp
 pub struct ResourceTimestamp(SyncTimestamp);
@@ -24,7 +24,7 @@ pub trait SyncServerRequest {
 
 impl SyncServerRequest for ServiceRequest {
     fn get_hawk_id(&self) -> Result<HawkIdentifier, Error> {
-        if DOCKER_FLOW_ENDPOINTS.contains(&self.uri().path().to_lowercase().as_str()) {
+        if is_dockerflow_request(self) {
             return Ok(HawkIdentifier::cmd_dummy());
         }
         let method = self.method().clone();
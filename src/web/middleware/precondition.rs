@@ -1,19 +1,25 @@
 use std::task::Context;
 use std::{cell::RefCell, rc::Rc};
 
+use crate::db::params;
+use crate::error::WeaveError;
+use crate::server::ServerState;
 use crate::web::middleware::sentry::queue_report;
 use crate::web::{
+    client_ip::ClientIp,
+    dockerflow::is_dockerflow_request,
     extractors::{
-        extrude_db, BsoParam, CollectionParam, PreConditionHeader, PreConditionHeaderOpt,
+        extrude_db, if_none_match_any, timestamp_slack, url_prefix, BsoParam, CollectionParam,
+        PreConditionHeader, PreConditionHeaderOpt,
     },
     middleware::SyncServerRequest,
     tags::Tags,
-    DOCKER_FLOW_ENDPOINTS, X_LAST_MODIFIED,
+    X_LAST_MODIFIED,
 };
 
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    http::{header, StatusCode},
+    http::{header, Method, StatusCode},
     Error, HttpMessage, HttpResponse,
 };
 use futures::future::{self, Either, FutureExt, LocalBoxFuture, TryFutureExt};
@@ -76,7 +82,7 @@ where
     }
 
     fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
-        if DOCKER_FLOW_ENDPOINTS.contains(&sreq.uri().path().to_lowercase().as_str()) {
+        if is_dockerflow_request(&sreq) {
             let mut service = Rc::clone(&self.service);
             return Box::new(service.call(sreq)).boxed_local();
         }
@@ -89,25 +95,29 @@ where
                 None => Tags::from_request_head(sreq.head()),
             }
         };
-        let precondition = match PreConditionHeaderOpt::extrude(&sreq.headers(), Some(tags.clone()))
-        {
-            Ok(precond) => match precond.opt {
-                Some(p) => p,
-                None => PreConditionHeader::NoHeader,
-            },
-            Err(e) => {
-                warn!("⚠️ Precondition error {:?}", e);
-                queue_report(sreq.extensions_mut(), &e);
-                return Box::pin(future::ok(
-                    sreq.into_response(
-                        HttpResponse::BadRequest()
-                            .content_type("application/json")
-                            .body("An error occurred in preprocessing".to_owned())
-                            .into_body(),
-                    ),
-                ));
-            }
-        };
+        let slack_secs = timestamp_slack(sreq.app_data::<ServerState>().as_deref());
+        let precondition =
+            match PreConditionHeaderOpt::extrude(&sreq.headers(), slack_secs, Some(tags.clone())) {
+                Ok(precond) => match precond.opt {
+                    Some(p) => p,
+                    None => PreConditionHeader::NoHeader,
+                },
+                Err(e) => {
+                    warn!(
+                        "⚠️ Precondition error {:?}", e;
+                        "client_ip" => ClientIp::log_value(&sreq)
+                    );
+                    queue_report(sreq.extensions_mut(), &e);
+                    return Box::pin(future::ok(
+                        sreq.into_response(
+                            HttpResponse::BadRequest()
+                                .content_type("application/json")
+                                .body("An error occurred in preprocessing".to_owned())
+                                .into_body(),
+                        ),
+                    ));
+                }
+            };
         let user_id = match sreq.get_hawk_id() {
             Ok(v) => v,
             Err(e) => {
@@ -140,7 +150,9 @@ where
             }
         };
         let uri = &sreq.uri();
-        let col_result = CollectionParam::extrude(&uri, &mut sreq.extensions_mut(), &tags);
+        let url_prefix = url_prefix(sreq.app_data::<ServerState>().as_deref());
+        let col_result =
+            CollectionParam::extrude(&uri, &mut sreq.extensions_mut(), &url_prefix, &tags);
         let collection = match col_result {
             Ok(v) => v.map(|c| c.collection),
             Err(e) => {
@@ -156,14 +168,46 @@ where
                 ));
             }
         };
-        let bso = BsoParam::extrude(sreq.head(), &mut sreq.extensions_mut()).ok();
+        let bso = BsoParam::extrude(sreq.head(), &mut sreq.extensions_mut(), &url_prefix).ok();
         let bso_opt = bso.map(|b| b.bso);
+        // Create only PUTs of existing BSOs fail early (put_bso also checks,
+        // atomically with the write)
+        let create_only =
+            *sreq.method() == Method::PUT && bso_opt.is_some() && if_none_match_any(sreq.headers());
+
+        // Collection GETs return the collection's ETag, writes within it
+        // may be conditional upon it
+        let read = matches!(*sreq.method(), Method::GET | Method::HEAD);
+        let if_match = if read {
+            None
+        } else {
+            sreq.headers()
+                .get(header::IF_MATCH)
+                .map(|value| value.to_str().unwrap_or_default().to_owned())
+        };
+        let etag_params = match &collection {
+            Some(collection) if (read && bso_opt.is_none()) || if_match.is_some() => {
+                Some(params::GetCollectionEtag {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                })
+            }
+            _ => None,
+        };
 
         let mut service = Rc::clone(&self.service);
+        let db2 = db.clone();
         Box::pin(
             db.extract_resource(user_id, collection, bso_opt)
+                .and_then(move |resource_ts| match etag_params {
+                    Some(params) => Either::Left(
+                        db2.get_collection_etag(params)
+                            .map_ok(move |etag| (resource_ts, Some(etag))),
+                    ),
+                    None => Either::Right(future::ok((resource_ts, None))),
+                })
                 .map_err(Into::into)
-                .and_then(move |resource_ts| {
+                .and_then(move |(resource_ts, etag)| {
                     let status = match precondition {
                         PreConditionHeader::IfModifiedSince(header_ts)
                             if resource_ts <= header_ts =>
@@ -177,22 +221,45 @@ where
                         }
                         _ => StatusCode::OK,
                     };
+                    let status = if create_only && resource_ts.as_i64() > 0 {
+                        StatusCode::PRECONDITION_FAILED
+                    } else {
+                        status
+                    };
+                    let status = match (&if_match, &etag) {
+                        (Some(if_match), Some(etag)) if !etag_matches(if_match, etag) => {
+                            StatusCode::PRECONDITION_FAILED
+                        }
+                        _ => status,
+                    };
                     if status != StatusCode::OK {
-                        return Either::Left(future::ok(
-                            sreq.into_response(
-                                HttpResponse::build(status)
-                                    .content_type("application/json")
-                                    .header(X_LAST_MODIFIED, resource_ts.as_header())
-                                    .body("".to_owned())
-                                    .into_body(),
-                            ),
-                        ));
+                        let mut builder = HttpResponse::build(status);
+                        if let Some(etag) = &etag {
+                            builder.header(header::ETAG, etag.as_str());
+                        }
+                        builder
+                            .content_type("application/json")
+                            .header(X_LAST_MODIFIED, resource_ts.as_header());
+                        // A failed precondition's an error (with a Weave error
+                        // body), unlike a 304
+                        let response = if status == StatusCode::PRECONDITION_FAILED {
+                            builder.json(WeaveError::UnknownError as i32)
+                        } else {
+                            builder.body("".to_owned())
+                        };
+                        return Either::Left(future::ok(sreq.into_response(response.into_body())));
                     };
 
                     // Make the call, then do all the post-processing steps.
                     Either::Right(service.call(sreq).map(move |resp| {
                         let mut resp =
                             resp.expect("Could not get resp in PreConditionCheckMiddleware::call");
+                        // Only a read's ETag is current as of its response
+                        if let Some(etag) = etag.filter(|_| read) {
+                            if let Ok(etag) = header::HeaderValue::from_str(&etag) {
+                                resp.headers_mut().insert(header::ETAG, etag);
+                            }
+                        }
                         if resp.headers().contains_key(X_LAST_MODIFIED) {
                             return Ok(resp);
                         }
@@ -213,3 +280,11 @@ where
         )
     }
 }
+
+/// Whether an `If-Match` header (a list of ETags or `*`) matches the ETag
+fn etag_matches(if_match: &str, etag: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
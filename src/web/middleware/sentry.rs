@@ -1,5 +1,6 @@
 use std::task::Context;
 use std::{
+    borrow::Cow,
     cell::{RefCell, RefMut},
     rc::Rc,
 };
@@ -14,7 +15,10 @@ use sentry::protocol::Event;
 use std::task::Poll;
 
 use crate::error::ApiError;
-use crate::web::tags::Tags;
+use crate::web::{
+    client_ip::{client_ip, ClientIp},
+    tags::Tags,
+};
 
 pub struct SentryWrapper;
 
@@ -62,7 +66,7 @@ pub fn queue_report(mut ext: RefMut<'_, Extensions>, err: &Error) {
             debug!("Not reporting error: {:?}", err);
             return;
         }
-        let event = sentry::integrations::failure::event_from_fail(apie);
+        let event = event_from_error(apie);
         if let Some(events) = ext.get_mut::<Vec<Event<'static>>>() {
             events.push(event);
         } else {
@@ -73,9 +77,35 @@ pub fn queue_report(mut ext: RefMut<'_, Extensions>, err: &Error) {
     }
 }
 
+/// Build a Sentry event from the error, tagging (and fingerprinting) db errors
+/// with the backend and operation they originated from so distinct failures
+/// group separately
+pub fn event_from_error(apie: &ApiError) -> Event<'static> {
+    let mut event = sentry::integrations::failure::event_from_fail(apie);
+    if let Some((backend, operation)) = apie.db_labels() {
+        error!(
+            "⚠️ Database error";
+            "db.backend" => backend,
+            "db.operation" => operation
+        );
+        event
+            .tags
+            .insert("db.backend".to_owned(), backend.to_owned());
+        event
+            .tags
+            .insert("db.operation".to_owned(), operation.to_owned());
+        event.fingerprint = Cow::Owned(vec![
+            Cow::Borrowed("{{ default }}"),
+            Cow::Borrowed(backend),
+            Cow::Borrowed(operation),
+        ]);
+    }
+    event
+}
+
 pub fn report(tags: &Tags, mut event: Event<'static>) {
     let tags = tags.clone();
-    event.tags = tags.clone().tag_tree();
+    event.tags.extend(tags.clone().tag_tree());
     event.extra = tags.extra_tree();
     debug!("Sending error to sentry: {:?}", &event);
     sentry::capture_event(event);
@@ -99,6 +129,11 @@ where
     fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
         let mut tags = Tags::from_request_head(sreq.head());
         let uri = sreq.head().uri.to_string();
+        if let Some(ip) = client_ip(&sreq) {
+            // Extra (not a tag): too high cardinality for metrics
+            tags.extra.insert("client_ip".to_owned(), ip.to_string());
+            sreq.extensions_mut().insert(ClientIp(ip));
+        }
         sreq.extensions_mut().insert(tags.clone());
 
         Box::pin(self.service.call(sreq).and_then(move |mut sresp| {
@@ -153,7 +188,7 @@ where
                         }
                     }
                     if let Some(apie) = apie {
-                        report(&tags, sentry::integrations::failure::event_from_fail(apie));
+                        report(&tags, event_from_error(apie));
                     }
                 }
             }
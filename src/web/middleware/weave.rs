@@ -12,7 +12,11 @@ use std::task::Poll;
 
 use crate::db::util::SyncTimestamp;
 use crate::error::{ApiError, ApiErrorKind};
-use crate::web::{DOCKER_FLOW_ENDPOINTS, X_LAST_MODIFIED, X_WEAVE_TIMESTAMP};
+use crate::server::ServerState;
+use crate::web::{
+    dockerflow::is_dockerflow_request, X_LAST_MODIFIED, X_WEAVE_TIMESTAMP,
+    X_WEAVE_TIMESTAMP_PRECISION,
+};
 
 pub struct WeaveTimestampMiddleware<S> {
     service: S,
@@ -34,14 +38,15 @@ where
     }
 
     fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
-        if DOCKER_FLOW_ENDPOINTS.contains(&sreq.uri().path().to_lowercase().as_str()) {
+        if is_dockerflow_request(&sreq) {
             return Box::pin(self.service.call(sreq));
         }
 
-        let ts = SyncTimestamp::default().as_seconds();
+        let ts = SyncTimestamp::default();
+        let millis = wants_millis(&sreq);
         Box::pin(self.service.call(sreq).and_then(move |mut resp| {
             future::ready(
-                set_weave_timestamp(resp.headers_mut(), ts)
+                set_weave_timestamp(resp.headers_mut(), ts, millis)
                     .map_err(Into::into)
                     .map(|_| resp),
             )
@@ -49,9 +54,28 @@ where
     }
 }
 
+/// Whether the client requested (and is allowed) millisecond precision
+/// timestamp headers
+fn wants_millis(sreq: &ServiceRequest) -> bool {
+    let allowed = sreq
+        .app_data::<ServerState>()
+        .map_or(false, |state| state.allow_millisecond_timestamps);
+    allowed
+        && sreq
+            .headers()
+            .get(X_WEAVE_TIMESTAMP_PRECISION)
+            .map_or(false, |precision| precision.as_bytes() == b"ms")
+}
+
 /// Set a X-Weave-Timestamp header on all responses (depending on the
 /// response's X-Last-Modified header)
-fn set_weave_timestamp(headers: &mut HeaderMap, ts: f64) -> Result<(), ApiError> {
+///
+/// Both are rendered with millisecond precision when `millis` is set.
+fn set_weave_timestamp(
+    headers: &mut HeaderMap,
+    ts: SyncTimestamp,
+    millis: bool,
+) -> Result<(), ApiError> {
     fn invalid_xlm<E>(e: E) -> ApiError
     where
         E: Display,
@@ -59,12 +83,25 @@ fn set_weave_timestamp(headers: &mut HeaderMap, ts: f64) -> Result<(), ApiError>
         ApiErrorKind::Internal(format!("Invalid X-Last-Modified response header: {}", e)).into()
     }
 
+    let as_header = |ts: SyncTimestamp| {
+        let val = if millis {
+            ts.as_header_millis()
+        } else {
+            ts.as_header()
+        };
+        header::HeaderValue::from_str(&val).map_err(invalid_xlm)
+    };
     let weave_ts = if let Some(val) = headers.get(X_LAST_MODIFIED) {
-        let resp_ts = val
-            .to_str()
-            .map_err(invalid_xlm)?
-            .parse::<f64>()
-            .map_err(invalid_xlm)?;
+        // The server's own timestamp: parsed as is (never bounded by the
+        // client timestamp slack, however far its clock may have drifted)
+        let resp_ts =
+            SyncTimestamp::from_header(val.to_str().map_err(invalid_xlm)?).map_err(invalid_xlm)?;
+        if millis {
+            headers.insert(
+                header::HeaderName::from_static(X_LAST_MODIFIED),
+                as_header(resp_ts)?,
+            );
+        }
         if resp_ts > ts {
             resp_ts
         } else {
@@ -75,7 +112,7 @@ fn set_weave_timestamp(headers: &mut HeaderMap, ts: f64) -> Result<(), ApiError>
     };
     headers.insert(
         header::HeaderName::from_static(X_WEAVE_TIMESTAMP),
-        header::HeaderValue::from_str(&format!("{:.2}", &weave_ts)).map_err(invalid_xlm)?,
+        as_header(weave_ts)?,
     );
     Ok(())
 }
@@ -122,7 +159,7 @@ mod tests {
     #[test]
     fn test_no_modified_header() {
         let mut resp = HttpResponse::build(http::StatusCode::OK).finish();
-        set_weave_timestamp(resp.headers_mut(), SyncTimestamp::default().as_seconds()).unwrap();
+        set_weave_timestamp(resp.headers_mut(), SyncTimestamp::default(), false).unwrap();
         let weave_hdr = resp
             .headers()
             .get(X_WEAVE_TIMESTAMP)
@@ -142,11 +179,16 @@ mod tests {
     #[test]
     fn test_older_timestamp() {
         let ts = (Utc::now().timestamp_millis() as u64) - 1000;
-        let hts = format!("{:.*}", 2, ts as f64 / 1_000.0);
+        let hts = SyncTimestamp::from_milliseconds(ts).as_header();
         let mut resp = HttpResponse::build(http::StatusCode::OK)
             .header(X_LAST_MODIFIED, hts.clone())
             .finish();
-        set_weave_timestamp(resp.headers_mut(), ts as f64).unwrap();
+        set_weave_timestamp(
+            resp.headers_mut(),
+            SyncTimestamp::from_milliseconds(ts + 1000),
+            false,
+        )
+        .unwrap();
         let weave_hdr = resp
             .headers()
             .get(X_WEAVE_TIMESTAMP)
@@ -162,11 +204,16 @@ mod tests {
     #[test]
     fn test_newer_timestamp() {
         let ts = (Utc::now().timestamp_millis() as u64) + 4000;
-        let hts = format!("{:.2}", ts as f64 / 1_000.0);
+        let hts = SyncTimestamp::from_milliseconds(ts).as_header();
         let mut resp = HttpResponse::build(http::StatusCode::OK)
             .header(X_LAST_MODIFIED, hts.clone())
             .finish();
-        set_weave_timestamp(resp.headers_mut(), ts as f64 / 1_000.0).unwrap();
+        set_weave_timestamp(
+            resp.headers_mut(),
+            SyncTimestamp::from_milliseconds(ts - 4000),
+            false,
+        )
+        .unwrap();
         let weave_hdr = resp
             .headers()
             .get(X_WEAVE_TIMESTAMP)
@@ -175,4 +222,31 @@ mod tests {
             .unwrap();
         assert_eq!(weave_hdr, hts);
     }
+
+    #[test]
+    fn test_far_future_timestamp() {
+        // Beyond any client timestamp slack, yet passed through
+        let ts =
+            SyncTimestamp::from_milliseconds(Utc::now().timestamp_millis() as u64 + 86_400_000);
+        let mut resp = HttpResponse::build(http::StatusCode::OK)
+            .header(X_LAST_MODIFIED, ts.as_header())
+            .finish();
+        set_weave_timestamp(resp.headers_mut(), SyncTimestamp::default(), false).unwrap();
+        let weave_hdr = resp.headers().get(X_WEAVE_TIMESTAMP).unwrap();
+        assert_eq!(weave_hdr.to_str().unwrap(), ts.as_header());
+    }
+
+    #[test]
+    fn test_millis() {
+        let ts = SyncTimestamp::from_milliseconds(Utc::now().timestamp_millis() as u64);
+        let mut resp = HttpResponse::build(http::StatusCode::OK)
+            .header(X_LAST_MODIFIED, ts.as_header())
+            .finish();
+        set_weave_timestamp(resp.headers_mut(), ts, true).unwrap();
+        let expected = ts.as_header_millis();
+        for name in &[X_LAST_MODIFIED, X_WEAVE_TIMESTAMP] {
+            let hdr = resp.headers().get(*name).unwrap().to_str().unwrap();
+            assert_eq!(hdr, expected);
+        }
+    }
 }
@@ -1,9 +1,12 @@
 //! Web authentication, handlers, and middleware
 pub mod auth;
+pub mod client_ip;
+pub mod dockerflow;
 pub mod error;
 pub mod extractors;
 pub mod handlers;
 pub mod middleware;
+pub mod schema;
 pub mod tags;
 pub mod tokenserver;
 
@@ -12,6 +15,10 @@ pub static X_LAST_MODIFIED: &str = "x-last-modified";
 pub static X_WEAVE_TIMESTAMP: &str = "x-weave-timestamp";
 pub static X_WEAVE_NEXT_OFFSET: &str = "x-weave-next-offset";
 pub static X_WEAVE_RECORDS: &str = "x-weave-records";
+pub static X_WEAVE_TOTAL_RECORDS: &str = "x-weave-total-records";
+pub static X_WEAVE_ALERT: &str = "x-weave-alert";
+pub static X_WEAVE_TIMESTAMP_PRECISION: &str = "x-weave-timestamp-precision";
+pub static X_WEAVE_REPLACE: &str = "x-weave-replace";
 
 // Known DockerFlow commands for Ops callbacks
 pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
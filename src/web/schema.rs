@@ -0,0 +1,89 @@
+//! Optional per-collection JSON schema validation of BSO payloads
+use std::{collections::HashMap, fmt, fs};
+
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::error::{ApiError, ApiErrorKind};
+
+/// Compiled JSON schemas that the payloads of the configured collections must
+/// conform to. Payloads of any other collection are unchecked.
+#[derive(Default)]
+pub struct PayloadSchemas {
+    schemas: HashMap<String, JSONSchema<'static>>,
+}
+
+impl PayloadSchemas {
+    /// Load and compile the JSON schema files configured per collection name
+    pub fn from_paths(paths: &HashMap<String, String>) -> Result<Self, ApiError> {
+        let mut schemas = HashMap::new();
+        for (collection, path) in paths {
+            let error = |e: &dyn fmt::Display| -> ApiError {
+                ApiErrorKind::Internal(format!(
+                    "Invalid payload schema for {} ({}): {}",
+                    collection, path, e
+                ))
+                .into()
+            };
+            let schema: Value =
+                serde_json::from_str(&fs::read_to_string(path).map_err(|e| error(&e))?)
+                    .map_err(|e| error(&e))?;
+            // Compiled schemas borrow their source, which is needed for the
+            // lifetime of the server
+            let schema: &'static Value = Box::leak(Box::new(schema));
+            let compiled = JSONSchema::compile(schema, None).map_err(|e| error(&e))?;
+            schemas.insert(collection.to_owned(), compiled);
+        }
+        Ok(Self { schemas })
+    }
+
+    /// Validate a payload against its collection's schema (if any), returning
+    /// a description of the failures
+    pub fn validate(&self, collection: &str, payload: &str) -> Result<(), String> {
+        let schema = match self.schemas.get(collection) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let payload: Value = serde_json::from_str(payload)
+            .map_err(|e| format!("Payload is not valid JSON: {}", e))?;
+        schema
+            .validate(&payload)
+            .map_err(|errors| errors.map(|e| e.to_string()).collect::<Vec<_>>().join("; "))
+    }
+}
+
+impl fmt::Debug for PayloadSchemas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut collections: Vec<_> = self.schemas.keys().collect();
+        collections.sort();
+        write!(f, "PayloadSchemas {{ collections: {:?} }}", collections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, fs};
+
+    use super::PayloadSchemas;
+
+    #[test]
+    fn validates_configured_collections() {
+        let path = env::temp_dir().join(format!("payload_schema_{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"type": "object", "required": ["title"], "properties": {"title": {"type": "string"}}}"#,
+        )
+        .unwrap();
+        let mut paths = HashMap::new();
+        paths.insert("bookmarks".to_owned(), path.to_string_lossy().into_owned());
+        let schemas = PayloadSchemas::from_paths(&paths).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(schemas.validate("bookmarks", r#"{"title": "x"}"#).is_ok());
+        assert!(schemas.validate("bookmarks", r#"{"title": 1}"#).is_err());
+        assert!(schemas.validate("bookmarks", "{}").is_err());
+        assert!(schemas.validate("bookmarks", "not json").is_err());
+        // Other collections are unchecked
+        assert!(schemas.validate("history", "not json").is_ok());
+    }
+}
@@ -14,6 +14,7 @@ use serde::{
 use serde_json::value::Value;
 use slog::{Key, Record, KV};
 
+use crate::db::is_std_collection;
 use crate::server::user_agent::parse_user_agent;
 
 #[derive(Clone, Debug)]
@@ -94,6 +95,19 @@ impl Tags {
         self.tags.extend(tags);
     }
 
+    /// Tag the collection: only standard collections are tagged by name,
+    /// custom collections are all tagged as "other" (bounding the metrics'
+    /// cardinality)
+    pub fn insert_collection(&mut self, collection: &str) {
+        let collection = if is_std_collection(collection) {
+            collection
+        } else {
+            "other"
+        };
+        self.tags
+            .insert("collection".to_owned(), collection.to_owned());
+    }
+
     pub fn tag_tree(self) -> BTreeMap<String, String> {
         let mut result = BTreeMap::new();
 
@@ -151,3 +165,18 @@ impl KV for Tags {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Tags;
+
+    #[test]
+    fn collection_tags() {
+        let mut tags = Tags::default();
+        tags.insert_collection("history");
+        assert_eq!(tags.get("collection"), "history");
+        // Custom collections are bucketed together
+        tags.insert_collection("my-extension");
+        assert_eq!(tags.get("collection"), "other");
+    }
+}